@@ -9,7 +9,8 @@ use {
             Response, RpcAccountBalance, RpcBlockProduction, RpcBlockProductionRange, RpcBlockhash,
             RpcConfirmedTransactionStatusWithSignature, RpcContactInfo, RpcFees, RpcIdentity,
             RpcInflationGovernor, RpcInflationRate, RpcInflationReward, RpcKeyedAccount,
-            RpcPerfSample, RpcResponseContext, RpcSimulateTransactionResult, RpcSnapshotSlotInfo,
+            RpcPerfSample, RpcResponseContext, RpcSimulateTransactionResult,
+            RpcSnapshotArchiveInfo, RpcSnapshotArchivesInfo, RpcSnapshotSlotInfo,
             RpcStakeActivation, RpcSupply, RpcVersionInfo, RpcVoteAccountInfo,
             RpcVoteAccountStatus, StakeActivationState,
         },
@@ -42,6 +43,7 @@ use {
 pub const PUBKEY: &str = "7RoSF9fUmdphVCpabEoefH81WwrW7orsWonXWqTXkKV8";
 pub const SIGNATURE: &str =
     "43yNSFC6fYTuPgTNFFhF4axw7AfWxB2BPdurme8yrsWEYwm8299xh8n6TAHjGymiSub1XtyxTNyd9GBfY2hxoBw8";
+const SNAPSHOT_HASH: &str = "AvFf9oS8A8U78HdjT9YG2sTTThLHJZmhaMn2g8vkWYnr";
 
 pub type Mocks = HashMap<RpcRequest, Value>;
 pub struct MockSender {
@@ -235,6 +237,20 @@ impl RpcSender for MockSender {
                 full: 100,
                 incremental: Some(110),
             }),
+            "getHighestSnapshotArchives" => json!(RpcSnapshotArchivesInfo {
+                full: RpcSnapshotArchiveInfo {
+                    slot: 100,
+                    hash: SNAPSHOT_HASH.to_string(),
+                    size: 1_000_000,
+                    path: format!("/snapshot-100-{}.tar.zst", SNAPSHOT_HASH),
+                },
+                incremental: vec![RpcSnapshotArchiveInfo {
+                    slot: 110,
+                    hash: SNAPSHOT_HASH.to_string(),
+                    size: 10_000,
+                    path: format!("/incremental-snapshot-100-110-{}.tar.zst", SNAPSHOT_HASH),
+                }],
+            }),
             "getBlockHeight" => Value::Number(Number::from(1234)),
             "getSlotLeaders" => json!([PUBKEY]),
             "getBlockProduction" => {
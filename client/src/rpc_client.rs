@@ -1341,6 +1341,30 @@ impl RpcClient {
         }
     }
 
+    /// Returns the hash, size, and download path of the highest full snapshot archive the node
+    /// has, and of every incremental snapshot archive _based on_ it.
+    ///
+    /// # RPC Reference
+    ///
+    /// This method corresponds directly to the [`getHighestSnapshotArchives`] RPC method.
+    ///
+    /// [`getHighestSnapshotArchives`]: https://docs.solana.com/developing/clients/jsonrpc-api#gethighestsnapshotarchives
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use solana_client::{
+    /// #     rpc_client::RpcClient,
+    /// #     client_error::ClientError,
+    /// # };
+    /// # let rpc_client = RpcClient::new_mock("succeeds".to_string());
+    /// let snapshot_archives_info = rpc_client.get_highest_snapshot_archives()?;
+    /// # Ok::<(), ClientError>(())
+    /// ```
+    pub fn get_highest_snapshot_archives(&self) -> ClientResult<RpcSnapshotArchivesInfo> {
+        self.send(RpcRequest::GetHighestSnapshotArchives, Value::Null)
+    }
+
     #[deprecated(
         since = "1.8.0",
         note = "Please use RpcClient::get_highest_snapshot_slot() instead"
@@ -80,6 +80,7 @@ pub enum RpcRequest {
     GetRecentBlockhash,
     GetRecentPerformanceSamples,
     GetHighestSnapshotSlot,
+    GetHighestSnapshotArchives,
     #[deprecated(
         since = "1.9.0",
         note = "Please use RpcRequest::GetHighestSnapshotSlot instead"
@@ -157,6 +158,7 @@ impl fmt::Display for RpcRequest {
             RpcRequest::GetRecentBlockhash => "getRecentBlockhash",
             RpcRequest::GetRecentPerformanceSamples => "getRecentPerformanceSamples",
             RpcRequest::GetHighestSnapshotSlot => "getHighestSnapshotSlot",
+            RpcRequest::GetHighestSnapshotArchives => "getHighestSnapshotArchives",
             RpcRequest::GetSnapshotSlot => "getSnapshotSlot",
             RpcRequest::GetSignaturesForAddress => "getSignaturesForAddress",
             RpcRequest::GetSignatureStatuses => "getSignatureStatuses",
@@ -449,3 +449,22 @@ pub struct RpcSnapshotSlotInfo {
     pub full: Slot,
     pub incremental: Option<Slot>,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcSnapshotArchiveInfo {
+    pub slot: Slot,
+    pub hash: String,
+    /// Size of the (compressed) archive, in bytes
+    pub size: u64,
+    /// Path the archive is served under by the node's RPC HTTP server
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RpcSnapshotArchivesInfo {
+    pub full: RpcSnapshotArchiveInfo,
+    /// The incremental snapshot archives based on `full`, from the lowest slot to the highest
+    pub incremental: Vec<RpcSnapshotArchiveInfo>,
+}
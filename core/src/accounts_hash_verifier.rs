@@ -279,7 +279,10 @@ impl AccountsHashVerifier {
 mod tests {
     use super::*;
     use solana_gossip::{cluster_info::make_accounts_hashes_message, contact_info::ContactInfo};
-    use solana_runtime::snapshot_utils::{ArchiveFormat, SnapshotVersion};
+    use solana_runtime::{
+        snapshot_profile::SnapshotProfiler,
+        snapshot_utils::{ArchiveFormat, SnapshotVersion},
+    };
     use solana_sdk::{
         genesis_config::ClusterType,
         hash::hash,
@@ -355,6 +358,7 @@ mod tests {
                 slot_deltas: vec![],
                 snapshot_links: TempDir::new().unwrap(),
                 snapshot_storages: vec![],
+                pruned_storages_dir: None,
                 hash: hash(&[i as u8]),
                 archive_format: ArchiveFormat::TarBzip2,
                 snapshot_version: SnapshotVersion::default(),
@@ -363,6 +367,8 @@ mod tests {
                 hash_for_testing: None,
                 cluster_type: ClusterType::MainnetBeta,
                 snapshot_type: None,
+                profiler: SnapshotProfiler::default(),
+                deterministic_serialization: false,
             };
 
             let ledger_path = TempDir::new().unwrap();
@@ -1718,6 +1718,10 @@ impl ReplayStage {
         if authorized_voter_keypairs.is_empty() {
             return None;
         }
+        if bank.is_accounts_hash_verification_pending() {
+            info!("Snapshot bank accounts hash is still being verified.  Unable to vote");
+            return None;
+        }
         let vote_account = match bank.get_vote_account(vote_account_pubkey) {
             None => {
                 warn!(
@@ -1,27 +1,34 @@
-use solana_gossip::cluster_info::{
-    ClusterInfo, MAX_INCREMENTAL_SNAPSHOT_HASHES, MAX_SNAPSHOT_HASHES,
+use solana_gossip::{
+    cluster_info::{ClusterInfo, MAX_INCREMENTAL_SNAPSHOT_HASHES, MAX_SNAPSHOT_HASHES},
+    crds_gossip_pull::CRDS_GOSSIP_PULL_CRDS_TIMEOUT_MS,
 };
 use solana_perf::thread::renice_this_thread;
 use solana_runtime::{
-    snapshot_archive_info::SnapshotArchiveInfoGetter,
+    snapshot_archive_info::{SnapshotArchiveInfo, SnapshotArchiveInfoGetter},
     snapshot_config::SnapshotConfig,
     snapshot_hash::{
         FullSnapshotHash, FullSnapshotHashes, IncrementalSnapshotHash, IncrementalSnapshotHashes,
         StartingSnapshotHashes,
     },
     snapshot_package::{PendingSnapshotPackage, SnapshotType},
-    snapshot_utils,
+    snapshot_utils::{self, SnapshotError},
 };
 use solana_sdk::{clock::Slot, hash::Hash};
 use std::{
+    fs,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     thread::{self, Builder, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+/// Push the snapshot hashes and archive sizes again no more often than a node pushes its own
+/// contact info
+pub const MIN_SNAPSHOT_GOSSIP_PUSH_INTERVAL_MS: u64 = CRDS_GOSSIP_PULL_CRDS_TIMEOUT_MS / 2;
+
 pub struct SnapshotPackagerService {
     t_snapshot_packager: JoinHandle<()>,
 }
@@ -37,14 +44,21 @@ impl SnapshotPackagerService {
     ) -> Self {
         let exit = exit.clone();
         let cluster_info = cluster_info.clone();
-        let max_full_snapshot_hashes = std::cmp::min(
-            MAX_SNAPSHOT_HASHES,
-            snapshot_config.maximum_full_snapshot_archives_to_retain,
-        );
-        let max_incremental_snapshot_hashes = std::cmp::min(
-            MAX_INCREMENTAL_SNAPSHOT_HASHES,
-            snapshot_config.maximum_incremental_snapshot_archives_to_retain,
-        );
+        let gossip_advertisement_depth = snapshot_config
+            .gossip_advertisement_depth
+            .unwrap_or(usize::MAX);
+        // The latest full snapshot hash is always advertised, since the incremental snapshot
+        // hashes are based on it
+        let max_full_snapshot_hashes = MAX_SNAPSHOT_HASHES
+            .min(snapshot_config.maximum_full_snapshot_archives_to_retain)
+            .min(gossip_advertisement_depth)
+            .max(1);
+        let max_incremental_snapshot_hashes = MAX_INCREMENTAL_SNAPSHOT_HASHES
+            .min(snapshot_config.maximum_incremental_snapshot_archives_to_retain)
+            .min(gossip_advertisement_depth);
+        let gossip_push_interval = snapshot_config.gossip_push_interval_ms.map(|interval_ms| {
+            Duration::from_millis(interval_ms.max(MIN_SNAPSHOT_GOSSIP_PUSH_INTERVAL_MS))
+        });
 
         let t_snapshot_packager = Builder::new()
             .name("snapshot-packager".to_string())
@@ -57,12 +71,19 @@ impl SnapshotPackagerService {
                         max_incremental_snapshot_hashes,
                         full_snapshot_hashes: FullSnapshotHashes::default(),
                         incremental_snapshot_hashes: IncrementalSnapshotHashes::default(),
+                        full_snapshot_archive_sizes: Vec::default(),
+                        incremental_snapshot_archive_sizes: Vec::default(),
+                        push_interval: gossip_push_interval,
+                        last_push: Instant::now(),
                     })
                 } else {
                     None
                 };
                 if let Some(snapshot_gossip_manager) = snapshot_gossip_manager.as_mut() {
-                    snapshot_gossip_manager.push_starting_snapshot_hashes(starting_snapshot_hashes);
+                    snapshot_gossip_manager.push_starting_snapshot_hashes(
+                        starting_snapshot_hashes,
+                        &snapshot_config.snapshot_archives_dir,
+                    );
                 }
 
                 loop {
@@ -72,26 +93,38 @@ impl SnapshotPackagerService {
 
                     let snapshot_package = pending_snapshot_package.lock().unwrap().take();
                     if snapshot_package.is_none() {
+                        if let Some(snapshot_gossip_manager) = snapshot_gossip_manager.as_mut() {
+                            snapshot_gossip_manager.push_again_if_due();
+                        }
                         std::thread::sleep(Duration::from_millis(100));
                         continue;
                     }
                     let snapshot_package = snapshot_package.unwrap();
 
-                    // Archiving the snapshot package is not allowed to fail.
-                    // AccountsBackgroundService calls `clean_accounts()` with a value for
-                    // last_full_snapshot_slot that requires this archive call to succeed.
-                    snapshot_utils::archive_snapshot_package(
+                    // Archiving the snapshot package is not allowed to fail, other than by a
+                    // hook vetoing it.  AccountsBackgroundService calls `clean_accounts()` with a
+                    // value for last_full_snapshot_slot that requires this archive call to
+                    // succeed, unless there are hooks.  Then it waits for the archive of a full
+                    // snapshot to be published, which is recorded in the hooks, before it cleans
+                    // with its slot or bases incremental snapshots on it.
+                    match snapshot_utils::archive_snapshot_package(
                         &snapshot_package,
-                        snapshot_config.maximum_full_snapshot_archives_to_retain,
-                        snapshot_config.maximum_incremental_snapshot_archives_to_retain,
-                    )
-                    .expect("failed to archive snapshot package");
+                        &snapshot_config,
+                    ) {
+                        Ok(()) => {}
+                        Err(SnapshotError::VetoedByHook(..)) => continue,
+                        Err(err) => panic!("failed to archive snapshot package: {:?}", err),
+                    }
 
                     if let Some(snapshot_gossip_manager) = snapshot_gossip_manager.as_mut() {
-                        snapshot_gossip_manager.push_snapshot_hash(
-                            snapshot_package.snapshot_type,
-                            (snapshot_package.slot(), *snapshot_package.hash()),
-                        );
+                        // Peers without the storage master key could not load encrypted archives
+                        if !is_archive_encrypted(snapshot_package.snapshot_archive_info()) {
+                            snapshot_gossip_manager.push_snapshot_hash(
+                                snapshot_package.snapshot_type,
+                                (snapshot_package.slot(), *snapshot_package.hash()),
+                                archive_size(snapshot_package.path()),
+                            );
+                        }
                     }
                 }
             })
@@ -113,33 +146,98 @@ struct SnapshotGossipManager {
     max_incremental_snapshot_hashes: usize,
     full_snapshot_hashes: FullSnapshotHashes,
     incremental_snapshot_hashes: IncrementalSnapshotHashes,
+    /// The sizes of the archives of the snapshots in `full_snapshot_hashes` and
+    /// `incremental_snapshot_hashes` that are known, by slot
+    full_snapshot_archive_sizes: Vec<(Slot, u64)>,
+    incremental_snapshot_archive_sizes: Vec<(Slot, u64)>,
+    /// Push the snapshot hashes and archive sizes again this long after they were last pushed
+    push_interval: Option<Duration>,
+    last_push: Instant,
 }
 
 impl SnapshotGossipManager {
     /// If there were starting snapshot hashes, add those to their respective vectors, then push
-    /// those vectors to the cluster via CRDS.
+    /// those vectors to the cluster via CRDS.  The sizes of their archives are looked up in
+    /// `snapshot_archives_dir`.  Neither is pushed if the full snapshot archive is encrypted, and
+    /// the incremental one is not if its archive is.
     fn push_starting_snapshot_hashes(
         &mut self,
         starting_snapshot_hashes: Option<StartingSnapshotHashes>,
+        snapshot_archives_dir: &Path,
     ) {
         if let Some(starting_snapshot_hashes) = starting_snapshot_hashes {
             let starting_full_snapshot_hash = starting_snapshot_hashes.full;
-            self.push_full_snapshot_hash(starting_full_snapshot_hash);
+            let full_snapshot_archives =
+                snapshot_utils::get_full_snapshot_archives(snapshot_archives_dir);
+            let archive_info = find_archive(
+                full_snapshot_archives
+                    .iter()
+                    .map(|archive_info| archive_info.snapshot_archive_info()),
+                starting_full_snapshot_hash.hash,
+            );
+            if archive_info.map_or(false, is_archive_encrypted) {
+                return;
+            }
+            self.push_full_snapshot_hash(
+                starting_full_snapshot_hash,
+                archive_info.and_then(|archive_info| archive_size(&archive_info.path)),
+            );
 
             if let Some(starting_incremental_snapshot_hash) = starting_snapshot_hashes.incremental {
-                self.push_incremental_snapshot_hash(starting_incremental_snapshot_hash);
+                let incremental_snapshot_archives =
+                    snapshot_utils::get_incremental_snapshot_archives(snapshot_archives_dir);
+                let archive_info = find_archive(
+                    incremental_snapshot_archives
+                        .iter()
+                        .map(|archive_info| archive_info.snapshot_archive_info()),
+                    starting_incremental_snapshot_hash.hash,
+                );
+                if archive_info.map_or(false, is_archive_encrypted) {
+                    return;
+                }
+                self.push_incremental_snapshot_hash(
+                    starting_incremental_snapshot_hash,
+                    archive_info.and_then(|archive_info| archive_size(&archive_info.path)),
+                );
             };
         }
     }
 
+    /// Push the snapshot hashes and archive sizes to the cluster again, if it has been
+    /// `push_interval` since they were last pushed, so that peers that missed them, or have
+    /// since purged them, get them sooner than from the next snapshot.
+    fn push_again_if_due(&mut self) {
+        let push_interval = match self.push_interval {
+            Some(push_interval) => push_interval,
+            None => return,
+        };
+        if self.full_snapshot_hashes.hashes.is_empty() || self.last_push.elapsed() < push_interval {
+            return;
+        }
+        self.cluster_info
+            .push_snapshot_hashes(self.full_snapshot_hashes.hashes.clone());
+        if !self.incremental_snapshot_hashes.hashes.is_empty() {
+            self.push_incremental_snapshot_hashes();
+        }
+        self.push_snapshot_archive_sizes();
+    }
+
     /// Add `snapshot_hash` to its respective vector of hashes, then push that vector to the
     /// cluster via CRDS.
-    fn push_snapshot_hash(&mut self, snapshot_type: SnapshotType, snapshot_hash: (Slot, Hash)) {
+    fn push_snapshot_hash(
+        &mut self,
+        snapshot_type: SnapshotType,
+        snapshot_hash: (Slot, Hash),
+        archive_size: Option<u64>,
+    ) {
         match snapshot_type {
             SnapshotType::FullSnapshot => {
-                self.push_full_snapshot_hash(FullSnapshotHash {
-                    hash: snapshot_hash,
-                });
+                self.push_full_snapshot_hash(
+                    FullSnapshotHash {
+                        hash: snapshot_hash,
+                    },
+                    archive_size,
+                );
             }
             SnapshotType::IncrementalSnapshot(base_slot) => {
                 let latest_full_snapshot_hash = *self.full_snapshot_hashes.hashes.last().unwrap();
@@ -148,25 +246,43 @@ impl SnapshotGossipManager {
                     "the incremental snapshot's base slot ({}) must match the latest full snapshot hash's slot ({})",
                     base_slot, latest_full_snapshot_hash.0,
                 );
-                self.push_incremental_snapshot_hash(IncrementalSnapshotHash {
-                    base: latest_full_snapshot_hash,
-                    hash: snapshot_hash,
-                });
+                self.push_incremental_snapshot_hash(
+                    IncrementalSnapshotHash {
+                        base: latest_full_snapshot_hash,
+                        hash: snapshot_hash,
+                    },
+                    archive_size,
+                );
             }
         }
     }
 
     /// Add `full_snapshot_hash` to the vector of full snapshot hashes, then push that vector to
-    /// the cluster via CRDS.
-    fn push_full_snapshot_hash(&mut self, full_snapshot_hash: FullSnapshotHash) {
+    /// the cluster via CRDS, along with the archive sizes.
+    fn push_full_snapshot_hash(
+        &mut self,
+        full_snapshot_hash: FullSnapshotHash,
+        archive_size: Option<u64>,
+    ) {
         self.full_snapshot_hashes
             .hashes
             .push(full_snapshot_hash.hash);
         while self.full_snapshot_hashes.hashes.len() > self.max_full_snapshot_hashes {
             self.full_snapshot_hashes.hashes.remove(0);
         }
+        if let Some(archive_size) = archive_size {
+            self.full_snapshot_archive_sizes
+                .push((full_snapshot_hash.hash.0, archive_size));
+        }
+        let full_snapshot_hashes = &self.full_snapshot_hashes.hashes;
+        self.full_snapshot_archive_sizes.retain(|(slot, _)| {
+            full_snapshot_hashes
+                .iter()
+                .any(|(hash_slot, _)| hash_slot == slot)
+        });
         self.cluster_info
             .push_snapshot_hashes(self.full_snapshot_hashes.hashes.clone());
+        self.push_snapshot_archive_sizes();
     }
 
     /// Add `incremental_snapshot_hash` to the vector of incremental snapshot hashes, then push
@@ -174,12 +290,14 @@ impl SnapshotGossipManager {
     fn push_incremental_snapshot_hash(
         &mut self,
         incremental_snapshot_hash: IncrementalSnapshotHash,
+        archive_size: Option<u64>,
     ) {
         // If the base snapshot hash is different from the one in IncrementalSnapshotHashes, then
         // that means the old incremental snapshot hashes are no longer valid, so clear them all
         // out.
         if incremental_snapshot_hash.base != self.incremental_snapshot_hashes.base {
             self.incremental_snapshot_hashes.hashes.clear();
+            self.incremental_snapshot_archive_sizes.clear();
             self.incremental_snapshot_hashes.base = incremental_snapshot_hash.base;
         }
 
@@ -189,6 +307,22 @@ impl SnapshotGossipManager {
         while self.incremental_snapshot_hashes.hashes.len() > self.max_incremental_snapshot_hashes {
             self.incremental_snapshot_hashes.hashes.remove(0);
         }
+        if let Some(archive_size) = archive_size {
+            self.incremental_snapshot_archive_sizes
+                .push((incremental_snapshot_hash.hash.0, archive_size));
+        }
+        let incremental_snapshot_hashes = &self.incremental_snapshot_hashes.hashes;
+        self.incremental_snapshot_archive_sizes.retain(|(slot, _)| {
+            incremental_snapshot_hashes
+                .iter()
+                .any(|(hash_slot, _)| hash_slot == slot)
+        });
+        self.push_incremental_snapshot_hashes();
+        self.push_snapshot_archive_sizes();
+    }
+
+    /// Push the vector of incremental snapshot hashes to the cluster via CRDS.
+    fn push_incremental_snapshot_hashes(&self) {
         // Pushing incremental snapshot hashes to the cluster should never fail.  The only error
         // case is when the length of the hashes is too big, but we account for that with
         // `max_incremental_snapshot_hashes`.  If this call ever does error, it's a programmer bug!
@@ -204,6 +338,38 @@ impl SnapshotGossipManager {
                  and a new error case has been added, which has not been handled here.",
             );
     }
+
+    /// Push the known archive sizes of the advertised snapshots to the cluster via CRDS.  This
+    /// is pushed after the snapshot hashes, so it counts as the last push.
+    fn push_snapshot_archive_sizes(&mut self) {
+        self.cluster_info.push_snapshot_archive_sizes(
+            self.full_snapshot_archive_sizes.clone(),
+            self.incremental_snapshot_archive_sizes.clone(),
+        );
+        self.last_push = Instant::now();
+    }
+}
+
+/// The size of the snapshot archive at `archive_path`, if it can be read
+fn archive_size(archive_path: &Path) -> Option<u64> {
+    fs::metadata(archive_path)
+        .map(|metadata| metadata.len())
+        .ok()
+}
+
+/// The archive among `archive_infos` of the snapshot with `snapshot_hash`, if any
+fn find_archive<'a>(
+    mut archive_infos: impl Iterator<Item = &'a SnapshotArchiveInfo>,
+    snapshot_hash: (Slot, Hash),
+) -> Option<&'a SnapshotArchiveInfo> {
+    archive_infos.find(|archive_info| (archive_info.slot, archive_info.hash) == snapshot_hash)
+}
+
+/// Whether the storages in the archive are encrypted, which they are taken to be if the archive
+/// cannot be read
+fn is_archive_encrypted(archive_info: &SnapshotArchiveInfo) -> bool {
+    snapshot_utils::is_snapshot_archive_encrypted(&archive_info.path, archive_info.archive_format)
+        .unwrap_or(true)
 }
 
 #[cfg(test)]
@@ -215,6 +381,7 @@ mod tests {
         bank::BankSlotDelta,
         snapshot_archive_info::SnapshotArchiveInfo,
         snapshot_package::{SnapshotPackage, SnapshotType},
+        snapshot_profile::SnapshotProfiler,
         snapshot_utils::{self, ArchiveFormat, SnapshotVersion, SNAPSHOT_STATUS_CACHE_FILE_NAME},
     };
     use solana_sdk::hash::Hash;
@@ -312,17 +479,16 @@ mod tests {
             slot_deltas: vec![],
             snapshot_links: link_snapshots_dir,
             snapshot_storages: vec![storage_entries],
+            pruned_storages_dir: None,
             snapshot_version: SnapshotVersion::default(),
             snapshot_type: SnapshotType::FullSnapshot,
+            profiler: SnapshotProfiler::default(),
+            deterministic_serialization: false,
         };
 
         // Make tarball from packageable snapshot
-        snapshot_utils::archive_snapshot_package(
-            &snapshot_package,
-            snapshot_utils::DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            snapshot_utils::DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-        )
-        .unwrap();
+        snapshot_utils::archive_snapshot_package(&snapshot_package, &SnapshotConfig::default())
+            .unwrap();
 
         // before we compare, stick an empty status_cache in this dir so that the package comparison works
         // This is needed since the status_cache is added by the packager and is not collected from
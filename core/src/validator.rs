@@ -476,6 +476,23 @@ impl Validator {
         if let Some(ref shrink_paths) = config.account_shrink_paths {
             bank.set_shrink_paths(shrink_paths.clone());
         }
+        if let Some(accounts_hash_verification_receiver) =
+            bank.take_accounts_hash_verification_receiver()
+        {
+            // The snapshot bank's accounts hash is still being verified; the node does not vote
+            // until it is, and must not carry on at all if it fails
+            Builder::new()
+                .name("solana-wait-for-hash-verify".to_string())
+                .spawn(move || {
+                    if accounts_hash_verification_receiver.recv().unwrap_or(false) {
+                        info!("Snapshot bank accounts hash verified");
+                    } else {
+                        error!("Snapshot bank accounts hash failed to verify");
+                        abort();
+                    }
+                })
+                .unwrap();
+        }
         let bank_forks = Arc::new(RwLock::new(bank_forks));
 
         let sample_performance_service =
@@ -1344,11 +1361,7 @@ fn new_banks_from_ledger(
         let full_snapshot_archive_info = snapshot_utils::bank_to_full_snapshot_archive(
             ledger_path,
             &bank_forks.root_bank(),
-            None,
-            &snapshot_config.snapshot_archives_dir,
-            snapshot_config.archive_format,
-            snapshot_config.maximum_full_snapshot_archives_to_retain,
-            snapshot_config.maximum_incremental_snapshot_archives_to_retain,
+            snapshot_config,
         )
         .unwrap_or_else(|err| {
             error!("Unable to create snapshot: {}", err);
@@ -100,6 +100,10 @@ mod tests {
     DEFINE_SNAPSHOT_VERSION_PARAMETERIZED_TEST_FUNCTIONS!(V1_2_0, Devnet, V1_2_0_Devnet);
     DEFINE_SNAPSHOT_VERSION_PARAMETERIZED_TEST_FUNCTIONS!(V1_2_0, Testnet, V1_2_0_Testnet);
     DEFINE_SNAPSHOT_VERSION_PARAMETERIZED_TEST_FUNCTIONS!(V1_2_0, MainnetBeta, V1_2_0_MainnetBeta);
+    DEFINE_SNAPSHOT_VERSION_PARAMETERIZED_TEST_FUNCTIONS!(V1_3_0, Development, V1_3_0_Development);
+    DEFINE_SNAPSHOT_VERSION_PARAMETERIZED_TEST_FUNCTIONS!(V1_3_0, Devnet, V1_3_0_Devnet);
+    DEFINE_SNAPSHOT_VERSION_PARAMETERIZED_TEST_FUNCTIONS!(V1_3_0, Testnet, V1_3_0_Testnet);
+    DEFINE_SNAPSHOT_VERSION_PARAMETERIZED_TEST_FUNCTIONS!(V1_3_0, MainnetBeta, V1_3_0_MainnetBeta);
 
     struct SnapshotTestConfig {
         accounts_dir: TempDir,
@@ -202,6 +206,7 @@ mod tests {
             check_hash_calculation,
             false,
             false,
+            old_bank_forks.snapshot_config.as_ref().unwrap(),
             Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
             None,
         )
@@ -291,12 +296,7 @@ mod tests {
         )
         .unwrap();
         let snapshot_package = SnapshotPackage::from(accounts_package);
-        snapshot_utils::archive_snapshot_package(
-            &snapshot_package,
-            snapshot_config.maximum_full_snapshot_archives_to_retain,
-            snapshot_config.maximum_incremental_snapshot_archives_to_retain,
-        )
-        .unwrap();
+        snapshot_utils::archive_snapshot_package(&snapshot_package, snapshot_config).unwrap();
 
         // Restore bank from snapshot
         let account_paths = &[snapshot_test_config.accounts_dir.path().to_path_buf()];
@@ -363,8 +363,15 @@ mod tests {
         // Take snapshot of zeroth bank
         let bank0 = bank_forks.get(0).unwrap();
         let storages = bank0.get_snapshot_storages(None);
-        snapshot_utils::add_bank_snapshot(bank_snapshots_dir, bank0, &storages, snapshot_version)
-            .unwrap();
+        snapshot_utils::add_bank_snapshot(
+            bank_snapshots_dir,
+            bank0,
+            &storages,
+            snapshot_version,
+            snapshot_config.durability,
+            snapshot_config.file_permissions,
+        )
+        .unwrap();
 
         // Set up snapshotting channels
         let (sender, receiver) = channel();
@@ -410,10 +417,7 @@ mod tests {
                 &bank,
                 vec![],
                 package_sender,
-                bank_snapshots_dir,
-                snapshot_archives_dir,
-                snapshot_config.snapshot_version,
-                snapshot_config.archive_format,
+                snapshot_config,
                 None,
                 Some(SnapshotType::FullSnapshot),
             )
@@ -770,12 +774,8 @@ mod tests {
             bank,
             &bank_snapshot_info,
             &snapshot_config.bank_snapshots_dir,
-            &snapshot_config.snapshot_archives_dir,
             bank.get_snapshot_storages(None),
-            snapshot_config.archive_format,
-            snapshot_config.snapshot_version,
-            snapshot_config.maximum_full_snapshot_archives_to_retain,
-            snapshot_config.maximum_incremental_snapshot_archives_to_retain,
+            snapshot_config,
         )?;
 
         Ok(())
@@ -807,12 +807,8 @@ mod tests {
             incremental_snapshot_base_slot,
             &bank_snapshot_info,
             &snapshot_config.bank_snapshots_dir,
-            &snapshot_config.snapshot_archives_dir,
             storages,
-            snapshot_config.archive_format,
-            snapshot_config.snapshot_version,
-            snapshot_config.maximum_full_snapshot_archives_to_retain,
-            snapshot_config.maximum_incremental_snapshot_archives_to_retain,
+            snapshot_config,
         )?;
 
         Ok(())
@@ -839,6 +835,7 @@ mod tests {
             false,
             false,
             false,
+            snapshot_config,
             Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
             None,
         )?;
@@ -1019,6 +1016,7 @@ mod tests {
             false,
             false,
             false,
+            &snapshot_test_config.snapshot_config,
             Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
             None,
         )
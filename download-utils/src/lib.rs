@@ -3,8 +3,9 @@ use console::Emoji;
 use indicatif::{ProgressBar, ProgressStyle};
 use log::*;
 use solana_runtime::{
+    serde_snapshot::SnapshotCompatibility,
     snapshot_package::SnapshotType,
-    snapshot_utils::{self, ArchiveFormat},
+    snapshot_utils::{self, ArchiveFormat, SnapshotArchiveProbe},
 };
 use solana_sdk::{clock::Slot, genesis_config::DEFAULT_GENESIS_ARCHIVE, hash::Hash};
 use std::fs::{self, File};
@@ -52,6 +53,15 @@ pub struct DownloadProgressRecord {
 type DownloadProgressCallback<'a> = Box<dyn FnMut(&DownloadProgressRecord) -> bool + 'a>;
 type DownloadProgressCallbackOption<'a> = Option<DownloadProgressCallback<'a>>;
 
+/// Looks over the first bytes of a download, a chunk at a time as they come in.  Returns `None`
+/// while it takes more of them to decide, `Some(Ok(()))` to carry on with the download without
+/// looking any further, or `Some(Err(reason))` to abort it.
+type DownloadPrefixCheck<'a> = &'a mut dyn FnMut(&[u8]) -> Option<Result<(), String>>;
+
+/// The most bytes a `DownloadPrefixCheck` is given to decide in, before the download carries on
+/// regardless
+const MAX_DOWNLOAD_PREFIX_CHECK_BYTES: usize = 1024 * 1024;
+
 /// This callback allows the caller to get notified of the download progress modelled by DownloadProgressRecord
 /// Return "true" to continue the download
 /// Return "false" to abort the download
@@ -60,6 +70,22 @@ pub fn download_file<'a, 'b>(
     destination_file: &Path,
     use_progress_bar: bool,
     progress_notify_callback: &'a mut DownloadProgressCallbackOption<'b>,
+) -> Result<(), String> {
+    download_file_with_prefix_check(
+        url,
+        destination_file,
+        use_progress_bar,
+        progress_notify_callback,
+        None,
+    )
+}
+
+fn download_file_with_prefix_check<'a, 'b, 'c>(
+    url: &str,
+    destination_file: &Path,
+    use_progress_bar: bool,
+    progress_notify_callback: &'a mut DownloadProgressCallbackOption<'b>,
+    prefix_check: Option<DownloadPrefixCheck<'c>>,
 ) -> Result<(), String> {
     if destination_file.is_file() {
         return Err(format!("{:?} already exists", destination_file));
@@ -116,7 +142,7 @@ pub fn download_file<'a, 'b>(
         info!("Downloading {} bytes from {}", download_size, url);
     }
 
-    struct DownloadProgress<'e, 'f, R> {
+    struct DownloadProgress<'e, 'f, 'g, R> {
         progress_bar: ProgressBar,
         response: R,
         last_print: Instant,
@@ -127,12 +153,26 @@ pub fn download_file<'a, 'b>(
         start_time: Instant,
         callback: &'f mut DownloadProgressCallbackOption<'e>,
         notification_count: u64,
+        prefix_check: Option<DownloadPrefixCheck<'g>>,
+        prefix_bytes: usize,
     }
 
-    impl<'e, 'f, R: Read> Read for DownloadProgress<'e, 'f, R> {
+    impl<'e, 'f, 'g, R: Read> Read for DownloadProgress<'e, 'f, 'g, R> {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             let n = self.response.read(buf)?;
 
+            if let Some(prefix_check) = &mut self.prefix_check {
+                self.prefix_bytes += n;
+                match prefix_check(&buf[..n]) {
+                    None if n > 0 && self.prefix_bytes < MAX_DOWNLOAD_PREFIX_CHECK_BYTES => {}
+                    None | Some(Ok(())) => self.prefix_check = None,
+                    Some(Err(err)) => {
+                        info!("Download is aborted: {}", err);
+                        return Err(io::Error::new(io::ErrorKind::Other, err));
+                    }
+                }
+            }
+
             self.current_bytes += n;
             let total_bytes_f32 = self.current_bytes as f32;
             let diff_bytes_f32 = (self.current_bytes - self.last_print_bytes) as f32;
@@ -189,7 +229,7 @@ pub fn download_file<'a, 'b>(
         }
     }
 
-    let mut source = DownloadProgress::<'b, 'a> {
+    let mut source = DownloadProgress::<'b, 'a, 'c> {
         progress_bar,
         response,
         last_print: Instant::now(),
@@ -200,6 +240,8 @@ pub fn download_file<'a, 'b>(
         start_time: Instant::now(),
         callback: progress_notify_callback,
         notification_count: 0,
+        prefix_check,
+        prefix_bytes: 0,
     };
 
     File::create(&temp_destination_file)
@@ -249,6 +291,15 @@ pub fn download_genesis_if_missing(
 
 /// Download a snapshot archive from `rpc_addr`.  Use `snapshot_type` to specify downloading either
 /// a full snapshot or an incremental snapshot.
+///
+/// The download is abandoned as soon as its start shows that the archive could not be loaded: if
+/// it is of an unknown version, or if its storages are encrypted and `has_storage_encryption_key`
+/// is false.  `SnapshotVersion::V1_2_0` archives only start with their version when their storages
+/// are encrypted, so the others of that version are only checked once they have been downloaded.
+///
+/// Downloaded archives are marked as such next to them, and the storages in them are fully
+/// validated whenever they are loaded, whatever validation is configured for the node's own.
+#[allow(clippy::too_many_arguments)]
 pub fn download_snapshot_archive<'a, 'b>(
     rpc_addr: &SocketAddr,
     snapshot_archives_dir: &Path,
@@ -256,6 +307,7 @@ pub fn download_snapshot_archive<'a, 'b>(
     snapshot_type: SnapshotType,
     maximum_full_snapshot_archives_to_retain: usize,
     maximum_incremental_snapshot_archives_to_retain: usize,
+    has_storage_encryption_key: bool,
     use_progress_bar: bool,
     progress_notify_callback: &'a mut DownloadProgressCallbackOption<'b>,
 ) -> Result<(), String> {
@@ -293,7 +345,32 @@ pub fn download_snapshot_archive<'a, 'b>(
             return Ok(());
         }
 
-        match download_file(
+        // Give up on archives this validator could not load as soon as that is known, rather
+        // than once all of them has been downloaded
+        let mut probe = SnapshotArchiveProbe::new(archive_format).map_err(|err| err.to_string())?;
+        let mut check_snapshot_archive = |archive_bytes: &[u8]| match probe.probe(archive_bytes) {
+            SnapshotCompatibility::Incomplete => None,
+            SnapshotCompatibility::Incompatible { version } => Some(Err(format!(
+                "snapshot archive version {} is not supported",
+                version
+            ))),
+            SnapshotCompatibility::NotASnapshot => Some(Err("not a snapshot archive".to_string())),
+            SnapshotCompatibility::Compatible { sections, .. }
+                if sections.encrypted_storages && !has_storage_encryption_key =>
+            {
+                Some(Err(
+                    "snapshot archive storages are encrypted, and no storage encryption key is \
+                     configured"
+                        .to_string(),
+                ))
+            }
+            SnapshotCompatibility::Undetermined | SnapshotCompatibility::Compatible { .. } => {
+                Some(Ok(()))
+            }
+        };
+        snapshot_utils::mark_downloaded_snapshot_archive(&destination_path)
+            .map_err(|err| err.to_string())?;
+        match download_file_with_prefix_check(
             &format!(
                 "http://{}/{}",
                 rpc_addr,
@@ -302,9 +379,13 @@ pub fn download_snapshot_archive<'a, 'b>(
             &destination_path,
             use_progress_bar,
             progress_notify_callback,
+            Some(&mut check_snapshot_archive),
         ) {
             Ok(()) => return Ok(()),
-            Err(err) => info!("{}", err),
+            Err(err) => {
+                snapshot_utils::unmark_downloaded_snapshot_archive(&destination_path);
+                info!("{}", err)
+            }
         }
     }
     Err(format!(
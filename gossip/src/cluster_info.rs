@@ -24,7 +24,8 @@ use {
         crds_gossip_pull::{CrdsFilter, ProcessPullStats, CRDS_GOSSIP_PULL_CRDS_TIMEOUT_MS},
         crds_value::{
             self, CrdsData, CrdsValue, CrdsValueLabel, EpochSlotsIndex, IncrementalSnapshotHashes,
-            LowestSlot, NodeInstance, SnapshotHashes, Version, Vote, MAX_WALLCLOCK,
+            LowestSlot, NodeInstance, SnapshotArchiveSizes, SnapshotHashes, Version, Vote,
+            MAX_WALLCLOCK,
         },
         epoch_slots::EpochSlots,
         gossip_error::GossipError,
@@ -53,7 +54,7 @@ use {
     solana_runtime::bank_forks::BankForks,
     solana_sdk::{
         clock::{Slot, DEFAULT_MS_PER_SLOT, DEFAULT_SLOTS_PER_EPOCH},
-        feature_set::FeatureSet,
+        feature_set::{self, FeatureSet},
         hash::Hash,
         pubkey::Pubkey,
         sanitize::{Sanitize, SanitizeError},
@@ -165,6 +166,9 @@ pub struct ClusterInfo {
     instance: RwLock<NodeInstance>,
     contact_info_path: PathBuf,
     socket_addr_space: SocketAddrSpace,
+    /// Whether the cluster, as of the root bank, understands SnapshotArchiveSizes, so that it is
+    /// not pushed to nodes that would fail to deserialize the messages carrying it
+    snapshot_archive_sizes_enabled: AtomicBool,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, AbiExample)]
@@ -259,7 +263,7 @@ pub fn make_accounts_hashes_message(
 pub(crate) type Ping = ping_pong::Ping<[u8; GOSSIP_PING_TOKEN_SIZE]>;
 
 // TODO These messages should go through the gpu pipeline for spam filtering
-#[frozen_abi(digest = "4VqzaZbxQkeTgo916HVoLtaWoM8bbGaQZy6Qgw7K9kLf")]
+#[frozen_abi(digest = "8eYpWBqwcxFt71RTYaMoHwwyHwoF8UpUA11P18uATMuo")]
 #[derive(Serialize, Deserialize, Debug, AbiEnumVisitor, AbiExample)]
 #[allow(clippy::large_enum_variant)]
 pub(crate) enum Protocol {
@@ -376,7 +380,9 @@ fn retain_staked(values: &mut Vec<CrdsValue>, stakes: &HashMap<Pubkey, u64>) {
             // Unstaked nodes can still help repair.
             CrdsData::EpochSlots(_, _) => true,
             // Unstaked nodes can still serve snapshots.
-            CrdsData::SnapshotHashes(_) | CrdsData::IncrementalSnapshotHashes(_) => true,
+            CrdsData::SnapshotHashes(_)
+            | CrdsData::IncrementalSnapshotHashes(_)
+            | CrdsData::SnapshotArchiveSizes(_) => true,
             // Otherwise unstaked voting nodes will show up with no version in
             // the various dashboards.
             CrdsData::Version(_) => true,
@@ -417,6 +423,7 @@ impl ClusterInfo {
             contact_info_path: PathBuf::default(),
             contact_save_interval: 0, // disabled
             socket_addr_space,
+            snapshot_archive_sizes_enabled: AtomicBool::default(),
         };
         me.insert_self();
         me.push_self(&HashMap::new(), None);
@@ -446,6 +453,9 @@ impl ClusterInfo {
             instance: RwLock::new(NodeInstance::new(&mut thread_rng(), *new_id, timestamp())),
             contact_info_path: PathBuf::default(),
             contact_save_interval: 0, // disabled
+            snapshot_archive_sizes_enabled: AtomicBool::new(
+                self.snapshot_archive_sizes_enabled.load(Ordering::Relaxed),
+            ),
             ..*self
         }
     }
@@ -976,6 +986,35 @@ impl ClusterInfo {
         Ok(())
     }
 
+    /// Push the sizes of the full and incremental snapshot archives whose hashes were pushed
+    /// with `push_snapshot_hashes()` and `push_incremental_snapshot_hashes()`, by slot.  Nothing
+    /// is pushed until the `gossip_snapshot_archive_sizes` feature is active in the root bank.
+    pub fn push_snapshot_archive_sizes(
+        &self,
+        full: Vec<(Slot, u64)>,
+        incremental: Vec<(Slot, u64)>,
+    ) {
+        if !self.snapshot_archive_sizes_enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        if full.len() > MAX_SNAPSHOT_HASHES || incremental.len() > MAX_INCREMENTAL_SNAPSHOT_HASHES {
+            warn!(
+                "snapshot archive sizes too large, ignored: {}, {}",
+                full.len(),
+                incremental.len(),
+            );
+            return;
+        }
+
+        let message = CrdsData::SnapshotArchiveSizes(SnapshotArchiveSizes {
+            from: self.id(),
+            full,
+            incremental,
+            wallclock: timestamp(),
+        });
+        self.push_message(CrdsValue::new_signed(message, &self.keypair()));
+    }
+
     pub fn push_vote_at_index(&self, vote: Transaction, vote_index: u8) {
         assert!((vote_index as usize) < MAX_LOCKOUT_HISTORY);
         let self_pubkey = self.id();
@@ -1174,6 +1213,18 @@ impl ClusterInfo {
             .cloned()
     }
 
+    pub fn get_snapshot_archive_sizes_for_node(
+        &self,
+        pubkey: &Pubkey,
+    ) -> Option<SnapshotArchiveSizes> {
+        self.gossip
+            .crds
+            .read()
+            .unwrap()
+            .get::<&SnapshotArchiveSizes>(*pubkey)
+            .cloned()
+    }
+
     /// Returns epoch-slots inserted since the given cursor.
     /// Excludes entries from nodes with unkown or different shred version.
     pub fn get_epoch_slots(&self, cursor: &mut Cursor) -> Vec<EpochSlots> {
@@ -1745,7 +1796,7 @@ impl ClusterInfo {
                         last_contact_info_save = start;
                     }
 
-                    let (stakes, _feature_set) = match bank_forks {
+                    let (stakes, feature_set) = match bank_forks {
                         Some(ref bank_forks) => {
                             let root_bank = bank_forks.read().unwrap().root_bank();
                             (
@@ -1755,6 +1806,13 @@ impl ClusterInfo {
                         }
                         None => (Arc::default(), None),
                     };
+                    if let Some(feature_set) = feature_set {
+                        self.snapshot_archive_sizes_enabled.store(
+                            feature_set
+                                .is_active(&feature_set::gossip_snapshot_archive_sizes::id()),
+                            Ordering::Relaxed,
+                        );
+                    }
                     let _ = self.run_gossip(
                         &thread_pool,
                         gossip_validators.as_ref(),
@@ -3297,6 +3355,61 @@ mod tests {
         assert!(Packet::from_data(Some(&socket), response).is_ok());
     }
 
+    #[test]
+    fn test_max_snapshot_archive_sizes_with_push_messages() {
+        let mut rng = rand::thread_rng();
+        let snapshot_archive_sizes = SnapshotArchiveSizes {
+            from: Pubkey::new_unique(),
+            full: vec![(Slot::default(), u64::MAX); MAX_SNAPSHOT_HASHES],
+            incremental: vec![(Slot::default(), u64::MAX); MAX_INCREMENTAL_SNAPSHOT_HASHES],
+            wallclock: timestamp(),
+        };
+        let crds_value = CrdsValue::new_signed(
+            CrdsData::SnapshotArchiveSizes(snapshot_archive_sizes),
+            &Keypair::new(),
+        );
+        let message = Protocol::PushMessage(Pubkey::new_unique(), vec![crds_value]);
+        let socket = new_rand_socket_addr(&mut rng);
+        assert!(Packet::from_data(Some(&socket), message).is_ok());
+    }
+
+    #[test]
+    fn test_push_snapshot_archive_sizes() {
+        let keypair = Arc::new(Keypair::new());
+        let contact_info = ContactInfo::new_localhost(&keypair.pubkey(), 0);
+        let cluster_info =
+            ClusterInfo::new(contact_info, keypair.clone(), SocketAddrSpace::Unspecified);
+
+        // Nothing is pushed before the cluster understands the sizes
+        cluster_info.push_snapshot_archive_sizes(vec![(100, 1_000)], Vec::default());
+        cluster_info.flush_push_queue();
+        assert!(cluster_info
+            .get_snapshot_archive_sizes_for_node(&keypair.pubkey())
+            .is_none());
+
+        cluster_info
+            .snapshot_archive_sizes_enabled
+            .store(true, Ordering::Relaxed);
+        cluster_info.push_snapshot_archive_sizes(vec![(100, 1_000)], vec![(110, 10), (120, 20)]);
+        cluster_info.flush_push_queue();
+        let sizes = cluster_info
+            .get_snapshot_archive_sizes_for_node(&keypair.pubkey())
+            .unwrap();
+        assert_eq!(sizes.full, vec![(100, 1_000)]);
+        assert_eq!(sizes.incremental, vec![(110, 10), (120, 20)]);
+
+        // Too many sizes are not pushed at all
+        cluster_info.push_snapshot_archive_sizes(
+            vec![(200, 1_000); MAX_SNAPSHOT_HASHES + 1],
+            Vec::default(),
+        );
+        cluster_info.flush_push_queue();
+        let sizes = cluster_info
+            .get_snapshot_archive_sizes_for_node(&keypair.pubkey())
+            .unwrap();
+        assert_eq!(sizes.full, vec![(100, 1_000)]);
+    }
+
     #[test]
     fn test_max_prune_data_pubkeys() {
         let mut rng = rand::thread_rng();
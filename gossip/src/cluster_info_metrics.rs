@@ -498,6 +498,9 @@ pub(crate) fn submit_gossip_stats(
             crds_stats.pull.counts[10],
             i64
         ),
+        ("SnapshotArchiveSizes", counts[11], i64),
+        ("SnapshotArchiveSizes-push", crds_stats.push.counts[11], i64),
+        ("SnapshotArchiveSizes-pull", crds_stats.pull.counts[11], i64),
         ("all", counts.iter().sum::<usize>(), i64),
         (
             "all-push",
@@ -560,6 +563,9 @@ pub(crate) fn submit_gossip_stats(
             crds_stats.pull.fails[10],
             i64
         ),
+        ("SnapshotArchiveSizes", fails[11], i64),
+        ("SnapshotArchiveSizes-push", crds_stats.push.fails[11], i64),
+        ("SnapshotArchiveSizes-pull", crds_stats.pull.fails[11], i64),
         ("all", fails.iter().sum::<usize>(), i64),
         ("all-push", crds_stats.push.fails.iter().sum::<usize>(), i64),
         ("all-pull", crds_stats.pull.fails.iter().sum::<usize>(), i64),
@@ -4,7 +4,7 @@ use {
         crds::VersionedCrdsValue,
         crds_value::{
             CrdsData, CrdsValue, CrdsValueLabel, IncrementalSnapshotHashes, LegacyVersion,
-            LowestSlot, SnapshotHashes, Version,
+            LowestSlot, SnapshotArchiveSizes, SnapshotHashes, Version,
         },
     },
     indexmap::IndexMap,
@@ -62,6 +62,11 @@ impl_crds_entry!(
     CrdsData::IncrementalSnapshotHashes(incremental_snapshot_hashes),
     incremental_snapshot_hashes
 );
+impl_crds_entry!(
+    SnapshotArchiveSizes,
+    CrdsData::SnapshotArchiveSizes(snapshot_archive_sizes),
+    snapshot_archive_sizes
+);
 
 impl<'a, 'b> CrdsEntry<'a, 'b> for &'a SnapshotHashes {
     type Key = Pubkey;
@@ -1,6 +1,6 @@
 use {
     crate::{
-        cluster_info::MAX_SNAPSHOT_HASHES,
+        cluster_info::{MAX_INCREMENTAL_SNAPSHOT_HASHES, MAX_SNAPSHOT_HASHES},
         contact_info::ContactInfo,
         deprecated,
         duplicate_shred::{DuplicateShred, DuplicateShredIndex, MAX_DUPLICATE_SHREDS},
@@ -92,6 +92,7 @@ pub enum CrdsData {
     NodeInstance(NodeInstance),
     DuplicateShred(DuplicateShredIndex, DuplicateShred),
     IncrementalSnapshotHashes(IncrementalSnapshotHashes),
+    SnapshotArchiveSizes(SnapshotArchiveSizes),
 }
 
 impl Sanitize for CrdsData {
@@ -129,6 +130,7 @@ impl Sanitize for CrdsData {
                 }
             }
             CrdsData::IncrementalSnapshotHashes(val) => val.sanitize(),
+            CrdsData::SnapshotArchiveSizes(val) => val.sanitize(),
         }
     }
 }
@@ -233,6 +235,34 @@ impl Sanitize for IncrementalSnapshotHashes {
     }
 }
 
+/// The sizes of the snapshot archives a node advertises in SnapshotHashes and
+/// IncrementalSnapshotHashes, by slot, so that nodes downloading snapshots can
+/// tell how much they would fetch from each peer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, AbiExample)]
+pub struct SnapshotArchiveSizes {
+    pub from: Pubkey,
+    pub full: Vec<(Slot, u64)>,
+    pub incremental: Vec<(Slot, u64)>,
+    pub wallclock: u64,
+}
+
+impl Sanitize for SnapshotArchiveSizes {
+    fn sanitize(&self) -> Result<(), SanitizeError> {
+        sanitize_wallclock(self.wallclock)?;
+        if self.full.len() > MAX_SNAPSHOT_HASHES
+            || self.incremental.len() > MAX_INCREMENTAL_SNAPSHOT_HASHES
+        {
+            return Err(SanitizeError::ValueOutOfBounds);
+        }
+        for (slot, _) in self.full.iter().chain(&self.incremental) {
+            if *slot >= MAX_SLOT {
+                return Err(SanitizeError::ValueOutOfBounds);
+            }
+        }
+        self.from.sanitize()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, AbiExample)]
 pub struct LowestSlot {
     pub from: Pubkey,
@@ -497,6 +527,7 @@ pub enum CrdsValueLabel {
     NodeInstance(Pubkey),
     DuplicateShred(DuplicateShredIndex, Pubkey),
     IncrementalSnapshotHashes(Pubkey),
+    SnapshotArchiveSizes(Pubkey),
 }
 
 impl fmt::Display for CrdsValueLabel {
@@ -515,6 +546,9 @@ impl fmt::Display for CrdsValueLabel {
             CrdsValueLabel::IncrementalSnapshotHashes(_) => {
                 write!(f, "IncrementalSnapshotHashes({})", self.pubkey())
             }
+            CrdsValueLabel::SnapshotArchiveSizes(_) => {
+                write!(f, "SnapshotArchiveSizes({})", self.pubkey())
+            }
         }
     }
 }
@@ -533,6 +567,7 @@ impl CrdsValueLabel {
             CrdsValueLabel::NodeInstance(p) => *p,
             CrdsValueLabel::DuplicateShred(_, p) => *p,
             CrdsValueLabel::IncrementalSnapshotHashes(p) => *p,
+            CrdsValueLabel::SnapshotArchiveSizes(p) => *p,
         }
     }
 }
@@ -582,6 +617,7 @@ impl CrdsValue {
             CrdsData::NodeInstance(node) => node.wallclock,
             CrdsData::DuplicateShred(_, shred) => shred.wallclock,
             CrdsData::IncrementalSnapshotHashes(hash) => hash.wallclock,
+            CrdsData::SnapshotArchiveSizes(sizes) => sizes.wallclock,
         }
     }
     pub fn pubkey(&self) -> Pubkey {
@@ -597,6 +633,7 @@ impl CrdsValue {
             CrdsData::NodeInstance(node) => node.from,
             CrdsData::DuplicateShred(_, shred) => shred.from,
             CrdsData::IncrementalSnapshotHashes(hash) => hash.from,
+            CrdsData::SnapshotArchiveSizes(sizes) => sizes.from,
         }
     }
     pub fn label(&self) -> CrdsValueLabel {
@@ -614,6 +651,9 @@ impl CrdsValue {
             CrdsData::IncrementalSnapshotHashes(_) => {
                 CrdsValueLabel::IncrementalSnapshotHashes(self.pubkey())
             }
+            CrdsData::SnapshotArchiveSizes(_) => {
+                CrdsValueLabel::SnapshotArchiveSizes(self.pubkey())
+            }
         }
     }
     pub fn contact_info(&self) -> Option<&ContactInfo> {
@@ -27,7 +27,7 @@ use solana_ledger::{
 };
 use solana_measure::measure::Measure;
 use solana_runtime::{
-    accounts_db::AccountsDbConfig,
+    accounts_db::{AccountsDbConfig, RentPayingAccountsReport},
     accounts_index::{AccountsIndexConfig, ScanConfig},
     bank::{Bank, RewardCalculationEvent},
     bank_forks::BankForks,
@@ -37,9 +37,11 @@ use solana_runtime::{
     snapshot_archive_info::SnapshotArchiveInfoGetter,
     snapshot_config::SnapshotConfig,
     snapshot_utils::{
-        self, ArchiveFormat, SnapshotVersion, DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+        self, ArchiveFormat, SnapshotDurability, SnapshotVersion,
+        DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
         DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
     },
+    storage_encryption::{MasterKey, MasterKeyCommand, MasterKeyFile, MasterKeyProvider},
 };
 use solana_sdk::{
     account::{AccountSharedData, ReadableAccount, WritableAccount},
@@ -700,6 +702,21 @@ fn hardforks_of(matches: &ArgMatches<'_>, name: &str) -> Option<Vec<Slot>> {
     }
 }
 
+/// The storage encryption key in the file of the `name` arg, or written out by the program of the
+/// `<name>_command` arg, if either is given
+fn storage_encryption_key_of(matches: &ArgMatches<'_>, name: &str) -> Option<MasterKey> {
+    let provider: Box<dyn MasterKeyProvider> = if let Some(key_file) = matches.value_of(name) {
+        Box::new(MasterKeyFile::new(PathBuf::from(key_file)))
+    } else {
+        let program = matches.value_of(format!("{}_command", name))?;
+        Box::new(MasterKeyCommand::new(PathBuf::from(program)))
+    };
+    Some(provider.master_key().unwrap_or_else(|err| {
+        eprintln!("Unable to get the storage encryption key: {}", err);
+        exit(1);
+    }))
+}
+
 fn load_bank_forks(
     arg_matches: &ArgMatches,
     genesis_config: &GenesisConfig,
@@ -834,6 +851,30 @@ fn assert_capitalization(bank: &Bank) {
     assert!(bank.calculate_and_verify_capitalization(debug_verify));
 }
 
+fn print_rent_paying_accounts_report(report: &RentPayingAccountsReport) {
+    println!(
+        "{} rent-paying accounts holding {} across {} owners, evictions projected from epoch {}",
+        report.total_count(),
+        Sol(report.total_lamports()),
+        report.by_owner.len(),
+        report.epoch,
+    );
+    for (owner, by_owner) in report.owners_by_lamports() {
+        println!("{}:", owner);
+        println!("  - accounts: {}", by_owner.count);
+        println!("  - balance: {} SOL", lamports_to_sol(by_owner.lamports));
+        if let (Some(first), Some(last)) = (
+            by_owner.eviction_epochs.keys().next(),
+            by_owner.eviction_epochs.keys().next_back(),
+        ) {
+            println!("  - projected eviction epochs: {} to {}", first, last);
+            for (epoch, count) in &by_owner.eviction_epochs {
+                println!("    - epoch {}: {} accounts", epoch, count);
+            }
+        }
+    }
+}
+
 #[allow(clippy::cognitive_complexity)]
 fn main() {
     // Ignore SIGUSR1 to prevent long-running calls being killed by logrotate
@@ -964,7 +1005,11 @@ fn main() {
         .validator(is_parsable::<SnapshotVersion>)
         .takes_value(true)
         .default_value(SnapshotVersion::default().into())
-        .help("Output snapshot version");
+        .help(
+            "Output snapshot version. Only 1.3.0 snapshots record the accounts delta \
+             hashes of their most recent slots, which are spot-checked against their \
+             storages when the snapshots are loaded",
+        );
 
     let default_max_full_snapshot_archives_to_retain =
         &DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN.to_string();
@@ -1256,6 +1301,13 @@ fn main() {
                     .takes_value(false)
                     .help("After verifying the ledger, print some information about the account stores"),
             )
+            .arg(
+                Arg::with_name("print_rent_paying_accounts")
+                    .long("print-rent-paying-accounts")
+                    .takes_value(false)
+                    .help("After verifying the ledger, print the rent-paying accounts found when \
+                           loading the snapshot, tallied per owner"),
+            )
         ).subcommand(
             SubCommand::with_name("graph")
             .about("Create a Graphviz rendering of the ledger")
@@ -1615,6 +1667,91 @@ fn main() {
                     .help("Slots that their blocks are computed for cost, default to all slots in ledger"),
             )
         )
+        .subcommand(
+            SubCommand::with_name("export-program-accounts")
+            .about("Export every account owned by a program, as of the latest snapshot archives, \
+                    along with the slot and hash of the archives they came from")
+            .arg(&max_genesis_archive_unpacked_size_arg)
+            .arg(
+                Arg::with_name("program_id")
+                    .long("program-id")
+                    .value_name("PUBKEY")
+                    .takes_value(true)
+                    .required(true)
+                    .validator(is_pubkey)
+                    .help("Export the accounts owned by this program"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .long("output")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .required(true)
+                    .help("File to write the exported accounts to"),
+            )
+            .arg(
+                Arg::with_name("no_incremental_snapshot")
+                    .long("no-incremental-snapshot")
+                    .takes_value(false)
+                    .help("Export the accounts as of the highest full snapshot archive, even if \
+                           there is an incremental snapshot archive on top of it"),
+            )
+            .arg(
+                Arg::with_name("storage_encryption_key")
+                    .long("storage-encryption-key")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .conflicts_with("storage_encryption_key_command")
+                    .help("File holding the master key the snapshot storages are encrypted under"),
+            )
+            .arg(
+                Arg::with_name("storage_encryption_key_command")
+                    .long("storage-encryption-key-command")
+                    .value_name("PROGRAM")
+                    .takes_value(true)
+                    .help("Program that writes the master key the snapshot storages are encrypted \
+                           under to stdout, as 32 bytes or in hex"),
+            )
+        )
+        .subcommand(
+            SubCommand::with_name("rotate-snapshot-storage-key")
+            .about("Rewrap the keys of the encrypted account storages in every snapshot archive \
+                    with a new master key")
+            .arg(
+                Arg::with_name("old_key")
+                    .long("old-key")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .required_unless("old_key_command")
+                    .conflicts_with("old_key_command")
+                    .help("File holding the master key the storages are encrypted under now"),
+            )
+            .arg(
+                Arg::with_name("old_key_command")
+                    .long("old-key-command")
+                    .value_name("PROGRAM")
+                    .takes_value(true)
+                    .help("Program that writes the master key the storages are encrypted under \
+                           now to stdout, as 32 bytes or in hex"),
+            )
+            .arg(
+                Arg::with_name("new_key")
+                    .long("new-key")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .required_unless("new_key_command")
+                    .conflicts_with("new_key_command")
+                    .help("File holding the master key to encrypt the storages under from now on"),
+            )
+            .arg(
+                Arg::with_name("new_key_command")
+                    .long("new-key-command")
+                    .value_name("PROGRAM")
+                    .takes_value(true)
+                    .help("Program that writes the master key to encrypt the storages under from \
+                           now on to stdout, as 32 bytes or in hex"),
+            )
+        )
         .get_matches();
 
     info!("{} {}", crate_name!(), solana_version::version!());
@@ -2013,11 +2150,13 @@ fn main() {
             }
 
             let filler_account_count = value_t!(arg_matches, "accounts_filler_count", usize).ok();
+            let print_rent_paying_accounts = arg_matches.is_present("print_rent_paying_accounts");
 
             let accounts_db_config = Some(AccountsDbConfig {
                 index: Some(accounts_index_config),
                 accounts_hash_cache_path: Some(ledger_path.clone()),
                 filler_account_count,
+                collect_rent_paying_accounts_report: print_rent_paying_accounts,
                 ..AccountsDbConfig::default()
             });
 
@@ -2067,6 +2206,14 @@ fn main() {
                 let working_bank = bank_forks.working_bank();
                 working_bank.print_accounts_stats();
             }
+            if print_rent_paying_accounts {
+                match bank_forks.root_bank().rent_paying_accounts_report() {
+                    Some(report) => print_rent_paying_accounts_report(&report),
+                    None => eprintln!(
+                        "No rent-paying accounts report, the ledger was not loaded from a snapshot"
+                    ),
+                }
+            }
             exit_signal.store(true, Ordering::Relaxed);
             system_monitor_service.join().unwrap();
             println!("Ok");
@@ -2411,6 +2558,14 @@ fn main() {
                         bank.slot(),
                     );
 
+                    let snapshot_config = SnapshotConfig {
+                        snapshot_archives_dir: output_directory,
+                        archive_format: ArchiveFormat::TarZstd,
+                        snapshot_version,
+                        maximum_full_snapshot_archives_to_retain,
+                        maximum_incremental_snapshot_archives_to_retain,
+                        ..SnapshotConfig::default()
+                    };
                     if is_incremental {
                         if starting_snapshot_hashes.is_none() {
                             eprintln!("Unable to create incremental snapshot without a base full snapshot");
@@ -2430,11 +2585,7 @@ fn main() {
                                 ledger_path,
                                 &bank,
                                 full_snapshot_slot,
-                                Some(snapshot_version),
-                                output_directory,
-                                ArchiveFormat::TarZstd,
-                                maximum_full_snapshot_archives_to_retain,
-                                maximum_incremental_snapshot_archives_to_retain,
+                                &snapshot_config,
                             )
                             .unwrap_or_else(|err| {
                                 eprintln!("Unable to create incremental snapshot: {}", err);
@@ -2453,11 +2604,7 @@ fn main() {
                             snapshot_utils::bank_to_full_snapshot_archive(
                                 ledger_path,
                                 &bank,
-                                Some(snapshot_version),
-                                output_directory,
-                                ArchiveFormat::TarZstd,
-                                maximum_full_snapshot_archives_to_retain,
-                                maximum_incremental_snapshot_archives_to_retain,
+                                &snapshot_config,
                             )
                             .unwrap_or_else(|err| {
                                 eprintln!("Unable to create snapshot: {}", err);
@@ -3306,6 +3453,117 @@ fn main() {
                 }
             }
         }
+        ("export-program-accounts", Some(arg_matches)) => {
+            let program_id = pubkey_of(arg_matches, "program_id").unwrap();
+            let output_path = PathBuf::from(value_t_or_exit!(arg_matches, "output", String));
+            let storage_encryption_key =
+                storage_encryption_key_of(arg_matches, "storage_encryption_key").map(Arc::new);
+            let genesis_config = open_genesis_config_by(&ledger_path, arg_matches);
+            let snapshot_archives_dir =
+                snapshot_archive_path.unwrap_or_else(|| ledger_path.clone());
+
+            let full_snapshot_archive_info =
+                snapshot_utils::get_highest_full_snapshot_archive_info(&snapshot_archives_dir)
+                    .unwrap_or_else(|| {
+                        eprintln!(
+                            "No snapshot archives found in {}",
+                            snapshot_archives_dir.display()
+                        );
+                        exit(1);
+                    });
+            let incremental_snapshot_archive_info =
+                if arg_matches.is_present("no_incremental_snapshot") {
+                    None
+                } else {
+                    snapshot_utils::get_highest_incremental_snapshot_archive_info(
+                        &snapshot_archives_dir,
+                        full_snapshot_archive_info.slot(),
+                    )
+                };
+
+            // The storages are only needed while exporting
+            let accounts_dir = ledger_path.join("program-accounts-export");
+            let result = snapshot_utils::export_program_accounts_from_snapshot_archives(
+                &[accounts_dir.clone()],
+                &snapshot_archives_dir,
+                &full_snapshot_archive_info,
+                incremental_snapshot_archive_info.as_ref(),
+                &genesis_config,
+                &SnapshotConfig::default(),
+                Some(AccountsDbConfig {
+                    storage_encryption_key,
+                    ..AccountsDbConfig::default()
+                }),
+                &program_id,
+                &output_path,
+            );
+            let _ = fs::remove_dir_all(&accounts_dir);
+            match result {
+                Ok(header) => println!(
+                    "Exported {} accounts owned by {} at slot {} (accounts hash {}) to {}",
+                    header.account_count,
+                    program_id,
+                    header.slot,
+                    header.accounts_hash,
+                    output_path.display()
+                ),
+                Err(err) => {
+                    eprintln!(
+                        "Unable to export the accounts owned by {}: {}",
+                        program_id, err
+                    );
+                    exit(1);
+                }
+            }
+        }
+        ("rotate-snapshot-storage-key", Some(arg_matches)) => {
+            // The args are required unless their commands are given, so there is always a key
+            let old_master_key = storage_encryption_key_of(arg_matches, "old_key").unwrap();
+            let new_master_key = storage_encryption_key_of(arg_matches, "new_key").unwrap();
+            let snapshot_archives_dir = snapshot_archive_path.unwrap_or(ledger_path);
+
+            let full_snapshot_archives =
+                snapshot_utils::get_full_snapshot_archives(&snapshot_archives_dir);
+            let incremental_snapshot_archives =
+                snapshot_utils::get_incremental_snapshot_archives(&snapshot_archives_dir);
+            let results = full_snapshot_archives
+                .iter()
+                .map(|archive| {
+                    (
+                        archive.path(),
+                        snapshot_utils::rotate_snapshot_archive_storage_encryption_key(
+                            archive,
+                            &old_master_key,
+                            &new_master_key,
+                            SnapshotDurability::FsyncDir,
+                        ),
+                    )
+                })
+                .chain(incremental_snapshot_archives.iter().map(|archive| {
+                    (
+                        archive.path(),
+                        snapshot_utils::rotate_snapshot_archive_storage_encryption_key(
+                            archive,
+                            &old_master_key,
+                            &new_master_key,
+                            SnapshotDurability::FsyncDir,
+                        ),
+                    )
+                }));
+            let mut failed = false;
+            for (path, result) in results {
+                match result {
+                    Ok(()) => println!("Rotated {}", path.display()),
+                    Err(err) => {
+                        eprintln!("Unable to rotate {}: {}", path.display(), err);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                exit(1);
+            }
+        }
         ("", _) => {
             eprintln!("{}", matches.usage());
             exit(1);
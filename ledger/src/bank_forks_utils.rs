@@ -180,6 +180,7 @@ fn load_from_snapshot(
             process_options.accounts_db_test_hash_calculation,
             process_options.accounts_db_skip_shrink,
             process_options.verify_index,
+            snapshot_config,
             process_options.accounts_db_config.clone(),
             accounts_update_notifier,
         )
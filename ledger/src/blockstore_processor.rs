@@ -28,7 +28,7 @@ use solana_runtime::{
     commitment::VOTE_THRESHOLD_SIZE,
     snapshot_config::SnapshotConfig,
     snapshot_package::{AccountsPackageSender, SnapshotType},
-    snapshot_utils::{self, BankFromArchiveTimings},
+    snapshot_utils::{self, BankFromArchiveTimings, SnapshotError},
     transaction_batch::TransactionBatch,
     vote_account::VoteAccount,
     vote_sender_types::ReplayVoteSender,
@@ -1184,6 +1184,8 @@ fn load_frozen_forks(
             };
 
             if let Some(new_root_bank) = new_root_bank {
+                let is_first_root_in_epoch =
+                    new_root_bank.epoch_schedule().get_epoch(*root) != new_root_bank.epoch();
                 *root = new_root_bank.slot();
                 last_root = new_root_bank.slot();
 
@@ -1192,11 +1194,15 @@ fn load_frozen_forks(
 
                 if let Some(snapshot_config) = snapshot_config {
                     let block_height = new_root_bank.block_height();
-                    if snapshot_utils::should_take_full_snapshot(
-                        block_height,
-                        snapshot_config.full_snapshot_archive_interval_slots,
-                    ) {
-                        info!("Taking snapshot of new root bank that has crossed the full snapshot interval! slot: {}", *root);
+                    if snapshot_config
+                        .full_snapshot_schedule
+                        .should_take_full_snapshot(
+                            block_height,
+                            snapshot_config.full_snapshot_archive_interval_slots,
+                            is_first_root_in_epoch,
+                        )
+                    {
+                        info!("Taking snapshot of new root bank that is due a full snapshot! slot: {}", *root);
                         *last_full_snapshot_slot = Some(*root);
                         new_root_bank.exhaustively_free_unused_resource(*last_full_snapshot_slot);
                         last_free = Instant::now();
@@ -1206,18 +1212,23 @@ fn load_frozen_forks(
                             Some(new_root_bank.epoch_schedule().slots_per_epoch),
                             false,
                         );
-                        snapshot_utils::snapshot_bank(
+                        let result = snapshot_utils::snapshot_bank(
                             new_root_bank,
                             new_root_bank.src.slot_deltas(&new_root_bank.src.roots()),
                             &accounts_package_sender,
-                            &snapshot_config.bank_snapshots_dir,
-                            &snapshot_config.snapshot_archives_dir,
-                            snapshot_config.snapshot_version,
-                            snapshot_config.archive_format,
+                            snapshot_config,
                             None,
                             Some(SnapshotType::FullSnapshot),
-                        )
-                        .expect("Failed to snapshot bank while loading frozen banks");
+                        );
+                        // A snapshot that a hook vetoed is only skipped, the veto is logged
+                        if let Err(err) = result {
+                            if !matches!(err, SnapshotError::VetoedByHook(..)) {
+                                panic!(
+                                    "Failed to snapshot bank while loading frozen banks: {:?}",
+                                    err
+                                );
+                            }
+                        }
                         trace!(
                             "took bank snapshot for new root bank, block height: {}, slot: {}",
                             block_height,
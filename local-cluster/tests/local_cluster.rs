@@ -1718,6 +1718,7 @@ fn test_snapshot_download() {
             .unwrap()
             .maximum_incremental_snapshot_archives_to_retain,
         false,
+        false,
         &mut None,
     )
     .unwrap();
@@ -1832,6 +1833,7 @@ fn test_incremental_snapshot_download() {
             .unwrap()
             .maximum_incremental_snapshot_archives_to_retain,
         false,
+        false,
         &mut None,
     )
     .unwrap();
@@ -1857,6 +1859,7 @@ fn test_incremental_snapshot_download() {
             .unwrap()
             .maximum_incremental_snapshot_archives_to_retain,
         false,
+        false,
         &mut None,
     )
     .unwrap();
@@ -1987,6 +1990,7 @@ fn test_incremental_snapshot_download_with_crossing_full_snapshot_interval_at_st
             .unwrap()
             .maximum_incremental_snapshot_archives_to_retain,
         false,
+        false,
         &mut None,
     )
     .unwrap();
@@ -2022,6 +2026,7 @@ fn test_incremental_snapshot_download_with_crossing_full_snapshot_interval_at_st
             .unwrap()
             .maximum_incremental_snapshot_archives_to_retain,
         false,
+        false,
         &mut None,
     )
     .unwrap();
@@ -95,6 +95,7 @@ fn initialize_from_snapshot(
         snapshot_config.maximum_full_snapshot_archives_to_retain,
         snapshot_config.maximum_incremental_snapshot_archives_to_retain,
         false,
+        false,
         &mut None,
     )
     .unwrap();
@@ -133,6 +134,7 @@ fn initialize_from_snapshot(
         process_options.accounts_db_test_hash_calculation,
         false,
         process_options.verify_index,
+        snapshot_config,
         process_options.accounts_db_config,
         None,
     )
@@ -45,6 +45,7 @@ use {
         commitment::{BlockCommitmentArray, BlockCommitmentCache, CommitmentSlots},
         inline_spl_token_v2_0::{SPL_TOKEN_ACCOUNT_MINT_OFFSET, SPL_TOKEN_ACCOUNT_OWNER_OFFSET},
         non_circulating_supply::calculate_non_circulating_supply,
+        snapshot_archive_info::SnapshotArchiveInfoGetter,
         snapshot_config::SnapshotConfig,
         snapshot_utils,
     },
@@ -2006,6 +2007,20 @@ impl JsonRpcRequestProcessor {
     }
 }
 
+/// Describe a snapshot archive for RPC, by where the RPC HTTP server serves it, if it still exists
+fn rpc_snapshot_archive_info(
+    archive_info: &impl SnapshotArchiveInfoGetter,
+) -> Option<RpcSnapshotArchiveInfo> {
+    let size = std::fs::metadata(archive_info.path()).ok()?.len();
+    let file_name = archive_info.path().file_name()?.to_str()?;
+    Some(RpcSnapshotArchiveInfo {
+        slot: archive_info.slot(),
+        hash: archive_info.hash().to_string(),
+        size,
+        path: format!("/{}", file_name),
+    })
+}
+
 fn optimize_filters(filters: &mut Vec<RpcFilterType>) {
     filters.iter_mut().for_each(|filter_type| {
         if let RpcFilterType::Memcmp(compare) = filter_type {
@@ -2361,6 +2376,12 @@ pub mod rpc_minimal {
         #[rpc(meta, name = "getHighestSnapshotSlot")]
         fn get_highest_snapshot_slot(&self, meta: Self::Metadata) -> Result<RpcSnapshotSlotInfo>;
 
+        #[rpc(meta, name = "getHighestSnapshotArchives")]
+        fn get_highest_snapshot_archives(
+            &self,
+            meta: Self::Metadata,
+        ) -> Result<RpcSnapshotArchivesInfo>;
+
         #[rpc(meta, name = "getTransactionCount")]
         fn get_transaction_count(
             &self,
@@ -2482,6 +2503,35 @@ pub mod rpc_minimal {
             })
         }
 
+        fn get_highest_snapshot_archives(
+            &self,
+            meta: Self::Metadata,
+        ) -> Result<RpcSnapshotArchivesInfo> {
+            debug!("get_highest_snapshot_archives rpc request received");
+
+            let snapshot_archives_dir = meta
+                .snapshot_config
+                .map(|snapshot_config| snapshot_config.snapshot_archives_dir)
+                .ok_or(RpcCustomError::NoSnapshot)?;
+
+            let full =
+                snapshot_utils::get_highest_full_snapshot_archive_info(&snapshot_archives_dir)
+                    .and_then(|archive_info| rpc_snapshot_archive_info(&archive_info))
+                    .ok_or(RpcCustomError::NoSnapshot)?;
+            let mut incremental_snapshot_archive_infos =
+                snapshot_utils::get_incremental_snapshot_archives(&snapshot_archives_dir);
+            incremental_snapshot_archive_infos
+                .retain(|archive_info| archive_info.base_slot() == full.slot);
+            incremental_snapshot_archive_infos.sort_unstable();
+            // Archives that are purged while this runs are left out
+            let incremental = incremental_snapshot_archive_infos
+                .iter()
+                .filter_map(rpc_snapshot_archive_info)
+                .collect();
+
+            Ok(RpcSnapshotArchivesInfo { full, incremental })
+        }
+
         fn get_transaction_count(
             &self,
             meta: Self::Metadata,
@@ -4301,7 +4351,10 @@ pub mod tests {
         bincode::deserialize,
         jsonrpc_core::{futures, ErrorCode, MetaIoHandler, Output, Response, Value},
         jsonrpc_core_client::transports::local,
-        solana_client::rpc_filter::{Memcmp, MemcmpEncodedBytes},
+        solana_client::{
+            rpc_custom_error::JSON_RPC_SERVER_ERROR_NO_SNAPSHOT,
+            rpc_filter::{Memcmp, MemcmpEncodedBytes},
+        },
         solana_gossip::{contact_info::ContactInfo, socketaddr},
         solana_ledger::{
             blockstore_meta::PerfSample,
@@ -4749,6 +4802,65 @@ pub mod tests {
         assert_eq!(expected, result);
     }
 
+    #[test]
+    fn test_rpc_get_highest_snapshot_archives() {
+        let genesis = create_genesis_config(10);
+        let bank = Arc::new(Bank::new_for_tests(&genesis.genesis_config));
+        let mut meta = JsonRpcRequestProcessor::new_from_bank(&bank, SocketAddrSpace::Unspecified);
+
+        let mut io = MetaIoHandler::default();
+        io.extend_with(rpc_minimal::MinimalImpl.to_delegate());
+        let req = r#"{"jsonrpc":"2.0","id":1,"method":"getHighestSnapshotArchives"}"#;
+
+        // Without a snapshot config there are no snapshots
+        let res = io.handle_request_sync(req, meta.clone());
+        let result: Value = serde_json::from_str(&res.expect("actual response"))
+            .expect("actual response deserialization");
+        assert_eq!(result["error"]["code"], JSON_RPC_SERVER_ERROR_NO_SNAPSHOT);
+
+        let snapshot_archives_dir = get_tmp_ledger_path!();
+        std::fs::create_dir_all(&snapshot_archives_dir).unwrap();
+        let hash = Hash::new_unique();
+        let archive_names = [
+            format!("snapshot-50-{}.tar.zst", hash),
+            format!("incremental-snapshot-50-60-{}.tar.zst", hash),
+            format!("snapshot-100-{}.tar.zst", hash),
+            format!("incremental-snapshot-100-120-{}.tar.zst", hash),
+            format!("incremental-snapshot-100-110-{}.tar.zst", hash),
+        ];
+        for (i, archive_name) in archive_names.iter().enumerate() {
+            std::fs::write(snapshot_archives_dir.join(archive_name), vec![0; i + 1]).unwrap();
+        }
+        meta.snapshot_config = Some(SnapshotConfig {
+            snapshot_archives_dir: snapshot_archives_dir.clone(),
+            ..SnapshotConfig::default()
+        });
+
+        let res = io.handle_request_sync(req, meta);
+        let result: Value = serde_json::from_str(&res.expect("actual response"))
+            .expect("actual response deserialization");
+        let snapshot_archives_info: RpcSnapshotArchivesInfo =
+            serde_json::from_value(result["result"].clone()).unwrap();
+        let archive_info = |slot, size, archive_name: &str| RpcSnapshotArchiveInfo {
+            slot,
+            hash: hash.to_string(),
+            size,
+            path: format!("/{}", archive_name),
+        };
+        assert_eq!(
+            snapshot_archives_info,
+            RpcSnapshotArchivesInfo {
+                full: archive_info(100, 3, &archive_names[2]),
+                incremental: vec![
+                    archive_info(110, 5, &archive_names[4]),
+                    archive_info(120, 4, &archive_names[3]),
+                ],
+            }
+        );
+
+        std::fs::remove_dir_all(snapshot_archives_dir).unwrap();
+    }
+
     #[test]
     fn test_rpc_minimum_ledger_slot() {
         let bob_pubkey = solana_sdk::pubkey::new_rand();
@@ -133,6 +133,26 @@ impl RpcRequestMiddleware {
             || self.incremental_snapshot_archive_path_regex.is_match(path)
     }
 
+    /// Whether the storages in the snapshot archive at `path` are encrypted, so that peers
+    /// without the storage master key could not load it
+    fn is_encrypted_snapshot_archive(path: &Path) -> bool {
+        let archive_format = path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|file_name| {
+                snapshot_utils::parse_full_snapshot_archive_filename(file_name)
+                    .map(|(_, _, archive_format)| archive_format)
+                    .or_else(|_| {
+                        snapshot_utils::parse_incremental_snapshot_archive_filename(file_name)
+                            .map(|(_, _, _, archive_format)| archive_format)
+                    })
+                    .ok()
+            });
+        archive_format.map_or(false, |archive_format| {
+            snapshot_utils::is_snapshot_archive_encrypted(path, archive_format).unwrap_or(false)
+        })
+    }
+
     #[cfg(unix)]
     async fn open_no_follow(path: impl AsRef<Path>) -> std::io::Result<tokio::fs::File> {
         tokio::fs::OpenOptions::new()
@@ -160,11 +180,20 @@ impl RpcRequestMiddleware {
                 }
                 _ => {
                     inc_new_counter_info!("rpc-get_snapshot", 1);
-                    self.snapshot_config
+                    let filename = self
+                        .snapshot_config
                         .as_ref()
                         .unwrap()
                         .snapshot_archives_dir
-                        .join(stem)
+                        .join(stem);
+                    if Self::is_encrypted_snapshot_archive(&filename) {
+                        info!(
+                            "get {} -> {:?} is encrypted, not serving it",
+                            path, filename
+                        );
+                        return Self::not_found().into();
+                    }
+                    filename
                 }
             }
         };
@@ -215,11 +244,17 @@ impl RequestMiddleware for RpcRequestMiddleware {
 
         if let Some(ref snapshot_config) = self.snapshot_config {
             if request.uri().path() == "/snapshot.tar.bz2" {
-                // Convenience redirect to the latest snapshot
+                // Convenience redirect to the latest snapshot that is not encrypted
                 return if let Some(full_snapshot_archive_info) =
-                    snapshot_utils::get_highest_full_snapshot_archive_info(
+                    snapshot_utils::get_full_snapshot_archives(
                         &snapshot_config.snapshot_archives_dir,
-                    ) {
+                    )
+                    .into_iter()
+                    .filter(|archive_info| {
+                        !Self::is_encrypted_snapshot_archive(archive_info.path())
+                    })
+                    .max_by_key(|archive_info| archive_info.slot())
+                {
                     RpcRequestMiddleware::redirect(&format!(
                         "/{}",
                         full_snapshot_archive_info
@@ -78,6 +78,8 @@ impl SendDroppedBankCallback {
 pub struct SnapshotRequest {
     pub snapshot_root_bank: Arc<Bank>,
     pub status_cache_slot_deltas: Vec<BankSlotDelta>,
+    /// The root bank is the first bank rooted in its epoch
+    pub is_first_root_in_epoch: bool,
 }
 
 pub struct SnapshotRequestHandler {
@@ -96,14 +98,29 @@ impl SnapshotRequestHandler {
         non_snapshot_time_us: u128,
         last_full_snapshot_slot: &mut Option<Slot>,
     ) -> Option<Result<u64, SnapshotError>> {
+        let full_snapshot_schedule = self.snapshot_config.full_snapshot_schedule;
         self.snapshot_request_receiver
             .try_iter()
-            .last()
+            .fold(None, |selected_request: Option<SnapshotRequest>, snapshot_request| {
+                // Only the latest request is handled, unless the schedule wants a full snapshot
+                // of an earlier one at an epoch boundary
+                match selected_request {
+                    Some(selected_request)
+                        if full_snapshot_schedule.includes_epoch_boundary()
+                            && selected_request.is_first_root_in_epoch
+                            && !snapshot_request.is_first_root_in_epoch =>
+                    {
+                        Some(selected_request)
+                    }
+                    _ => Some(snapshot_request),
+                }
+            })
             .map(|snapshot_request| {
                 let mut total_time = Measure::start("snapshot_request_receiver_total_time");
                 let SnapshotRequest {
                     snapshot_root_bank,
                     status_cache_slot_deltas,
+                    is_first_root_in_epoch,
                 } = snapshot_request;
 
                 let previous_hash = if test_hash_calculation {
@@ -158,6 +175,12 @@ impl SnapshotRequestHandler {
                 };
                 hash_time.stop();
 
+                // With hooks that may still veto its archive, a full snapshot only becomes the
+                // base of incremental snapshots, and of cleaning, once its archive is published
+                let snapshot_hooks = &self.snapshot_config.hooks;
+                *last_full_snapshot_slot =
+                    (*last_full_snapshot_slot).max(snapshot_hooks.last_published_full_snapshot_slot());
+
                 let mut clean_time = Measure::start("clean_time");
                 // Don't clean the slot we're snapshotting because it may have zero-lamport
                 // accounts that were included in the bank delta hash when the bank was frozen,
@@ -173,11 +196,14 @@ impl SnapshotRequestHandler {
                 }
 
                 let block_height = snapshot_root_bank.block_height();
-                let snapshot_type = if snapshot_utils::should_take_full_snapshot(
+                let snapshot_type = if full_snapshot_schedule.should_take_full_snapshot(
                     block_height,
                     self.snapshot_config.full_snapshot_archive_interval_slots,
+                    is_first_root_in_epoch,
                 ) {
-                    *last_full_snapshot_slot = Some(snapshot_root_bank.slot());
+                    if snapshot_hooks.is_empty() {
+                        *last_full_snapshot_slot = Some(snapshot_root_bank.slot());
+                    }
                     Some(SnapshotType::FullSnapshot)
                 } else if snapshot_utils::should_take_incremental_snapshot(
                     block_height,
@@ -198,10 +224,7 @@ impl SnapshotRequestHandler {
                     &snapshot_root_bank,
                     status_cache_slot_deltas,
                     &self.accounts_package_sender,
-                    &self.snapshot_config.bank_snapshots_dir,
-                    &self.snapshot_config.snapshot_archives_dir,
-                    self.snapshot_config.snapshot_version,
-                    self.snapshot_config.archive_format,
+                    &self.snapshot_config,
                     hash_for_testing,
                     snapshot_type,
                 );
@@ -276,6 +299,19 @@ impl SnapshotRequestHandler {
             SnapshotError::MismatchedBaseSlot(..) => true,
             SnapshotError::NoSnapshotArchives => true,
             SnapshotError::MismatchedSlotHash(..) => true,
+            SnapshotError::ArchiveHashMismatch(..) => true,
+            SnapshotError::Scan(..) => true,
+            SnapshotError::CreateAccountPath(..) => true,
+            SnapshotError::NoStorageEntries => true,
+            SnapshotError::StorageIdTooLarge(..) => true,
+            SnapshotError::AccountsDbStillShared => true,
+            SnapshotError::RestoreNotifierPanicked => true,
+            SnapshotError::RestoreNotifierThreadPool(..) => true,
+            SnapshotError::TooManyStorages(..) => true,
+            SnapshotError::TooManyAccountsInStorage(..) => true,
+            SnapshotError::TooManyStorageBytes(..) => true,
+            SnapshotError::VetoedByHook(..) => false,
+            SnapshotError::AccountsVerificationFailed(..) => true,
         }
     }
 }
@@ -393,6 +429,14 @@ impl AccountsBackgroundService {
 
                     Self::expire_old_recycle_stores(&bank, &mut last_expiration_check_time);
 
+                    // While the storages of the bank rebuilt from a snapshot are still being
+                    // hashed, they must not be cleaned or shrunk.  So the snapshot requests,
+                    // which clean and shrink too, are left in the channel until the verification
+                    // is done.  The cache still gets flushed, as that only adds storages for the
+                    // slots rooted since.
+                    let accounts_hash_verification_pending =
+                        bank.is_accounts_hash_verification_pending();
+
                     let non_snapshot_time = last_snapshot_end_time
                         .map(|last_snapshot_end_time: Instant| {
                             last_snapshot_end_time.elapsed().as_micros()
@@ -416,14 +460,18 @@ impl AccountsBackgroundService {
                     // request for `N` to the snapshot request channel before setting a root `R > N`, and
                     // snapshot_request_handler.handle_requests() will always look for the latest
                     // available snapshot in the channel.
-                    let snapshot_block_height_option_result = request_handler
-                        .handle_snapshot_requests(
+                    let snapshot_block_height_option_result = if accounts_hash_verification_pending
+                    {
+                        None
+                    } else {
+                        request_handler.handle_snapshot_requests(
                             accounts_db_caching_enabled,
                             test_hash_calculation,
                             use_index_hash_calculation,
                             non_snapshot_time,
                             &mut last_full_snapshot_slot,
-                        );
+                        )
+                    };
                     if snapshot_block_height_option_result.is_some() {
                         last_snapshot_end_time = Some(Instant::now());
                     }
@@ -446,7 +494,7 @@ impl AccountsBackgroundService {
                             exit.store(true, Ordering::Relaxed);
                             return;
                         }
-                    } else {
+                    } else if !accounts_hash_verification_pending {
                         if accounts_db_caching_enabled {
                             bank.shrink_candidate_slots();
                         } else {
@@ -30,13 +30,17 @@ use crate::{
     },
     accounts_update_notifier_interface::AccountsUpdateNotifier,
     ancestors::Ancestors,
-    append_vec::{AppendVec, StoredAccountMeta, StoredMeta, StoredMetaWriteVersion},
+    append_vec::{
+        AppendVec, AppendVecValidation, StoredAccountMeta, StoredMeta, StoredMetaWriteVersion,
+    },
     cache_hash_data::CacheHashData,
     contains::Contains,
     pubkey_bins::PubkeyBinCalculator24,
     read_only_accounts_cache::ReadOnlyAccountsCache,
     rent_collector::RentCollector,
+    serde_snapshot::SnapshotStorageLimits,
     sorted_storages::SortedStorages,
+    storage_encryption::MasterKey,
 };
 use blake3::traits::digest::Digest;
 use crossbeam_channel::{unbounded, Receiver, Sender};
@@ -65,7 +69,7 @@ use solana_vote_program::vote_state::MAX_LOCKOUT_HISTORY;
 use std::{
     borrow::{Borrow, Cow},
     boxed::Box,
-    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
     convert::TryFrom,
     hash::{Hash as StdHash, Hasher as StdHasher},
     io::{Error as IoError, Result as IoResult},
@@ -74,7 +78,7 @@ use std::{
     str::FromStr,
     sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     sync::{Arc, Condvar, Mutex, MutexGuard, RwLock},
-    thread::Builder,
+    thread::{Builder, JoinHandle},
     time::Instant,
 };
 use tempfile::TempDir;
@@ -129,23 +133,80 @@ pub const ACCOUNTS_DB_CONFIG_FOR_TESTING: AccountsDbConfig = AccountsDbConfig {
     index: Some(ACCOUNTS_INDEX_CONFIG_FOR_TESTING),
     accounts_hash_cache_path: None,
     filler_account_count: None,
+    lazy_filler_accounts: false,
+    verify_accounts_hash_in_background: false,
     hash_calc_num_passes: None,
+    collect_rent_paying_accounts_report: false,
+    snapshot_restore_notify_mode: SnapshotRestoreNotifyMode::Background,
+    storage_validation: AppendVecValidation::Full,
+    deterministic_snapshot_serialization: false,
+    snapshot_storage_limits: SnapshotStorageLimits::DEFAULT,
+    storage_encryption_key: None,
 };
 pub const ACCOUNTS_DB_CONFIG_FOR_BENCHMARKS: AccountsDbConfig = AccountsDbConfig {
     index: Some(ACCOUNTS_INDEX_CONFIG_FOR_BENCHMARKS),
     accounts_hash_cache_path: None,
     filler_account_count: None,
+    lazy_filler_accounts: false,
+    verify_accounts_hash_in_background: false,
     hash_calc_num_passes: None,
+    collect_rent_paying_accounts_report: false,
+    snapshot_restore_notify_mode: SnapshotRestoreNotifyMode::Background,
+    storage_validation: AppendVecValidation::Full,
+    deterministic_snapshot_serialization: false,
+    snapshot_storage_limits: SnapshotStorageLimits::DEFAULT,
+    storage_encryption_key: None,
 };
 
 pub type BinnedHashData = Vec<Vec<CalculateHashIntermediate>>;
 
+/// How the accounts update notifier is told about the accounts restored from a snapshot.  In every
+/// mode the notifications are done by the time the AccountsDb is returned, and nothing is spawned
+/// if there is no notifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotRestoreNotifyMode {
+    /// Notify on the restoring thread, once the index has been generated
+    Inline,
+    /// Notify from a single thread, alongside generating the index
+    Background,
+    /// Notify from a pool of this many threads, alongside generating the index.  The accounts are
+    /// split among the threads by slot.
+    Pool(usize),
+}
+
+impl Default for SnapshotRestoreNotifyMode {
+    fn default() -> Self {
+        Self::Background
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct AccountsDbConfig {
     pub index: Option<AccountsIndexConfig>,
     pub accounts_hash_cache_path: Option<PathBuf>,
     pub filler_account_count: Option<usize>,
+    /// Add the filler accounts on a background thread once the bank has been rebuilt from a
+    /// snapshot, instead of while rebuilding it
+    pub lazy_filler_accounts: bool,
+    /// Verify the accounts hash of a bank rebuilt from a snapshot on a background thread once the
+    /// bank has been returned, instead of before returning it
+    pub verify_accounts_hash_in_background: bool,
     pub hash_calc_num_passes: Option<usize>,
+    /// Tally the rent-paying accounts while generating the index at startup
+    pub collect_rent_paying_accounts_report: bool,
+    pub snapshot_restore_notify_mode: SnapshotRestoreNotifyMode,
+    /// How thoroughly to check the storages of a snapshot before rebuilding from them
+    pub storage_validation: AppendVecValidation,
+    /// Serialize and archive snapshots so the same bank always produces the same bytes: the bank
+    /// snapshot files are written with their maps in key order and the storages renumbered in
+    /// slot and id order, and the archives with their entries in a fixed order and with
+    /// normalized metadata.  Encrypted storages cannot be archived this way.
+    pub deterministic_snapshot_serialization: bool,
+    /// Upper bounds on the storages of a snapshot to rebuild from
+    pub snapshot_storage_limits: SnapshotStorageLimits,
+    /// Master key to encrypt the storages in the account paths under, and to decrypt them with,
+    /// if any.  See `storage_encryption`.
+    pub storage_encryption_key: Option<Arc<MasterKey>>,
 }
 
 struct FoundStoredAccount<'a> {
@@ -230,6 +291,54 @@ struct GenerateIndexTimings {
     pub total_duplicates: u64,
 }
 
+/// Rent-paying accounts owned by a single program
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct RentPayingAccountsByOwner {
+    /// number of rent-paying accounts
+    pub count: u64,
+    /// sum of the lamports held by the rent-paying accounts
+    pub lamports: u64,
+    /// number of accounts projected to run out of lamports, keyed by the epoch they are evicted in
+    pub eviction_epochs: BTreeMap<Epoch, u64>,
+}
+
+/// Tally of the rent-paying (non-exempt) accounts found while generating the index
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct RentPayingAccountsReport {
+    /// the epoch eviction projections are made from
+    pub epoch: Epoch,
+    pub by_owner: HashMap<Pubkey, RentPayingAccountsByOwner>,
+}
+
+impl RentPayingAccountsReport {
+    pub fn total_count(&self) -> u64 {
+        self.by_owner.values().map(|by_owner| by_owner.count).sum()
+    }
+
+    pub fn total_lamports(&self) -> u64 {
+        self.by_owner
+            .values()
+            .map(|by_owner| by_owner.lamports)
+            .sum()
+    }
+
+    /// owners ordered by the lamports held in their rent-paying accounts, largest first
+    pub fn owners_by_lamports(&self) -> Vec<(&Pubkey, &RentPayingAccountsByOwner)> {
+        let mut owners = self.by_owner.iter().collect::<Vec<_>>();
+        owners.sort_unstable_by(|a, b| b.1.lamports.cmp(&a.1.lamports).then(a.0.cmp(b.0)));
+        owners
+    }
+}
+
+/// The latest rent-paying version seen of an account while generating the index
+struct RentPayingAccount {
+    slot: Slot,
+    owner: Pubkey,
+    lamports: u64,
+    eviction_epoch: Option<Epoch>,
+}
+type RentPayingAccountsMap = DashMap<Pubkey, RentPayingAccount>;
+
 #[derive(Default, Debug, PartialEq)]
 struct StorageSizeAndCount {
     pub stored_size: usize,
@@ -644,9 +753,33 @@ pub struct AccountStorageEntry {
 
 impl AccountStorageEntry {
     pub fn new(path: &Path, slot: Slot, id: usize, file_size: u64) -> Self {
+        Self::new_with_encryption(path, slot, id, file_size, None)
+    }
+
+    /// Like `new()`, but with the storage encrypted under `storage_encryption_key`, if there is one
+    pub fn new_with_encryption(
+        path: &Path,
+        slot: Slot,
+        id: usize,
+        file_size: u64,
+        storage_encryption_key: Option<Arc<MasterKey>>,
+    ) -> Self {
         let tail = AppendVec::file_name(slot, id);
         let path = Path::new(path).join(tail);
-        let accounts = AppendVec::new(&path, true, file_size as usize);
+        let accounts = match storage_encryption_key {
+            None => AppendVec::new(&path, true, file_size as usize),
+            Some(storage_encryption_key) => {
+                AppendVec::new_encrypted(&path, file_size as usize, storage_encryption_key)
+                    .unwrap_or_else(|e| {
+                        panic!(
+                            "Unable to create encrypted data file {} in current dir({:?}): {:?}",
+                            path.display(),
+                            std::env::current_dir(),
+                            e
+                        )
+                    })
+            }
+        };
 
         Self {
             id: AtomicUsize::new(id),
@@ -698,6 +831,7 @@ impl AccountStorageEntry {
     pub fn recycle(&self, slot: Slot, id: usize) {
         let mut count_and_status = self.count_and_status.write().unwrap();
         self.accounts.reset();
+        self.accounts.rebind(slot, id);
         *count_and_status = (0, AccountStorageStatus::Available);
         self.slot.store(slot, Ordering::Release);
         self.id.store(id, Ordering::Relaxed);
@@ -1065,6 +1199,34 @@ pub struct AccountsDb {
 
     filler_account_count: usize,
     pub filler_account_suffix: Option<Pubkey>,
+    /// Leave the filler accounts out of reconstructing from a snapshot, for
+    /// `maybe_add_filler_accounts_in_background()` to add afterwards
+    pub(crate) lazy_filler_accounts: bool,
+    /// Whether all of the filler accounts have been added (trivially so if there are none)
+    filler_accounts_added: AtomicBool,
+    /// The thread adding the filler accounts in the background, once it has been started
+    filler_accounts_thread: Mutex<Option<JoinHandle<()>>>,
+
+    /// Leave verifying the accounts hash of a bank rebuilt from a snapshot to
+    /// `verify_bank_hash_and_lamports_in_background()`
+    pub(crate) verify_accounts_hash_in_background: bool,
+    /// Whether the background accounts hash verification has been started and not finished yet
+    accounts_hash_verification_pending: AtomicBool,
+    /// The thread verifying the accounts hash in the background, once it has been started
+    accounts_hash_verification_thread: Mutex<Option<JoinHandle<()>>>,
+    /// Where the background accounts hash verification delivers its result, until it is taken
+    accounts_hash_verification_receiver: Mutex<Option<Receiver<bool>>>,
+
+    collect_rent_paying_accounts_report: bool,
+    /// Rent-paying accounts found by `generate_index`, if `collect_rent_paying_accounts_report`
+    rent_paying_accounts_report: RwLock<Option<RentPayingAccountsReport>>,
+
+    /// Whether snapshots are serialized and archived so that the same bank always produces the
+    /// same bytes, see `AccountsDbConfig::deterministic_snapshot_serialization`
+    pub(crate) deterministic_snapshot_serialization: bool,
+
+    /// Master key to encrypt new storages under, and to decrypt the storages of snapshots with
+    pub(crate) storage_encryption_key: Option<Arc<MasterKey>>,
 
     // # of passes should be a function of the total # of accounts that are active.
     // higher passes = slower total time, lower dynamic memory usage
@@ -1599,6 +1761,17 @@ impl AccountsDb {
             accounts_update_notifier: None,
             filler_account_count: 0,
             filler_account_suffix: None,
+            lazy_filler_accounts: false,
+            filler_accounts_added: AtomicBool::new(true),
+            filler_accounts_thread: Mutex::default(),
+            verify_accounts_hash_in_background: false,
+            accounts_hash_verification_pending: AtomicBool::default(),
+            accounts_hash_verification_thread: Mutex::default(),
+            accounts_hash_verification_receiver: Mutex::default(),
+            collect_rent_paying_accounts_report: false,
+            rent_paying_accounts_report: RwLock::default(),
+            deterministic_snapshot_serialization: false,
+            storage_encryption_key: None,
             num_hash_scan_passes,
         }
     }
@@ -1650,6 +1823,25 @@ impl AccountsDb {
         } else {
             None
         };
+        let lazy_filler_accounts = accounts_db_config
+            .as_ref()
+            .map(|cfg| cfg.lazy_filler_accounts)
+            .unwrap_or_default();
+        let verify_accounts_hash_in_background = accounts_db_config
+            .as_ref()
+            .map(|cfg| cfg.verify_accounts_hash_in_background)
+            .unwrap_or_default();
+        let collect_rent_paying_accounts_report = accounts_db_config
+            .as_ref()
+            .map(|cfg| cfg.collect_rent_paying_accounts_report)
+            .unwrap_or_default();
+        let deterministic_snapshot_serialization = accounts_db_config
+            .as_ref()
+            .map(|cfg| cfg.deterministic_snapshot_serialization)
+            .unwrap_or_default();
+        let storage_encryption_key = accounts_db_config
+            .as_ref()
+            .and_then(|cfg| cfg.storage_encryption_key.clone());
         let paths_is_empty = paths.is_empty();
         let mut new = Self {
             paths,
@@ -1660,6 +1852,12 @@ impl AccountsDb {
             accounts_update_notifier,
             filler_account_count,
             filler_account_suffix,
+            lazy_filler_accounts,
+            verify_accounts_hash_in_background,
+            filler_accounts_added: AtomicBool::new(filler_account_count == 0),
+            collect_rent_paying_accounts_report,
+            deterministic_snapshot_serialization,
+            storage_encryption_key,
             ..Self::default_with_accounts_index(
                 accounts_index,
                 accounts_hash_cache_path,
@@ -1714,11 +1912,12 @@ impl AccountsDb {
     }
 
     fn new_storage_entry(&self, slot: Slot, path: &Path, size: u64) -> AccountStorageEntry {
-        AccountStorageEntry::new(
+        AccountStorageEntry::new_with_encryption(
             path,
             slot,
             self.next_id.fetch_add(1, Ordering::Relaxed),
             size,
+            self.storage_encryption_key.clone(),
         )
     }
 
@@ -2056,6 +2255,8 @@ impl AccountsDb {
         is_startup: bool,
         last_full_snapshot_slot: Option<Slot>,
     ) {
+        self.wait_for_filler_accounts();
+        self.wait_for_accounts_hash_verification();
         let mut measure_all = Measure::start("clean_accounts");
         let max_clean_root = self.max_clean_root(max_clean_root);
 
@@ -2979,6 +3180,8 @@ impl AccountsDb {
     }
 
     pub fn shrink_candidate_slots(&self) -> usize {
+        self.wait_for_filler_accounts();
+        self.wait_for_accounts_hash_verification();
         let shrink_candidates_slots =
             std::mem::take(&mut *self.shrink_candidate_slots.lock().unwrap());
         let (shrink_slots, shrink_slots_next_batch) = {
@@ -3025,6 +3228,8 @@ impl AccountsDb {
     }
 
     pub fn shrink_all_slots(&self, is_startup: bool, last_full_snapshot_slot: Option<Slot>) {
+        self.wait_for_filler_accounts();
+        self.wait_for_accounts_hash_verification();
         const DIRTY_STORES_CLEANING_THRESHOLD: usize = 10_000;
         const OUTER_CHUNK_SIZE: usize = 2000;
         if is_startup && self.caching_enabled {
@@ -5779,6 +5984,90 @@ impl AccountsDb {
         }
     }
 
+    /// Run `verify_bank_hash_and_lamports()` on a background thread.  The result is sent over
+    /// the channel returned by `take_accounts_hash_verification_receiver()`, and until it is,
+    /// `accounts_hash_verification_pending()` is true.  Meanwhile, cleaning and shrinking wait
+    /// for it in `wait_for_accounts_hash_verification()`, as the storages being hashed must not
+    /// change underneath it.
+    pub(crate) fn verify_bank_hash_and_lamports_in_background(
+        self: &Arc<Self>,
+        slot: Slot,
+        ancestors: Ancestors,
+        total_lamports: u64,
+        test_hash_calculation: bool,
+    ) {
+        let (sender, receiver) = unbounded();
+        *self.accounts_hash_verification_receiver.lock().unwrap() = Some(receiver);
+        let mut accounts_hash_verification_thread =
+            self.accounts_hash_verification_thread.lock().unwrap();
+        self.accounts_hash_verification_pending
+            .store(true, Ordering::Release);
+        let accounts_db = self.clone();
+        let thread = Builder::new()
+            .name("solana-db-verify-hash".to_string())
+            .spawn(move || {
+                let mut verify_time = Measure::start("verify_bank_hash");
+                let verified = match accounts_db.verify_bank_hash_and_lamports(
+                    slot,
+                    &ancestors,
+                    total_lamports,
+                    test_hash_calculation,
+                ) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        warn!("verify_bank_hash failed in the background: {:?}", err);
+                        false
+                    }
+                };
+                verify_time.stop();
+                accounts_db
+                    .accounts_hash_verification_pending
+                    .store(false, Ordering::Release);
+                datapoint_info!(
+                    "verify_bank_hash_in_background",
+                    ("slot", slot, i64),
+                    ("verified", verified, bool),
+                    ("verify_bank_hash_us", verify_time.as_us(), i64),
+                );
+                // nobody may be listening for the result
+                let _ = sender.send(verified);
+            })
+            .unwrap();
+        *accounts_hash_verification_thread = Some(thread);
+    }
+
+    /// Block until the background accounts hash verification started by
+    /// `verify_bank_hash_and_lamports_in_background()` is done.  Returns right away if it was
+    /// never started.
+    pub fn wait_for_accounts_hash_verification(&self) {
+        if !self.accounts_hash_verification_pending() {
+            return;
+        }
+        // Hold on to the lock while joining, so that every caller waits, not just the first one
+        let mut accounts_hash_verification_thread =
+            self.accounts_hash_verification_thread.lock().unwrap();
+        if let Some(accounts_hash_verification_thread) = accounts_hash_verification_thread.take() {
+            accounts_hash_verification_thread.join().unwrap();
+        }
+    }
+
+    /// true while the accounts hash of the bank rebuilt from a snapshot is being verified in the
+    /// background.  The storages being hashed must not be cleaned or shrunk meanwhile, so cleaning
+    /// and shrinking wait for it to finish.
+    pub fn accounts_hash_verification_pending(&self) -> bool {
+        self.accounts_hash_verification_pending
+            .load(Ordering::Acquire)
+    }
+
+    /// Take the channel that the result of the background accounts hash verification is sent
+    /// over, if it was started and the channel has not been taken already
+    pub fn take_accounts_hash_verification_receiver(&self) -> Option<Receiver<bool>> {
+        self.accounts_hash_verification_receiver
+            .lock()
+            .unwrap()
+            .take()
+    }
+
     /// Perform the scan for pubkeys that were written to in a slot
     fn do_scan_slot_for_dirty_pubkeys(
         &self,
@@ -5822,6 +6111,58 @@ impl AccountsDb {
         self.uncleaned_pubkeys.insert(slot, dirty_pubkeys);
     }
 
+    /// The accounts delta hash of the latest versions of the accounts in `storages`, which must
+    /// all be of one slot.  This is the slot's accounts delta hash, unless its storages were shrunk
+    /// since.  With `recompute_hashes`, the hashes of the accounts are computed from their data
+    /// instead of being taken from the storages.
+    pub fn calculate_storages_delta_hash(
+        storages: &[Arc<AccountStorageEntry>],
+        recompute_hashes: bool,
+    ) -> Hash {
+        Self::calculate_storages_delta_hash_excluding(storages, recompute_hashes, |_| false)
+    }
+
+    /// Like `calculate_storages_delta_hash()`, but leaving out the accounts that `is_excluded`,
+    /// e.g. the filler accounts that were added to the storages after they were loaded
+    pub fn calculate_storages_delta_hash_excluding(
+        storages: &[Arc<AccountStorageEntry>],
+        recompute_hashes: bool,
+        is_excluded: impl Fn(&Pubkey) -> bool,
+    ) -> Hash {
+        let mut latest_hashes = HashMap::<Pubkey, (StoredMetaWriteVersion, Hash)>::new();
+        for storage in storages {
+            let slot = storage.slot();
+            for account in storage
+                .all_accounts()
+                .into_iter()
+                .filter(|account| !is_excluded(&account.meta.pubkey))
+            {
+                let write_version = account.meta.write_version;
+                let hash = if recompute_hashes {
+                    Self::hash_stored_account(slot, &account)
+                } else {
+                    *account.hash
+                };
+                match latest_hashes.entry(account.meta.pubkey) {
+                    Entry::Occupied(mut occupied_entry) => {
+                        if write_version > occupied_entry.get().0 {
+                            occupied_entry.insert((write_version, hash));
+                        }
+                    }
+                    Entry::Vacant(vacant_entry) => {
+                        vacant_entry.insert((write_version, hash));
+                    }
+                }
+            }
+        }
+        AccountsHash::accumulate_account_hashes(
+            latest_hashes
+                .into_iter()
+                .map(|(pubkey, (_write_version, hash))| (pubkey, hash))
+                .collect(),
+        )
+    }
+
     pub fn get_accounts_delta_hash(&self, slot: Slot) -> Hash {
         let mut scan = Measure::start("scan");
 
@@ -6563,6 +6904,7 @@ impl AccountsDb {
         snapshot_base_slot: Option<Slot>,
         ancestors: Option<&Ancestors>,
     ) -> (SnapshotStorages, Vec<Slot>) {
+        self.wait_for_filler_accounts();
         let mut m = Measure::start("get slots");
         let slots = self
             .storage
@@ -6639,6 +6981,129 @@ impl AccountsDb {
         (result, slots)
     }
 
+    /// Leave the storages of the slots before `boundary_slot` out of a full snapshot's
+    /// `snapshot_storages`, as returned by `get_snapshot_storages()` for `snapshot_slot`, except
+    /// for the latest versions of the accounts that are not written again up to `snapshot_slot`.
+    /// Where a slot has other accounts too, the latest versions are rewritten into a new storage
+    /// of that same slot in `pruned_storages_dir`, so their hashes do not change.  The new
+    /// storages are only referenced by the returned storages, and so go away with them; the
+    /// storages in use are left untouched.
+    ///
+    /// Which versions are the latest is looked up in the accounts index, as of `snapshot_slot`,
+    /// so the snapshot still has every account it would have had otherwise, and loads like any
+    /// other.
+    pub fn prune_snapshot_storages(
+        &self,
+        snapshot_storages: SnapshotStorages,
+        boundary_slot: Slot,
+        snapshot_slot: Slot,
+        pruned_storages_dir: &Path,
+    ) -> std::io::Result<SnapshotStorages> {
+        let mut measure = Measure::start("prune_snapshot_storages");
+        let (old_storages, mut pruned_storages): (SnapshotStorages, SnapshotStorages) =
+            snapshot_storages.into_iter().partition(|slot_storages| {
+                slot_storages
+                    .first()
+                    .map_or(false, |storage| storage.slot() < boundary_slot)
+            });
+        let old_storage_count = old_storages.iter().map(Vec::len).sum::<usize>();
+
+        // the index points at the version that is read at `snapshot_slot`, which is one written
+        // again from the boundary on if there is any
+        let is_latest_version =
+            |storage: &AccountStorageEntry, account: &StoredAccountMeta| match self
+                .accounts_index
+                .get(&account.meta.pubkey, None, Some(snapshot_slot))
+            {
+                AccountIndexGetResult::Found(entry, index) => {
+                    let (slot, account_info) = &entry.slot_list()[index];
+                    *slot == storage.slot()
+                        && account_info.store_id == storage.append_vec_id()
+                        && account_info.offset == account.offset
+                }
+                AccountIndexGetResult::NotFoundOnFork | AccountIndexGetResult::Missing(_) => false,
+            };
+
+        let retained_storages = self.thread_pool_clean.install(|| {
+            old_storages
+                .into_par_iter()
+                .map(|slot_storages| {
+                    let slot = match slot_storages.first() {
+                        Some(storage) => storage.slot(),
+                        None => return Ok(None),
+                    };
+                    let mut account_count = 0;
+                    let retained = slot_storages
+                        .iter()
+                        .flat_map(|storage| {
+                            let accounts = storage.all_accounts();
+                            account_count += accounts.len();
+                            accounts
+                                .into_iter()
+                                .filter(|account| {
+                                    account.lamports() != 0 && is_latest_version(storage, account)
+                                })
+                                .collect::<Vec<_>>()
+                        })
+                        .collect::<Vec<_>>();
+                    if retained.is_empty() {
+                        return Ok(None);
+                    }
+                    if retained.len() == account_count {
+                        // nothing to leave out, so the storages can go in as they are
+                        return Ok(Some(slot_storages));
+                    }
+
+                    let size = retained
+                        .iter()
+                        .map(|account| account.stored_size as u64)
+                        .sum();
+                    let store = Arc::new(self.new_storage_entry(
+                        slot,
+                        pruned_storages_dir,
+                        Self::page_align(size),
+                    ));
+                    let metas = retained
+                        .iter()
+                        .map(|account| (account.meta.clone(), Some(account)))
+                        .collect::<Vec<_>>();
+                    let hashes = retained
+                        .iter()
+                        .map(|account| account.hash)
+                        .collect::<Vec<_>>();
+                    let offsets = store.accounts.append_accounts(&metas, &hashes);
+                    if offsets.len() != retained.len() + 1 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            format!(
+                                "the pruned storage of slot {} only took {} of its {} accounts",
+                                slot,
+                                offsets.len().saturating_sub(1),
+                                retained.len()
+                            ),
+                        ));
+                    }
+                    retained
+                        .iter()
+                        .for_each(|account| store.add_account(account.stored_size));
+                    Ok(Some(vec![store]))
+                })
+                .collect::<std::io::Result<Vec<_>>>()
+        })?;
+
+        let retained_slot_count = retained_storages.iter().flatten().count();
+        pruned_storages.extend(retained_storages.into_iter().flatten());
+        measure.stop();
+        datapoint_info!(
+            "prune_snapshot_storages",
+            ("boundary_slot", boundary_slot, i64),
+            ("old_storages", old_storage_count, i64),
+            ("retained_slots", retained_slot_count, i64),
+            ("total_us", measure.as_us(), i64),
+        );
+        Ok(pruned_storages)
+    }
+
     fn process_storage_slot<'a>(
         &self,
         storage_maps: &'a [Arc<AccountStorageEntry>],
@@ -6650,6 +7115,11 @@ impl AccountsDb {
         let mut accounts_map = GenerateIndexAccountsMap::with_capacity(num_accounts);
         storage_maps.iter().for_each(|storage| {
             let accounts = storage.all_accounts();
+            // The storage may have been opened without counting its accounts, see
+            // `AppendVecValidation`
+            storage
+                .approx_store_count
+                .store(accounts.len(), Ordering::Relaxed);
             accounts.into_iter().for_each(|stored_account| {
                 let this_version = stored_account.meta.write_version;
                 let pubkey = stored_account.meta.pubkey;
@@ -6681,11 +7151,13 @@ impl AccountsDb {
     }
 
     /// return time_us, # accts rent exempt, total # accts
+    /// if 'rent_paying_accounts' is Some, the rent-paying accounts in this slot are recorded in it
     fn generate_index_for_slot<'a>(
         &self,
         accounts_map: GenerateIndexAccountsMap<'a>,
         slot: &Slot,
         rent_collector: &RentCollector,
+        rent_paying_accounts: Option<&RentPayingAccountsMap>,
     ) -> (u64, u64, u64) {
         if accounts_map.is_empty() {
             return (0, 0, 0);
@@ -6718,6 +7190,21 @@ impl AccountsDb {
                     exempt
                 } {
                     rent_exempt += 1;
+                } else if let Some(rent_paying_accounts) = rent_paying_accounts {
+                    let lamports = stored_account.account_meta.lamports;
+                    if lamports > 0 {
+                        Self::record_rent_paying_account(
+                            rent_paying_accounts,
+                            pubkey,
+                            RentPayingAccount {
+                                slot: *slot,
+                                owner: stored_account.account_meta.owner,
+                                lamports,
+                                eviction_epoch: rent_collector
+                                    .get_projected_eviction_epoch(&stored_account),
+                            },
+                        );
+                    }
                 }
 
                 (
@@ -6745,6 +7232,66 @@ impl AccountsDb {
         (insert_us, rent_exempt, len as u64)
     }
 
+    /// keep only the rent-paying version of 'pubkey' from the highest slot
+    fn record_rent_paying_account(
+        rent_paying_accounts: &RentPayingAccountsMap,
+        pubkey: Pubkey,
+        rent_paying_account: RentPayingAccount,
+    ) {
+        match rent_paying_accounts.entry(pubkey) {
+            Occupied(mut entry) => {
+                if entry.get().slot < rent_paying_account.slot {
+                    entry.insert(rent_paying_account);
+                }
+            }
+            Vacant(entry) => {
+                entry.insert(rent_paying_account);
+            }
+        }
+    }
+
+    /// Fold the rent-paying accounts found while generating the index into a per-owner report.
+    /// A rent-paying version of an account only counts if no newer version replaced it, which
+    /// requires the index to be fully generated.
+    fn build_rent_paying_accounts_report(
+        &self,
+        rent_paying_accounts: RentPayingAccountsMap,
+        epoch: Epoch,
+    ) -> RentPayingAccountsReport {
+        let mut report = RentPayingAccountsReport {
+            epoch,
+            ..RentPayingAccountsReport::default()
+        };
+        for (pubkey, rent_paying_account) in rent_paying_accounts.into_iter() {
+            let is_latest_version = self
+                .accounts_index
+                .get_account_read_entry(&pubkey)
+                .map(|entry| {
+                    entry.slot_list().iter().map(|(slot, _)| *slot).max()
+                        == Some(rent_paying_account.slot)
+                })
+                .unwrap_or_default();
+            if !is_latest_version {
+                continue;
+            }
+            let by_owner = report
+                .by_owner
+                .entry(rent_paying_account.owner)
+                .or_default();
+            by_owner.count += 1;
+            by_owner.lamports += rent_paying_account.lamports;
+            if let Some(eviction_epoch) = rent_paying_account.eviction_epoch {
+                *by_owner.eviction_epochs.entry(eviction_epoch).or_default() += 1;
+            }
+        }
+        report
+    }
+
+    /// the rent-paying accounts found when the index was generated, if they were collected
+    pub fn rent_paying_accounts_report(&self) -> Option<RentPayingAccountsReport> {
+        self.rent_paying_accounts_report.read().unwrap().clone()
+    }
+
     fn filler_unique_id_bytes() -> usize {
         std::mem::size_of::<u32>()
     }
@@ -6783,7 +7330,7 @@ impl AccountsDb {
     /// retain slots in 'roots' that are > (max(roots) - slots_per_epoch)
     fn retain_roots_within_one_epoch_range(roots: &mut Vec<Slot>, slots_per_epoch: SlotCount) {
         if let Some(max) = roots.iter().max() {
-            let min = max - slots_per_epoch;
+            let min = max.saturating_sub(slots_per_epoch);
             roots.retain(|slot| slot > &min);
         }
     }
@@ -6795,9 +7342,19 @@ impl AccountsDb {
     /// The accounts added in a slot are setup to have pubkeys such that rent will be collected from them before (or when?) their slot becomes an epoch old.
     /// Thus, the filler accounts are rewritten by rent and the old slot can be thrown away successfully.
     pub fn maybe_add_filler_accounts(&self, epoch_schedule: &EpochSchedule) {
+        self.add_filler_accounts(epoch_schedule, true)
+    }
+
+    /// Add the filler accounts, switching the index in and out of startup mode around each pass
+    /// if `startup`, i.e. if nothing else can be using the index meanwhile
+    fn add_filler_accounts(&self, epoch_schedule: &EpochSchedule, startup: bool) {
         if self.filler_account_count == 0 {
             return;
         }
+        if self.filler_accounts_added.load(Ordering::Acquire) {
+            // already added, e.g. by the background thread
+            return;
+        }
 
         info!("adding {} filler accounts", self.filler_account_count);
         // break this up to force the accounts out of memory after each pass
@@ -6815,12 +7372,19 @@ impl AccountsDb {
         let account = AccountSharedData::new(lamports, space, &owner);
         let added = AtomicUsize::default();
         for pass in 0..=passes {
-            self.accounts_index.set_startup(true);
             let roots_in_this_pass = roots
                 .iter()
                 .skip(pass * per_pass)
                 .take(per_pass)
                 .collect::<Vec<_>>();
+            if roots_in_this_pass.is_empty() {
+                // every root has been filled, and switching the index in and out of startup mode
+                // for nothing is not free (nor welcome, if this is running in the background)
+                break;
+            }
+            if startup {
+                self.accounts_index.set_startup(true);
+            }
             self.thread_pool.install(|| {
                 roots_in_this_pass.into_par_iter().for_each(|slot| {
                     let storage_maps: Vec<Arc<AccountStorageEntry>> = self
@@ -6867,9 +7431,56 @@ impl AccountsDb {
                     self.store_accounts_frozen(*slot, &add[..], Some(&hashes[..]), None, None);
                 })
             });
-            self.accounts_index.set_startup(false);
+            if startup {
+                self.accounts_index.set_startup(false);
+            }
         }
         info!("added {} filler accounts", added.load(Ordering::Relaxed));
+        self.filler_accounts_added.store(true, Ordering::Release);
+    }
+
+    /// If the filler accounts were left out of reconstructing from a snapshot by
+    /// `lazy_filler_accounts`, start adding them on a background thread.  Use
+    /// `filler_accounts_added()` or `wait_for_filler_accounts()` to find out when they are all in.
+    /// Meanwhile, cleaning, shrinking, and getting the storages to snapshot all wait for them, as
+    /// they would otherwise see the storages of the old slots change underneath them.  The index
+    /// is left out of startup mode, since the bank goes on being used alongside.
+    pub fn maybe_add_filler_accounts_in_background(
+        self: &Arc<Self>,
+        epoch_schedule: EpochSchedule,
+    ) {
+        if !self.lazy_filler_accounts || self.filler_accounts_added.load(Ordering::Acquire) {
+            return;
+        }
+        let mut filler_accounts_thread = self.filler_accounts_thread.lock().unwrap();
+        if filler_accounts_thread.is_some() {
+            return;
+        }
+        let accounts_db = self.clone();
+        *filler_accounts_thread = Some(
+            Builder::new()
+                .name("solana-db-filler-accounts".to_string())
+                .spawn(move || accounts_db.add_filler_accounts(&epoch_schedule, false))
+                .unwrap(),
+        );
+    }
+
+    /// true once all of the filler accounts have been added, including when there are none
+    pub fn filler_accounts_added(&self) -> bool {
+        self.filler_accounts_added.load(Ordering::Acquire)
+    }
+
+    /// Block until the background thread started by `maybe_add_filler_accounts_in_background()`
+    /// is done adding the filler accounts.  Returns right away if it was never started.
+    pub fn wait_for_filler_accounts(&self) {
+        if self.filler_accounts_added() {
+            return;
+        }
+        // Hold on to the lock while joining, so that every caller waits, not just the first one
+        let mut filler_accounts_thread = self.filler_accounts_thread.lock().unwrap();
+        if let Some(filler_accounts_thread) = filler_accounts_thread.take() {
+            filler_accounts_thread.join().unwrap();
+        }
     }
 
     #[allow(clippy::needless_collect)]
@@ -6910,6 +7521,8 @@ impl AccountsDb {
             let insertion_time_us = AtomicU64::new(0);
             let rent_exempt = AtomicU64::new(0);
             let total_duplicates = AtomicU64::new(0);
+            let rent_paying_accounts = (pass == 0 && self.collect_rent_paying_accounts_report)
+                .then(RentPayingAccountsMap::default);
             let storage_info_timings = Mutex::new(GenerateIndexTimings::default());
             let scan_time: u64 = slots
                 .par_chunks(chunk_size)
@@ -6938,8 +7551,13 @@ impl AccountsDb {
 
                         let insert_us = if pass == 0 {
                             // generate index
-                            let (insert_us, rent_exempt_this_slot, total_this_slot) =
-                                self.generate_index_for_slot(accounts_map, slot, &rent_collector);
+                            let (insert_us, rent_exempt_this_slot, total_this_slot) = self
+                                .generate_index_for_slot(
+                                    accounts_map,
+                                    slot,
+                                    &rent_collector,
+                                    rent_paying_accounts.as_ref(),
+                                );
                             rent_exempt.fetch_add(rent_exempt_this_slot, Ordering::Relaxed);
                             total_duplicates.fetch_add(total_this_slot, Ordering::Relaxed);
                             insert_us
@@ -7029,6 +7647,20 @@ impl AccountsDb {
                 }
 
                 self.set_storage_count_and_alive_bytes(storage_info, &mut timings);
+
+                if let Some(rent_paying_accounts) = rent_paying_accounts {
+                    let report = self.build_rent_paying_accounts_report(
+                        rent_paying_accounts,
+                        rent_collector.epoch,
+                    );
+                    info!(
+                        "rent paying accounts: {} across {} owners, {} lamports",
+                        report.total_count(),
+                        report.by_owner.len(),
+                        report.total_lamports(),
+                    );
+                    *self.rent_paying_accounts_report.write().unwrap() = Some(report);
+                }
             }
             timings.report();
         }
@@ -7340,6 +7972,8 @@ impl AccountsDb {
     }
 
     pub fn process_stale_slot_v1(&self) -> usize {
+        self.wait_for_filler_accounts();
+        self.wait_for_accounts_hash_verification();
         let mut measure = Measure::start("stale_slot_shrink-ms");
         let candidates = self.shrink_candidate_slots_v1.try_lock();
         if candidates.is_err() {
@@ -10011,6 +10645,44 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_verify_bank_hash_in_background() {
+        solana_logger::setup();
+        let db = Arc::new(AccountsDb::new(Vec::new(), &ClusterType::Development));
+        assert!(db.take_accounts_hash_verification_receiver().is_none());
+
+        let key = solana_sdk::pubkey::new_rand();
+        let some_slot: Slot = 0;
+        let account = AccountSharedData::new(1, 0, &key);
+        let ancestors: Ancestors = vec![(some_slot, 0)].into_iter().collect();
+
+        db.store_uncached(some_slot, &[(&key, &account)]);
+        db.add_root(some_slot);
+        db.update_accounts_hash_test(some_slot, &ancestors);
+        for (total_lamports, verified) in [(1, true), (2, false)] {
+            db.verify_bank_hash_and_lamports_in_background(
+                some_slot,
+                ancestors.clone(),
+                total_lamports,
+                true,
+            );
+            let receiver = db.take_accounts_hash_verification_receiver().unwrap();
+            assert_eq!(receiver.recv().unwrap(), verified);
+            assert!(!db.accounts_hash_verification_pending());
+            assert!(db.take_accounts_hash_verification_receiver().is_none());
+        }
+
+        // Cleaning and shrinking wait for the verification to be done
+        db.verify_bank_hash_and_lamports_in_background(some_slot, ancestors.clone(), 1, true);
+        db.clean_accounts(None, false, None);
+        assert!(!db.accounts_hash_verification_pending());
+        db.verify_bank_hash_and_lamports_in_background(some_slot, ancestors, 1, true);
+        db.shrink_all_slots(false, None);
+        assert!(!db.accounts_hash_verification_pending());
+        let receiver = db.take_accounts_hash_verification_receiver().unwrap();
+        assert!(receiver.recv().unwrap());
+    }
+
     #[test]
     fn test_verify_bank_capitalization() {
         use BankHashVerificationError::*;
@@ -10236,6 +10908,103 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_prune_snapshot_storages() {
+        solana_logger::setup();
+        let db = AccountsDb::new(Vec::new(), &ClusterType::Development);
+        let keys = (0..6)
+            .map(|_| solana_sdk::pubkey::new_rand())
+            .collect::<Vec<_>>();
+        let store = |slot, stores: &[(usize, u64)]| {
+            let accounts = stores
+                .iter()
+                .map(|(key, lamports)| (keys[*key], AccountSharedData::new(*lamports, 0, &keys[0])))
+                .collect::<Vec<_>>();
+            let accounts = accounts
+                .iter()
+                .map(|(pubkey, account)| (pubkey, account))
+                .collect::<Vec<_>>();
+            db.store_uncached(slot, &accounts);
+            db.add_root(slot);
+        };
+        store(0, &[(0, 1), (1, 2), (2, 3)]);
+        // a zero lamport account is left out along with the older versions
+        store(1, &[(0, 4), (3, 0)]);
+        store(2, &[(4, 5)]);
+        let boundary_slot = 3;
+        store(boundary_slot, &[(1, 6), (5, 7)]);
+
+        let snapshot_storages = db.get_snapshot_storages(boundary_slot, None, None).0;
+        let pruned_storages_dir = TempDir::new().unwrap();
+        let pruned_storages = db
+            .prune_snapshot_storages(
+                snapshot_storages.clone(),
+                boundary_slot,
+                boundary_slot,
+                pruned_storages_dir.path(),
+            )
+            .unwrap();
+        assert_eq!(pruned_storages.len(), 4);
+        let storages_by_slot = |storages: &SnapshotStorages| {
+            storages
+                .iter()
+                .map(|slot_storages| (slot_storages[0].slot(), slot_storages.clone()))
+                .collect::<HashMap<_, _>>()
+        };
+        let original = storages_by_slot(&snapshot_storages);
+        let pruned = storages_by_slot(&pruned_storages);
+        let lamports = |slot| {
+            let mut lamports = pruned[&slot]
+                .iter()
+                .flat_map(|storage| storage.all_accounts())
+                .map(|account| (account.meta.pubkey, account.account_meta.lamports))
+                .collect::<Vec<_>>();
+            lamports.sort_unstable();
+            lamports
+        };
+        assert_eq!(lamports(0), vec![(keys[2], 3)]);
+        assert_eq!(lamports(1), vec![(keys[0], 4)]);
+
+        // slots with nothing to leave out keep the storages in use
+        for slot in [2, boundary_slot] {
+            assert!(Arc::ptr_eq(&pruned[&slot][0], &original[&slot][0]));
+        }
+        for slot in [0, 1] {
+            assert_ne!(
+                pruned[&slot][0].append_vec_id(),
+                original[&slot][0].append_vec_id()
+            );
+            assert!(pruned[&slot][0]
+                .get_path()
+                .starts_with(pruned_storages_dir.path()));
+        }
+
+        let calculate_hash = |storages: &SnapshotStorages| {
+            AccountsDb::calculate_accounts_hash_without_index(
+                TempDir::new().unwrap().path(),
+                &get_storage_refs(storages),
+                None,
+                HashStats::default(),
+                true,
+                None,
+                None,
+                None,
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            calculate_hash(&pruned_storages),
+            calculate_hash(&snapshot_storages)
+        );
+
+        // the rewritten storages go away with the pruned storages
+        let path = pruned[&0][0].get_path();
+        assert!(path.exists());
+        drop(pruned);
+        drop(pruned_storages);
+        assert!(!path.exists());
+    }
+
     #[test]
     #[should_panic(expected = "double remove of account in slot: 0/store: 0!!")]
     fn test_storage_remove_account_double_remove() {
@@ -1,8 +1,10 @@
 use {
     crate::{
         accounts_db::AccountsDb,
-        append_vec::{StoredAccountMeta, StoredMeta},
+        append_vec::{StoredAccountMeta, StoredMeta, StoredMetaWriteVersion},
     },
+    dashmap::DashMap,
+    rayon::{prelude::*, ThreadPool},
     solana_measure::measure::Measure,
     solana_metrics::*,
     solana_sdk::{account::AccountSharedData, clock::Slot, pubkey::Pubkey},
@@ -21,6 +23,16 @@ pub struct AccountsDbPluginNotifyAtSnapshotRestoreStats {
 }
 
 impl AccountsDbPluginNotifyAtSnapshotRestoreStats {
+    fn accumulate(&mut self, other: &Self) {
+        self.total_accounts += other.total_accounts;
+        self.skipped_accounts += other.skipped_accounts;
+        self.notified_accounts += other.notified_accounts;
+        self.elapsed_filtering_us += other.elapsed_filtering_us;
+        self.total_pure_notify += other.total_pure_notify;
+        self.total_pure_bookeeping += other.total_pure_bookeeping;
+        self.elapsed_notifying_us += other.elapsed_notifying_us;
+    }
+
     pub fn report(&self) {
         datapoint_info!(
             "accountsdb_plugin_notify_account_restore_from_snapshot_summary",
@@ -53,6 +65,97 @@ impl AccountsDb {
             self.notify_accounts_in_slot(slot, &mut notified_accounts, &mut notify_stats);
         }
 
+        self.notify_end_of_restore_from_snapshot(&notify_stats);
+    }
+
+    /// Same as `notify_account_restore_from_snapshot()`, but with the slots split among the
+    /// threads of `thread_pool`.  The pool first finds the newest version of every account, and
+    /// then notifies just those versions, so an account is still only streamed once, and each
+    /// storage is read twice however many threads there are.
+    pub fn notify_account_restore_from_snapshot_in_pool(&self, thread_pool: &ThreadPool) {
+        if self.accounts_update_notifier.is_none() {
+            return;
+        }
+
+        let slots = self.storage.all_slots();
+        let mut measure_filter = Measure::start("accountsdb-plugin-finding-newest-accounts");
+        let newest_versions: DashMap<Pubkey, (Slot, StoredMetaWriteVersion)> = DashMap::default();
+        thread_pool.install(|| {
+            slots.par_iter().for_each(|slot| {
+                let slot_stores = self.storage.get_slot_stores(*slot).unwrap();
+                for storage_entry in slot_stores.read().unwrap().values() {
+                    for account in storage_entry.all_accounts() {
+                        let version = (*slot, account.meta.write_version);
+                        newest_versions
+                            .entry(account.meta.pubkey)
+                            .and_modify(|newest| *newest = version.max(*newest))
+                            .or_insert(version);
+                    }
+                }
+            })
+        });
+        measure_filter.stop();
+
+        let mut notify_stats = thread_pool.install(|| {
+            slots
+                .par_iter()
+                .map(|slot| self.notify_newest_accounts_in_slot(*slot, &newest_versions))
+                .reduce(
+                    AccountsDbPluginNotifyAtSnapshotRestoreStats::default,
+                    |mut notify_stats, slot_stats| {
+                        notify_stats.accumulate(&slot_stats);
+                        notify_stats
+                    },
+                )
+        });
+        notify_stats.elapsed_filtering_us += measure_filter.as_us() as usize;
+        self.notify_end_of_restore_from_snapshot(&notify_stats);
+    }
+
+    /// Notify the accounts in `slot` that are the newest versions in `newest_versions`
+    fn notify_newest_accounts_in_slot(
+        &self,
+        slot: Slot,
+        newest_versions: &DashMap<Pubkey, (Slot, StoredMetaWriteVersion)>,
+    ) -> AccountsDbPluginNotifyAtSnapshotRestoreStats {
+        let slot_stores = self.storage.get_slot_stores(slot).unwrap();
+
+        let slot_stores = slot_stores.read().unwrap();
+        let mut accounts_to_stream: HashMap<Pubkey, StoredAccountMeta> = HashMap::default();
+        let mut notify_stats = AccountsDbPluginNotifyAtSnapshotRestoreStats::default();
+        let mut measure_filter = Measure::start("accountsdb-plugin-filtering-accounts");
+        for (_, storage_entry) in slot_stores.iter() {
+            for account in storage_entry.all_accounts() {
+                notify_stats.total_accounts += 1;
+                let version = (slot, account.meta.write_version);
+                if newest_versions
+                    .get(&account.meta.pubkey)
+                    .map_or(false, |newest| *newest == version)
+                {
+                    accounts_to_stream.insert(account.meta.pubkey, account);
+                } else {
+                    notify_stats.skipped_accounts += 1;
+                }
+            }
+        }
+        measure_filter.stop();
+        notify_stats.elapsed_filtering_us += measure_filter.as_us() as usize;
+
+        // Only the newest versions are streamed, so there is nothing to skip on later slots
+        let mut notified_accounts = HashSet::default();
+        self.notify_filtered_accounts(
+            slot,
+            &mut notified_accounts,
+            &accounts_to_stream,
+            &mut notify_stats,
+        );
+        notify_stats
+    }
+
+    fn notify_end_of_restore_from_snapshot(
+        &self,
+        notify_stats: &AccountsDbPluginNotifyAtSnapshotRestoreStats,
+    ) {
         let accounts_update_notifier = self.accounts_update_notifier.as_ref().unwrap();
         let notifier = &accounts_update_notifier.read().unwrap();
         notifier.notify_end_of_restore_from_snapshot();
@@ -320,6 +423,40 @@ pub mod tests {
         assert!(notifier.is_startup_done.load(Ordering::Relaxed));
     }
 
+    #[test]
+    fn test_notify_account_restore_from_snapshot_in_pool() {
+        let mut accounts = AccountsDb::new_single_for_tests();
+        // Every account is stored in slot0 and then updated in slot1 -- each should only get
+        // notified once, with its slot1 version, no matter which thread in the pool handles it.
+        let keys: Vec<_> = (0..16).map(|_| solana_sdk::pubkey::new_rand()).collect();
+        let (slot0, slot1) = (0, 1);
+        for (i, key) in keys.iter().enumerate() {
+            let account = AccountSharedData::new(i as u64 + 1, 1, &Pubkey::default());
+            accounts.store_uncached(slot0, &[(key, &account)]);
+            let account = AccountSharedData::new(i as u64 + 100, 1, &Pubkey::default());
+            accounts.store_uncached(slot1, &[(key, &account)]);
+        }
+
+        let notifier = Arc::new(RwLock::new(AccountsDbTestPlugin::default()));
+        accounts.set_accountsdb_plugin_notifer(Some(notifier.clone()));
+
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        accounts.notify_account_restore_from_snapshot_in_pool(&thread_pool);
+
+        let notifier = notifier.write().unwrap();
+        assert_eq!(notifier.accounts_notified.len(), keys.len());
+        for (i, key) in keys.iter().enumerate() {
+            let notified = notifier.accounts_notified.get(key).unwrap();
+            assert_eq!(notified.len(), 1);
+            assert_eq!(notified[0].0, slot1);
+            assert_eq!(notified[0].1.lamports(), i as u64 + 100);
+        }
+        assert!(notifier.is_startup_done.load(Ordering::Relaxed));
+    }
+
     #[test]
     fn test_notify_account_at_accounts_update() {
         let mut accounts = AccountsDb::new_single_for_tests_with_caching();
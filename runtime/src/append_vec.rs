@@ -4,6 +4,7 @@
 //!
 //! <https://docs.solana.com/implemented-proposals/persistent-account-storage>
 
+use crate::storage_encryption::{self, EncryptedStorage, MasterKey, StorageBinding};
 use log::*;
 use memmap2::MmapMut;
 use serde::{Deserialize, Serialize};
@@ -19,9 +20,10 @@ use std::{
     io,
     io::{Seek, SeekFrom, Write},
     mem,
+    ops::Range,
     path::{Path, PathBuf},
-    sync::atomic::{AtomicUsize, Ordering},
-    sync::Mutex,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
 };
 
 // Data placement should be aligned at the next boundary. Without alignment accessing the memory may
@@ -35,8 +37,35 @@ macro_rules! u64_align {
 
 const MAXIMUM_APPEND_VEC_FILE_SIZE: usize = 16 * 1024 * 1024 * 1024; // 16 GiB
 
+/// How many accounts `AppendVecValidation::Sampled` checks from the start of a file
+const SAMPLED_VALIDATION_ACCOUNTS: usize = 64;
+
+/// The least room an account takes up in an append vec, which is when it has no data
+pub(crate) const MIN_STORED_ACCOUNT_SIZE: usize =
+    mem::size_of::<StoredMeta>() + mem::size_of::<AccountMeta>() + mem::size_of::<Hash>();
+
 pub type StoredMetaWriteVersion = u64;
 
+/// How thoroughly an existing file is checked before it is used as an `AppendVec`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendVecValidation {
+    /// Only check the length of the accounts against the size of the file.  The accounts are not
+    /// read at all, so this is only for files that are known to have been written by an
+    /// `AppendVec`.
+    HeaderOnly,
+    /// Also check the accounts at the start of the file, which catches files of the wrong format
+    /// but not ones that were cut short or corrupted further in
+    Sampled,
+    /// Check every account, and that they end exactly at the length
+    Full,
+}
+
+impl Default for AppendVecValidation {
+    fn default() -> Self {
+        Self::Full
+    }
+}
+
 /// Meta contains enough context to recover the index from storage itself
 /// This struct will be backed by mmaped and snapshotted data files.
 /// So the data layout must be stable and consistent across the entire cluster!
@@ -108,6 +137,16 @@ impl<'a> StoredAccountMeta<'a> {
         })
     }
 
+    /// Return a `StoredAccount` that owns a copy of everything referenced by the
+    /// `StoredAccountMeta`.
+    pub fn clone_stored_account(&self) -> StoredAccount {
+        StoredAccount {
+            meta: self.meta.clone(),
+            account: self.clone_account(),
+            hash: *self.hash,
+        }
+    }
+
     fn sanitize(&self) -> bool {
         self.sanitize_executable() && self.sanitize_lamports()
     }
@@ -132,10 +171,23 @@ impl<'a> StoredAccountMeta<'a> {
     }
 }
 
+/// An account read out of an `AppendVec` that no longer borrows from it; useful when the
+/// `AppendVec` does not outlive the account (e.g. when streaming accounts out of a snapshot).
+#[derive(Clone, PartialEq, Debug)]
+pub struct StoredAccount {
+    pub meta: StoredMeta,
+    pub account: AccountSharedData,
+    pub hash: Hash,
+}
+
 /// A thread-safe, file-backed block of memory used to store `Account` instances. Append operations
 /// are serialized such that only one thread updates the internal `append_lock` at a time. No
 /// restrictions are placed on reading. That is, one may read items from one thread while another
 /// is appending new items.
+///
+/// The file of an encrypted append vec is not mapped at all: its chunks are decrypted into a map
+/// of an unlinked temporary file as they are first read, and the chunks that were appended to are
+/// only sealed in the file again by `flush()`.  See `storage_encryption`.
 #[derive(Debug, AbiExample)]
 pub struct AppendVec {
     /// The file path where the data is stored.
@@ -155,6 +207,37 @@ pub struct AppendVec {
 
     /// True if the file should automatically be deleted when this AppendVec is dropped.
     remove_on_drop: bool,
+
+    /// The file and its chunks, if it is encrypted
+    encrypted_chunks: Option<EncryptedChunks>,
+}
+
+/// The chunks of an encrypted append vec, and which of them are decrypted into its map
+#[derive(Debug)]
+struct EncryptedChunks {
+    storage: EncryptedStorage,
+
+    /// Whether each chunk is decrypted into the map yet
+    decrypted: Vec<AtomicBool>,
+
+    /// Whether each chunk was appended to since it was last sealed in the file
+    dirty: Vec<AtomicBool>,
+
+    /// Held while a chunk is decrypted, so that it only is once
+    decrypt_lock: Mutex<()>,
+}
+
+impl EncryptedChunks {
+    /// The chunks of `storage`, which are all already in the map if `is_new`, as it is all zeros
+    fn new(storage: EncryptedStorage, is_new: bool) -> Self {
+        let num_chunks = storage.num_chunks();
+        Self {
+            storage,
+            decrypted: (0..num_chunks).map(|_| AtomicBool::new(is_new)).collect(),
+            dirty: (0..num_chunks).map(|_| AtomicBool::default()).collect(),
+            decrypt_lock: Mutex::new(()),
+        }
+    }
 }
 
 impl Drop for AppendVec {
@@ -223,9 +306,72 @@ impl AppendVec {
             current_len: AtomicUsize::new(initial_len),
             file_size: size as u64,
             remove_on_drop: true,
+            encrypted_chunks: None,
         }
     }
 
+    /// Like `new()` creating the file, but with the file encrypted under `storage_encryption_key`,
+    /// and bound to the slot and id in its name, see `file_name()`
+    pub fn new_encrypted(
+        file: &Path,
+        size: usize,
+        storage_encryption_key: Arc<MasterKey>,
+    ) -> io::Result<Self> {
+        AppendVec::sanitize_len_and_size(0, size)?;
+        let binding = Self::storage_binding(file)?;
+        let map = Self::map_decrypted(size)?;
+        let storage = EncryptedStorage::create(file, storage_encryption_key, binding, size as u64)?;
+
+        Ok(AppendVec {
+            path: file.to_path_buf(),
+            map,
+            append_lock: Mutex::new(()),
+            current_len: AtomicUsize::new(0),
+            file_size: size as u64,
+            remove_on_drop: true,
+            encrypted_chunks: Some(EncryptedChunks::new(storage, true)),
+        })
+    }
+
+    /// Map the memory an encrypted append vec is decrypted into: an unlinked file in the
+    /// temporary directory, so that the kernel can drop its pages under memory pressure, which it
+    /// cannot do for anonymous memory.  Nothing is decrypted into it until it is read.
+    fn map_decrypted(size: usize) -> io::Result<MmapMut> {
+        let file = tempfile::tempfile()?;
+        file.set_len(size as u64)?;
+        //UNSAFE: Required to create a Mmap.  Nothing else has the unlinked file open.
+        unsafe { MmapMut::map_mut(&file) }.map_err(|e| {
+            error!(
+                "Failed to create VM map for encrypted storage (size: {}): {}.\n
+                    Please increase sysctl vm.max_map_count or equivalent for your platform.",
+                size, e
+            );
+            e
+        })
+    }
+
+    /// The slot and id of the append vec at `path`, going by its file name, see `file_name()`
+    fn storage_binding(path: &Path) -> io::Result<StorageBinding> {
+        path.file_name()
+            .and_then(|file_name| file_name.to_str())
+            .and_then(|file_name| {
+                let (slot, id) = file_name.split_once('.')?;
+                Some(StorageBinding {
+                    slot: slot.parse().ok()?,
+                    id: id.parse().ok()?,
+                })
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is not named after the slot and id of an append vec",
+                        path.display()
+                    ),
+                )
+            })
+    }
+
     pub fn set_no_remove_on_drop(&mut self) {
         self.remove_on_drop = false;
     }
@@ -247,6 +393,7 @@ impl AppendVec {
             current_len: AtomicUsize::new(current_len),
             file_size: 0, // will be filled by set_file()
             remove_on_drop: true,
+            encrypted_chunks: None,
         }
     }
 
@@ -271,8 +418,49 @@ impl AppendVec {
         }
     }
 
+    /// Write the accounts out to the file.  Only the chunks of an encrypted append vec that were
+    /// appended to since it was last flushed are sealed in its file again.
     pub fn flush(&self) -> io::Result<()> {
-        self.map.flush()
+        match &self.encrypted_chunks {
+            None => self.map.flush(),
+            Some(encrypted_chunks) => {
+                // Nothing can be appended while the chunks are sealed, so that none are marked
+                // clean with accounts missing from them
+                let _lock = self.append_lock.lock().unwrap();
+                for (index, dirty) in encrypted_chunks.dirty.iter().enumerate() {
+                    if dirty.swap(false, Ordering::Relaxed) {
+                        let range = encrypted_chunks.storage.chunk_range(index);
+                        if let Err(err) = encrypted_chunks
+                            .storage
+                            .write_chunk(index as u64, &self.map[range])
+                        {
+                            dirty.store(true, Ordering::Relaxed);
+                            return Err(err);
+                        }
+                    }
+                }
+                encrypted_chunks.storage.set_stored_len(self.len() as u64);
+                encrypted_chunks.storage.write_header_and_sync()
+            }
+        }
+    }
+
+    /// The id of the master key the file is encrypted under, if it is
+    pub fn storage_master_key_id(&self) -> Option<Hash> {
+        self.encrypted_chunks
+            .as_ref()
+            .map(|encrypted_chunks| encrypted_chunks.storage.master_key_id())
+    }
+
+    /// Bind an encrypted append vec to `slot` and `id` instead, once it is next flushed, e.g. when
+    /// its storage is recycled
+    pub fn rebind(&self, slot: Slot, id: usize) {
+        if let Some(encrypted_chunks) = &self.encrypted_chunks {
+            encrypted_chunks.storage.set_binding(StorageBinding {
+                slot,
+                id: id as u64,
+            });
+        }
     }
 
     pub fn reset(&self) {
@@ -280,6 +468,11 @@ impl AppendVec {
         // See UNSAFE usage in `append_ptr`
         let _lock = self.append_lock.lock().unwrap();
         self.current_len.store(0, Ordering::Relaxed);
+        if let Some(encrypted_chunks) = &self.encrypted_chunks {
+            // Nothing is stored until the next flush, so that the chunks of a recycled storage
+            // are sealed for the slot and id it is rebound to
+            encrypted_chunks.storage.set_stored_len(0);
+        }
     }
 
     pub fn len(&self) -> usize {
@@ -299,22 +492,92 @@ impl AppendVec {
     }
 
     pub fn new_from_file<P: AsRef<Path>>(path: P, current_len: usize) -> io::Result<(Self, usize)> {
-        let data = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(false)
-            .open(&path)?;
+        Self::new_from_file_with_validation(path, current_len, AppendVecValidation::Full)
+    }
 
-        let file_size = std::fs::metadata(&path)?.len();
-        AppendVec::sanitize_len_and_size(current_len, file_size as usize)?;
+    /// Like `new_from_file()`, but only checking the file as thoroughly as `validation` says.  The
+    /// number of accounts returned is the number that were checked, so it is only the number in
+    /// the file for `AppendVecValidation::Full`.
+    pub fn new_from_file_with_validation<P: AsRef<Path>>(
+        path: P,
+        current_len: usize,
+        validation: AppendVecValidation,
+    ) -> io::Result<(Self, usize)> {
+        Self::new_from_file_with_encryption(path, current_len, validation, None)
+    }
 
-        let map = unsafe {
-            let result = MmapMut::map_mut(&data);
-            if result.is_err() {
-                // for vm.max_map_count, error is: {code: 12, kind: Other, message: "Cannot allocate memory"}
-                info!("memory map error: {:?}. This may be because vm.max_map_count is not set correctly.", result);
+    /// Like `new_from_file_with_validation()`, but decrypting the file with
+    /// `storage_encryption_key` if it is encrypted.  Errors if it is, but there is no key, if it
+    /// is not bound to the slot and id in its name, or if it fails to decrypt.  A plain file opened
+    /// with a key is sealed in place, once, before it is opened.  The chunks of an encrypted file
+    /// are only decrypted into the append vec as they are read, e.g. by `validation`.
+    pub fn new_from_file_with_encryption<P: AsRef<Path>>(
+        path: P,
+        current_len: usize,
+        validation: AppendVecValidation,
+        storage_encryption_key: Option<Arc<MasterKey>>,
+    ) -> io::Result<(Self, usize)> {
+        let is_encrypted = storage_encryption::is_encrypted_storage(path.as_ref())?;
+        let (map, file_size, encrypted_chunks) = match storage_encryption_key {
+            None if is_encrypted => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{} is encrypted, but no storage encryption key is configured",
+                        path.as_ref().display()
+                    ),
+                ));
+            }
+            None => {
+                let data = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(false)
+                    .open(&path)?;
+
+                let file_size = std::fs::metadata(&path)?.len();
+                AppendVec::sanitize_len_and_size(current_len, file_size as usize)?;
+
+                let map = unsafe {
+                    let result = MmapMut::map_mut(&data);
+                    if result.is_err() {
+                        // for vm.max_map_count, error is: {code: 12, kind: Other, message: "Cannot allocate memory"}
+                        info!("memory map error: {:?}. This may be because vm.max_map_count is not set correctly.", result);
+                    }
+                    result?
+                };
+                (map, file_size, None)
+            }
+            Some(storage_encryption_key) => {
+                let binding = Self::storage_binding(path.as_ref())?;
+                if !is_encrypted {
+                    let file_size = std::fs::metadata(&path)?.len();
+                    AppendVec::sanitize_len_and_size(current_len, file_size as usize)?;
+                    storage_encryption::seal_plain_storage(
+                        path.as_ref(),
+                        storage_encryption_key.clone(),
+                        binding,
+                        current_len as u64,
+                    )?;
+                }
+                let storage =
+                    EncryptedStorage::open(path.as_ref(), storage_encryption_key, binding)?;
+                let file_size = storage.file_size();
+                AppendVec::sanitize_len_and_size(current_len, file_size as usize)?;
+                if current_len as u64 > storage.stored_len() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "{} only holds {} bytes of accounts, not {}",
+                            path.as_ref().display(),
+                            storage.stored_len(),
+                            current_len
+                        ),
+                    ));
+                }
+                let map = Self::map_decrypted(file_size as usize)?;
+                (map, file_size, Some(EncryptedChunks::new(storage, false)))
             }
-            result?
         };
 
         let new = AppendVec {
@@ -324,20 +587,36 @@ impl AppendVec {
             current_len: AtomicUsize::new(current_len),
             file_size,
             remove_on_drop: true,
+            encrypted_chunks,
         };
 
-        let (sanitized, num_accounts) = new.sanitize_layout_and_length();
+        let num_accounts = new.validate(validation)?;
+
+        Ok((new, num_accounts))
+    }
+
+    /// Check the accounts in the file as thoroughly as `validation` says.  The number of accounts
+    /// returned is the number that were checked, as for `new_from_file_with_validation()`.
+    pub fn validate(&self, validation: AppendVecValidation) -> io::Result<usize> {
+        let (sanitized, num_accounts) = match validation {
+            AppendVecValidation::HeaderOnly => (true, 0),
+            AppendVecValidation::Sampled => {
+                self.sanitize_layout_and_length(Some(SAMPLED_VALIDATION_ACCOUNTS))
+            }
+            AppendVecValidation::Full => self.sanitize_layout_and_length(None),
+        };
         if !sanitized {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "incorrect layout/length/data",
             ));
         }
-
-        Ok((new, num_accounts))
+        Ok(num_accounts)
     }
 
-    fn sanitize_layout_and_length(&self) -> (bool, usize) {
+    /// Check the accounts from the start, and that they end at the length.  If `max_accounts`
+    /// are checked before the end is reached, the rest (and the length) are taken on trust.
+    fn sanitize_layout_and_length(&self, max_accounts: Option<usize>) -> (bool, usize) {
         let mut offset = 0;
 
         // This discards allocated accounts immediately after check at each loop iteration.
@@ -352,12 +631,26 @@ impl AppendVec {
             }
             offset = next_offset;
             num_accounts += 1;
+            if Some(num_accounts) == max_accounts {
+                return (true, num_accounts);
+            }
         }
         let aligned_current_len = u64_align!(self.current_len.load(Ordering::Relaxed));
 
         (offset == aligned_current_len, num_accounts)
     }
 
+    /// The number of accounts, going by their metas alone
+    pub(crate) fn count_accounts(&self) -> usize {
+        let mut offset = 0;
+        let mut num_accounts = 0;
+        while let Some((_account, next_offset)) = self.get_account(offset) {
+            offset = next_offset;
+            num_accounts += 1;
+        }
+        num_accounts
+    }
+
     /// Get a reference to the data at `offset` of `size` bytes if that slice
     /// doesn't overrun the internal buffer. Otherwise return None.
     /// Also return the offset of the first byte after the requested data that
@@ -367,6 +660,15 @@ impl AppendVec {
         if overflow || next > self.len() {
             return None;
         }
+        if let Err(err) = self.decrypt_chunks(offset..next) {
+            error!(
+                "Unable to decrypt bytes {:?} of {}: {}",
+                offset..next,
+                self.path.display(),
+                err
+            );
+            return None;
+        }
         let data = &self.map[offset..next];
         let next = u64_align!(next);
 
@@ -378,6 +680,34 @@ impl AppendVec {
         ))
     }
 
+    /// Decrypt the chunks of an encrypted append vec that hold `range` into the map, if they are
+    /// not already.  The file was authenticated when it was opened, so this only fails if it was
+    /// changed or removed since; the chunks that fail are left to be decrypted again next time.
+    fn decrypt_chunks(&self, range: Range<usize>) -> io::Result<()> {
+        let encrypted_chunks = match &self.encrypted_chunks {
+            Some(encrypted_chunks) => encrypted_chunks,
+            None => return Ok(()),
+        };
+        for index in encrypted_chunks.storage.chunks_in(range) {
+            if encrypted_chunks.decrypted[index].load(Ordering::Acquire) {
+                continue;
+            }
+            let _lock = encrypted_chunks.decrypt_lock.lock().unwrap();
+            if encrypted_chunks.decrypted[index].load(Ordering::Acquire) {
+                continue;
+            }
+            let chunk_range = encrypted_chunks.storage.chunk_range(index);
+            let data = &self.map[chunk_range];
+            //UNSAFE: Nothing reads or writes the chunk until it is marked decrypted, and
+            //`decrypt_lock` guarantees that only one thread decrypts it
+            let chunk =
+                unsafe { std::slice::from_raw_parts_mut(data.as_ptr() as *mut u8, data.len()) };
+            encrypted_chunks.storage.read_chunk(index as u64, chunk)?;
+            encrypted_chunks.decrypted[index].store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
     /// Copy `len` bytes from `src` to the first 64-byte boundary after position `offset` of
     /// the internal buffer. Then update `offset` to the first byte after the copied data.
     fn append_ptr(&self, offset: &mut usize, src: *const u8, len: usize) {
@@ -409,6 +739,22 @@ impl AppendVec {
         }
 
         let pos = u64_align!(*offset);
+        if let Some(encrypted_chunks) = &self.encrypted_chunks {
+            // The rest of the chunks appended to must be in the map before they are sealed again.
+            // If they cannot be decrypted, the append vec is treated as full.
+            if let Err(err) = self.decrypt_chunks(pos..end) {
+                error!(
+                    "Unable to decrypt bytes {:?} of {} to append to them: {}",
+                    pos..end,
+                    self.path.display(),
+                    err
+                );
+                return None;
+            }
+            for index in encrypted_chunks.storage.chunks_in(pos..end) {
+                encrypted_chunks.dirty[index].store(true, Ordering::Relaxed);
+            }
+        }
         for val in vals {
             self.append_ptr(offset, val.0, val.1)
         }
@@ -833,6 +1179,255 @@ pub mod tests {
         assert_matches!(result, Err(ref message) if message.to_string() == *"incorrect layout/length/data");
     }
 
+    #[test]
+    fn test_new_from_file_with_encryption() {
+        // Encrypted append vecs are bound to the slot and id in their file name
+        let file = get_append_vec_path("123.0");
+        let path = &file.path;
+        let master_key = Arc::new(MasterKey::new_rand());
+        assert!(AppendVec::new_encrypted(
+            &path.with_file_name("test_new_from_file_with_encryption"),
+            1024 * 1024,
+            master_key.clone()
+        )
+        .is_err());
+        let mut av = AppendVec::new_encrypted(path, 1024 * 1024, master_key.clone()).unwrap();
+        av.set_no_remove_on_drop();
+        assert_eq!(av.storage_master_key_id(), Some(master_key.id()));
+        assert!(storage_encryption::is_encrypted_storage(path).unwrap());
+
+        // The first account fills most of the first chunk, so the ones after it spill into the next
+        let mut accounts = vec![create_test_account(5)];
+        accounts[0]
+            .1
+            .set_data(vec![1; storage_encryption::CHUNK_LEN - 300]);
+        accounts[0].0.data_len = accounts[0].1.data().len() as u64;
+        accounts.extend((1..4).map(|sample| {
+            let (mut stored_meta, account) = create_test_account(sample);
+            stored_meta.pubkey = solana_sdk::pubkey::new_rand();
+            (stored_meta, account)
+        }));
+        for account in &accounts {
+            av.append_account_test(account).unwrap();
+        }
+        // Nothing but the empty storage is on disk until it is flushed, and then only encrypted
+        assert!(std::fs::metadata(path).unwrap().len() < 1024);
+        av.flush().unwrap();
+        let encrypted = std::fs::read(path).unwrap();
+        assert!(!encrypted
+            .windows(mem::size_of::<Pubkey>())
+            .any(|window| window == accounts[1].0.pubkey.as_ref()));
+
+        // Only the chunks that were appended to are sealed again
+        let (mut stored_meta, account) = create_test_account(4);
+        stored_meta.pubkey = solana_sdk::pubkey::new_rand();
+        accounts.push((stored_meta, account));
+        av.append_account_test(accounts.last().unwrap()).unwrap();
+        av.flush().unwrap();
+        let reencrypted = std::fs::read(path).unwrap();
+        assert_ne!(reencrypted, encrypted);
+        assert_eq!(
+            reencrypted[1024..storage_encryption::CHUNK_LEN],
+            encrypted[1024..storage_encryption::CHUNK_LEN]
+        );
+        let accounts_len = av.len();
+        drop(av);
+
+        let open = |storage_encryption_key, validation| {
+            AppendVec::new_from_file_with_encryption(
+                path,
+                accounts_len,
+                validation,
+                storage_encryption_key,
+            )
+            .map(|(mut av, num_accounts)| {
+                av.set_no_remove_on_drop();
+                (av, num_accounts)
+            })
+        };
+        let (av, num_accounts) = open(Some(master_key.clone()), AppendVecValidation::Full).unwrap();
+        assert_eq!(num_accounts, accounts.len());
+        assert_eq!(av.capacity(), 1024 * 1024);
+        drop(av);
+        // Chunks that were not checked are decrypted once they are read
+        let (av, _num_accounts) =
+            open(Some(master_key.clone()), AppendVecValidation::HeaderOnly).unwrap();
+        let offsets: Vec<_> = av
+            .accounts(0)
+            .iter()
+            .map(|account| account.offset)
+            .collect();
+        assert_eq!(offsets.len(), accounts.len());
+        for (offset, account) in offsets.into_iter().zip(&accounts) {
+            assert_eq!(&av.get_account_test(offset).unwrap(), account);
+        }
+        // Opening it does not write it out again
+        drop(av);
+        assert_eq!(std::fs::read(path).unwrap(), reencrypted);
+
+        // A chunk that changed since the file was opened is not read, and is read once it is back
+        let (av, _num_accounts) =
+            open(Some(master_key.clone()), AppendVecValidation::HeaderOnly).unwrap();
+        let mut tampered = reencrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        std::fs::write(path, &tampered).unwrap();
+        assert!(av.accounts(0).len() < accounts.len());
+        std::fs::write(path, &reencrypted).unwrap();
+        assert_eq!(av.accounts(0).len(), accounts.len());
+        drop(av);
+
+        // It cannot be opened without the key, with another key, or as longer than it was stored
+        assert!(open(None, AppendVecValidation::Full).is_err());
+        assert!(open(
+            Some(Arc::new(MasterKey::new_rand())),
+            AppendVecValidation::Full
+        )
+        .is_err());
+        assert!(AppendVec::new_from_file_with_encryption(
+            path,
+            accounts_len + 1,
+            AppendVecValidation::HeaderOnly,
+            Some(master_key.clone()),
+        )
+        .is_err());
+
+        // Nor as another storage, unless it is rebound to it
+        let rebound_file = TempFile {
+            path: path.with_file_name("124.1"),
+        };
+        std::fs::copy(path, &rebound_file.path).unwrap();
+        let open_rebound = || {
+            AppendVec::new_from_file_with_encryption(
+                &rebound_file.path,
+                accounts_len,
+                AppendVecValidation::Full,
+                Some(master_key.clone()),
+            )
+            .map(|(mut av, num_accounts)| {
+                av.set_no_remove_on_drop();
+                (av, num_accounts)
+            })
+        };
+        assert!(open_rebound().is_err());
+        let (av, _num_accounts) =
+            open(Some(master_key.clone()), AppendVecValidation::Full).unwrap();
+        av.rebind(124, 1);
+        av.flush().unwrap();
+        drop(av);
+        std::fs::rename(path, &rebound_file.path).unwrap();
+        let (av, num_accounts) = open_rebound().unwrap();
+        assert_eq!(num_accounts, accounts.len());
+
+        // A recycled storage is sealed for the slot and id it is rebound to
+        av.reset();
+        av.rebind(126, 2);
+        av.append_account_test(&accounts[1]).unwrap();
+        av.flush().unwrap();
+        let recycled_len = av.len();
+        drop(av);
+        let recycled_file = TempFile {
+            path: path.with_file_name("126.2"),
+        };
+        std::fs::rename(&rebound_file.path, &recycled_file.path).unwrap();
+        let (mut av, num_accounts) = AppendVec::new_from_file_with_encryption(
+            &recycled_file.path,
+            recycled_len,
+            AppendVecValidation::Full,
+            Some(master_key.clone()),
+        )
+        .unwrap();
+        av.set_no_remove_on_drop();
+        assert_eq!(num_accounts, 1);
+        assert_eq!(&av.get_account_test(0).unwrap(), &accounts[1]);
+
+        // A plain file is sealed in place, once, when it is opened with a key
+        let plain_file = get_append_vec_path("125.0");
+        let mut plain_av = AppendVec::new(&plain_file.path, true, 1024 * 1024);
+        plain_av.set_no_remove_on_drop();
+        for account in &accounts {
+            plain_av.append_account_test(account).unwrap();
+        }
+        plain_av.flush().unwrap();
+        drop(plain_av);
+        let open_plain = || {
+            AppendVec::new_from_file_with_encryption(
+                &plain_file.path,
+                accounts_len,
+                AppendVecValidation::Full,
+                Some(master_key.clone()),
+            )
+            .map(|(mut av, num_accounts)| {
+                av.set_no_remove_on_drop();
+                (av, num_accounts)
+            })
+        };
+        let (av, num_accounts) = open_plain().unwrap();
+        assert_eq!(num_accounts, accounts.len());
+        assert!(storage_encryption::is_encrypted_storage(&plain_file.path).unwrap());
+        drop(av);
+        let sealed = std::fs::read(&plain_file.path).unwrap();
+        let (_av, num_accounts) = open_plain().unwrap();
+        assert_eq!(num_accounts, accounts.len());
+        assert_eq!(std::fs::read(&plain_file.path).unwrap(), sealed);
+    }
+
+    #[test]
+    fn test_new_from_file_with_validation() {
+        let file = get_append_vec_path("test_new_from_file_with_validation");
+        let path = &file.path;
+        let mut av = AppendVec::new(path, true, 1024 * 1024);
+        av.set_no_remove_on_drop();
+
+        for sample in 0..=SAMPLED_VALIDATION_ACCOUNTS {
+            av.append_account_test(&create_test_account(sample))
+                .unwrap();
+        }
+        av.flush().unwrap();
+        let accounts_len = av.len();
+        // A load that fails removes the file it was given, so load a copy each time
+        let load = |validation| {
+            let copy = get_append_vec_path("test_new_from_file_with_validation_copy");
+            std::fs::copy(path, &copy.path).unwrap();
+            AppendVec::new_from_file_with_validation(&copy.path, accounts_len, validation).map(
+                |(mut av, num_accounts)| {
+                    av.set_no_remove_on_drop();
+                    num_accounts
+                },
+            )
+        };
+        assert_eq!(
+            load(AppendVecValidation::Full).unwrap(),
+            SAMPLED_VALIDATION_ACCOUNTS + 1
+        );
+
+        // Crafting the last account is only caught by checking every account
+        let accounts = av.accounts(0);
+        accounts.last().unwrap().set_data_len_unsafe(1);
+        av.flush().unwrap();
+        assert_matches!(load(AppendVecValidation::Full), Err(ref message) if message.to_string() == *"incorrect layout/length/data");
+        assert_eq!(
+            load(AppendVecValidation::Sampled).unwrap(),
+            SAMPLED_VALIDATION_ACCOUNTS
+        );
+        assert_eq!(load(AppendVecValidation::HeaderOnly).unwrap(), 0);
+
+        // Crafting the first account is caught by sampling, but not when trusting the header
+        let accounts = av.accounts(0);
+        accounts.first().unwrap().set_data_len_unsafe(1);
+        av.flush().unwrap();
+        drop(av);
+        assert!(load(AppendVecValidation::Sampled).is_err());
+        assert_eq!(load(AppendVecValidation::HeaderOnly).unwrap(), 0);
+
+        // The length must still fit in the file
+        assert!(AppendVec::new_from_file_with_validation(
+            path,
+            1024 * 1024 + 1,
+            AppendVecValidation::HeaderOnly
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_new_from_file_too_large_data_len() {
         let file = get_append_vec_path("test_new_from_file_too_large_data_len");
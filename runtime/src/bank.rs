@@ -36,8 +36,8 @@
 use crate::{
     accounts::{AccountAddressFilter, Accounts, TransactionAccounts, TransactionLoadResult},
     accounts_db::{
-        AccountShrinkThreshold, AccountsDbConfig, ErrorCounters, SnapshotStorages,
-        ACCOUNTS_DB_CONFIG_FOR_BENCHMARKS, ACCOUNTS_DB_CONFIG_FOR_TESTING,
+        AccountShrinkThreshold, AccountsDbConfig, ErrorCounters, RentPayingAccountsReport,
+        SnapshotStorages, ACCOUNTS_DB_CONFIG_FOR_BENCHMARKS, ACCOUNTS_DB_CONFIG_FOR_TESTING,
     },
     accounts_index::{AccountSecondaryIndexes, IndexKey, ScanConfig, ScanResult},
     accounts_update_notifier_interface::AccountsUpdateNotifier,
@@ -60,6 +60,7 @@ use crate::{
     vote_account::VoteAccount,
 };
 use byteorder::{ByteOrder, LittleEndian};
+use crossbeam_channel::Receiver;
 use dashmap::DashMap;
 use itertools::Itertools;
 use log::*;
@@ -5636,6 +5637,9 @@ impl Bank {
 
     /// A snapshot bank should be purged of 0 lamport accounts which are not part of the hash
     /// calculation and could shield other real accounts.
+    ///
+    /// If the accounts db verifies accounts hashes in the background, the accounts hash is not
+    /// verified before returning; see `take_accounts_hash_verification_receiver()` for its result.
     pub fn verify_snapshot_bank(
         &self,
         test_hash_calculation: bool,
@@ -5661,9 +5665,30 @@ impl Bank {
         }
         shrink_all_slots_time.stop();
 
-        info!("verify_bank_hash..");
         let mut verify_time = Measure::start("verify_bank_hash");
-        let mut verify = self.verify_bank_hash(test_hash_calculation);
+        let mut verify = if self
+            .rc
+            .accounts
+            .accounts_db
+            .verify_accounts_hash_in_background
+        {
+            // The bank hash is only known to be valid once the background verification reports
+            // so; until then, the verify_hash() below is taken on trust
+            info!("verify_bank_hash in the background..");
+            self.rc
+                .accounts
+                .accounts_db
+                .verify_bank_hash_and_lamports_in_background(
+                    self.slot(),
+                    self.ancestors.clone(),
+                    self.capitalization(),
+                    test_hash_calculation,
+                );
+            true
+        } else {
+            info!("verify_bank_hash..");
+            self.verify_bank_hash(test_hash_calculation)
+        };
         verify_time.stop();
         self.rc
             .accounts
@@ -5956,10 +5981,35 @@ impl Bank {
             .shrink_all_slots(is_startup, last_full_snapshot_slot);
     }
 
+    /// true while the accounts hash of the bank rebuilt from a snapshot is being verified in the
+    /// background
+    pub fn is_accounts_hash_verification_pending(&self) -> bool {
+        self.rc
+            .accounts
+            .accounts_db
+            .accounts_hash_verification_pending()
+    }
+
+    /// Take the channel that the background accounts hash verification started by
+    /// `verify_snapshot_bank()` sends its result over; `None` if it was not started, or the
+    /// channel has already been taken.
+    pub fn take_accounts_hash_verification_receiver(&self) -> Option<Receiver<bool>> {
+        self.rc
+            .accounts
+            .accounts_db
+            .take_accounts_hash_verification_receiver()
+    }
+
     pub fn print_accounts_stats(&self) {
         self.rc.accounts.accounts_db.print_accounts_stats("");
     }
 
+    /// The rent-paying accounts found while generating the accounts index, if the AccountsDb was
+    /// configured to collect them
+    pub fn rent_paying_accounts_report(&self) -> Option<RentPayingAccountsReport> {
+        self.rc.accounts.accounts_db.rent_paying_accounts_report()
+    }
+
     pub fn process_stale_slot_with_budget(
         &self,
         mut consumed_budget: usize,
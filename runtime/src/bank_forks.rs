@@ -198,7 +198,8 @@ impl BankForks {
             .get(&root)
             .expect("root bank didn't exist in bank_forks");
         let new_epoch = root_bank.epoch();
-        if old_epoch != new_epoch {
+        let is_first_root_in_epoch = old_epoch != new_epoch;
+        if is_first_root_in_epoch {
             info!(
                 "Root entering
                     epoch: {},
@@ -214,6 +215,19 @@ impl BankForks {
                     .node_id_to_vote_accounts()
             );
         }
+        // Full snapshots at epoch boundaries need a snapshot request for the first root in each
+        // epoch, whether or not it is at the accounts hash interval.  That is this root even if
+        // earlier slots of the epoch get rooted along with it: once the accounts are cleaned up
+        // to this root, a snapshot of any of its parents could be missing accounts.
+        let request_epoch_boundary_snapshot = is_first_root_in_epoch
+            && self
+                .snapshot_config
+                .as_ref()
+                .map_or(false, |snapshot_config| {
+                    snapshot_config
+                        .full_snapshot_schedule
+                        .includes_epoch_boundary()
+                });
         let root_tx_count = root_bank
             .parents()
             .last()
@@ -230,7 +244,8 @@ impl BankForks {
         let mut total_snapshot_ms = 0;
         for bank in banks.iter() {
             let bank_slot = bank.slot();
-            if bank.block_height() % self.accounts_hash_interval_slots == 0
+            if (bank.block_height() % self.accounts_hash_interval_slots == 0
+                || (request_epoch_boundary_snapshot && bank_slot == root))
                 && bank_slot > self.last_accounts_hash_slot
             {
                 self.last_accounts_hash_slot = bank_slot;
@@ -252,6 +267,7 @@ impl BankForks {
                             // if another `set_root()` is called before the snapshots package
                             // can be generated
                             status_cache_slot_deltas: bank.src.slot_deltas(&bank.src.roots()),
+                            is_first_root_in_epoch,
                         })
                     {
                         warn!(
@@ -468,6 +484,7 @@ mod tests {
         genesis_utils::{
             create_genesis_config, create_genesis_config_with_leader, GenesisConfigInfo,
         },
+        snapshot_utils::FullSnapshotSchedule,
     };
     use solana_sdk::hash::Hash;
     use solana_sdk::{
@@ -688,6 +705,78 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_bank_forks_set_root_requests_epoch_boundary_snapshot() {
+        let GenesisConfigInfo {
+            mut genesis_config, ..
+        } = create_genesis_config(10_000);
+        let slots_in_epoch = 32;
+        genesis_config.epoch_schedule = EpochSchedule::new(slots_in_epoch);
+
+        let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+        let mut bank_forks = BankForks::new_from_banks(&[bank0.clone()], 0);
+        bank_forks.set_snapshot_config(Some(SnapshotConfig {
+            full_snapshot_schedule: FullSnapshotSchedule::EpochBoundary,
+            ..SnapshotConfig::default()
+        }));
+        let (snapshot_request_sender, snapshot_request_receiver) = crossbeam_channel::unbounded();
+        let abs_request_sender = AbsRequestSender::new(Some(snapshot_request_sender));
+
+        // The accounts hash interval is never reached, so the only snapshot request is for the
+        // first root in epoch 1
+        let mut parent = bank0;
+        for slot in 1..=slots_in_epoch + 1 {
+            parent = bank_forks.insert(Bank::new_from_parent(&parent, &Pubkey::default(), slot));
+            bank_forks.set_root(slot, &abs_request_sender, None);
+        }
+        let snapshot_requests: Vec<_> = snapshot_request_receiver.try_iter().collect();
+        assert_eq!(snapshot_requests.len(), 1);
+        assert_eq!(
+            snapshot_requests[0].snapshot_root_bank.slot(),
+            slots_in_epoch
+        );
+        assert!(snapshot_requests[0].is_first_root_in_epoch);
+    }
+
+    #[test]
+    fn test_bank_forks_set_root_across_epoch_boundary_requests_snapshot_of_new_root() {
+        let GenesisConfigInfo {
+            mut genesis_config, ..
+        } = create_genesis_config(10_000);
+        let slots_in_epoch = 32;
+        genesis_config.epoch_schedule = EpochSchedule::new(slots_in_epoch);
+
+        let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+        let mut bank_forks = BankForks::new_from_banks(&[bank0.clone()], 0);
+        bank_forks.set_snapshot_config(Some(SnapshotConfig {
+            full_snapshot_schedule: FullSnapshotSchedule::EpochBoundary,
+            ..SnapshotConfig::default()
+        }));
+        let (snapshot_request_sender, snapshot_request_receiver) = crossbeam_channel::unbounded();
+        let abs_request_sender = AbsRequestSender::new(Some(snapshot_request_sender));
+
+        // Root the last slot of epoch 0, then jump straight to a root a few slots into epoch 1,
+        // rooting the first slots of epoch 1 along the way
+        let mut parent = bank0;
+        for slot in 1..slots_in_epoch + 3 {
+            parent = bank_forks.insert(Bank::new_from_parent(&parent, &Pubkey::default(), slot));
+            if slot == slots_in_epoch - 1 {
+                bank_forks.set_root(slot, &abs_request_sender, None);
+            }
+        }
+        bank_forks.set_root(slots_in_epoch + 2, &abs_request_sender, None);
+
+        // The epoch boundary snapshot is of the first root observed in epoch 1, not of its first
+        // slot
+        let snapshot_requests: Vec<_> = snapshot_request_receiver.try_iter().collect();
+        assert_eq!(snapshot_requests.len(), 1);
+        assert_eq!(
+            snapshot_requests[0].snapshot_root_bank.slot(),
+            slots_in_epoch + 2
+        );
+        assert!(snapshot_requests[0].is_first_root_in_epoch);
+    }
+
     #[test]
     fn test_bank_forks_with_highest_confirmed_root() {
         let GenesisConfigInfo { genesis_config, .. } = create_genesis_config(10_000);
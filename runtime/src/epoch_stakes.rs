@@ -108,6 +108,11 @@ impl EpochStakes {
                 }
             })
             .collect();
+        // in a fixed order rather than the hash map's, so that the same epoch stakes always
+        // serialize to the same bytes
+        node_id_to_vote_accounts
+            .values_mut()
+            .for_each(|node_vote_accounts| node_vote_accounts.vote_accounts.sort_unstable());
         (
             total_stake,
             node_id_to_vote_accounts,
@@ -3,6 +3,7 @@ use {
     bzip2::bufread::BzDecoder,
     log::*,
     rand::{thread_rng, Rng},
+    serde::{Deserialize, Serialize},
     solana_sdk::genesis_config::GenesisConfig,
     std::{
         collections::HashMap,
@@ -31,6 +32,97 @@ pub enum UnpackError {
 
 pub type Result<T> = std::result::Result<T, UnpackError>;
 
+/// The permissions given to snapshot archives, and to the files and directories unpacked from
+/// them, regardless of the umask.  Only the read-only flag is touched on Windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilePermissions {
+    pub file_mode: u32,
+    pub dir_mode: u32,
+}
+
+impl Default for FilePermissions {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl FilePermissions {
+    /// Everyone can read files and list directories, only the owner can write
+    pub const DEFAULT: Self = Self {
+        file_mode: 0o644,
+        dir_mode: 0o755,
+    };
+
+    /// Only the owner can read (or write) anything
+    pub const OWNER_ONLY: Self = Self {
+        file_mode: 0o600,
+        dir_mode: 0o700,
+    };
+
+    pub fn set_file_permissions(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        set_perms(path.as_ref(), self.file_mode)
+    }
+
+    pub fn set_dir_permissions(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        set_perms(path.as_ref(), self.dir_mode)
+    }
+
+    /// Create (or truncate) the file at `path` for writing.  The file never exists with any
+    /// wider permissions than these, even for a moment.
+    pub fn create_file(&self, path: impl AsRef<Path>) -> std::io::Result<File> {
+        let path = path.as_ref();
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(self.file_mode);
+        }
+        let file = options.open(path)?;
+        // The mode only applies to a new file, and even then through the umask
+        self.set_file_permissions(path)?;
+        Ok(file)
+    }
+
+    /// Recursively create the directory at `path` and all of its missing parents, none of which
+    /// ever exists with any wider permissions than these.
+    pub fn create_dir_all(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        if path.as_os_str().is_empty() || path.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(self.dir_mode);
+        }
+        match builder.create(path) {
+            Ok(()) => self.set_dir_permissions(path),
+            Err(_) if path.is_dir() => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn set_perms(dst: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let perm = fs::Permissions::from_mode(mode as _);
+    fs::set_permissions(dst, perm)
+}
+
+#[cfg(windows)]
+fn set_perms(dst: &Path, _mode: u32) -> std::io::Result<()> {
+    let mut perm = fs::metadata(dst)?.permissions();
+    perm.set_readonly(false);
+    fs::set_permissions(dst, perm)
+}
+
 // 64 TiB; some safe margin to the max 128 TiB in amd64 linux userspace VmSize
 // (ref: https://unix.stackexchange.com/a/386555/364236)
 // note that this is directly related to the mmaped data size
@@ -63,6 +155,11 @@ fn checked_total_size_sum(total_size: u64, entry_size: u64, limit_size: u64) ->
     Ok(total_size)
 }
 
+fn check_unpack_result(unpack_result: std::io::Result<()>, path: String) -> Result<()> {
+    unpack_result
+        .map_err(|err| UnpackError::Archive(format!("failed to unpack {:?}: {}", path, err)))
+}
+
 fn checked_total_count_increment(total_count: u64, limit_count: u64) -> Result<u64> {
     let total_count = total_count + 1;
     if total_count > limit_count {
@@ -74,16 +171,6 @@ fn checked_total_count_increment(total_count: u64, limit_count: u64) -> Result<u
     Ok(total_count)
 }
 
-fn check_unpack_result(unpack_result: bool, path: String) -> Result<()> {
-    if !unpack_result {
-        return Err(UnpackError::Archive(format!(
-            "failed to unpack: {:?}",
-            path
-        )));
-    }
-    Ok(())
-}
-
 pub enum UnpackPath<'a> {
     Valid(&'a Path),
     Ignore,
@@ -95,6 +182,7 @@ fn unpack_archive<'a, A: Read, C>(
     apparent_limit_size: u64,
     actual_limit_size: u64,
     limit_count: u64,
+    permissions: FilePermissions,
     mut entry_checker: C,
 ) -> Result<()>
 where
@@ -161,21 +249,25 @@ where
         )?;
         total_count = checked_total_count_increment(total_count, limit_count)?;
 
-        let target = sanitize_path(&entry.path()?, unpack_dir)?; // ? handles file system errors
+        let target = sanitize_path(&entry.path()?, unpack_dir, permissions)?; // ? handles file system errors
         if target.is_none() {
             continue; // skip it
         }
         let target = target.unwrap();
 
-        let unpack = entry.unpack(target);
-        check_unpack_result(unpack.map(|_unpack| true)?, path_str)?;
-
-        // Sanitize permissions.
-        let mode = match entry.header().entry_type() {
-            GNUSparse | Regular => 0o644,
-            _ => 0o755,
+        // Rather than `entry.unpack()`, which would create the target with the mode in the header
+        // and only then could have it narrowed, create the target with the permissions and copy
+        // the data of the entry into it
+        let unpack = match kind {
+            GNUSparse | Regular => permissions
+                .create_file(&target)
+                .and_then(|mut file| std::io::copy(&mut entry, &mut file))
+                .map(|_copied| ()),
+            _ => permissions
+                .create_dir_all(&target)
+                .and_then(|()| permissions.set_dir_permissions(&target)),
         };
-        set_perms(&unpack_dir.join(entry.path()?), mode)?;
+        check_unpack_result(unpack, path_str)?;
 
         total_entries += 1;
         let now = Instant::now();
@@ -186,28 +278,17 @@ where
     }
     info!("unpacked {} entries total", total_entries);
 
-    return Ok(());
-
-    #[cfg(unix)]
-    fn set_perms(dst: &Path, mode: u32) -> std::io::Result<()> {
-        use std::os::unix::fs::PermissionsExt;
-
-        let perm = fs::Permissions::from_mode(mode as _);
-        fs::set_permissions(dst, perm)
-    }
-
-    #[cfg(windows)]
-    fn set_perms(dst: &Path, _mode: u32) -> std::io::Result<()> {
-        let mut perm = fs::metadata(dst)?.permissions();
-        perm.set_readonly(false);
-        fs::set_permissions(dst, perm)
-    }
+    Ok(())
 }
 
 // return Err on file system error
 // return Some(path) if path is good
 // return None if we should skip this file
-fn sanitize_path(entry_path: &Path, dst: &Path) -> Result<Option<PathBuf>> {
+fn sanitize_path(
+    entry_path: &Path,
+    dst: &Path,
+    permissions: FilePermissions,
+) -> Result<Option<PathBuf>> {
     // We cannot call unpack_in because it errors if we try to use 2 account paths.
     // So, this code is borrowed from unpack_in
     // ref: https://docs.rs/tar/*/tar/struct.Entry.html#method.unpack_in
@@ -245,7 +326,11 @@ fn sanitize_path(entry_path: &Path, dst: &Path) -> Result<Option<PathBuf>> {
         None => return SKIP,
     };
 
-    fs::create_dir_all(parent)?;
+    // A parent that is not in the archive itself (e.g. the accounts directory under every account
+    // path but one) still gets the directory permissions
+    if !parent.exists() {
+        permissions.create_dir_all(parent)?;
+    }
 
     // Here we are different than untar_in. The code for tar::unpack_in internally calling unpack is a little different.
     // ignore return value here
@@ -298,6 +383,7 @@ pub fn unpack_snapshot<A: Read>(
     ledger_dir: &Path,
     account_paths: &[PathBuf],
     parallel_selector: Option<ParallelSelector>,
+    permissions: FilePermissions,
 ) -> Result<UnpackedAppendVecMap> {
     assert!(!account_paths.is_empty());
     let mut unpacked_append_vec_map = UnpackedAppendVecMap::new();
@@ -308,6 +394,7 @@ pub fn unpack_snapshot<A: Read>(
         MAX_SNAPSHOT_ARCHIVE_UNPACKED_APPARENT_SIZE,
         MAX_SNAPSHOT_ARCHIVE_UNPACKED_ACTUAL_SIZE,
         MAX_SNAPSHOT_ARCHIVE_UNPACKED_COUNT,
+        permissions,
         |parts, kind| {
             if is_valid_snapshot_archive_entry(parts, kind) {
                 i += 1;
@@ -374,9 +461,15 @@ fn like_storage(v: &str) -> bool {
     saw_numbers && periods == 1
 }
 
+// the storages listing that some snapshot versions keep next to the bank snapshot file
+fn like_storages_file(v: &str) -> bool {
+    v.strip_suffix(".storages").map_or(false, all_digits)
+}
+
 fn is_valid_snapshot_archive_entry(parts: &[&str], kind: tar::EntryType) -> bool {
     match (parts, kind) {
         (["version"], Regular) => true,
+        (["storage_master_key_id"], Regular) => true,
         (["accounts"], Directory) => true,
         (["accounts", file], GNUSparse) if like_storage(file) => true,
         (["accounts", file], Regular) if like_storage(file) => true,
@@ -385,6 +478,10 @@ fn is_valid_snapshot_archive_entry(parts: &[&str], kind: tar::EntryType) -> bool
         (["snapshots", "status_cache"], Regular) => true,
         (["snapshots", dir, file], GNUSparse) if all_digits(dir) && all_digits(file) => true,
         (["snapshots", dir, file], Regular) if all_digits(dir) && all_digits(file) => true,
+        (["snapshots", dir, file], GNUSparse) if all_digits(dir) && like_storages_file(file) => {
+            true
+        }
+        (["snapshots", dir, file], Regular) if all_digits(dir) && like_storages_file(file) => true,
         (["snapshots", dir], Directory) if all_digits(dir) => true,
         _ => false,
     }
@@ -449,6 +546,7 @@ fn unpack_genesis<A: Read>(
         max_genesis_archive_unpacked_size,
         max_genesis_archive_unpacked_size,
         MAX_GENESIS_ARCHIVE_UNPACKED_COUNT,
+        FilePermissions::default(),
         |p, k| {
             if is_valid_genesis_archive_entry(p, k) {
                 UnpackPath::Valid(unpack_dir)
@@ -480,6 +578,14 @@ mod tests {
 
     #[test]
     fn test_archive_is_valid_entry() {
+        assert!(is_valid_snapshot_archive_entry(
+            &["storage_master_key_id"],
+            tar::EntryType::Regular
+        ));
+        assert!(!is_valid_snapshot_archive_entry(
+            &["storage_master_key_id"],
+            tar::EntryType::Directory
+        ));
         assert!(is_valid_snapshot_archive_entry(
             &["snapshots"],
             tar::EntryType::Directory
@@ -529,6 +635,18 @@ mod tests {
             &["snapshots", "0", "aa"],
             tar::EntryType::Regular
         ));
+        assert!(is_valid_snapshot_archive_entry(
+            &["snapshots", "3", "3.storages"],
+            tar::EntryType::Regular
+        ));
+        assert!(!is_valid_snapshot_archive_entry(
+            &["snapshots", "3", ".storages"],
+            tar::EntryType::Regular
+        ));
+        assert!(!is_valid_snapshot_archive_entry(
+            &["snapshots", "3", "3.storages"],
+            tar::EntryType::Directory
+        ));
         assert!(!is_valid_snapshot_archive_entry(
             &["aaaa"],
             tar::EntryType::Regular
@@ -652,7 +770,7 @@ mod tests {
 
     fn finalize_and_unpack_snapshot(archive: tar::Builder<Vec<u8>>) -> Result<()> {
         with_finalize_and_unpack(archive, |a, b| {
-            unpack_snapshot(a, b, &[PathBuf::new()], None).map(|_| ())
+            unpack_snapshot(a, b, &[PathBuf::new()], None, FilePermissions::default()).map(|_| ())
         })
     }
 
@@ -678,6 +796,73 @@ mod tests {
         assert_matches!(result, Ok(()));
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_archive_unpack_snapshot_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut archive = Builder::new(Vec::new());
+
+        let mut header = Header::new_gnu();
+        header.set_path("accounts").unwrap();
+        header.set_entry_type(Directory);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_cksum();
+        archive.append(&header, &[][..]).unwrap();
+
+        let mut header = Header::new_gnu();
+        header.set_path("accounts/123.456").unwrap();
+        header.set_size(4);
+        header.set_mode(0o666);
+        header.set_cksum();
+        archive.append(&header, &[1, 2, 3, 4][..]).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let result = with_finalize_and_unpack(archive, |a, b| {
+            let other_account_path = b.join("other");
+            unpack_snapshot(
+                a,
+                b,
+                &[other_account_path.clone()],
+                None,
+                FilePermissions::OWNER_ONLY,
+            )?;
+            // The accounts directory was only created to hold the storage, since the account
+            // path is not the unpack directory
+            assert_eq!(mode(&other_account_path.join("accounts")), 0o700);
+            assert_eq!(mode(&other_account_path.join("accounts/123.456")), 0o600);
+            assert_eq!(
+                fs::read(other_account_path.join("accounts/123.456")).unwrap(),
+                [1, 2, 3, 4]
+            );
+            Ok(())
+        });
+        assert_matches!(result, Ok(()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_with_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        for permissions in [FilePermissions::DEFAULT, FilePermissions::OWNER_ONLY] {
+            let dir = temp_dir.path().join(format!("{:o}", permissions.dir_mode));
+            let nested_dir = dir.join("nested");
+            permissions.create_dir_all(&nested_dir).unwrap();
+            assert_eq!(mode(&dir), permissions.dir_mode);
+            assert_eq!(mode(&nested_dir), permissions.dir_mode);
+            // An existing directory is left as is
+            permissions.create_dir_all(&nested_dir).unwrap();
+
+            let file = nested_dir.join("file");
+            permissions.create_file(&file).unwrap();
+            assert_eq!(mode(&file), permissions.file_mode);
+        }
+    }
+
     #[test]
     fn test_archive_unpack_genesis_ok() {
         let mut header = Header::new_gnu();
@@ -858,8 +1043,11 @@ mod tests {
 
     #[test]
     fn test_archive_unpack_snapshot_bad_unpack() {
-        let result = check_unpack_result(false, "abc".to_string());
-        assert_matches!(result, Err(UnpackError::Archive(ref message)) if message == "failed to unpack: \"abc\"");
+        let result = check_unpack_result(
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "oops")),
+            "abc".to_string(),
+        );
+        assert_matches!(result, Err(UnpackError::Archive(ref message)) if message == "failed to unpack \"abc\": oops");
     }
 
     #[test]
@@ -36,6 +36,7 @@ pub mod loader_utils;
 pub mod message_processor;
 pub mod non_circulating_supply;
 mod nonce_keyed_account;
+pub mod program_accounts_snapshot;
 mod pubkey_bins;
 mod read_only_accounts_cache;
 pub mod rent_collector;
@@ -45,12 +46,15 @@ mod shared_buffer_reader;
 pub mod snapshot_archive_info;
 pub mod snapshot_config;
 pub mod snapshot_hash;
+pub mod snapshot_hooks;
 pub mod snapshot_package;
+pub mod snapshot_profile;
 pub mod snapshot_utils;
 pub mod sorted_storages;
 pub mod stake_weighted_timestamp;
 pub mod stakes;
 pub mod status_cache;
+pub mod storage_encryption;
 mod system_instruction_processor;
 pub mod transaction_batch;
 pub mod vote_account;
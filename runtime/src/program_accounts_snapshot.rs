@@ -0,0 +1,246 @@
+//! Export of every account owned by one program, as of a snapshot's slot
+//!
+//! The export is a single self-describing file: a magic number, then a header with the program
+//! id, the number of accounts, and the slot and hash of the snapshot archive(s) the accounts were
+//! loaded from, then the accounts themselves as a zstd compressed stream, sorted by pubkey.  The
+//! header is left uncompressed so the provenance of an export can be read without decompressing
+//! the accounts.
+use {
+    bincode::Options,
+    serde::{Deserialize, Serialize},
+    solana_sdk::{
+        account::{Account, AccountSharedData},
+        clock::Slot,
+        hash::Hash,
+        pubkey::Pubkey,
+    },
+    std::{
+        fs::File,
+        io::{self, BufReader, BufWriter, Read, Write},
+        path::Path,
+    },
+};
+
+/// The first bytes of every program accounts snapshot
+pub const PROGRAM_ACCOUNTS_SNAPSHOT_MAGIC: &[u8; 8] = b"SOLPGACC";
+
+/// The version of the program accounts snapshot format that is written
+pub const PROGRAM_ACCOUNTS_SNAPSHOT_VERSION: u32 = 1;
+
+const MAX_HEADER_SIZE: u64 = 4 * 1024;
+const MAX_ACCOUNT_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Which snapshot the accounts of a program accounts snapshot came from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProgramAccountsSnapshotHeader {
+    pub version: u32,
+    pub program_id: Pubkey,
+    /// The slot the accounts are as of, i.e. the incremental snapshot's slot if there was one
+    pub slot: Slot,
+    /// The accounts hash of the snapshot at `slot`
+    pub accounts_hash: Hash,
+    pub full_snapshot_slot: Slot,
+    pub full_snapshot_hash: Hash,
+    /// The slot and hash of the incremental snapshot, if the accounts were loaded from one
+    pub incremental_snapshot: Option<(Slot, Hash)>,
+    pub account_count: u64,
+}
+
+fn bincode_options(limit: u64) -> impl Options {
+    bincode::options()
+        .with_limit(limit)
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+fn to_io_error(err: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Write `accounts` out as a program accounts snapshot, each one as soon as it is yielded.  It is
+/// an error for there to be any more or fewer of them than `header.account_count`.
+pub fn write_program_accounts_snapshot<W: Write>(
+    writer: W,
+    header: &ProgramAccountsSnapshotHeader,
+    accounts: impl IntoIterator<Item = io::Result<(Pubkey, AccountSharedData)>>,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    writer.write_all(PROGRAM_ACCOUNTS_SNAPSHOT_MAGIC)?;
+    bincode_options(MAX_HEADER_SIZE)
+        .serialize_into(&mut writer, header)
+        .map_err(to_io_error)?;
+
+    let mut encoder = zstd::stream::Encoder::new(writer, 0)?;
+    let mut account_count = 0;
+    for item in accounts {
+        let (pubkey, account) = item?;
+        account_count += 1;
+        if account_count > header.account_count {
+            break;
+        }
+        bincode_options(MAX_ACCOUNT_SIZE)
+            .serialize_into(&mut encoder, &(pubkey, Account::from(account)))
+            .map_err(to_io_error)?;
+    }
+    if account_count != header.account_count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the header claims {} accounts, but there are {}",
+                header.account_count,
+                if account_count > header.account_count {
+                    "more"
+                } else {
+                    "fewer"
+                }
+            ),
+        ));
+    }
+    encoder.finish()?.flush()
+}
+
+/// Reads a program accounts snapshot back, yielding its accounts in pubkey order
+pub struct ProgramAccountsSnapshotReader<R: Read> {
+    header: ProgramAccountsSnapshotHeader,
+    accounts: zstd::stream::read::Decoder<'static, BufReader<R>>,
+    remaining: u64,
+}
+
+impl ProgramAccountsSnapshotReader<File> {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl<R: Read> ProgramAccountsSnapshotReader<R> {
+    /// Read and check the header; the accounts are only read as they are iterated over
+    pub fn new(reader: R) -> io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let mut magic = [0u8; PROGRAM_ACCOUNTS_SNAPSHOT_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != PROGRAM_ACCOUNTS_SNAPSHOT_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a program accounts snapshot",
+            ));
+        }
+        let header: ProgramAccountsSnapshotHeader = bincode_options(MAX_HEADER_SIZE)
+            .deserialize_from(&mut reader)
+            .map_err(to_io_error)?;
+        if header.version != PROGRAM_ACCOUNTS_SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported program accounts snapshot version: {}",
+                    header.version
+                ),
+            ));
+        }
+        let remaining = header.account_count;
+        Ok(Self {
+            header,
+            accounts: zstd::stream::read::Decoder::with_buffer(reader)?,
+            remaining,
+        })
+    }
+
+    pub fn header(&self) -> &ProgramAccountsSnapshotHeader {
+        &self.header
+    }
+}
+
+impl<R: Read> Iterator for ProgramAccountsSnapshotReader<R> {
+    type Item = io::Result<(Pubkey, Account)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let item = bincode_options(MAX_ACCOUNT_SIZE)
+            .deserialize_from(&mut self.accounts)
+            .map_err(to_io_error);
+        if item.is_err() {
+            // A truncated or corrupt stream will not get any better
+            self.remaining = 0;
+        }
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, solana_sdk::account::WritableAccount};
+
+    #[test]
+    fn test_program_accounts_snapshot_roundtrip() {
+        let program_id = Pubkey::new_unique();
+        let mut accounts: Vec<_> = (0..10)
+            .map(|i| {
+                let mut account = AccountSharedData::new(i + 1, i as usize * 100, &program_id);
+                account.set_rent_epoch(i);
+                (Pubkey::new_unique(), account)
+            })
+            .collect();
+        accounts.sort_unstable_by_key(|(pubkey, _)| *pubkey);
+        let header = ProgramAccountsSnapshotHeader {
+            version: PROGRAM_ACCOUNTS_SNAPSHOT_VERSION,
+            program_id,
+            slot: 42,
+            accounts_hash: Hash::new_unique(),
+            full_snapshot_slot: 40,
+            full_snapshot_hash: Hash::new_unique(),
+            incremental_snapshot: Some((42, Hash::new_unique())),
+            account_count: accounts.len() as u64,
+        };
+
+        let mut buf = vec![];
+        write_program_accounts_snapshot(&mut buf, &header, accounts.iter().cloned().map(Ok))
+            .unwrap();
+
+        let reader = ProgramAccountsSnapshotReader::new(buf.as_slice()).unwrap();
+        assert_eq!(reader.header(), &header);
+        let read_accounts = reader
+            .map(|item| item.map(|(pubkey, account)| (pubkey, AccountSharedData::from(account))))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read_accounts, accounts);
+
+        // A truncated export errors instead of coming up short
+        let truncated_accounts = ProgramAccountsSnapshotReader::new(&buf[..buf.len() - 8])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>();
+        assert!(truncated_accounts.is_err());
+
+        // The header has to tell the number of accounts written after it
+        for account_count in [accounts.len() - 1, accounts.len() + 1] {
+            let header = ProgramAccountsSnapshotHeader {
+                account_count: account_count as u64,
+                ..header.clone()
+            };
+            assert_eq!(
+                write_program_accounts_snapshot(
+                    &mut vec![],
+                    &header,
+                    accounts.iter().cloned().map(Ok)
+                )
+                .unwrap_err()
+                .kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+
+        // Anything else is rejected up front
+        assert_eq!(
+            ProgramAccountsSnapshotReader::new(&buf[1..])
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
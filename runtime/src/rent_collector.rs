@@ -82,6 +82,29 @@ impl RentCollector {
             .due(account.lamports(), account.data().len(), years_elapsed)
     }
 
+    /// given an account that 'should_collect_rent' and is not exempt,
+    /// returns the epoch in which collecting rent is projected to have drained its lamports
+    /// returns None if the account is rent exempt or no rent is ever due
+    pub fn get_projected_eviction_epoch(&self, account: &impl ReadableAccount) -> Option<Epoch> {
+        let epoch = std::cmp::max(account.rent_epoch(), self.epoch);
+        let slots_per_epoch = self.epoch_schedule.get_slots_in_epoch(epoch);
+        let years_per_epoch = if self.slots_per_year != 0.0 {
+            slots_per_epoch as f64 / self.slots_per_year
+        } else {
+            0.0
+        };
+
+        match self
+            .rent
+            .due(account.lamports(), account.data().len(), years_per_epoch)
+        {
+            (_, true) | (0, _) => None,
+            (rent_per_epoch, false) => {
+                Some(epoch.saturating_add(account.lamports() / rent_per_epoch))
+            }
+        }
+    }
+
     // updates this account's lamports and status and returns
     //  the account rent collected, if any
     // This is NOT thread safe at some level. If we try to collect from the same account in parallel, we may collect twice.
@@ -222,6 +245,48 @@ mod tests {
         assert_ne!(collected, 0);
     }
 
+    #[test]
+    fn test_projected_eviction_epoch() {
+        let epoch = 3;
+        let rent_collector = RentCollector::new(
+            epoch,
+            &EpochSchedule::without_warmup(),
+            GenesisConfig::default().slots_per_year(),
+            &Rent::default(),
+        );
+        let mut account = AccountSharedData::new(1, 0, &Pubkey::default());
+
+        let years_per_epoch = rent_collector.epoch_schedule.get_slots_in_epoch(epoch) as f64
+            / rent_collector.slots_per_year;
+        let (rent_per_epoch, exempt) = rent_collector.rent.due(1, 0, years_per_epoch);
+        assert!(!exempt);
+        assert!(rent_per_epoch > 0);
+
+        // not enough lamports to pay even a single epoch of rent
+        assert_eq!(
+            rent_collector.get_projected_eviction_epoch(&account),
+            Some(epoch)
+        );
+
+        // enough lamports to pay for 10 epochs
+        account.set_lamports(rent_per_epoch * 10);
+        assert_eq!(
+            rent_collector.get_projected_eviction_epoch(&account),
+            Some(epoch + 10)
+        );
+
+        // projections start from the account's rent epoch if it is later
+        account.set_rent_epoch(epoch + 5);
+        assert_eq!(
+            rent_collector.get_projected_eviction_epoch(&account),
+            Some(epoch + 15)
+        );
+
+        // rent exempt accounts are never evicted
+        account.set_lamports(rent_collector.rent.minimum_balance(0));
+        assert_eq!(rent_collector.get_projected_eviction_epoch(&account), None);
+    }
+
     #[test]
     fn test_rent_exempt_sysvar() {
         let tiny_lamports = 1;
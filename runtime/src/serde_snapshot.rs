@@ -3,12 +3,14 @@ use {
         accounts::Accounts,
         accounts_db::{
             AccountShrinkThreshold, AccountStorageEntry, AccountsDb, AccountsDbConfig, AppendVecId,
-            BankHashInfo,
+            BankHashInfo, RentPayingAccountsReport, SnapshotRestoreNotifyMode,
         },
         accounts_index::AccountSecondaryIndexes,
         accounts_update_notifier_interface::AccountsUpdateNotifier,
         ancestors::Ancestors,
-        append_vec::{AppendVec, StoredMetaWriteVersion},
+        append_vec::{
+            AppendVec, AppendVecValidation, StoredMetaWriteVersion, MIN_STORED_ACCOUNT_SIZE,
+        },
         bank::{Bank, BankFieldsToDeserialize, BankRc},
         blockhash_queue::BlockhashQueue,
         builtins::Builtins,
@@ -16,11 +18,14 @@ use {
         hardened_unpack::UnpackedAppendVecMap,
         rent_collector::RentCollector,
         serde_snapshot::future::SerializableStorage,
+        snapshot_utils::{atomic_replace_file, SnapshotError, SnapshotVersion},
         stakes::Stakes,
+        storage_encryption::{self, MasterKey, StorageBinding},
     },
     bincode,
     bincode::{config::Options, Error},
     log::*,
+    rand::{seq::SliceRandom, thread_rng},
     rayon::prelude::*,
     serde::{de::DeserializeOwned, Deserialize, Serialize},
     solana_measure::measure::Measure,
@@ -36,6 +41,7 @@ use {
         pubkey::Pubkey,
     },
     std::{
+        cmp::Reverse,
         collections::{HashMap, HashSet},
         io::{self, BufReader, BufWriter, Read, Write},
         path::{Path, PathBuf},
@@ -44,19 +50,30 @@ use {
             atomic::{AtomicUsize, Ordering},
             Arc, RwLock,
         },
-        thread::Builder,
+        thread::{self, Builder},
+        time::Duration,
     },
 };
 
 #[cfg(RUSTC_WITH_SPECIALIZATION)]
 use solana_frozen_abi::abi_example::IgnoreAsHelper;
 
+mod canonical;
 mod common;
 mod future;
+mod probe;
+mod sharded_storages;
+mod storage_iter;
+mod storage_limits;
 mod tests;
 mod utils;
 
+pub(crate) use canonical::serialize_into as canonical_serialize_into;
 use future::Context as TypeContextFuture;
+pub use probe::{probe, SnapshotCompatibility, SnapshotSections, MAX_PROBE_BYTES};
+pub use storage_iter::{DeserializedAccountsDbFields, SnapshotStorageAccountsIter};
+pub use storage_limits::SnapshotStorageLimits;
+use storage_limits::StorageTally;
 #[allow(unused_imports)]
 use utils::{serialize_iter_as_map, serialize_iter_as_seq, serialize_iter_as_tuple};
 
@@ -73,6 +90,17 @@ pub(crate) enum SerdeStyle {
 
 const MAX_STREAM_SIZE: u64 = 32 * 1024 * 1024 * 1024;
 
+/// Number of times an IO operation that fails with a transient error is retried while remapping
+/// the unpacked append vecs, and how long to wait before the first retry (doubled for each retry
+/// after that)
+const MAX_REMAP_IO_RETRIES: usize = 5;
+const REMAP_IO_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Number of the most recent slots whose accounts delta hashes are written after the bank
+/// manifest, and how many of those are spot-checked against their storages when loading it
+const MAX_SLOT_DELTA_HASHES: usize = 32;
+const SLOT_DELTA_HASH_SPOT_CHECKS: usize = 4;
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize, AbiExample)]
 struct AccountsDbFields<T>(
     HashMap<Slot, Vec<T>>,
@@ -81,11 +109,36 @@ struct AccountsDbFields<T>(
     BankHashInfo,
 );
 
+/// Stats gathered while reconstructing an AccountsDb from the deserialized snapshot fields
+#[derive(Debug, Default, Clone)]
+pub struct ReconstructAccountsDbStats {
+    /// time spent remapping the unpacked append vecs to their new ids
+    pub remap_us: u64,
+    /// number of append vec ids that collided with an existing file while remapping
+    pub remap_collisions: usize,
+    /// number of times a rename or open of an append vec was retried after a transient IO error
+    pub remap_io_retries: usize,
+    /// time spent notifying the accounts update notifier of the restored accounts
+    pub notify_us: u64,
+    /// rent-paying accounts found while generating the index, if the AccountsDbConfig asked for
+    /// them with `collect_rent_paying_accounts_report`
+    pub rent_paying_accounts_report: Option<RentPayingAccountsReport>,
+}
+
 /// Helper type to wrap BufReader streams when deserializing and reconstructing from either just a
 /// full snapshot, or both a full and incremental snapshot
 pub struct SnapshotStreams<'a, R> {
     pub full_snapshot_stream: &'a mut BufReader<R>,
     pub incremental_snapshot_stream: Option<&'a mut BufReader<R>>,
+    /// The storages listing of the full snapshot, for snapshot versions that keep it in its own
+    /// file instead of in the bank manifest
+    pub full_snapshot_storages_stream: Option<&'a mut BufReader<R>>,
+    /// The storages listing of the incremental snapshot, likewise
+    pub incremental_snapshot_storages_stream: Option<&'a mut BufReader<R>>,
+    /// The versions of the full and incremental snapshots, which tell what their bank manifests
+    /// hold past the bank and AccountsDb fields
+    pub full_snapshot_version: SnapshotVersion,
+    pub incremental_snapshot_version: Option<SnapshotVersion>,
 }
 
 /// Helper type to wrap AccountsDbFields when reconstructing AccountsDb from either just a full
@@ -140,8 +193,9 @@ impl<T> SnapshotAccountsDbFields<T> {
 trait TypeContext<'a> {
     type SerializableAccountStorageEntry: Serialize
         + DeserializeOwned
-        + From<&'a AccountStorageEntry>
+        + From<SnapshotStorageEntryInfo>
         + SerializableStorage
+        + Send
         + Sync;
 
     fn serialize_bank_and_storage<S: serde::ser::Serializer>(
@@ -158,8 +212,16 @@ trait TypeContext<'a> {
     where
         Self: std::marker::Sized;
 
+    fn serialize_account_storage_entries<W: Write>(
+        stream: &mut W,
+        serializable_storages: &SerializableAccountStorages<'a, Self>,
+    ) -> Result<(), Error>
+    where
+        Self: std::marker::Sized;
+
     fn deserialize_bank_fields<R>(
         stream: &mut BufReader<R>,
+        storage_tally: &StorageTally,
     ) -> Result<
         (
             BankFieldsToDeserialize,
@@ -172,9 +234,123 @@ trait TypeContext<'a> {
 
     fn deserialize_accounts_db_fields<R>(
         stream: &mut BufReader<R>,
+        storage_tally: &StorageTally,
     ) -> Result<AccountsDbFields<Self::SerializableAccountStorageEntry>, Error>
     where
         R: Read;
+
+    fn deserialize_account_storage_entries<R>(
+        stream: &mut BufReader<R>,
+        storage_tally: &StorageTally,
+    ) -> Result<HashMap<Slot, Vec<Self::SerializableAccountStorageEntry>>, Error>
+    where
+        R: Read;
+}
+
+/// Deserialize the bank fields and AccountsDb fields of one snapshot.  If the snapshot keeps its
+/// storages listing in a stream of its own, the manifest and the listing are parsed in parallel
+/// and the listing takes the place of the manifest's (empty) storages map.  The storages are
+/// tallied in `storage_tally` as they are deserialized.
+#[allow(clippy::type_complexity)]
+fn deserialize_bank_fields_and_storages<'a, C, R>(
+    stream: &mut BufReader<R>,
+    storages_stream: Option<&mut BufReader<R>>,
+    storage_tally: &StorageTally,
+) -> Result<
+    (
+        BankFieldsToDeserialize,
+        AccountsDbFields<C::SerializableAccountStorageEntry>,
+    ),
+    Error,
+>
+where
+    C: TypeContext<'a>,
+    R: Read + Send,
+{
+    let storages_stream = match storages_stream {
+        Some(storages_stream) => storages_stream,
+        None => return C::deserialize_bank_fields(stream, storage_tally),
+    };
+    let (bank_and_accounts_db_fields, account_storage_entries) = rayon::join(
+        || C::deserialize_bank_fields(stream, storage_tally),
+        || C::deserialize_account_storage_entries(storages_stream, storage_tally),
+    );
+    let (bank_fields, mut accounts_db_fields) = bank_and_accounts_db_fields?;
+    if !accounts_db_fields.0.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bank manifest lists storages even though they have a file of their own",
+        )
+        .into());
+    }
+    accounts_db_fields.0 = account_storage_entries?;
+    Ok((bank_fields, accounts_db_fields))
+}
+
+/// Deserialize the bank fields and AccountsDb fields of the full snapshot, and of the incremental
+/// snapshot if there is one, along with the accounts delta hashes that follow them in the bank
+/// manifests.  The storages of both snapshots are tallied together in `storage_tally`.
+#[allow(clippy::type_complexity)]
+fn deserialize_snapshot_streams<'a, C, R>(
+    snapshot_streams: &mut SnapshotStreams<R>,
+    storage_tally: &StorageTally,
+) -> Result<
+    (
+        BankFieldsToDeserialize,
+        Option<BankFieldsToDeserialize>,
+        SnapshotAccountsDbFields<C::SerializableAccountStorageEntry>,
+        Vec<(Slot, Hash)>,
+    ),
+    SnapshotError,
+>
+where
+    C: TypeContext<'a>,
+    R: Read + Send,
+{
+    let (full_snapshot_bank_fields, full_snapshot_accounts_db_fields) =
+        storage_tally.within_limits(deserialize_bank_fields_and_storages::<C, _>(
+            snapshot_streams.full_snapshot_stream,
+            snapshot_streams
+                .full_snapshot_storages_stream
+                .as_deref_mut(),
+            storage_tally,
+        ))?;
+    let mut slot_delta_hashes = deserialize_slot_delta_hashes(
+        snapshot_streams.full_snapshot_stream,
+        snapshot_streams.full_snapshot_version,
+    )?;
+    let (incremental_snapshot_bank_fields, incremental_snapshot_accounts_db_fields) =
+        if let Some(ref mut incremental_snapshot_stream) =
+            snapshot_streams.incremental_snapshot_stream
+        {
+            let (bank_fields, accounts_db_fields) =
+                storage_tally.within_limits(deserialize_bank_fields_and_storages::<C, _>(
+                    incremental_snapshot_stream,
+                    snapshot_streams
+                        .incremental_snapshot_storages_stream
+                        .as_deref_mut(),
+                    storage_tally,
+                ))?;
+            slot_delta_hashes.extend(deserialize_slot_delta_hashes(
+                incremental_snapshot_stream,
+                snapshot_streams
+                    .incremental_snapshot_version
+                    .unwrap_or(snapshot_streams.full_snapshot_version),
+            )?);
+            (Some(bank_fields), Some(accounts_db_fields))
+        } else {
+            (None, None)
+        };
+
+    Ok((
+        full_snapshot_bank_fields,
+        incremental_snapshot_bank_fields,
+        SnapshotAccountsDbFields {
+            full_snapshot_accounts_db_fields,
+            incremental_snapshot_accounts_db_fields,
+        },
+        slot_delta_hashes,
+    ))
 }
 
 fn deserialize_from<R, T>(reader: R) -> bincode::Result<T>
@@ -189,6 +365,70 @@ where
         .deserialize_from::<R, T>(reader)
 }
 
+/// Read the accounts delta hashes that follow the bank and AccountsDb fields in the bank manifests
+/// of `SnapshotVersion::V1_3_0` snapshots.  The manifests of older versions have none, so whatever
+/// follows their fields is left unread.
+fn deserialize_slot_delta_hashes<R>(
+    stream: &mut BufReader<R>,
+    snapshot_version: SnapshotVersion,
+) -> Result<Vec<(Slot, Hash)>, Error>
+where
+    R: Read,
+{
+    match snapshot_version {
+        SnapshotVersion::V1_2_0 => Ok(Vec::new()),
+        SnapshotVersion::V1_3_0 => deserialize_from(stream),
+    }
+}
+
+/// Check a random few of `slot_delta_hashes` against the accounts delta hashes recomputed from the
+/// storages that `accounts_db` was rebuilt from, to catch storages that do not match the manifest
+/// long before the accounts hash of the whole bank would.  The filler accounts, which go into the
+/// storages of existing slots, are left out of the recomputed hashes.
+fn spot_check_slot_delta_hashes(
+    accounts_db: &AccountsDb,
+    slot_delta_hashes: &[(Slot, Hash)],
+) -> Result<(), Error> {
+    let mut measure = Measure::start("spot_check_slot_delta_hashes");
+    let spot_checks = slot_delta_hashes
+        .choose_multiple(&mut thread_rng(), SLOT_DELTA_HASH_SPOT_CHECKS)
+        .collect::<Vec<_>>();
+    let mismatch = spot_checks.par_iter().find_any(|(slot, delta_hash)| {
+        // slots that were not loaded, e.g. with `limit_load_slot_count_from_snapshot`, are skipped
+        accounts_db
+            .storage
+            .get_slot_stores(*slot)
+            .map_or(false, |slot_stores| {
+                let storages = slot_stores
+                    .read()
+                    .unwrap()
+                    .values()
+                    .cloned()
+                    .collect::<Vec<_>>();
+                AccountsDb::calculate_storages_delta_hash_excluding(&storages, true, |pubkey| {
+                    accounts_db.is_filler_account(pubkey)
+                }) != *delta_hash
+            })
+    });
+    measure.stop();
+    datapoint_info!(
+        "spot_check_slot_delta_hashes",
+        ("slots", spot_checks.len(), i64),
+        ("total_us", measure.as_us(), i64),
+    );
+    match mismatch {
+        Some((slot, _)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the storages of slot {} do not match its accounts delta hash",
+                slot
+            ),
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn bank_from_streams<R>(
     serde_style: SerdeStyle,
@@ -206,30 +446,25 @@ pub(crate) fn bank_from_streams<R>(
     verify_index: bool,
     accounts_db_config: Option<AccountsDbConfig>,
     accounts_update_notifier: Option<AccountsUpdateNotifier>,
-) -> std::result::Result<Bank, Error>
+) -> Result<(Bank, ReconstructAccountsDbStats), SnapshotError>
 where
-    R: Read,
+    R: Read + Send,
 {
+    let storage_tally = StorageTally::new(
+        accounts_db_config
+            .as_ref()
+            .map(|config| config.snapshot_storage_limits)
+            .unwrap_or_default(),
+    );
     macro_rules! INTO {
         ($x:ident) => {{
-            let (full_snapshot_bank_fields, full_snapshot_accounts_db_fields) =
-                $x::deserialize_bank_fields(snapshot_streams.full_snapshot_stream)?;
-            let (incremental_snapshot_bank_fields, incremental_snapshot_accounts_db_fields) =
-                if let Some(ref mut incremental_snapshot_stream) =
-                    snapshot_streams.incremental_snapshot_stream
-                {
-                    let (bank_fields, accounts_db_fields) =
-                        $x::deserialize_bank_fields(incremental_snapshot_stream)?;
-                    (Some(bank_fields), Some(accounts_db_fields))
-                } else {
-                    (None, None)
-                };
-
-            let snapshot_accounts_db_fields = SnapshotAccountsDbFields {
-                full_snapshot_accounts_db_fields,
-                incremental_snapshot_accounts_db_fields,
-            };
-            let bank = reconstruct_bank_from_fields(
+            let (
+                full_snapshot_bank_fields,
+                incremental_snapshot_bank_fields,
+                snapshot_accounts_db_fields,
+                slot_delta_hashes,
+            ) = deserialize_snapshot_streams::<$x, _>(snapshot_streams, &storage_tally)?;
+            let (bank, reconstruct_accounts_db_stats) = reconstruct_bank_from_fields(
                 incremental_snapshot_bank_fields.unwrap_or(full_snapshot_bank_fields),
                 snapshot_accounts_db_fields,
                 genesis_config,
@@ -246,7 +481,8 @@ where
                 accounts_db_config,
                 accounts_update_notifier,
             )?;
-            Ok(bank)
+            spot_check_slot_delta_hashes(&bank.rc.accounts.accounts_db, &slot_delta_hashes)?;
+            Ok((bank, reconstruct_accounts_db_stats))
         }};
     }
     match serde_style {
@@ -258,22 +494,141 @@ where
     })
 }
 
-pub(crate) fn bank_to_stream<W>(
+/// Reconstruct only the AccountsDb from the snapshot stream(s), for read-only consumers of the
+/// account state that do not need a Bank (and so no genesis builtins, status cache, etc.).
+/// Returns the AccountsDb along with the snapshot's slot, bank hash info and capitalization.
+#[allow(clippy::too_many_arguments)]
+pub fn accounts_db_from_snapshot_streams<R>(
+    snapshot_version: SnapshotVersion,
+    snapshot_streams: &mut SnapshotStreams<R>,
+    account_paths: &[PathBuf],
+    unpacked_append_vec_map: UnpackedAppendVecMap,
+    genesis_config: &GenesisConfig,
+    account_secondary_indexes: AccountSecondaryIndexes,
+    caching_enabled: bool,
+    limit_load_slot_count_from_snapshot: Option<usize>,
+    shrink_ratio: AccountShrinkThreshold,
+    verify_index: bool,
+    accounts_db_config: Option<AccountsDbConfig>,
+    accounts_update_notifier: Option<AccountsUpdateNotifier>,
+) -> Result<(AccountsDb, Slot, BankHashInfo, u64), SnapshotError>
+where
+    R: Read + Send,
+{
+    let storage_limits = accounts_db_config
+        .as_ref()
+        .map(|config| config.snapshot_storage_limits)
+        .unwrap_or_default();
+    let accounts_db_fields = DeserializedAccountsDbFields::from_streams(
+        snapshot_version,
+        snapshot_streams,
+        storage_limits,
+    )?;
+    let slot = accounts_db_fields.slot();
+    let bank_hash_info = accounts_db_fields.bank_hash_info().clone();
+    let capitalization = accounts_db_fields.capitalization();
+    let slot_delta_hashes = accounts_db_fields.slot_delta_hashes().to_vec();
+
+    // the fields have already been collapsed, so there is nothing left for an incremental snapshot
+    let snapshot_accounts_db_fields = SnapshotAccountsDbFields {
+        full_snapshot_accounts_db_fields: accounts_db_fields.accounts_db_fields,
+        incremental_snapshot_accounts_db_fields: None,
+    };
+    let (accounts_db, _reconstruct_accounts_db_stats) = reconstruct_accountsdb_from_fields(
+        snapshot_accounts_db_fields,
+        account_paths,
+        unpacked_append_vec_map,
+        genesis_config,
+        account_secondary_indexes,
+        caching_enabled,
+        limit_load_slot_count_from_snapshot,
+        shrink_ratio,
+        verify_index,
+        accounts_db_config,
+        accounts_update_notifier,
+    )
+    .and_then(|(accounts_db, stats)| {
+        spot_check_slot_delta_hashes(&accounts_db, &slot_delta_hashes)?;
+        Ok((accounts_db, stats))
+    })
+    .map_err(|err| {
+        warn!("accounts_db_from_snapshot_streams error: {:?}", err);
+        err
+    })?;
+
+    Ok((accounts_db, slot, bank_hash_info, capitalization))
+}
+
+pub(crate) fn bank_to_stream<W, S>(
     serde_style: SerdeStyle,
     stream: &mut BufWriter<W>,
     bank: &Bank,
-    snapshot_storages: &[SnapshotStorage],
+    snapshot_storages: &S,
+) -> Result<(), Error>
+where
+    W: Write,
+    S: SnapshotStoragesProvider + ?Sized,
+{
+    let snapshot_storages = snapshot_storages.snapshot_storages();
+    let deterministic = bank
+        .rc
+        .accounts
+        .accounts_db
+        .deterministic_snapshot_serialization;
+    macro_rules! INTO {
+        ($x:ident) => {{
+            let serializable_bank = SerializableBankAndStorage::<$x> {
+                bank,
+                snapshot_storages: &snapshot_storages,
+                phantom: std::marker::PhantomData::default(),
+            };
+            if deterministic {
+                canonical::serialize_into(stream, &serializable_bank)
+            } else {
+                bincode::serialize_into(stream, &serializable_bank)
+            }
+        }};
+    }
+    match serde_style {
+        SerdeStyle::Newer => INTO!(TypeContextFuture),
+    }
+    .map_err(|err| {
+        warn!("bankrc_to_stream error: {:?}", err);
+        err
+    })
+}
+
+/// Serialize the accounts delta hashes that follow the bank and AccountsDb fields in a bank manifest
+pub(crate) fn slot_delta_hashes_to_stream<W>(
+    stream: &mut BufWriter<W>,
+    slot_delta_hashes: &[(Slot, Hash)],
+) -> Result<(), Error>
+where
+    W: Write,
+{
+    bincode::serialize_into(stream, slot_delta_hashes)
+}
+
+/// Serialize just the storages listing of a snapshot, for snapshot versions that keep it in a
+/// file of its own.  The bank manifest then goes through `bank_to_stream()` without storages.
+pub(crate) fn account_storage_entries_to_stream<W, S>(
+    serde_style: SerdeStyle,
+    stream: &mut BufWriter<W>,
+    accounts_db: &AccountsDb,
+    snapshot_storages: &S,
 ) -> Result<(), Error>
 where
     W: Write,
+    S: SnapshotStoragesProvider + ?Sized,
 {
+    let snapshot_storages = snapshot_storages.snapshot_storages();
     macro_rules! INTO {
         ($x:ident) => {
-            bincode::serialize_into(
+            $x::serialize_account_storage_entries(
                 stream,
-                &SerializableBankAndStorage::<$x> {
-                    bank,
-                    snapshot_storages,
+                &SerializableAccountStorages::<$x> {
+                    accounts_db,
+                    account_storage_entries: &snapshot_storages,
                     phantom: std::marker::PhantomData::default(),
                 },
             )
@@ -283,14 +638,103 @@ where
         SerdeStyle::Newer => INTO!(TypeContextFuture),
     }
     .map_err(|err| {
-        warn!("bankrc_to_stream error: {:?}", err);
+        warn!("account_storage_entries_to_stream error: {:?}", err);
         err
     })
 }
 
+/// The ids that the storages of a deterministic snapshot are listed and archived under: their
+/// positions in slot and then id order, so that they do not depend on the append vec ids of the
+/// node that took the snapshot
+pub(crate) fn canonical_storage_ids(
+    storages: impl IntoIterator<Item = (Slot, AppendVecId)>,
+) -> HashMap<(Slot, AppendVecId), AppendVecId> {
+    let mut storages = storages.into_iter().collect::<Vec<_>>();
+    storages.sort_unstable();
+    storages
+        .into_iter()
+        .enumerate()
+        .map(|(canonical_id, storage)| (storage, canonical_id))
+        .collect()
+}
+
+/// The fields of a storage entry that get serialized into a snapshot
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SnapshotStorageEntryInfo {
+    /// id of the storage's append vec, which names its file (see `AppendVec::file_name()`)
+    pub id: AppendVecId,
+    /// number of bytes used in the append vec
+    pub current_len: usize,
+}
+
+impl From<&AccountStorageEntry> for SnapshotStorageEntryInfo {
+    fn from(storage_entry: &AccountStorageEntry) -> Self {
+        Self {
+            id: storage_entry.append_vec_id(),
+            current_len: storage_entry.accounts.len(),
+        }
+    }
+}
+
+/// Provides the storage entries to serialize into a snapshot, so that snapshots can also be
+/// synthesized from storages that do not belong to an AccountsDb (e.g. append vecs written out by
+/// a tool).  The storages from an AccountsDb, i.e. `[SnapshotStorage]`, are the usual provider.
+pub trait SnapshotStoragesProvider {
+    /// The storage entries of each slot to serialize.  Each slot must appear once, with at least
+    /// one storage entry.
+    fn snapshot_storages(&self) -> Vec<(Slot, Vec<SnapshotStorageEntryInfo>)>;
+
+    /// The accounts delta hashes of the most recent slots, computed from their storages, for the
+    /// loader to spot-check the storages against.  None by default.
+    fn slot_delta_hashes(&self) -> Vec<(Slot, Hash)> {
+        Vec::new()
+    }
+}
+
+impl SnapshotStoragesProvider for [SnapshotStorage] {
+    fn snapshot_storages(&self) -> Vec<(Slot, Vec<SnapshotStorageEntryInfo>)> {
+        self.iter()
+            .map(|slot_storages| {
+                (
+                    slot_storages.first().unwrap().slot(),
+                    slot_storages
+                        .iter()
+                        .map(|storage_entry| SnapshotStorageEntryInfo::from(storage_entry.as_ref()))
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn slot_delta_hashes(&self) -> Vec<(Slot, Hash)> {
+        let mut recent_storages = self.iter().collect::<Vec<_>>();
+        recent_storages.sort_unstable_by_key(|slot_storages| Reverse(slot_storages[0].slot()));
+        recent_storages.truncate(MAX_SLOT_DELTA_HASHES);
+        recent_storages
+            .into_par_iter()
+            .map(|slot_storages| {
+                (
+                    slot_storages[0].slot(),
+                    AccountsDb::calculate_storages_delta_hash(slot_storages, false),
+                )
+            })
+            .collect()
+    }
+}
+
+impl SnapshotStoragesProvider for Vec<SnapshotStorage> {
+    fn snapshot_storages(&self) -> Vec<(Slot, Vec<SnapshotStorageEntryInfo>)> {
+        self.as_slice().snapshot_storages()
+    }
+
+    fn slot_delta_hashes(&self) -> Vec<(Slot, Hash)> {
+        self.as_slice().slot_delta_hashes()
+    }
+}
+
 struct SerializableBankAndStorage<'a, C> {
     bank: &'a Bank,
-    snapshot_storages: &'a [SnapshotStorage],
+    snapshot_storages: &'a [(Slot, Vec<SnapshotStorageEntryInfo>)],
     phantom: std::marker::PhantomData<C>,
 }
 
@@ -306,7 +750,7 @@ impl<'a, C: TypeContext<'a>> Serialize for SerializableBankAndStorage<'a, C> {
 struct SerializableAccountsDb<'a, C> {
     accounts_db: &'a AccountsDb,
     slot: Slot,
-    account_storage_entries: &'a [SnapshotStorage],
+    account_storage_entries: &'a [(Slot, Vec<SnapshotStorageEntryInfo>)],
     phantom: std::marker::PhantomData<C>,
 }
 
@@ -322,6 +766,12 @@ impl<'a, C: TypeContext<'a>> Serialize for SerializableAccountsDb<'a, C> {
 #[cfg(RUSTC_WITH_SPECIALIZATION)]
 impl<'a, C> IgnoreAsHelper for SerializableAccountsDb<'a, C> {}
 
+struct SerializableAccountStorages<'a, C> {
+    accounts_db: &'a AccountsDb,
+    account_storage_entries: &'a [(Slot, Vec<SnapshotStorageEntryInfo>)],
+    phantom: std::marker::PhantomData<C>,
+}
+
 #[allow(clippy::too_many_arguments)]
 fn reconstruct_bank_from_fields<E>(
     bank_fields: BankFieldsToDeserialize,
@@ -339,11 +789,11 @@ fn reconstruct_bank_from_fields<E>(
     verify_index: bool,
     accounts_db_config: Option<AccountsDbConfig>,
     accounts_update_notifier: Option<AccountsUpdateNotifier>,
-) -> Result<Bank, Error>
+) -> Result<(Bank, ReconstructAccountsDbStats), SnapshotError>
 where
     E: SerializableStorage + std::marker::Sync,
 {
-    let mut accounts_db = reconstruct_accountsdb_from_fields(
+    let (mut accounts_db, reconstruct_accounts_db_stats) = reconstruct_accountsdb_from_fields(
         snapshot_accounts_db_fields,
         account_paths,
         unpacked_append_vec_map,
@@ -376,7 +826,48 @@ where
 
     info!("rent_collector: {:?}", bank.rent_collector());
 
-    Ok(bank)
+    Ok((bank, reconstruct_accounts_db_stats))
+}
+
+/// Errors that are worth retrying, since the operation may well succeed if tried again, e.g. on
+/// network filesystems or busy disks
+fn is_transient_io_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// Run `io_op` until it either succeeds, fails with an error that is not transient, or has been
+/// retried `MAX_REMAP_IO_RETRIES` times, backing off between retries.  Every retry is counted in
+/// `num_retries`.
+fn retry_transient_io<T>(
+    num_retries: &AtomicUsize,
+    mut io_op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut backoff = REMAP_IO_INITIAL_BACKOFF;
+    for _ in 0..MAX_REMAP_IO_RETRIES {
+        match io_op() {
+            Err(err) if is_transient_io_error(&err) => {
+                warn!("transient IO error: {}, retrying in {:?}", err, backoff);
+                num_retries.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    io_op()
+}
+
+/// Is there nothing at `path`?  Failing to tell is an error, rather than taken to mean that
+/// something is there, which would have the remap loop try new paths forever.
+fn is_path_vacant(path: &Path) -> io::Result<bool> {
+    match std::fs::symlink_metadata(path) {
+        Ok(_) => Ok(false),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(err),
+    }
 }
 
 fn reconstruct_single_storage<E>(
@@ -384,14 +875,40 @@ fn reconstruct_single_storage<E>(
     append_vec_path: &Path,
     storage_entry: &E,
     remapped_append_vec_id: Option<AppendVecId>,
+    validation: AppendVecValidation,
+    max_accounts: usize,
+    storage_encryption_key: Option<&Arc<MasterKey>>,
     new_slot_storage: &mut HashMap<AppendVecId, Arc<AccountStorageEntry>>,
-) -> Result<(), Error>
+    num_io_retries: &AtomicUsize,
+) -> Result<(), SnapshotError>
 where
     E: SerializableStorage,
 {
     let append_vec_id = remapped_append_vec_id.unwrap_or_else(|| storage_entry.id());
-    let (accounts, num_accounts) =
-        AppendVec::new_from_file(append_vec_path, storage_entry.current_len())?;
+    let (accounts, num_accounts) = retry_transient_io(num_io_retries, || {
+        AppendVec::new_from_file_with_encryption(
+            append_vec_path,
+            storage_entry.current_len(),
+            validation,
+            storage_encryption_key.cloned(),
+        )
+    })?;
+    // Validation short of `Full` does not count every account, if it counts them at all, so they
+    // are counted here whenever the storage has room for more than the limit
+    let num_accounts = if validation != AppendVecValidation::Full
+        && storage_entry.current_len() / MIN_STORED_ACCOUNT_SIZE > max_accounts
+    {
+        accounts.count_accounts()
+    } else {
+        num_accounts
+    };
+    if num_accounts > max_accounts {
+        return Err(SnapshotError::TooManyAccountsInStorage(
+            append_vec_path.to_path_buf(),
+            num_accounts,
+            max_accounts,
+        ));
+    }
     let u_storage_entry =
         AccountStorageEntry::new_existing(*slot, append_vec_id, accounts, num_accounts);
 
@@ -412,10 +929,29 @@ fn reconstruct_accountsdb_from_fields<E>(
     verify_index: bool,
     accounts_db_config: Option<AccountsDbConfig>,
     accounts_update_notifier: Option<AccountsUpdateNotifier>,
-) -> Result<AccountsDb, Error>
+) -> Result<(AccountsDb, ReconstructAccountsDbStats), SnapshotError>
 where
     E: SerializableStorage + std::marker::Sync,
 {
+    let storage_validation = accounts_db_config
+        .as_ref()
+        .map(|config| config.storage_validation)
+        .unwrap_or_default();
+    let max_accounts_per_storage = accounts_db_config
+        .as_ref()
+        .map(|config| config.snapshot_storage_limits.max_accounts_per_storage)
+        .unwrap_or(SnapshotStorageLimits::DEFAULT.max_accounts_per_storage);
+    let notify_mode = accounts_update_notifier.as_ref().map(|_| {
+        accounts_db_config
+            .as_ref()
+            .map(|config| config.snapshot_restore_notify_mode)
+            .unwrap_or_default()
+    });
+    // Ensure all account paths exist, before the AccountsDb would panic on creating them itself
+    for path in account_paths {
+        std::fs::create_dir_all(path)
+            .map_err(|err| SnapshotError::CreateAccountPath(path.clone(), err))?;
+    }
     let mut accounts_db = AccountsDb::new_with_config(
         account_paths.to_vec(),
         &genesis_config.cluster_type,
@@ -426,6 +962,8 @@ where
         accounts_update_notifier,
     );
 
+    let storage_encryption_key = accounts_db.storage_encryption_key.clone();
+
     let AccountsDbFields(
         snapshot_storages,
         snapshot_version,
@@ -435,14 +973,9 @@ where
 
     let snapshot_storages = snapshot_storages.into_iter().collect::<Vec<_>>();
 
-    // Ensure all account paths exist
-    for path in &accounts_db.paths {
-        std::fs::create_dir_all(path)
-            .unwrap_or_else(|err| panic!("Failed to create directory {}: {}", path.display(), err));
-    }
-
     // Remap the deserialized AppendVec paths to point to correct local paths
     let num_collisions = AtomicUsize::new(0);
+    let num_io_retries = AtomicUsize::new(0);
     let next_append_vec_id = AtomicUsize::new(0);
     let mut measure_remap = Measure::start("remap");
     let mut storage = (0..snapshot_storages.len())
@@ -466,7 +999,7 @@ where
                     let remapped_append_vec_id = next_append_vec_id.fetch_add(1, Ordering::Relaxed);
                     let remapped_file_name = AppendVec::file_name(*slot, remapped_append_vec_id);
                     let remapped_append_vec_path =
-                        append_vec_path.parent().unwrap().join(&remapped_file_name);
+                        append_vec_path.with_file_name(&remapped_file_name);
 
                     // Break out of the loop in the following situations:
                     // 1. The new ID is the same as the original ID.  This means we do not need to
@@ -476,7 +1009,9 @@ where
                     //    **DEVELOPER NOTE:**  Keep this check last so that it can short-circuit if
                     //    possible.
                     if storage_entry.id() == remapped_append_vec_id
-                        || std::fs::metadata(&remapped_append_vec_path).is_err()
+                        || retry_transient_io(&num_io_retries, || {
+                            is_path_vacant(&remapped_append_vec_path)
+                        })?
                     {
                         break (remapped_append_vec_id, remapped_append_vec_path);
                     }
@@ -487,7 +1022,27 @@ where
                 };
                 // Only rename the file if the new ID is actually different from the original.
                 if storage_entry.id() != remapped_append_vec_id {
-                    std::fs::rename(append_vec_path, &remapped_append_vec_path)?;
+                    retry_transient_io(&num_io_retries, || {
+                        atomic_replace_file(append_vec_path, &remapped_append_vec_path)
+                    })?;
+                    // An encrypted storage is bound to its id, so only its header is rewritten to
+                    // match the new one; a plain one is bound to it when it is sealed on opening
+                    if let Some(storage_encryption_key) = &storage_encryption_key {
+                        if storage_encryption::is_encrypted_storage(&remapped_append_vec_path)? {
+                            storage_encryption::rebind_encrypted_storage(
+                                &remapped_append_vec_path,
+                                storage_encryption_key,
+                                StorageBinding {
+                                    slot: *slot,
+                                    id: storage_entry.id() as u64,
+                                },
+                                StorageBinding {
+                                    slot: *slot,
+                                    id: remapped_append_vec_id as u64,
+                                },
+                            )?;
+                        }
+                    }
                 }
 
                 reconstruct_single_storage(
@@ -495,30 +1050,31 @@ where
                     &remapped_append_vec_path,
                     storage_entry,
                     Some(remapped_append_vec_id),
+                    storage_validation,
+                    max_accounts_per_storage,
+                    storage_encryption_key.as_ref(),
                     &mut new_slot_storage,
+                    &num_io_retries,
                 )?;
             }
             Ok((*slot, new_slot_storage))
         })
-        .collect::<Result<HashMap<Slot, _>, Error>>()?;
+        .collect::<Result<HashMap<Slot, _>, SnapshotError>>()?;
     measure_remap.stop();
 
     // discard any slots with no storage entries
     // this can happen if a non-root slot was serialized
     // but non-root stores should not be included in the snapshot
     storage.retain(|_slot, stores| !stores.is_empty());
-    assert!(
-        !storage.is_empty(),
-        "At least one storage entry must exist from deserializing stream"
-    );
+    if storage.is_empty() {
+        return Err(SnapshotError::NoStorageEntries);
+    }
 
     let next_append_vec_id = next_append_vec_id.load(Ordering::Relaxed);
     let max_append_vec_id = next_append_vec_id - 1;
-    assert!(
-        max_append_vec_id <= AppendVecId::MAX / 2,
-        "Storage id {} larger than allowed max",
-        max_append_vec_id
-    );
+    if max_append_vec_id > AppendVecId::MAX / 2 {
+        return Err(SnapshotError::StorageIdTooLarge(max_append_vec_id));
+    }
 
     // Process deserialized data, set necessary fields in self
     accounts_db
@@ -541,34 +1097,68 @@ where
     let mut measure_notify = Measure::start("accounts_notify");
 
     let accounts_db = Arc::new(accounts_db);
-    let accoounts_db_clone = accounts_db.clone();
-    let handle = Builder::new()
-        .name("notify_account_restore_from_snapshot".to_string())
-        .spawn(move || {
-            accoounts_db_clone.notify_account_restore_from_snapshot();
-        })
-        .unwrap();
+    let handle = match notify_mode {
+        Some(SnapshotRestoreNotifyMode::Inline) | None => None,
+        Some(notify_mode) => {
+            let thread_pool = match notify_mode {
+                SnapshotRestoreNotifyMode::Pool(num_threads) => Some(
+                    rayon::ThreadPoolBuilder::new()
+                        .num_threads(num_threads.max(1))
+                        .thread_name(|i| format!("solana-notify-restore-{}", i))
+                        .build()
+                        .map_err(SnapshotError::RestoreNotifierThreadPool)?,
+                ),
+                _ => None,
+            };
+            let accounts_db = accounts_db.clone();
+            Some(
+                Builder::new()
+                    .name("notify_account_restore_from_snapshot".to_string())
+                    .spawn(move || match thread_pool {
+                        Some(thread_pool) => {
+                            accounts_db.notify_account_restore_from_snapshot_in_pool(&thread_pool)
+                        }
+                        None => accounts_db.notify_account_restore_from_snapshot(),
+                    })
+                    .map_err(|e| SnapshotError::IoWithSource(e, "spawn restore notifier thread"))?,
+            )
+        }
+    };
 
     accounts_db.generate_index(
         limit_load_slot_count_from_snapshot,
         verify_index,
         genesis_config,
     );
-    accounts_db.maybe_add_filler_accounts(&genesis_config.epoch_schedule);
+    if !accounts_db.lazy_filler_accounts {
+        accounts_db.maybe_add_filler_accounts(&genesis_config.epoch_schedule);
+    }
 
-    handle.join().unwrap();
+    if let Some(handle) = handle {
+        handle
+            .join()
+            .map_err(|_| SnapshotError::RestoreNotifierPanicked)?;
+    } else if notify_mode == Some(SnapshotRestoreNotifyMode::Inline) {
+        accounts_db.notify_account_restore_from_snapshot();
+    }
     measure_notify.stop();
 
+    let stats = ReconstructAccountsDbStats {
+        remap_us: measure_remap.as_us(),
+        remap_collisions: num_collisions.load(Ordering::Relaxed),
+        remap_io_retries: num_io_retries.load(Ordering::Relaxed),
+        notify_us: measure_notify.as_us(),
+        rent_paying_accounts_report: accounts_db.rent_paying_accounts_report(),
+    };
     datapoint_info!(
         "reconstruct_accountsdb_from_fields()",
-        ("remap-time-us", measure_remap.as_us(), i64),
-        (
-            "remap-collisions",
-            num_collisions.load(Ordering::Relaxed),
-            i64
-        ),
-        ("accountsdb-notify-at-start-us", measure_notify.as_us(), i64),
+        ("remap-time-us", stats.remap_us, i64),
+        ("remap-collisions", stats.remap_collisions, i64),
+        ("remap-io-retries", stats.remap_io_retries, i64),
+        ("accountsdb-notify-at-start-us", stats.notify_us, i64),
     );
 
-    Ok(Arc::try_unwrap(accounts_db).unwrap())
+    let accounts_db =
+        Arc::try_unwrap(accounts_db).map_err(|_| SnapshotError::AccountsDbStillShared)?;
+    Ok((accounts_db, stats))
 }
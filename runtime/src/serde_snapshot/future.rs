@@ -1,7 +1,15 @@
 use super::common::UnusedAccounts;
 #[cfg(all(test, RUSTC_WITH_SPECIALIZATION))]
 use solana_frozen_abi::abi_example::IgnoreAsHelper;
-use {super::*, solana_measure::measure::Measure, std::cell::RefCell};
+use {
+    super::{
+        sharded_storages::{deserialize_sharded_storages, serialize_sharded_storages},
+        storage_limits::deserialize_storages_from,
+        *,
+    },
+    solana_measure::measure::Measure,
+    std::{borrow::Cow, cell::RefCell},
+};
 
 use crate::ancestors::AncestorsForSerialization;
 
@@ -31,11 +39,11 @@ impl SerializableStorage for SerializableAccountStorageEntry {
 #[cfg(all(test, RUSTC_WITH_SPECIALIZATION))]
 impl solana_frozen_abi::abi_example::IgnoreAsHelper for SerializableAccountStorageEntry {}
 
-impl From<&AccountStorageEntry> for SerializableAccountStorageEntry {
-    fn from(rhs: &AccountStorageEntry) -> Self {
+impl From<SnapshotStorageEntryInfo> for SerializableAccountStorageEntry {
+    fn from(rhs: SnapshotStorageEntryInfo) -> Self {
         Self {
-            id: rhs.append_vec_id(),
-            accounts_current_len: rhs.accounts.len(),
+            id: rhs.id,
+            accounts_current_len: rhs.current_len,
         }
     }
 }
@@ -245,18 +253,21 @@ impl<'a> TypeContext<'a> for Context {
             .load(Ordering::Acquire);
 
         // (1st of 3 elements) write the list of account storage entry lists out as a map
+        let account_storage_entries = ordered_account_storage_entries(
+            serializable_db.accounts_db,
+            serializable_db.account_storage_entries,
+        );
         let entry_count = RefCell::<usize>::new(0);
-        let entries =
-            serialize_iter_as_map(serializable_db.account_storage_entries.iter().map(|x| {
-                *entry_count.borrow_mut() += x.len();
-                (
-                    x.first().unwrap().slot(),
-                    serialize_iter_as_seq(
-                        x.iter()
-                            .map(|x| Self::SerializableAccountStorageEntry::from(x.as_ref())),
-                    ),
-                )
-            }));
+        let entries = serialize_iter_as_map(account_storage_entries.iter().map(|x| {
+            *entry_count.borrow_mut() += x.1.len();
+            (
+                x.0,
+                serialize_iter_as_seq(
+                    x.1.iter()
+                        .map(|x| Self::SerializableAccountStorageEntry::from(*x)),
+                ),
+            )
+        }));
         let slot = serializable_db.slot;
         let hash = serializable_db
             .accounts_db
@@ -278,23 +289,119 @@ impl<'a> TypeContext<'a> for Context {
         result
     }
 
+    fn serialize_account_storage_entries<W: Write>(
+        stream: &mut W,
+        serializable_storages: &SerializableAccountStorages<'a, Self>,
+    ) -> Result<(), Error>
+    where
+        Self: std::marker::Sized,
+    {
+        // the shards cover slot ranges, so the storages always have to be in slot order here
+        let mut account_storage_entries = ordered_account_storage_entries(
+            serializable_storages.accounts_db,
+            serializable_storages.account_storage_entries,
+        )
+        .into_owned();
+        account_storage_entries.sort_unstable_by_key(|(slot, _)| *slot);
+
+        let mut serialize_account_storage_timer = Measure::start("serialize_account_storage_ms");
+        let result = serialize_sharded_storages::<_, Self::SerializableAccountStorageEntry>(
+            stream,
+            &account_storage_entries,
+        );
+        serialize_account_storage_timer.stop();
+        datapoint_info!(
+            "serialize_account_storage_ms",
+            ("duration", serialize_account_storage_timer.as_ms(), i64),
+            (
+                "num_entries",
+                account_storage_entries
+                    .iter()
+                    .map(|x| x.1.len())
+                    .sum::<usize>(),
+                i64
+            ),
+        );
+        result
+    }
+
     fn deserialize_bank_fields<R>(
         mut stream: &mut BufReader<R>,
+        storage_tally: &StorageTally,
     ) -> Result<(BankFieldsToDeserialize, AccountsDbFields), Error>
     where
         R: Read,
     {
         let bank_fields = deserialize_from::<_, DeserializableVersionedBank>(&mut stream)?.into();
-        let accounts_db_fields = Self::deserialize_accounts_db_fields(stream)?;
+        let accounts_db_fields = Self::deserialize_accounts_db_fields(stream, storage_tally)?;
         Ok((bank_fields, accounts_db_fields))
     }
 
     fn deserialize_accounts_db_fields<R>(
-        stream: &mut BufReader<R>,
+        mut stream: &mut BufReader<R>,
+        storage_tally: &StorageTally,
     ) -> Result<AccountsDbFields, Error>
     where
         R: Read,
     {
-        deserialize_from(stream)
+        // field by field, the same as the tuple struct would be, so that the storages map can be
+        // tallied as it is read
+        let storages = deserialize_storages_from(&mut stream, storage_tally)?;
+        let write_version = deserialize_from(&mut stream)?;
+        let slot = deserialize_from(&mut stream)?;
+        let bank_hash_info = deserialize_from(&mut stream)?;
+        Ok(super::AccountsDbFields(
+            storages,
+            write_version,
+            slot,
+            bank_hash_info,
+        ))
+    }
+
+    fn deserialize_account_storage_entries<R>(
+        stream: &mut BufReader<R>,
+        storage_tally: &StorageTally,
+    ) -> Result<HashMap<Slot, Vec<SerializableAccountStorageEntry>>, Error>
+    where
+        R: Read,
+    {
+        let mut deserialize_account_storage_timer =
+            Measure::start("deserialize_account_storage_ms");
+        let account_storage_entries = deserialize_sharded_storages(stream, storage_tally)?;
+        deserialize_account_storage_timer.stop();
+        datapoint_info!(
+            "deserialize_account_storage_ms",
+            ("duration", deserialize_account_storage_timer.as_ms(), i64),
+            ("num_slots", account_storage_entries.len(), i64),
+        );
+        Ok(account_storage_entries)
+    }
+}
+
+/// The storages to serialize, put in slot and then id order first, and renumbered with their
+/// canonical ids, if the AccountsDb was asked for deterministic snapshot serialization, since
+/// they usually come out of hash maps
+fn ordered_account_storage_entries<'b>(
+    accounts_db: &AccountsDb,
+    account_storage_entries: &'b [(Slot, Vec<SnapshotStorageEntryInfo>)],
+) -> Cow<'b, [(Slot, Vec<SnapshotStorageEntryInfo>)]> {
+    if accounts_db.deterministic_snapshot_serialization {
+        let canonical_ids =
+            canonical_storage_ids(account_storage_entries.iter().flat_map(
+                |(slot, slot_storages)| slot_storages.iter().map(move |x| (*slot, x.id)),
+            ));
+        let mut account_storage_entries = account_storage_entries.to_vec();
+        account_storage_entries.sort_unstable_by_key(|(slot, _)| *slot);
+        account_storage_entries
+            .iter_mut()
+            .for_each(|(slot, slot_storages)| {
+                slot_storages.sort_unstable_by_key(|x| x.id);
+                slot_storages
+                    .iter_mut()
+                    .for_each(|x| x.id = canonical_ids[&(*slot, x.id)]);
+            });
+        Cow::Owned(account_storage_entries)
+    } else {
+        Cow::Borrowed(account_storage_entries)
     }
 }
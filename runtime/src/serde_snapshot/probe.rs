@@ -0,0 +1,148 @@
+//! Telling what a snapshot archive holds from just the start of its stream
+//!
+//! From `SnapshotVersion::V1_3_0` on, archives start with their `version` file, followed by the
+//! id of the storage master key if the storages are encrypted, and only then by the bank
+//! snapshots and the storages.  So the first few tar blocks of the (uncompressed) stream are
+//! enough to tell whether the rest is worth fetching.  Older archives put the `version` file
+//! after all of the storages, unless their storages are encrypted, and cannot be told apart
+//! without unpacking them.
+use {
+    crate::{snapshot_utils::SnapshotVersion, storage_encryption::STORAGE_MASTER_KEY_ID_FILE_NAME},
+    std::{
+        convert::TryFrom,
+        ops::Range,
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
+};
+
+/// The size of a tar block, which headers take up a whole one of and entries are padded to
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// Where the checksum is in a tar header, and how long it is
+const TAR_CHECKSUM_RANGE: Range<usize> = 148..156;
+
+/// Larger than any version file that was ever written
+const MAX_VERSION_FILE_SIZE: usize = 64;
+
+/// `probe()` has decided by the time it is given this many bytes: the `version` file's header
+/// and its data, and the header of the entry after it
+pub const MAX_PROBE_BYTES: usize = 3 * TAR_BLOCK_SIZE;
+
+/// What the start of a snapshot archive's stream says about the archive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotCompatibility {
+    /// It takes more of the stream to tell
+    Incomplete,
+    /// The archive does not start with its version, so the whole of it is needed to tell
+    Undetermined,
+    /// The stream is not that of a snapshot archive
+    NotASnapshot,
+    /// The archive is of a version this binary can read
+    Compatible {
+        version: SnapshotVersion,
+        sections: SnapshotSections,
+    },
+    /// The archive is of a version this binary does not know how to read
+    Incompatible { version: String },
+}
+
+/// The optional sections found in a snapshot archive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotSections {
+    /// The storages listing is in a file of its own, apart from the bank manifest
+    pub separate_storages_file: bool,
+    /// The storages are encrypted, and the id of their master key is in the archive
+    pub encrypted_storages: bool,
+}
+
+/// Probe the first bytes of a snapshot archive's uncompressed tar stream for its version, and
+/// which of the optional sections it has.  `header_bytes` need not end on an entry boundary;
+/// `SnapshotCompatibility::Incomplete` is returned while they are too few to tell.
+///
+/// A version string this binary does not know is only reported as incompatible when the archive
+/// starts with it, so archives of future versions must keep the `version` file first.
+pub fn probe(header_bytes: &[u8]) -> SnapshotCompatibility {
+    let mut offset = 0;
+    let mut version = None;
+    loop {
+        let block = match header_bytes.get(offset..offset + TAR_BLOCK_SIZE) {
+            Some(block) => block,
+            None => return SnapshotCompatibility::Incomplete,
+        };
+        if block.iter().all(|byte| *byte == 0) {
+            // the end of the archive, which is only a snapshot archive if its version was in it
+            return match version {
+                Some(version) => compatible(version, false),
+                None => SnapshotCompatibility::NotASnapshot,
+            };
+        }
+        let (path, size) = match parse_tar_header(block) {
+            Some(header) => header,
+            None => return SnapshotCompatibility::NotASnapshot,
+        };
+        let data_start = offset + TAR_BLOCK_SIZE;
+
+        if let Some(version) = version {
+            // whatever follows the version file says whether the storages are encrypted
+            return compatible(version, path == Path::new(STORAGE_MASTER_KEY_ID_FILE_NAME));
+        }
+        if path == Path::new("version") {
+            if size > MAX_VERSION_FILE_SIZE {
+                return SnapshotCompatibility::NotASnapshot;
+            }
+            let data = match header_bytes.get(data_start..data_start + size) {
+                Some(data) => data,
+                None => return SnapshotCompatibility::Incomplete,
+            };
+            let version_string = String::from_utf8_lossy(data).trim().to_string();
+            match SnapshotVersion::from_str(&version_string) {
+                Ok(snapshot_version) => version = Some(snapshot_version),
+                Err(_) => {
+                    return SnapshotCompatibility::Incompatible {
+                        version: version_string,
+                    }
+                }
+            }
+        } else if path.starts_with("accounts") || path.starts_with("snapshots") {
+            return SnapshotCompatibility::Undetermined;
+        } else {
+            return SnapshotCompatibility::NotASnapshot;
+        }
+
+        offset = data_start + size + (TAR_BLOCK_SIZE - size % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+    }
+}
+
+fn compatible(version: SnapshotVersion, encrypted_storages: bool) -> SnapshotCompatibility {
+    SnapshotCompatibility::Compatible {
+        version,
+        sections: SnapshotSections {
+            separate_storages_file: version.has_separate_storages_file(),
+            encrypted_storages,
+        },
+    }
+}
+
+/// The path and size of the entry with the header in `block`, if it is a sound tar header
+fn parse_tar_header(block: &[u8]) -> Option<(PathBuf, usize)> {
+    let header = tar::Header::from_byte_slice(block);
+    // The checksum is taken over the header with its own field as spaces
+    let checksum = block
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| {
+            if TAR_CHECKSUM_RANGE.contains(&i) {
+                u32::from(b' ')
+            } else {
+                u32::from(*byte)
+            }
+        })
+        .sum::<u32>();
+    if header.cksum().ok()? != checksum {
+        return None;
+    }
+    let path = header.path().ok()?.into_owned();
+    let size = usize::try_from(header.entry_size().ok()?).ok()?;
+    Some((path, size))
+}
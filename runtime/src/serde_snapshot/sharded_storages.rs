@@ -0,0 +1,146 @@
+//! The sharded encoding of a storages listing that is kept in a file of its own
+//!
+//! The storages are split by slot into shards of at most `MAX_SLOTS_PER_SHARD` slots.  The file
+//! is the number of shards, then each shard as a header with its (inclusive) slot range and
+//! length, followed by that many bytes of the shard's storages map.  The length prefixes let the
+//! shards be cut out of the stream without parsing them, so they can then be deserialized on
+//! multiple threads and merged.  The shards must be in ascending slot order without overlapping,
+//! so merging them never has to resolve a slot that is in more than one shard.
+use {
+    super::{
+        deserialize_from,
+        future::SerializableStorage,
+        storage_limits::{deserialize_storages_from, StorageTally},
+        utils::{serialize_iter_as_map, serialize_iter_as_seq},
+        SnapshotStorageEntryInfo,
+    },
+    bincode::Error,
+    rayon::prelude::*,
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    solana_sdk::clock::Slot,
+    std::{
+        collections::HashMap,
+        io::{self, Read, Write},
+    },
+};
+
+/// The most slots that are put in one shard
+pub(super) const MAX_SLOTS_PER_SHARD: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+struct ShardHeader {
+    first_slot: Slot,
+    last_slot: Slot,
+    len: u64,
+}
+
+fn invalid_data(error: String) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, error).into()
+}
+
+/// Write out `account_storage_entries`, which must be sorted by slot, as shards of storage
+/// entries of type `T`
+pub(super) fn serialize_sharded_storages<W, T>(
+    stream: &mut W,
+    account_storage_entries: &[(Slot, Vec<SnapshotStorageEntryInfo>)],
+) -> Result<(), Error>
+where
+    W: Write,
+    T: Serialize + From<SnapshotStorageEntryInfo>,
+{
+    debug_assert!(account_storage_entries
+        .windows(2)
+        .all(|pair| pair[0].0 < pair[1].0));
+    let shards = account_storage_entries
+        .par_chunks(MAX_SLOTS_PER_SHARD)
+        .map(|shard| {
+            // serialized in one pass, since the map can only be iterated over once
+            let mut bytes = vec![];
+            bincode::serialize_into(
+                &mut bytes,
+                &serialize_iter_as_map(shard.iter().map(|(slot, slot_storages)| {
+                    (
+                        *slot,
+                        serialize_iter_as_seq(slot_storages.iter().map(|x| T::from(*x))),
+                    )
+                })),
+            )?;
+            let header = ShardHeader {
+                first_slot: shard.first().unwrap().0,
+                last_slot: shard.last().unwrap().0,
+                len: bytes.len() as u64,
+            };
+            Ok((header, bytes))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    bincode::serialize_into(&mut *stream, &(shards.len() as u64))?;
+    for (header, bytes) in shards {
+        bincode::serialize_into(&mut *stream, &header)?;
+        stream.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+/// Read back the shards written by `serialize_sharded_storages()`, deserializing them in parallel
+/// and tallying their storages in `storage_tally`
+pub(super) fn deserialize_sharded_storages<R, T>(
+    stream: &mut R,
+    storage_tally: &StorageTally,
+) -> Result<HashMap<Slot, Vec<T>>, Error>
+where
+    R: Read,
+    T: DeserializeOwned + SerializableStorage + Send,
+{
+    // Only cut the shards out of the stream here; the shard count and lengths are not trusted
+    // for allocations up front, since the file may be corrupt
+    let shard_count: u64 = deserialize_from(&mut *stream)?;
+    let mut shards = vec![];
+    let mut previous_last_slot = None;
+    for _ in 0..shard_count {
+        let header: ShardHeader = deserialize_from(&mut *stream)?;
+        if header.first_slot > header.last_slot
+            || previous_last_slot.map_or(false, |last_slot| header.first_slot <= last_slot)
+        {
+            return Err(invalid_data(format!(
+                "storages shard for slots {}..={} is out of order",
+                header.first_slot, header.last_slot
+            )));
+        }
+        previous_last_slot = Some(header.last_slot);
+
+        let mut bytes = vec![];
+        stream.by_ref().take(header.len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != header.len {
+            return Err(invalid_data(format!(
+                "storages shard for slots {}..={} is truncated",
+                header.first_slot, header.last_slot
+            )));
+        }
+        shards.push((header, bytes));
+    }
+
+    let shards = shards
+        .into_par_iter()
+        .map(|(header, bytes)| {
+            let shard: HashMap<Slot, Vec<T>> =
+                deserialize_storages_from(bytes.as_slice(), storage_tally)?;
+            if shard
+                .keys()
+                .any(|slot| *slot < header.first_slot || *slot > header.last_slot)
+            {
+                return Err(invalid_data(format!(
+                    "storages shard for slots {}..={} has storages outside of its slots",
+                    header.first_slot, header.last_slot
+                )));
+            }
+            Ok(shard)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut account_storage_entries = HashMap::with_capacity(shards.iter().map(HashMap::len).sum());
+    shards
+        .into_iter()
+        .for_each(|shard| account_storage_entries.extend(shard));
+    Ok(account_storage_entries)
+}
@@ -0,0 +1,198 @@
+use {
+    super::{
+        deserialize_snapshot_streams,
+        future::{SerializableAccountStorageEntry, SerializableStorage},
+        AccountsDbFields, SerdeStyle, SnapshotStorageLimits, SnapshotStreams, StorageTally,
+        TypeContextFuture,
+    },
+    crate::{
+        accounts_db::{AppendVecId, BankHashInfo},
+        append_vec::{AppendVec, AppendVecValidation, StoredAccount},
+        hardened_unpack::UnpackedAppendVecMap,
+        snapshot_utils::{SnapshotError, SnapshotVersion},
+        storage_encryption::{self, MasterKey},
+    },
+    solana_sdk::{clock::Slot, hash::Hash},
+    std::{
+        io::{self, Read},
+        path::PathBuf,
+        sync::Arc,
+    },
+};
+
+/// AccountsDb fields deserialized from the bank snapshot file of a full snapshot, and optionally
+/// an incremental snapshot, without reconstructing an AccountsDb from them
+#[derive(Debug)]
+pub struct DeserializedAccountsDbFields {
+    pub(super) accounts_db_fields: AccountsDbFields<SerializableAccountStorageEntry>,
+    slot_delta_hashes: Vec<(Slot, Hash)>,
+    capitalization: u64,
+}
+
+impl DeserializedAccountsDbFields {
+    /// Deserialize the AccountsDb fields from the bank snapshot stream(s), skipping over the bank
+    /// fields but for the capitalization.  If there is an incremental snapshot stream, its fields
+    /// are collapsed on top of the full snapshot's, the same way they are when rebuilding a bank.
+    /// Storages listings that go over `storage_limits` fail to deserialize.
+    pub fn from_streams<R: Read + Send>(
+        snapshot_version: SnapshotVersion,
+        snapshot_streams: &mut SnapshotStreams<R>,
+        storage_limits: SnapshotStorageLimits,
+    ) -> Result<Self, SnapshotError> {
+        let storage_tally = StorageTally::new(storage_limits);
+        let serde_style = match snapshot_version {
+            SnapshotVersion::V1_2_0 | SnapshotVersion::V1_3_0 => SerdeStyle::Newer,
+        };
+        let (
+            full_snapshot_bank_fields,
+            incremental_snapshot_bank_fields,
+            snapshot_accounts_db_fields,
+            slot_delta_hashes,
+        ) = match serde_style {
+            SerdeStyle::Newer => deserialize_snapshot_streams::<TypeContextFuture, _>(
+                snapshot_streams,
+                &storage_tally,
+            ),
+        }?;
+        let capitalization = incremental_snapshot_bank_fields
+            .unwrap_or(full_snapshot_bank_fields)
+            .capitalization;
+        let accounts_db_fields = snapshot_accounts_db_fields.collapse_into()?;
+
+        Ok(Self {
+            accounts_db_fields,
+            slot_delta_hashes,
+            capitalization,
+        })
+    }
+
+    /// The slot of the snapshot, i.e. the incremental snapshot's slot if there is one
+    pub fn slot(&self) -> Slot {
+        self.accounts_db_fields.2
+    }
+
+    pub fn bank_hash_info(&self) -> &BankHashInfo {
+        &self.accounts_db_fields.3
+    }
+
+    /// The capitalization of the snapshot's bank, i.e. the lamports in all of its accounts
+    pub fn capitalization(&self) -> u64 {
+        self.capitalization
+    }
+
+    /// The accounts delta hashes of the most recent slots, if the snapshot has them
+    pub fn slot_delta_hashes(&self) -> &[(Slot, Hash)] {
+        &self.slot_delta_hashes
+    }
+
+    /// The number of storages (i.e. append vecs) across all the slots in the snapshot
+    pub fn storage_count(&self) -> usize {
+        self.accounts_db_fields.0.values().map(Vec::len).sum()
+    }
+}
+
+/// An iterator over every account stored in a snapshot's storages, yielded as `(slot, account)`
+/// pairs ordered by slot and then by append vec id.  Only one append vec is opened at a time, and
+/// the unpacked files are left in place once the iterator is done with them, even the file of a
+/// storage that fails to sanitize.
+///
+/// Every version of an account that is stored in the snapshot is yielded, not just the latest one.
+#[derive(Debug)]
+pub struct SnapshotStorageAccountsIter {
+    storages: std::vec::IntoIter<(Slot, AppendVecId, PathBuf, usize)>,
+    current_storage: Option<(Slot, AppendVec)>,
+    offset: usize,
+    storage_encryption_key: Option<Arc<MasterKey>>,
+}
+
+impl SnapshotStorageAccountsIter {
+    /// Look up the unpacked file of every storage in `accounts_db_fields`.  Errors if any of them
+    /// are missing from `unpacked_append_vec_map`; the files themselves are only opened as the
+    /// iterator reaches them, and decrypted with `storage_encryption_key` if they are encrypted.
+    pub fn new(
+        accounts_db_fields: &DeserializedAccountsDbFields,
+        unpacked_append_vec_map: &UnpackedAppendVecMap,
+        storage_encryption_key: Option<Arc<MasterKey>>,
+    ) -> io::Result<Self> {
+        let mut storages = accounts_db_fields
+            .accounts_db_fields
+            .0
+            .iter()
+            .flat_map(|(slot, slot_storage)| {
+                slot_storage
+                    .iter()
+                    .map(move |storage_entry| (*slot, storage_entry))
+            })
+            .map(|(slot, storage_entry)| {
+                let file_name = AppendVec::file_name(slot, storage_entry.id());
+                let append_vec_path = unpacked_append_vec_map.get(&file_name).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} not found in unpacked append vecs", file_name),
+                    )
+                })?;
+                Ok((
+                    slot,
+                    storage_entry.id(),
+                    append_vec_path.clone(),
+                    storage_entry.current_len(),
+                ))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        storages.sort_unstable_by_key(|(slot, id, _, _)| (*slot, *id));
+
+        Ok(Self {
+            storages: storages.into_iter(),
+            current_storage: None,
+            offset: 0,
+            storage_encryption_key,
+        })
+    }
+}
+
+impl Iterator for SnapshotStorageAccountsIter {
+    type Item = io::Result<(Slot, StoredAccount)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((slot, append_vec)) = &self.current_storage {
+                if let Some((stored_account, next_offset)) = append_vec.get_account(self.offset) {
+                    self.offset = next_offset;
+                    return Some(Ok((*slot, stored_account.clone_stored_account())));
+                }
+            }
+
+            // Done with the current storage (if any), so unmap it before opening the next one
+            self.current_storage = None;
+            let (slot, _id, append_vec_path, current_len) = self.storages.next()?;
+            // The key is only for decrypting, as a plain file opened with it would be sealed in
+            // place, and the unpacked files belong to the caller
+            let storage_encryption_key =
+                match storage_encryption::is_encrypted_storage(&append_vec_path) {
+                    Ok(true) => self.storage_encryption_key.clone(),
+                    Ok(false) => None,
+                    Err(err) => return Some(Err(err)),
+                };
+            // The unpacked files belong to the caller, so they must not be removed on drop, which
+            // is why the accounts are only checked once the append vec has been told so
+            let append_vec = AppendVec::new_from_file_with_encryption(
+                &append_vec_path,
+                current_len,
+                AppendVecValidation::HeaderOnly,
+                storage_encryption_key,
+            )
+            .and_then(|(mut append_vec, _num_accounts)| {
+                append_vec.set_no_remove_on_drop();
+                append_vec.validate(AppendVecValidation::Full)?;
+                Ok(append_vec)
+            });
+            match append_vec {
+                Ok(append_vec) => {
+                    self.current_storage = Some((slot, append_vec));
+                    self.offset = 0;
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Bounding what the storages listings of a snapshot may declare
+//!
+//! The listings are tallied while they are deserialized, so that a listing that goes over the
+//! limits stops being read right where it does, rather than only once all of it is in memory.
+use {
+    super::{future::SerializableStorage, MAX_STREAM_SIZE},
+    crate::snapshot_utils::SnapshotError,
+    bincode::{config::Options, Error},
+    serde::de::{self, DeserializeOwned, DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    solana_sdk::clock::Slot,
+    std::{
+        collections::HashMap,
+        fmt,
+        io::Read,
+        marker::PhantomData,
+        sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+/// Upper bounds on what the accounts-db fields of a snapshot may declare, so a corrupt or
+/// malicious snapshot fails to load before reconstructing from it exhausts memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotStorageLimits {
+    /// The most storages, over all slots, of a full snapshot and its incremental snapshot
+    pub max_storages: usize,
+    /// The most accounts in one storage
+    pub max_accounts_per_storage: usize,
+    /// The most bytes of accounts, over all storages
+    pub max_storage_bytes: u64,
+}
+
+impl Default for SnapshotStorageLimits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl SnapshotStorageLimits {
+    /// Well beyond what the snapshots of any cluster hold
+    pub const DEFAULT: Self = Self {
+        max_storages: 1 << 24,
+        max_accounts_per_storage: 1 << 27,
+        max_storage_bytes: 1 << 42,
+    };
+}
+
+/// The storages deserialized so far from the listings of a full snapshot and its incremental
+/// snapshot, which may be deserialized on several threads at once
+#[derive(Debug)]
+pub(crate) struct StorageTally {
+    limits: SnapshotStorageLimits,
+    storages: AtomicUsize,
+    storage_bytes: AtomicU64,
+}
+
+impl StorageTally {
+    pub(crate) fn new(limits: SnapshotStorageLimits) -> Self {
+        Self {
+            limits,
+            storages: AtomicUsize::default(),
+            storage_bytes: AtomicU64::default(),
+        }
+    }
+
+    /// Tally one more storage of `len` bytes of accounts
+    fn add(&self, len: usize) -> Result<(), SnapshotError> {
+        self.storages.fetch_add(1, Ordering::Relaxed);
+        self.storage_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.check()
+    }
+
+    /// The `result` of deserializing storages listings into this tally, with a failure that was
+    /// down to the listings going over the limits told apart from them being malformed
+    pub(crate) fn within_limits<T>(&self, result: Result<T, Error>) -> Result<T, SnapshotError> {
+        self.check()?;
+        Ok(result?)
+    }
+
+    fn check(&self) -> Result<(), SnapshotError> {
+        let storages = self.storages.load(Ordering::Relaxed);
+        if storages > self.limits.max_storages {
+            return Err(SnapshotError::TooManyStorages(
+                storages,
+                self.limits.max_storages,
+            ));
+        }
+        let storage_bytes = self.storage_bytes.load(Ordering::Relaxed);
+        if storage_bytes > self.limits.max_storage_bytes {
+            return Err(SnapshotError::TooManyStorageBytes(
+                storage_bytes,
+                self.limits.max_storage_bytes,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Deserialize a storages map, i.e. `HashMap<Slot, Vec<T>>`, tallying its storages in `tally` as
+/// they come
+pub(super) fn deserialize_storages_from<R, T>(
+    reader: R,
+    tally: &StorageTally,
+) -> Result<HashMap<Slot, Vec<T>>, Error>
+where
+    R: Read,
+    T: DeserializeOwned + SerializableStorage,
+{
+    bincode::options()
+        .with_limit(MAX_STREAM_SIZE)
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .deserialize_from_seed(
+            TalliedStorages {
+                tally,
+                _storage: PhantomData,
+            },
+            reader,
+        )
+}
+
+/// Seeds the deserialization of a storages map, and then of each slot's storages within it
+struct TalliedStorages<'a, T> {
+    tally: &'a StorageTally,
+    _storage: PhantomData<T>,
+}
+
+impl<'a, T> Clone for TalliedStorages<'a, T> {
+    fn clone(&self) -> Self {
+        Self {
+            tally: self.tally,
+            _storage: PhantomData,
+        }
+    }
+}
+
+impl<'de, 'a, T> DeserializeSeed<'de> for TalliedStorages<'a, T>
+where
+    T: DeserializeOwned + SerializableStorage,
+{
+    type Value = HashMap<Slot, Vec<T>>;
+
+    fn deserialize<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, T> Visitor<'de> for TalliedStorages<'a, T>
+where
+    T: DeserializeOwned + SerializableStorage,
+{
+    type Value = HashMap<Slot, Vec<T>>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of slots to their storages")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        // the declared length is not trusted for allocating up front
+        let mut storages = HashMap::new();
+        while let Some(slot) = map.next_key::<Slot>()? {
+            let slot_storages = map.next_value_seed(TalliedSlotStorages(self.clone()))?;
+            storages.insert(slot, slot_storages);
+        }
+        Ok(storages)
+    }
+}
+
+struct TalliedSlotStorages<'a, T>(TalliedStorages<'a, T>);
+
+impl<'de, 'a, T> DeserializeSeed<'de> for TalliedSlotStorages<'a, T>
+where
+    T: DeserializeOwned + SerializableStorage,
+{
+    type Value = Vec<T>;
+
+    fn deserialize<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a, T> Visitor<'de> for TalliedSlotStorages<'a, T>
+where
+    T: DeserializeOwned + SerializableStorage,
+{
+    type Value = Vec<T>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("the storages of a slot")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut slot_storages = Vec::new();
+        while let Some(storage) = seq.next_element::<T>()? {
+            self.0
+                .tally
+                .add(storage.current_len())
+                .map_err(de::Error::custom)?;
+            slot_storages.push(storage);
+        }
+        Ok(slot_storages)
+    }
+}
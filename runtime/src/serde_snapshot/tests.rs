@@ -3,17 +3,21 @@ use {
     super::*,
     crate::{
         accounts::{create_test_accounts, Accounts},
-        accounts_db::{get_temp_accounts_paths, AccountShrinkThreshold},
+        accounts_db::{get_temp_accounts_paths, AccountShrinkThreshold, AccountsDbConfig},
+        append_vec::StoredAccount,
         bank::{Bank, StatusCacheRc},
         hardened_unpack::UnpackedAppendVecMap,
     },
+    assert_matches::assert_matches,
     bincode::serialize_into,
     rand::{thread_rng, Rng},
     solana_sdk::{
         account::{AccountSharedData, ReadableAccount},
         clock::Slot,
+        epoch_schedule::EpochSchedule,
         genesis_config::{create_genesis_config, ClusterType},
         pubkey::Pubkey,
+        rent::Rent,
         signature::{Keypair, Signer},
     },
     std::{
@@ -62,13 +66,16 @@ fn context_accountsdb_from_stream<'a, C, R>(
     stream: &mut BufReader<R>,
     account_paths: &[PathBuf],
     unpacked_append_vec_map: UnpackedAppendVecMap,
-) -> Result<AccountsDb, Error>
+) -> Result<AccountsDb, SnapshotError>
 where
     C: TypeContext<'a>,
     R: Read,
 {
     // read and deserialise the accounts database directly from the stream
-    let accounts_db_fields = C::deserialize_accounts_db_fields(stream)?;
+    let accounts_db_fields = C::deserialize_accounts_db_fields(
+        stream,
+        &StorageTally::new(SnapshotStorageLimits::default()),
+    )?;
     let snapshot_accounts_db_fields = SnapshotAccountsDbFields {
         full_snapshot_accounts_db_fields: accounts_db_fields,
         incremental_snapshot_accounts_db_fields: None,
@@ -89,6 +96,7 @@ where
         Some(crate::accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING),
         None,
     )
+    .map(|(accounts_db, _)| accounts_db)
 }
 
 #[cfg(test)]
@@ -97,7 +105,7 @@ fn accountsdb_from_stream<R>(
     stream: &mut BufReader<R>,
     account_paths: &[PathBuf],
     unpacked_append_vec_map: UnpackedAppendVecMap,
-) -> Result<AccountsDb, Error>
+) -> Result<AccountsDb, SnapshotError>
 where
     R: Read,
 {
@@ -127,7 +135,7 @@ where
             &SerializableAccountsDb::<TypeContextFuture> {
                 accounts_db,
                 slot,
-                account_storage_entries,
+                account_storage_entries: &account_storage_entries.snapshot_storages(),
                 phantom: std::marker::PhantomData::default(),
             },
         ),
@@ -183,6 +191,234 @@ fn test_accounts_serialize_style(serde_style: SerdeStyle) {
     assert_eq!(accounts.bank_hash_at(0), daccounts.bank_hash_at(0));
 }
 
+#[test]
+fn test_accounts_db_deterministic_snapshot_serialization() {
+    solana_logger::setup();
+    let mut accounts_db = AccountsDb::new_single_for_tests();
+    for slot in 0..8 {
+        let account = AccountSharedData::new(slot + 1, 0, &Pubkey::default());
+        accounts_db.store_uncached(slot, &[(&Pubkey::new_unique(), &account)]);
+        accounts_db.add_root(slot);
+    }
+    let snapshot_storages = accounts_db.get_snapshot_storages(7, None, None).0;
+    let mut reversed_snapshot_storages = snapshot_storages.clone();
+    reversed_snapshot_storages.reverse();
+
+    let serialize = |accounts_db: &AccountsDb, snapshot_storages: &[SnapshotStorage]| {
+        let mut buf = vec![];
+        accountsdb_to_stream(
+            SerdeStyle::Newer,
+            &mut buf,
+            accounts_db,
+            7,
+            snapshot_storages,
+        )
+        .unwrap();
+        buf
+    };
+
+    // By default the storages are serialized in the order they come in
+    assert_ne!(
+        serialize(&accounts_db, &snapshot_storages),
+        serialize(&accounts_db, &reversed_snapshot_storages)
+    );
+
+    accounts_db.deterministic_snapshot_serialization = true;
+    assert_eq!(
+        serialize(&accounts_db, &snapshot_storages),
+        serialize(&accounts_db, &reversed_snapshot_storages)
+    );
+}
+
+#[test]
+fn test_canonical_serialize_into() {
+    fn canonical_bytes<T: Serialize>(value: &T) -> Vec<u8> {
+        let mut bytes = vec![];
+        canonical_serialize_into(&mut bytes, value).unwrap();
+        bytes
+    }
+
+    // the same encoding as bincode's
+    let value = (
+        (true, -1i8, 2u16, -3i32, u64::MAX, i128::MIN),
+        (1.5f32, -2.5f64, 'é', "four"),
+        (Some(vec![5u32, 6]), None::<u8>, ()),
+        (Ok::<_, String>(7u8), Err::<u8, _>("eight".to_string())),
+    );
+    assert_eq!(canonical_bytes(&value), bincode::serialize(&value).unwrap());
+
+    // but with the entries of maps in key order, whatever order they are iterated over in
+    let map = (0..100)
+        .map(|i| (Pubkey::new_unique(), vec![i; i]))
+        .collect::<HashMap<_, _>>();
+    let rehashed_map = map
+        .iter()
+        .map(|(key, value)| (*key, value.clone()))
+        .collect::<HashMap<_, _>>();
+    let nested_map = |map: &HashMap<Pubkey, Vec<usize>>| {
+        (0..3u64)
+            .map(|i| (i, map.clone()))
+            .collect::<HashMap<_, _>>()
+    };
+    assert_eq!(canonical_bytes(&map), canonical_bytes(&rehashed_map));
+    assert_eq!(
+        canonical_bytes(&nested_map(&map)),
+        canonical_bytes(&nested_map(&rehashed_map))
+    );
+    let deserialized_map: HashMap<Pubkey, Vec<usize>> =
+        bincode::deserialize(&canonical_bytes(&map)).unwrap();
+    assert_eq!(deserialized_map, map);
+}
+
+#[test]
+fn test_probe() {
+    use crate::storage_encryption::STORAGE_MASTER_KEY_ID_FILE_NAME;
+
+    let tar_stream = |entries: &[(&str, &[u8])]| {
+        let mut archive = tar::Builder::new(vec![]);
+        for (path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, path, *data).unwrap();
+        }
+        archive.into_inner().unwrap()
+    };
+    let manifest: &[u8] = &[1; 1000];
+    let compatible = |version, encrypted_storages| SnapshotCompatibility::Compatible {
+        version,
+        sections: SnapshotSections {
+            separate_storages_file: version == SnapshotVersion::V1_3_0,
+            encrypted_storages,
+        },
+    };
+
+    let stream = tar_stream(&[("version", &b"1.3.0"[..]), ("snapshots/1/1", manifest)]);
+    assert_eq!(probe(&stream), compatible(SnapshotVersion::V1_3_0, false));
+    // the header of the entry after the version is enough
+    assert_eq!(
+        probe(&stream[..3 * 512]),
+        compatible(SnapshotVersion::V1_3_0, false)
+    );
+    for len in [0, 100, 512, 1000, 2 * 512, 3 * 512 - 1] {
+        assert_eq!(probe(&stream[..len]), SnapshotCompatibility::Incomplete);
+    }
+
+    let stream = tar_stream(&[
+        ("version", &b"1.2.0\n"[..]),
+        (STORAGE_MASTER_KEY_ID_FILE_NAME, &[2; 32][..]),
+        ("snapshots/1/1", manifest),
+    ]);
+    assert_eq!(probe(&stream), compatible(SnapshotVersion::V1_2_0, true));
+
+    let stream = tar_stream(&[("version", &b"9.9.9"[..]), ("snapshots/1/1", manifest)]);
+    assert_eq!(
+        probe(&stream),
+        SnapshotCompatibility::Incompatible {
+            version: "9.9.9".to_string()
+        }
+    );
+
+    let stream = tar_stream(&[("accounts/1.0", manifest), ("version", &b"1.2.0"[..])]);
+    assert_eq!(probe(&stream), SnapshotCompatibility::Undetermined);
+
+    let stream = tar_stream(&[("genesis.bin", manifest)]);
+    assert_eq!(probe(&stream), SnapshotCompatibility::NotASnapshot);
+    let mut stream = tar_stream(&[("version", &b"1.3.0"[..])]);
+    assert_eq!(probe(&stream), compatible(SnapshotVersion::V1_3_0, false));
+    stream[0] ^= 0xff;
+    assert_eq!(probe(&stream), SnapshotCompatibility::NotASnapshot);
+    assert_eq!(probe(&[0; 1024]), SnapshotCompatibility::NotASnapshot);
+}
+
+#[test]
+fn test_sharded_storages_roundtrip() {
+    use {
+        super::{future::SerializableAccountStorageEntry, sharded_storages::*},
+        bincode::Options,
+    };
+
+    // Enough slots, with gaps, for several shards
+    let account_storage_entries: Vec<_> = (0..(MAX_SLOTS_PER_SHARD as Slot * 5 / 2))
+        .map(|i| {
+            let slot_storages = (0..(i % 3) + 1)
+                .map(|id| SnapshotStorageEntryInfo {
+                    id: (i * 3 + id) as AppendVecId,
+                    current_len: thread_rng().gen_range(0, 1_000_000),
+                })
+                .collect::<Vec<_>>();
+            (i * 2, slot_storages)
+        })
+        .collect();
+    let expected: HashMap<_, _> = account_storage_entries
+        .iter()
+        .map(|(slot, slot_storages)| {
+            (
+                *slot,
+                slot_storages
+                    .iter()
+                    .map(|x| SerializableAccountStorageEntry::from(*x))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .collect();
+
+    let mut buf = vec![];
+    serialize_sharded_storages::<_, SerializableAccountStorageEntry>(
+        &mut buf,
+        &account_storage_entries,
+    )
+    .unwrap();
+    let shard_count: u64 = bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .deserialize(&buf)
+        .unwrap();
+    assert_eq!(shard_count, 3);
+    let storage_tally = || StorageTally::new(SnapshotStorageLimits::default());
+    let deserialized: HashMap<Slot, Vec<SerializableAccountStorageEntry>> =
+        deserialize_sharded_storages(&mut buf.as_slice(), &storage_tally()).unwrap();
+    assert_eq!(deserialized, expected);
+
+    // A truncated listing is rejected instead of coming up short
+    assert!(
+        deserialize_sharded_storages::<_, SerializableAccountStorageEntry>(
+            &mut &buf[..buf.len() - 1],
+            &storage_tally(),
+        )
+        .is_err()
+    );
+
+    // So are shards that are out of slot order, here two single shard listings spliced together
+    // with the later slots first
+    let serialize_one_shard =
+        |account_storage_entries: &[(Slot, Vec<SnapshotStorageEntryInfo>)]| {
+            let mut buf = vec![];
+            serialize_sharded_storages::<_, SerializableAccountStorageEntry>(
+                &mut buf,
+                account_storage_entries,
+            )
+            .unwrap();
+            // skip over the shard count
+            buf.split_off(std::mem::size_of::<u64>())
+        };
+    let mut reversed_buf = bincode::serialize(&2u64).unwrap();
+    reversed_buf.extend(serialize_one_shard(
+        &account_storage_entries[MAX_SLOTS_PER_SHARD..MAX_SLOTS_PER_SHARD * 2],
+    ));
+    reversed_buf.extend(serialize_one_shard(
+        &account_storage_entries[..MAX_SLOTS_PER_SHARD],
+    ));
+    assert!(
+        deserialize_sharded_storages::<_, SerializableAccountStorageEntry>(
+            &mut reversed_buf.as_slice(),
+            &storage_tally(),
+        )
+        .is_err()
+    );
+}
+
 #[cfg(test)]
 fn test_bank_serialize_style(serde_style: SerdeStyle) {
     solana_logger::setup();
@@ -234,8 +470,12 @@ fn test_bank_serialize_style(serde_style: SerdeStyle) {
     let mut snapshot_streams = SnapshotStreams {
         full_snapshot_stream: &mut reader,
         incremental_snapshot_stream: None,
+        full_snapshot_storages_stream: None,
+        incremental_snapshot_storages_stream: None,
+        full_snapshot_version: SnapshotVersion::V1_2_0,
+        incremental_snapshot_version: None,
     };
-    let mut dbank = crate::serde_snapshot::bank_from_streams(
+    let (mut dbank, _) = crate::serde_snapshot::bank_from_streams(
         serde_style,
         &mut snapshot_streams,
         &dbank_paths,
@@ -260,6 +500,96 @@ fn test_bank_serialize_style(serde_style: SerdeStyle) {
     assert!(bank2 == dbank);
 }
 
+#[test]
+fn test_bank_slot_delta_hashes() {
+    solana_logger::setup();
+    let (genesis_config, _) = create_genesis_config(500);
+    let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+    let bank1 = Bank::new_from_parent(&bank0, &Pubkey::default(), 1);
+    bank1.deposit(&Pubkey::new_unique(), 10).unwrap();
+    bank1.freeze();
+    bank1.squash();
+    bank1.force_flush_accounts_cache();
+
+    let snapshot_storages = bank1.get_snapshot_storages(None);
+    let slot_delta_hashes = snapshot_storages.slot_delta_hashes();
+    // the storages have not been shrunk, so this is the delta hash of the bank
+    let (_, delta_hash) = slot_delta_hashes
+        .iter()
+        .find(|(slot, _)| *slot == bank1.slot())
+        .unwrap();
+    assert_eq!(
+        *delta_hash,
+        bank1
+            .rc
+            .accounts
+            .accounts_db
+            .get_accounts_delta_hash(bank1.slot())
+    );
+
+    let load = |snapshot_version: SnapshotVersion,
+                slot_delta_hashes: Option<&[(Slot, Hash)]>,
+                cut_off: usize,
+                filler_account_count: Option<usize>| {
+        let mut buf = vec![];
+        {
+            let mut writer = std::io::BufWriter::new(Cursor::new(&mut buf));
+            bank_to_stream(SerdeStyle::Newer, &mut writer, &bank1, &snapshot_storages).unwrap();
+            if let Some(slot_delta_hashes) = slot_delta_hashes {
+                slot_delta_hashes_to_stream(&mut writer, slot_delta_hashes).unwrap();
+            }
+        }
+        buf.truncate(buf.len() - cut_off);
+        let mut reader = std::io::BufReader::new(&buf[..]);
+        let (_accounts_dir, dbank_paths) = get_temp_accounts_paths(4).unwrap();
+        let copied_accounts = TempDir::new().unwrap();
+        let unpacked_append_vec_map =
+            copy_append_vecs(&bank1.rc.accounts.accounts_db, copied_accounts.path()).unwrap();
+        bank_from_streams(
+            SerdeStyle::Newer,
+            &mut SnapshotStreams {
+                full_snapshot_stream: &mut reader,
+                incremental_snapshot_stream: None,
+                full_snapshot_storages_stream: None,
+                incremental_snapshot_storages_stream: None,
+                full_snapshot_version: snapshot_version,
+                incremental_snapshot_version: None,
+            },
+            &dbank_paths,
+            unpacked_append_vec_map,
+            &genesis_config,
+            &[],
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            Some(AccountsDbConfig {
+                filler_account_count,
+                ..crate::accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING
+            }),
+            None,
+        )
+        .map(|_| ())
+    };
+    let (v1_2_0, v1_3_0) = (SnapshotVersion::V1_2_0, SnapshotVersion::V1_3_0);
+    assert!(load(v1_3_0, Some(&slot_delta_hashes), 0, None).is_ok());
+    // older manifests end without any, and whatever follows them is not read
+    assert!(load(v1_2_0, None, 0, None).is_ok());
+    assert!(load(v1_2_0, Some(&[(1, Hash::new_unique())]), 0, None).is_ok());
+    // but newer ones must not end before or partway through them
+    assert!(load(v1_3_0, None, 0, None).is_err());
+    assert!(load(v1_3_0, Some(&slot_delta_hashes), 1, None).is_err());
+    assert!(load(v1_3_0, Some(&[(1, Hash::new_unique())]), 0, None).is_err());
+    // slots without storages are not checked
+    assert!(load(v1_3_0, Some(&[(3, Hash::new_unique())]), 0, None).is_ok());
+    // the filler accounts that go into the storages are left out of the checks
+    assert!(load(v1_3_0, Some(&slot_delta_hashes), 0, Some(4)).is_ok());
+    assert!(load(v1_3_0, Some(&[(1, Hash::new_unique())]), 0, Some(4)).is_err());
+}
+
 #[cfg(test)]
 pub(crate) fn reconstruct_accounts_db_via_serialization(
     accounts: &AccountsDb,
@@ -307,6 +637,666 @@ fn test_bank_serialize_newer() {
     test_bank_serialize_style(SerdeStyle::Newer)
 }
 
+#[test]
+fn test_reconstruct_rent_paying_accounts_report() {
+    solana_logger::setup();
+    let (_accounts_dir, paths) = get_temp_accounts_paths(4).unwrap();
+    let accounts = Accounts::new_with_config_for_tests(
+        paths,
+        &ClusterType::Development,
+        AccountSecondaryIndexes::default(),
+        false,
+        AccountShrinkThreshold::default(),
+    );
+
+    // rent-paying accounts with balances of 1..=10 lamports, owned by the default pubkey
+    let mut pubkeys: Vec<Pubkey> = vec![];
+    create_test_accounts(&accounts, &mut pubkeys, 10, 0);
+    accounts.add_root(0);
+
+    // a newer rent-exempt version replaces the first rent-paying account, and one more
+    // rent-paying account is added under a different owner
+    let owner = Pubkey::new_unique();
+    let exempt_account = AccountSharedData::new(Rent::default().minimum_balance(0), 0, &owner);
+    accounts.store_slow_uncached(1, &pubkeys[0], &exempt_account);
+    let rent_paying_account = AccountSharedData::new(42, 0, &owner);
+    accounts.store_slow_uncached(1, &Pubkey::new_unique(), &rent_paying_account);
+    accounts.add_root(1);
+    accounts
+        .accounts_db
+        .bank_hashes
+        .write()
+        .unwrap()
+        .insert(1, BankHashInfo::default());
+
+    let mut writer = Cursor::new(vec![]);
+    accountsdb_to_stream(
+        SerdeStyle::Newer,
+        &mut writer,
+        &*accounts.accounts_db,
+        1,
+        &accounts.accounts_db.get_snapshot_storages(1, None, None).0,
+    )
+    .unwrap();
+    let copied_accounts = TempDir::new().unwrap();
+    let unpacked_append_vec_map =
+        copy_append_vecs(&accounts.accounts_db, copied_accounts.path()).unwrap();
+
+    let buf = writer.into_inner();
+    let mut reader = BufReader::new(&buf[..]);
+    let (_accounts_dir, daccounts_paths) = get_temp_accounts_paths(2).unwrap();
+    let snapshot_accounts_db_fields = SnapshotAccountsDbFields {
+        full_snapshot_accounts_db_fields: TypeContextFuture::deserialize_accounts_db_fields(
+            &mut reader,
+            &StorageTally::new(SnapshotStorageLimits::default()),
+        )
+        .unwrap(),
+        incremental_snapshot_accounts_db_fields: None,
+    };
+    let (_accounts_db, stats) = reconstruct_accountsdb_from_fields(
+        snapshot_accounts_db_fields,
+        &daccounts_paths,
+        unpacked_append_vec_map,
+        &GenesisConfig {
+            cluster_type: ClusterType::Development,
+            epoch_schedule: EpochSchedule::without_warmup(),
+            ..GenesisConfig::default()
+        },
+        AccountSecondaryIndexes::default(),
+        false,
+        None,
+        AccountShrinkThreshold::default(),
+        false,
+        Some(AccountsDbConfig {
+            collect_rent_paying_accounts_report: true,
+            ..crate::accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING
+        }),
+        None,
+    )
+    .unwrap();
+
+    let report = stats.rent_paying_accounts_report.unwrap();
+    assert_eq!(report.by_owner.len(), 2);
+    assert_eq!(report.total_count(), 10);
+    assert_eq!(report.total_lamports(), (2..=10).sum::<u64>() + 42);
+
+    let by_default_owner = &report.by_owner[&Pubkey::default()];
+    assert_eq!(by_default_owner.count, 9);
+    assert_eq!(by_default_owner.lamports, (2..=10).sum::<u64>());
+    assert_eq!(by_default_owner.eviction_epochs.values().sum::<u64>(), 9);
+
+    let by_owner = &report.by_owner[&owner];
+    assert_eq!(by_owner.count, 1);
+    assert_eq!(by_owner.lamports, 42);
+    assert_eq!(report.owners_by_lamports()[0].0, &Pubkey::default());
+}
+
+#[test]
+fn test_reconstruct_with_storage_validation() {
+    solana_logger::setup();
+    let (_accounts_dir, paths) = get_temp_accounts_paths(4).unwrap();
+    let accounts = Accounts::new_with_config_for_tests(
+        paths,
+        &ClusterType::Development,
+        AccountSecondaryIndexes::default(),
+        false,
+        AccountShrinkThreshold::default(),
+    );
+
+    let mut pubkeys: Vec<Pubkey> = vec![];
+    create_test_accounts(&accounts, &mut pubkeys, 100, 0);
+    accounts.add_root(0);
+
+    let mut writer = Cursor::new(vec![]);
+    accountsdb_to_stream(
+        SerdeStyle::Newer,
+        &mut writer,
+        &*accounts.accounts_db,
+        0,
+        &accounts.accounts_db.get_snapshot_storages(0, None, None).0,
+    )
+    .unwrap();
+    let buf = writer.into_inner();
+
+    for storage_validation in [
+        AppendVecValidation::HeaderOnly,
+        AppendVecValidation::Sampled,
+        AppendVecValidation::Full,
+    ] {
+        let copied_accounts = TempDir::new().unwrap();
+        let unpacked_append_vec_map =
+            copy_append_vecs(&accounts.accounts_db, copied_accounts.path()).unwrap();
+        let mut reader = BufReader::new(&buf[..]);
+        let (_accounts_dir, daccounts_paths) = get_temp_accounts_paths(2).unwrap();
+        let snapshot_accounts_db_fields = SnapshotAccountsDbFields {
+            full_snapshot_accounts_db_fields: TypeContextFuture::deserialize_accounts_db_fields(
+                &mut reader,
+                &StorageTally::new(SnapshotStorageLimits::default()),
+            )
+            .unwrap(),
+            incremental_snapshot_accounts_db_fields: None,
+        };
+        let (accounts_db, _stats) = reconstruct_accountsdb_from_fields(
+            snapshot_accounts_db_fields,
+            &daccounts_paths,
+            unpacked_append_vec_map,
+            &GenesisConfig {
+                cluster_type: ClusterType::Development,
+                ..GenesisConfig::default()
+            },
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            Some(AccountsDbConfig {
+                storage_validation,
+                ..crate::accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING
+            }),
+            None,
+        )
+        .unwrap();
+
+        // however little of the storages was checked, the count of their accounts is exact
+        let slot_stores = accounts_db.storage.get_slot_stores(0).unwrap();
+        let approx_stored_count: usize = slot_stores
+            .read()
+            .unwrap()
+            .values()
+            .map(|storage| storage.approx_stored_count())
+            .sum();
+        assert_eq!(approx_stored_count, 100, "{:?}", storage_validation);
+        check_accounts(&Accounts::new_empty(accounts_db), &pubkeys, pubkeys.len());
+    }
+}
+
+#[test]
+fn test_reconstruct_with_storage_limits() {
+    solana_logger::setup();
+    let (_accounts_dir, paths) = get_temp_accounts_paths(4).unwrap();
+    let accounts = Accounts::new_with_config_for_tests(
+        paths,
+        &ClusterType::Development,
+        AccountSecondaryIndexes::default(),
+        false,
+        AccountShrinkThreshold::default(),
+    );
+
+    let mut pubkeys: Vec<Pubkey> = vec![];
+    create_test_accounts(&accounts, &mut pubkeys, 100, 0);
+    accounts.add_root(0);
+
+    let mut writer = Cursor::new(vec![]);
+    accountsdb_to_stream(
+        SerdeStyle::Newer,
+        &mut writer,
+        &*accounts.accounts_db,
+        0,
+        &accounts.accounts_db.get_snapshot_storages(0, None, None).0,
+    )
+    .unwrap();
+    let buf = writer.into_inner();
+
+    let reconstruct = |snapshot_storage_limits, storage_validation| {
+        let copied_accounts = TempDir::new().unwrap();
+        let unpacked_append_vec_map =
+            copy_append_vecs(&accounts.accounts_db, copied_accounts.path()).unwrap();
+        let mut reader = BufReader::new(&buf[..]);
+        let (_accounts_dir, daccounts_paths) = get_temp_accounts_paths(2).unwrap();
+        let storage_tally = StorageTally::new(snapshot_storage_limits);
+        let snapshot_accounts_db_fields = SnapshotAccountsDbFields {
+            full_snapshot_accounts_db_fields: storage_tally.within_limits(
+                TypeContextFuture::deserialize_accounts_db_fields(&mut reader, &storage_tally),
+            )?,
+            incremental_snapshot_accounts_db_fields: None,
+        };
+        reconstruct_accountsdb_from_fields(
+            snapshot_accounts_db_fields,
+            &daccounts_paths,
+            unpacked_append_vec_map,
+            &GenesisConfig {
+                cluster_type: ClusterType::Development,
+                ..GenesisConfig::default()
+            },
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            Some(AccountsDbConfig {
+                snapshot_storage_limits,
+                storage_validation,
+                ..crate::accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING
+            }),
+            None,
+        )
+        .map(|(accounts_db, _stats)| accounts_db)
+    };
+
+    let accounts_db =
+        reconstruct(SnapshotStorageLimits::default(), AppendVecValidation::Full).unwrap();
+    check_accounts(&Accounts::new_empty(accounts_db), &pubkeys, pubkeys.len());
+
+    // the storages are tallied while the listing is deserialized
+    assert_matches!(
+        reconstruct(
+            SnapshotStorageLimits {
+                max_storages: 0,
+                ..SnapshotStorageLimits::default()
+            },
+            AppendVecValidation::Full
+        ),
+        Err(SnapshotError::TooManyStorages(1, 0))
+    );
+    assert_matches!(
+        reconstruct(
+            SnapshotStorageLimits {
+                max_storage_bytes: 1,
+                ..SnapshotStorageLimits::default()
+            },
+            AppendVecValidation::Full
+        ),
+        Err(SnapshotError::TooManyStorageBytes(_, 1))
+    );
+    // and the accounts are counted however thoroughly the storages are validated
+    for storage_validation in [
+        AppendVecValidation::HeaderOnly,
+        AppendVecValidation::Sampled,
+        AppendVecValidation::Full,
+    ] {
+        assert_matches!(
+            reconstruct(
+                SnapshotStorageLimits {
+                    max_accounts_per_storage: 99,
+                    ..SnapshotStorageLimits::default()
+                },
+                storage_validation
+            ),
+            Err(SnapshotError::TooManyAccountsInStorage(_, 100, 99))
+        );
+    }
+}
+
+#[test]
+fn test_reconstruct_errors() {
+    solana_logger::setup();
+    let (_accounts_dir, paths) = get_temp_accounts_paths(4).unwrap();
+    let accounts = Accounts::new_with_config_for_tests(
+        paths,
+        &ClusterType::Development,
+        AccountSecondaryIndexes::default(),
+        false,
+        AccountShrinkThreshold::default(),
+    );
+    let serialize = |snapshot_storages: &[SnapshotStorage]| {
+        let mut buf = vec![];
+        accountsdb_to_stream(
+            SerdeStyle::Newer,
+            &mut buf,
+            &*accounts.accounts_db,
+            0,
+            snapshot_storages,
+        )
+        .unwrap();
+        buf
+    };
+
+    // a snapshot without any storages
+    let buf = serialize(&[]);
+    let (_accounts_dir, daccounts_paths) = get_temp_accounts_paths(2).unwrap();
+    let result = accountsdb_from_stream(
+        SerdeStyle::Newer,
+        &mut BufReader::new(&buf[..]),
+        &daccounts_paths,
+        UnpackedAppendVecMap::new(),
+    );
+    assert_matches!(result, Err(SnapshotError::NoStorageEntries));
+
+    // an account path that cannot be created, since a file is in the way
+    let mut pubkeys: Vec<Pubkey> = vec![];
+    create_test_accounts(&accounts, &mut pubkeys, 10, 0);
+    accounts.add_root(0);
+    let buf = serialize(&accounts.accounts_db.get_snapshot_storages(0, None, None).0);
+    let copied_accounts = TempDir::new().unwrap();
+    let unpacked_append_vec_map =
+        copy_append_vecs(&accounts.accounts_db, copied_accounts.path()).unwrap();
+    let blocking_file = copied_accounts.path().join("blocking_file");
+    std::fs::write(&blocking_file, b"").unwrap();
+    let result = accountsdb_from_stream(
+        SerdeStyle::Newer,
+        &mut BufReader::new(&buf[..]),
+        &[blocking_file.join("accounts")],
+        unpacked_append_vec_map,
+    );
+    assert_matches!(result, Err(SnapshotError::CreateAccountPath(path, _)) if path == blocking_file.join("accounts"));
+}
+
+#[test]
+fn test_snapshot_storage_accounts_iter() {
+    solana_logger::setup();
+    let (genesis_config, _) = create_genesis_config(500);
+    let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+    let bank1 = Bank::new_from_parent(&bank0, &Pubkey::default(), 1);
+    let key1 = Keypair::new();
+    bank1.deposit(&key1.pubkey(), 5).unwrap();
+    let key2 = Keypair::new();
+    bank1.deposit(&key2.pubkey(), 10).unwrap();
+    bank1.freeze();
+    bank1.squash();
+    bank1.force_flush_accounts_cache();
+
+    let snapshot_storages = bank1.get_snapshot_storages(None);
+    let mut buf = vec![];
+    let mut writer = Cursor::new(&mut buf);
+    crate::serde_snapshot::bank_to_stream(
+        SerdeStyle::Newer,
+        &mut std::io::BufWriter::new(&mut writer),
+        &bank1,
+        &snapshot_storages,
+    )
+    .unwrap();
+    let mut reader = BufReader::new(&buf[..]);
+    let copied_accounts = TempDir::new().unwrap();
+    let unpacked_append_vec_map =
+        copy_append_vecs(&bank1.rc.accounts.accounts_db, copied_accounts.path()).unwrap();
+
+    let accounts_db_fields = DeserializedAccountsDbFields::from_streams(
+        crate::snapshot_utils::SnapshotVersion::V1_2_0,
+        &mut SnapshotStreams {
+            full_snapshot_stream: &mut reader,
+            incremental_snapshot_stream: None,
+            full_snapshot_storages_stream: None,
+            incremental_snapshot_storages_stream: None,
+            full_snapshot_version: SnapshotVersion::V1_2_0,
+            incremental_snapshot_version: None,
+        },
+        SnapshotStorageLimits::default(),
+    )
+    .unwrap();
+    assert_eq!(accounts_db_fields.slot(), 1);
+    assert_eq!(
+        accounts_db_fields.storage_count(),
+        snapshot_storages.iter().flatten().count()
+    );
+
+    // keep only the latest version of each account, like the index would
+    let mut latest_accounts = HashMap::<Pubkey, (Slot, StoredAccount)>::new();
+    for item in
+        SnapshotStorageAccountsIter::new(&accounts_db_fields, &unpacked_append_vec_map, None)
+            .unwrap()
+    {
+        let (slot, stored_account) = item.unwrap();
+        assert!(slot <= 1);
+        let is_newer = latest_accounts
+            .get(&stored_account.meta.pubkey)
+            .map(|(_, latest)| stored_account.meta.write_version > latest.meta.write_version)
+            .unwrap_or(true);
+        if is_newer {
+            latest_accounts.insert(stored_account.meta.pubkey, (slot, stored_account));
+        }
+    }
+    let (slot, stored_account) = &latest_accounts[&key1.pubkey()];
+    assert_eq!(*slot, 1);
+    assert_eq!(stored_account.account.lamports(), 5);
+    assert_eq!(latest_accounts[&key2.pubkey()].1.account.lamports(), 10);
+    for (pubkey, (_, stored_account)) in latest_accounts.iter() {
+        assert_eq!(
+            bank1.get_account(pubkey).unwrap_or_default(),
+            stored_account.account
+        );
+    }
+
+    // the iterator must not have removed any of the unpacked files
+    assert!(unpacked_append_vec_map.values().all(|path| path.exists()));
+
+    // nor one that fails to sanitize
+    let corrupted_path = unpacked_append_vec_map.values().next().unwrap();
+    let mut corrupted_data = std::fs::read(corrupted_path).unwrap();
+    corrupted_data[..MIN_STORED_ACCOUNT_SIZE].fill(0xff);
+    std::fs::write(corrupted_path, corrupted_data).unwrap();
+    assert!(
+        SnapshotStorageAccountsIter::new(&accounts_db_fields, &unpacked_append_vec_map, None)
+            .unwrap()
+            .any(|item| item.is_err())
+    );
+    assert!(corrupted_path.exists());
+
+    // a storage missing from the unpacked append vecs is reported up front
+    let mut incomplete_append_vec_map = unpacked_append_vec_map;
+    let removed = incomplete_append_vec_map.keys().next().cloned().unwrap();
+    incomplete_append_vec_map.remove(&removed);
+    assert_eq!(
+        SnapshotStorageAccountsIter::new(&accounts_db_fields, &incomplete_append_vec_map, None)
+            .unwrap_err()
+            .kind(),
+        std::io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn test_bank_to_stream_with_storages_provider() {
+    solana_logger::setup();
+    let (genesis_config, _) = create_genesis_config(500);
+    let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+    let bank1 = Bank::new_from_parent(&bank0, &Pubkey::default(), 1);
+    let key1 = Keypair::new();
+    bank1.deposit(&key1.pubkey(), 5).unwrap();
+    bank1.freeze();
+    bank1.squash();
+    bank1.force_flush_accounts_cache();
+    let snapshot_storages = bank1.get_snapshot_storages(None);
+
+    // a provider that only knows the ids and lengths of the storages, like a tool that writes
+    // out its own append vecs would
+    struct SynthesizedStorages(Vec<(Slot, Vec<SnapshotStorageEntryInfo>)>);
+    impl SnapshotStoragesProvider for SynthesizedStorages {
+        fn snapshot_storages(&self) -> Vec<(Slot, Vec<SnapshotStorageEntryInfo>)> {
+            self.0.clone()
+        }
+    }
+    let synthesized_storages = SynthesizedStorages(
+        snapshot_storages
+            .iter()
+            .map(|slot_storages| {
+                (
+                    slot_storages[0].slot(),
+                    slot_storages
+                        .iter()
+                        .map(|storage_entry| SnapshotStorageEntryInfo {
+                            id: storage_entry.append_vec_id(),
+                            current_len: storage_entry.accounts.len(),
+                        })
+                        .collect(),
+                )
+            })
+            .collect(),
+    );
+
+    let mut buf = vec![];
+    bank_to_stream(
+        SerdeStyle::Newer,
+        &mut BufWriter::new(&mut buf),
+        &bank1,
+        &snapshot_storages,
+    )
+    .unwrap();
+    let mut synthesized_buf = vec![];
+    bank_to_stream(
+        SerdeStyle::Newer,
+        &mut BufWriter::new(&mut synthesized_buf),
+        &bank1,
+        &synthesized_storages,
+    )
+    .unwrap();
+    // the bank fields include hash maps, so compare the deserialized storages rather than bytes
+    let deserialize_storages = |buf: &[u8]| {
+        DeserializedAccountsDbFields::from_streams(
+            crate::snapshot_utils::SnapshotVersion::V1_2_0,
+            &mut SnapshotStreams {
+                full_snapshot_stream: &mut BufReader::new(buf),
+                incremental_snapshot_stream: None,
+                full_snapshot_storages_stream: None,
+                incremental_snapshot_storages_stream: None,
+                full_snapshot_version: SnapshotVersion::V1_2_0,
+                incremental_snapshot_version: None,
+            },
+            SnapshotStorageLimits::default(),
+        )
+        .unwrap()
+        .accounts_db_fields
+        .0
+    };
+    assert_eq!(
+        deserialize_storages(&buf),
+        deserialize_storages(&synthesized_buf)
+    );
+
+    // and the synthesized snapshot loads like any other
+    let (_accounts_dir, dbank_paths) = get_temp_accounts_paths(4).unwrap();
+    let copied_accounts = TempDir::new().unwrap();
+    let unpacked_append_vec_map =
+        copy_append_vecs(&bank1.rc.accounts.accounts_db, copied_accounts.path()).unwrap();
+    let (dbank, _) = bank_from_streams(
+        SerdeStyle::Newer,
+        &mut SnapshotStreams {
+            full_snapshot_stream: &mut BufReader::new(&synthesized_buf[..]),
+            incremental_snapshot_stream: None,
+            full_snapshot_storages_stream: None,
+            incremental_snapshot_storages_stream: None,
+            full_snapshot_version: SnapshotVersion::V1_2_0,
+            incremental_snapshot_version: None,
+        },
+        &dbank_paths,
+        unpacked_append_vec_map,
+        &genesis_config,
+        &[],
+        None,
+        None,
+        AccountSecondaryIndexes::default(),
+        false,
+        None,
+        AccountShrinkThreshold::default(),
+        false,
+        Some(crate::accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING),
+        None,
+    )
+    .unwrap();
+    assert_eq!(dbank.get_balance(&key1.pubkey()), 5);
+}
+
+#[test]
+fn test_accounts_db_from_snapshot_streams() {
+    solana_logger::setup();
+    let (genesis_config, _) = create_genesis_config(500);
+    let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+    let bank1 = Bank::new_from_parent(&bank0, &Pubkey::default(), 1);
+    let key1 = Keypair::new();
+    bank1.deposit(&key1.pubkey(), 5).unwrap();
+    bank1.freeze();
+    bank1.squash();
+    bank1.force_flush_accounts_cache();
+
+    let snapshot_storages = bank1.get_snapshot_storages(None);
+    let mut buf = vec![];
+    bank_to_stream(
+        SerdeStyle::Newer,
+        &mut BufWriter::new(&mut buf),
+        &bank1,
+        &snapshot_storages,
+    )
+    .unwrap();
+    let (_accounts_dir, daccounts_paths) = get_temp_accounts_paths(2).unwrap();
+    let copied_accounts = TempDir::new().unwrap();
+    let unpacked_append_vec_map =
+        copy_append_vecs(&bank1.rc.accounts.accounts_db, copied_accounts.path()).unwrap();
+
+    let (accounts_db, slot, bank_hash_info, capitalization) = accounts_db_from_snapshot_streams(
+        crate::snapshot_utils::SnapshotVersion::V1_2_0,
+        &mut SnapshotStreams {
+            full_snapshot_stream: &mut BufReader::new(&buf[..]),
+            incremental_snapshot_stream: None,
+            full_snapshot_storages_stream: None,
+            incremental_snapshot_storages_stream: None,
+            full_snapshot_version: SnapshotVersion::V1_2_0,
+            incremental_snapshot_version: None,
+        },
+        &daccounts_paths,
+        unpacked_append_vec_map,
+        &genesis_config,
+        AccountSecondaryIndexes::default(),
+        false,
+        None,
+        AccountShrinkThreshold::default(),
+        false,
+        Some(crate::accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING),
+        None,
+    )
+    .unwrap();
+    assert_eq!(slot, 1);
+    assert_eq!(
+        bank_hash_info,
+        bank1.rc.accounts.accounts_db.bank_hashes.read().unwrap()[&1]
+    );
+    assert_eq!(capitalization, bank1.capitalization());
+    let ancestors = vec![(slot, 0)].into_iter().collect();
+    assert_eq!(
+        accounts_db
+            .load_without_fixed_root(&ancestors, &key1.pubkey())
+            .map(|(account, _)| account.lamports()),
+        Some(5)
+    );
+}
+
+#[test]
+fn test_retry_transient_io() {
+    let num_retries = AtomicUsize::new(0);
+    let mut num_calls = 0;
+    let result = retry_transient_io(&num_retries, || {
+        num_calls += 1;
+        if num_calls <= 2 {
+            Err(std::io::Error::from(std::io::ErrorKind::Interrupted))
+        } else {
+            Ok(num_calls)
+        }
+    });
+    assert_eq!(result.unwrap(), 3);
+    assert_eq!(num_retries.load(Ordering::Relaxed), 2);
+
+    // errors that are not transient are returned right away
+    let num_retries = AtomicUsize::new(0);
+    let mut num_calls = 0;
+    let result = retry_transient_io(&num_retries, || -> std::io::Result<()> {
+        num_calls += 1;
+        Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+    });
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    assert_eq!(num_calls, 1);
+    assert_eq!(num_retries.load(Ordering::Relaxed), 0);
+
+    // and transient errors are only retried so many times
+    let num_retries = AtomicUsize::new(0);
+    let mut num_calls = 0;
+    let result = retry_transient_io(&num_retries, || -> std::io::Result<()> {
+        num_calls += 1;
+        Err(std::io::Error::from(std::io::ErrorKind::WouldBlock))
+    });
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+    assert_eq!(num_calls, MAX_REMAP_IO_RETRIES + 1);
+    assert_eq!(num_retries.load(Ordering::Relaxed), MAX_REMAP_IO_RETRIES);
+}
+
+#[test]
+fn test_is_path_vacant() {
+    let temp_dir = tempfile::TempDir::new().unwrap();
+    let file = temp_dir.path().join("file");
+    assert!(is_path_vacant(&file).unwrap());
+    std::fs::write(&file, b"").unwrap();
+    assert!(!is_path_vacant(&file).unwrap());
+
+    // a lookup that fails for any other reason is an error, not a vacancy or a collision
+    assert!(is_path_vacant(&file.join("file")).is_err());
+}
+
 #[cfg(all(test, RUSTC_WITH_SPECIALIZATION))]
 mod test_bank_serialize {
     use super::*;
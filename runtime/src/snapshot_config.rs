@@ -1,13 +1,25 @@
-use crate::snapshot_utils::{self, ArchiveFormat, SnapshotVersion};
-use solana_sdk::clock::Slot;
+use crate::hardened_unpack::FilePermissions;
+use crate::snapshot_hooks::SnapshotHooks;
+use crate::snapshot_utils::{
+    self, ArchiveFormat, FullSnapshotSchedule, SnapshotDurability, SnapshotVersion,
+};
+use serde::{Deserialize, Serialize};
+use solana_sdk::clock::{Epoch, Slot};
 use std::path::PathBuf;
 
 /// Snapshot configuration and runtime information
-#[derive(Clone, Debug)]
+///
+/// Everything but the hooks can be (de)serialized, e.g. to keep the configuration in a file.
+/// Fields that are missing when deserializing get their defaults.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SnapshotConfig {
     /// Generate a new full snapshot archive every this many slots
     pub full_snapshot_archive_interval_slots: Slot,
 
+    /// Whether full snapshot archives are generated at the interval, at epoch boundaries, or both
+    pub full_snapshot_schedule: FullSnapshotSchedule,
+
     /// Generate a new incremental snapshot archive every this many slots
     pub incremental_snapshot_archive_interval_slots: Slot,
 
@@ -20,9 +32,23 @@ pub struct SnapshotConfig {
     /// The archive format to use for snapshots
     pub archive_format: ArchiveFormat,
 
+    /// The compression level to use for the archive format, or the format's default if None
+    pub archive_compression_level: Option<i32>,
+
     /// Snapshot version to generate
     pub snapshot_version: SnapshotVersion,
 
+    /// Whether to fsync snapshot files and archives (and their directories) once written
+    pub durability: SnapshotDurability,
+
+    /// Leave the storages of slots from before the last this many epochs out of full snapshot
+    /// archives, keeping only the latest versions of the accounts in them, if set
+    pub full_snapshot_retained_epochs: Option<Epoch>,
+
+    /// Permissions to give snapshot archives, bank snapshots, and the files and directories
+    /// unpacked from snapshot archives
+    pub file_permissions: FilePermissions,
+
     /// Maximum number of full snapshot archives to retain
     pub maximum_full_snapshot_archives_to_retain: usize,
 
@@ -30,14 +56,33 @@ pub struct SnapshotConfig {
     /// NOTE: Incremental snapshots will only be kept for the latest full snapshot
     pub maximum_incremental_snapshot_archives_to_retain: usize,
 
+    /// Advertise the hashes of at most this many of the latest full snapshots, and of the
+    /// incremental snapshots based on the latest of them, over gossip.  If None, as many as are
+    /// retained are advertised.  Either way, no more than fit in a gossip message are, and the
+    /// latest full snapshot's hash always is.
+    pub gossip_advertisement_depth: Option<usize>,
+
+    /// Push the advertised snapshot hashes and archive sizes to gossip again every this many
+    /// milliseconds, and not only as new snapshots are made, if set.  Intervals shorter than
+    /// the snapshot packager's minimum are raised to it.
+    pub gossip_push_interval_ms: Option<u64>,
+
     /// This is the `use_index` parameter to use when calling `update_accounts_hash()`
     pub accounts_hash_use_index: bool,
 
     /// This is the `debug_verify` parameter to use when calling `update_accounts_hash()`
     pub accounts_hash_debug_verify: bool,
 
-    // Thread niceness adjustment for snapshot packager service
+    /// Thread niceness adjustment for snapshot packager service
     pub packager_thread_niceness_adj: i8,
+
+    /// Write the timings of each phase of creating or loading a snapshot next to its archive, see
+    /// `snapshot_profile`
+    pub profile: bool,
+
+    /// Hooks to check snapshots with as they are made, which can veto them, see `snapshot_hooks`
+    #[serde(skip)]
+    pub hooks: SnapshotHooks,
 }
 
 impl Default for SnapshotConfig {
@@ -45,19 +90,66 @@ impl Default for SnapshotConfig {
         Self {
             full_snapshot_archive_interval_slots:
                 snapshot_utils::DEFAULT_FULL_SNAPSHOT_ARCHIVE_INTERVAL_SLOTS,
+            full_snapshot_schedule: FullSnapshotSchedule::default(),
             incremental_snapshot_archive_interval_slots:
                 snapshot_utils::DEFAULT_INCREMENTAL_SNAPSHOT_ARCHIVE_INTERVAL_SLOTS,
             snapshot_archives_dir: PathBuf::default(),
             bank_snapshots_dir: PathBuf::default(),
             archive_format: ArchiveFormat::TarBzip2,
+            archive_compression_level: None,
             snapshot_version: SnapshotVersion::default(),
+            durability: SnapshotDurability::default(),
+            full_snapshot_retained_epochs: None,
+            file_permissions: FilePermissions::default(),
             maximum_full_snapshot_archives_to_retain:
                 snapshot_utils::DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
             maximum_incremental_snapshot_archives_to_retain:
                 snapshot_utils::DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+            gossip_advertisement_depth: None,
+            gossip_push_interval_ms: None,
             accounts_hash_use_index: false,
             accounts_hash_debug_verify: false,
             packager_thread_niceness_adj: 0,
+            profile: false,
+            hooks: SnapshotHooks::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_config_serde() {
+        let snapshot_config = SnapshotConfig {
+            full_snapshot_archive_interval_slots: 25_000,
+            full_snapshot_schedule: FullSnapshotSchedule::IntervalAndEpochBoundary,
+            snapshot_archives_dir: PathBuf::from("/snapshot/archives"),
+            archive_format: ArchiveFormat::TarZstd,
+            archive_compression_level: Some(5),
+            durability: SnapshotDurability::FsyncDir,
+            file_permissions: FilePermissions::OWNER_ONLY,
+            ..SnapshotConfig::default()
+        };
+        let serialized = serde_yaml::to_string(&snapshot_config).unwrap();
+        assert!(serialized.contains("epoch-boundary"));
+        assert!(serialized.contains("fsync-dir"));
+
+        let deserialized: SnapshotConfig = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(serde_yaml::to_string(&deserialized).unwrap(), serialized);
+
+        // Missing fields get their defaults
+        let deserialized: SnapshotConfig = serde_yaml::from_str(
+            "---\nfull_snapshot_archive_interval_slots: 50000\narchive_format: gzip\n",
+        )
+        .unwrap();
+        assert_eq!(deserialized.full_snapshot_archive_interval_slots, 50_000);
+        assert_eq!(deserialized.archive_format, ArchiveFormat::TarGzip);
+        assert_eq!(
+            deserialized.incremental_snapshot_archive_interval_slots,
+            SnapshotConfig::default().incremental_snapshot_archive_interval_slots
+        );
+        assert_eq!(deserialized.snapshot_version, SnapshotVersion::default());
+    }
+}
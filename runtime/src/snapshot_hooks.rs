@@ -0,0 +1,165 @@
+//! Hooks that check snapshots as they are made, and veto those that fail the checks
+//!
+//! Hooks are registered in `SnapshotConfig::hooks`, and run in the order they are registered
+//! there.  The first hook to return an error vetoes the snapshot: a veto before serialization
+//! keeps the bank snapshot from being taken at all, and a veto before publication keeps the
+//! archive from being moved to where it would be served from, and removes it.
+//!
+//! Since a full snapshot's archive can be vetoed well after AccountsBackgroundService took the
+//! bank snapshot, the service only bases incremental snapshots, and cleaning, on a full snapshot
+//! once its archive has been published, see `SnapshotHooks::last_published_full_snapshot_slot()`.
+use {
+    crate::{
+        accounts_db::SnapshotStorage,
+        bank::Bank,
+        snapshot_archive_info::SnapshotArchiveInfoGetter,
+        snapshot_package::SnapshotPackage,
+        snapshot_utils::{Result, SnapshotError},
+    },
+    log::*,
+    solana_sdk::clock::Slot,
+    std::{
+        fmt,
+        path::{Path, PathBuf},
+        process::Command,
+        sync::{Arc, RwLock},
+    },
+};
+
+pub trait SnapshotHook: fmt::Debug + Send + Sync {
+    /// The name of the hook in logs, and in the errors of its vetoes
+    fn name(&self) -> &str;
+
+    /// Called with the bank and the storages of a snapshot before the bank is serialized
+    fn before_serialization(
+        &self,
+        _bank: &Bank,
+        _snapshot_storages: &[SnapshotStorage],
+    ) -> std::result::Result<(), String> {
+        Ok(())
+    }
+
+    /// Called once the archive of `snapshot_package` has been written to `archive_path` and
+    /// checked, before it is published at `snapshot_package.path()`
+    fn before_publication(
+        &self,
+        _archive_path: &Path,
+        _snapshot_package: &SnapshotPackage,
+    ) -> std::result::Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The hooks registered for the snapshots of a node, along with the latest full snapshot whose
+/// archive got past them.  Clones share the latter, so the snapshot packager can tell
+/// AccountsBackgroundService through their `SnapshotConfig`s.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotHooks {
+    hooks: Vec<Arc<dyn SnapshotHook>>,
+    last_published_full_snapshot_slot: Arc<RwLock<Option<Slot>>>,
+}
+
+impl SnapshotHooks {
+    pub fn new(hooks: Vec<Arc<dyn SnapshotHook>>) -> Self {
+        Self {
+            hooks,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// The slot of the latest full snapshot whose archive was published, if any was since these
+    /// hooks were registered
+    pub fn last_published_full_snapshot_slot(&self) -> Option<Slot> {
+        *self.last_published_full_snapshot_slot.read().unwrap()
+    }
+
+    pub(crate) fn full_snapshot_published(&self, slot: Slot) {
+        let mut last_published_full_snapshot_slot =
+            self.last_published_full_snapshot_slot.write().unwrap();
+        *last_published_full_snapshot_slot = (*last_published_full_snapshot_slot).max(Some(slot));
+    }
+
+    /// Run the `before_serialization()` of each hook, until one of them vetoes the snapshot
+    pub(crate) fn run_before_serialization(
+        &self,
+        bank: &Bank,
+        snapshot_storages: &[SnapshotStorage],
+    ) -> Result<()> {
+        for hook in &self.hooks {
+            hook.before_serialization(bank, snapshot_storages)
+                .map_err(|reason| vetoed(hook.as_ref(), bank.slot(), reason))?;
+        }
+        Ok(())
+    }
+
+    /// Run the `before_publication()` of each hook, until one of them vetoes the archive
+    pub(crate) fn run_before_publication(
+        &self,
+        archive_path: &Path,
+        snapshot_package: &SnapshotPackage,
+    ) -> Result<()> {
+        for hook in &self.hooks {
+            hook.before_publication(archive_path, snapshot_package)
+                .map_err(|reason| vetoed(hook.as_ref(), snapshot_package.slot(), reason))?;
+        }
+        Ok(())
+    }
+}
+
+fn vetoed(hook: &dyn SnapshotHook, slot: Slot, reason: String) -> SnapshotError {
+    warn!(
+        "Snapshot hook '{}' vetoed the snapshot of slot {}: {}",
+        hook.name(),
+        slot,
+        reason
+    );
+    SnapshotError::VetoedByHook(hook.name().to_string(), reason)
+}
+
+/// A hook that runs a program on each archive before it is published, with the path of the
+/// archive, and the slot and hash of its snapshot, as the arguments.  The program failing vetoes
+/// the archive, with what it wrote to stderr as the reason.
+#[derive(Debug)]
+pub struct CommandHook {
+    program: PathBuf,
+    name: String,
+}
+
+impl CommandHook {
+    pub fn new(program: PathBuf) -> Self {
+        let name = program.display().to_string();
+        Self { program, name }
+    }
+}
+
+impl SnapshotHook for CommandHook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn before_publication(
+        &self,
+        archive_path: &Path,
+        snapshot_package: &SnapshotPackage,
+    ) -> std::result::Result<(), String> {
+        let output = Command::new(&self.program)
+            .arg(archive_path)
+            .arg(snapshot_package.slot().to_string())
+            .arg(snapshot_package.hash().to_string())
+            .output()
+            .map_err(|err| format!("failed to run: {}", err))?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+}
@@ -4,6 +4,7 @@ use crate::{
 };
 use crate::{
     snapshot_archive_info::{SnapshotArchiveInfo, SnapshotArchiveInfoGetter},
+    snapshot_profile::SnapshotProfiler,
     snapshot_utils::{
         self, ArchiveFormat, BankSnapshotInfo, Result, SnapshotVersion, TMP_BANK_SNAPSHOT_PREFIX,
     },
@@ -42,6 +43,8 @@ pub struct AccountsPackage {
     pub slot_deltas: Vec<BankSlotDelta>,
     pub snapshot_links: TempDir,
     pub snapshot_storages: SnapshotStorages,
+    /// Where the storages rewritten to leave older accounts out are, if any were
+    pub pruned_storages_dir: Option<TempDir>,
     pub hash: Hash, // temporarily here while we still have to calculate hash before serializing bank
     pub archive_format: ArchiveFormat,
     pub snapshot_version: SnapshotVersion,
@@ -50,6 +53,11 @@ pub struct AccountsPackage {
     pub hash_for_testing: Option<Hash>,
     pub cluster_type: ClusterType,
     pub snapshot_type: Option<SnapshotType>,
+    /// The phases of creating the snapshot so far, if it is being profiled
+    pub profiler: SnapshotProfiler,
+    /// Whether to archive the snapshot so that nodes with the same bank write the same bytes, see
+    /// `AccountsDbConfig::deterministic_snapshot_serialization`
+    pub deterministic_serialization: bool,
 }
 
 impl AccountsPackage {
@@ -98,10 +106,17 @@ impl AccountsPackage {
                 .path()
                 .join(bank_snapshot_info.slot.to_string());
             fs::create_dir_all(&snapshot_hardlink_dir)?;
-            fs::hard_link(
-                &bank_snapshot_info.snapshot_path,
-                &snapshot_hardlink_dir.join(bank_snapshot_info.slot.to_string()),
-            )?;
+            let snapshot_hardlink_path =
+                snapshot_hardlink_dir.join(bank_snapshot_info.slot.to_string());
+            fs::hard_link(&bank_snapshot_info.snapshot_path, &snapshot_hardlink_path)?;
+            if snapshot_version.has_separate_storages_file() {
+                fs::hard_link(
+                    snapshot_utils::get_snapshot_storages_file_path(
+                        &bank_snapshot_info.snapshot_path,
+                    ),
+                    snapshot_utils::get_snapshot_storages_file_path(&snapshot_hardlink_path),
+                )?;
+            }
         }
 
         Ok(Self {
@@ -110,6 +125,7 @@ impl AccountsPackage {
             slot_deltas,
             snapshot_links,
             snapshot_storages,
+            pruned_storages_dir: None,
             hash: bank.get_accounts_hash(),
             archive_format,
             snapshot_version,
@@ -118,6 +134,12 @@ impl AccountsPackage {
             hash_for_testing,
             cluster_type: bank.cluster_type(),
             snapshot_type,
+            profiler: SnapshotProfiler::default(),
+            deterministic_serialization: bank
+                .rc
+                .accounts
+                .accounts_db
+                .deterministic_snapshot_serialization,
         })
     }
 }
@@ -128,8 +150,14 @@ pub struct SnapshotPackage {
     pub slot_deltas: Vec<BankSlotDelta>,
     pub snapshot_links: TempDir,
     pub snapshot_storages: SnapshotStorages,
+    /// Where the storages rewritten to leave older accounts out are, if any were
+    pub pruned_storages_dir: Option<TempDir>,
     pub snapshot_version: SnapshotVersion,
     pub snapshot_type: SnapshotType,
+    /// The phases of creating the snapshot so far, if it is being profiled
+    pub profiler: SnapshotProfiler,
+    /// Whether to archive the snapshot so that nodes with the same bank write the same bytes
+    pub deterministic_serialization: bool,
 }
 
 impl From<AccountsPackage> for SnapshotPackage {
@@ -168,8 +196,11 @@ impl From<AccountsPackage> for SnapshotPackage {
             slot_deltas: accounts_package.slot_deltas,
             snapshot_links: accounts_package.snapshot_links,
             snapshot_storages: accounts_package.snapshot_storages,
+            pruned_storages_dir: accounts_package.pruned_storages_dir,
             snapshot_version: accounts_package.snapshot_version,
             snapshot_type: accounts_package.snapshot_type.unwrap(),
+            profiler: accounts_package.profiler,
+            deterministic_serialization: accounts_package.deterministic_serialization,
         }
     }
 }
@@ -0,0 +1,235 @@
+//! Fine-grained timings and byte counts of creating and loading snapshots
+//!
+//! Profiling is turned on for both creating and loading snapshots with `SnapshotConfig::profile`,
+//! or by setting the `SOLANA_SNAPSHOT_PROFILE` environment variable.  Each profiled run is
+//! recorded as a `SnapshotProfile`, which is written as JSON next to the snapshot archive it made
+//! or loaded, and kept in memory until the next run of its kind for `last_snapshot_profile()` to
+//! return.
+use {
+    crate::hardened_unpack::FilePermissions,
+    lazy_static::lazy_static,
+    log::*,
+    serde::{Deserialize, Serialize},
+    solana_sdk::clock::Slot,
+    std::{
+        collections::HashMap,
+        ffi::OsString,
+        fs,
+        io::Write,
+        path::{Path, PathBuf},
+        sync::RwLock,
+        time::Instant,
+    },
+};
+
+/// Setting this environment variable to anything but `0` turns profiling on for both creating and
+/// loading snapshots, whatever the configuration says
+pub const SNAPSHOT_PROFILE_ENV_VAR: &str = "SOLANA_SNAPSHOT_PROFILE";
+
+lazy_static! {
+    static ref LAST_SNAPSHOT_PROFILES: RwLock<HashMap<SnapshotProfileKind, SnapshotProfile>> =
+        RwLock::default();
+}
+
+/// Is profiling on, either because it is `configured` or by the environment variable?
+pub fn is_profiling_enabled(configured: bool) -> bool {
+    configured
+        || std::env::var_os(SNAPSHOT_PROFILE_ENV_VAR)
+            .map_or(false, |value| !value.is_empty() && value != "0")
+}
+
+/// The profile of the last run of `kind` to finish, if any run of it was profiled
+pub fn last_snapshot_profile(kind: SnapshotProfileKind) -> Option<SnapshotProfile> {
+    LAST_SNAPSHOT_PROFILES.read().unwrap().get(&kind).cloned()
+}
+
+/// Where the profile of a run of `kind` for `snapshot_archive_path` is written
+pub fn snapshot_profile_path(
+    snapshot_archive_path: impl AsRef<Path>,
+    kind: SnapshotProfileKind,
+) -> PathBuf {
+    let mut path = OsString::from(snapshot_archive_path.as_ref());
+    path.push(format!(".{}-profile.json", kind.as_str()));
+    PathBuf::from(path)
+}
+
+/// Remove the profiles written next to `snapshot_archive_path`, as the archive is purged
+pub fn remove_snapshot_profiles(snapshot_archive_path: impl AsRef<Path>) {
+    for kind in [SnapshotProfileKind::Create, SnapshotProfileKind::Load] {
+        let _ = fs::remove_file(snapshot_profile_path(&snapshot_archive_path, kind));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotProfileKind {
+    /// Taking a bank snapshot and archiving it
+    Create,
+    /// Unarchiving snapshot archives and rebuilding a bank from them
+    Load,
+}
+
+impl SnapshotProfileKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Load => "load",
+        }
+    }
+}
+
+/// One phase of a profiled run, in the order it finished
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotProfilePhase {
+    pub name: String,
+    pub us: u64,
+    /// How many bytes the phase wrote or read, for the phases that tell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotProfile {
+    pub kind: SnapshotProfileKind,
+    pub slot: Slot,
+    /// From the start of the run to its end, including any time between its phases
+    pub total_us: u64,
+    pub phases: Vec<SnapshotProfilePhase>,
+}
+
+/// Records the phases of one run; does nothing if profiling was not enabled for it
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotProfiler {
+    profile: Option<(Instant, SnapshotProfile)>,
+}
+
+impl SnapshotProfiler {
+    pub fn new(kind: SnapshotProfileKind, slot: Slot, configured: bool) -> Self {
+        let profile = is_profiling_enabled(configured).then(|| {
+            (
+                Instant::now(),
+                SnapshotProfile {
+                    kind,
+                    slot,
+                    total_us: 0,
+                    phases: Vec::new(),
+                },
+            )
+        });
+        Self { profile }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    pub fn record(&mut self, name: &str, us: u64, bytes: Option<u64>) {
+        if let Some((_start, profile)) = &mut self.profile {
+            profile.phases.push(SnapshotProfilePhase {
+                name: name.to_string(),
+                us,
+                bytes,
+            });
+        }
+    }
+
+    /// End the run, writing the profile out next to `snapshot_archive_path`, with the same
+    /// `file_permissions` as the archive.  Failing to write it is only logged, since the run
+    /// itself did not fail.
+    pub fn finish(
+        self,
+        snapshot_archive_path: impl AsRef<Path>,
+        file_permissions: FilePermissions,
+    ) -> Option<SnapshotProfile> {
+        let (start, mut profile) = self.profile?;
+        profile.total_us = start.elapsed().as_micros() as u64;
+
+        let profile_path = snapshot_profile_path(snapshot_archive_path, profile.kind);
+        if let Err(err) = serde_json::to_vec_pretty(&profile)
+            .map_err(std::io::Error::from)
+            .and_then(|json| {
+                file_permissions
+                    .create_file(&profile_path)?
+                    .write_all(&json)
+            })
+        {
+            warn!(
+                "Failed to write snapshot profile to {}: {}",
+                profile_path.display(),
+                err
+            );
+        }
+        LAST_SNAPSHOT_PROFILES
+            .write()
+            .unwrap()
+            .insert(profile.kind, profile.clone());
+        Some(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, tempfile::TempDir};
+
+    #[test]
+    fn test_snapshot_profiler() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive_path = temp_dir.path().join("snapshot-1-hash.tar.zst");
+
+        let mut profiler = SnapshotProfiler::new(SnapshotProfileKind::Load, 1, false);
+        if !profiler.is_enabled() {
+            // unless the environment variable is set, nothing is recorded or written
+            profiler.record("unarchive", 1, Some(2));
+            assert!(profiler
+                .finish(&archive_path, FilePermissions::default())
+                .is_none());
+            assert!(fs::read_dir(temp_dir.path()).unwrap().next().is_none());
+        }
+
+        let mut profiler = SnapshotProfiler::new(SnapshotProfileKind::Load, 1, true);
+        profiler.record("unarchive", 10, Some(1024));
+        profiler.record("rebuild-bank", 20, None);
+        let profile = profiler
+            .finish(&archive_path, FilePermissions::OWNER_ONLY)
+            .unwrap();
+        assert_eq!(profile.kind, SnapshotProfileKind::Load);
+        assert_eq!(profile.slot, 1);
+        assert_eq!(
+            profile.phases,
+            vec![
+                SnapshotProfilePhase {
+                    name: "unarchive".to_string(),
+                    us: 10,
+                    bytes: Some(1024),
+                },
+                SnapshotProfilePhase {
+                    name: "rebuild-bank".to_string(),
+                    us: 20,
+                    bytes: None,
+                },
+            ]
+        );
+
+        let profile_path = snapshot_profile_path(&archive_path, SnapshotProfileKind::Load);
+        assert_eq!(
+            profile_path,
+            temp_dir
+                .path()
+                .join("snapshot-1-hash.tar.zst.load-profile.json")
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&profile_path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode, 0o600);
+        }
+        let json = fs::read_to_string(&profile_path).unwrap();
+        assert!(json.contains("\"kind\": \"load\""));
+        let written: SnapshotProfile = serde_json::from_str(&json).unwrap();
+        assert_eq!(written, profile);
+        assert_eq!(
+            last_snapshot_profile(SnapshotProfileKind::Load),
+            Some(profile)
+        );
+    }
+}
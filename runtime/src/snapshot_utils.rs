@@ -1,23 +1,43 @@
 use {
     crate::{
-        accounts_db::{AccountShrinkThreshold, AccountsDbConfig},
-        accounts_index::AccountSecondaryIndexes,
+        accounts::Accounts,
+        accounts_db::{
+            AccountShrinkThreshold, AccountsDbConfig, AppendVecId, BankHashVerificationError,
+        },
+        accounts_index::{
+            AccountIndex, AccountSecondaryIndexes, AccountSecondaryIndexesIncludeExclude,
+            IndexKey, ScanConfig, ScanError,
+        },
         accounts_update_notifier_interface::AccountsUpdateNotifier,
+        ancestors::Ancestors,
+        append_vec::AppendVecValidation,
         bank::{Bank, BankSlotDelta},
         builtins::Builtins,
-        hardened_unpack::{unpack_snapshot, ParallelSelector, UnpackError, UnpackedAppendVecMap},
+        hardened_unpack::{
+            unpack_snapshot, FilePermissions, ParallelSelector, UnpackError, UnpackedAppendVecMap,
+        },
+        program_accounts_snapshot::{
+            write_program_accounts_snapshot, ProgramAccountsSnapshotHeader,
+            PROGRAM_ACCOUNTS_SNAPSHOT_VERSION,
+        },
         serde_snapshot::{
-            bank_from_streams, bank_to_stream, SerdeStyle, SnapshotStorage, SnapshotStorages,
+            self, account_storage_entries_to_stream, accounts_db_from_snapshot_streams,
+            bank_from_streams, bank_to_stream, canonical_serialize_into, canonical_storage_ids,
+            slot_delta_hashes_to_stream, ReconstructAccountsDbStats, SerdeStyle,
+            SnapshotCompatibility, SnapshotStorage, SnapshotStorages, SnapshotStoragesProvider,
             SnapshotStreams,
         },
         shared_buffer_reader::{SharedBuffer, SharedBufferReader},
         snapshot_archive_info::{
             FullSnapshotArchiveInfo, IncrementalSnapshotArchiveInfo, SnapshotArchiveInfoGetter,
         },
+        snapshot_config::SnapshotConfig,
         snapshot_package::{
             AccountsPackage, AccountsPackageSendError, AccountsPackageSender, SnapshotPackage,
             SnapshotType,
         },
+        snapshot_profile::{self, SnapshotProfileKind, SnapshotProfiler},
+        storage_encryption::{self, MasterKey, STORAGE_MASTER_KEY_ID_FILE_NAME},
     },
     bincode::{config::Options, serialize_into},
     bzip2::bufread::BzDecoder,
@@ -26,20 +46,28 @@ use {
     log::*,
     rayon::prelude::*,
     regex::Regex,
+    serde::{Deserialize, Serialize},
     solana_measure::measure::Measure,
-    solana_sdk::{clock::Slot, genesis_config::GenesisConfig, hash::Hash, pubkey::Pubkey},
+    solana_sdk::{
+        account::ReadableAccount,
+        clock::{Epoch, Slot},
+        genesis_config::GenesisConfig,
+        hash::{Hash, Hasher},
+        pubkey::Pubkey,
+    },
     std::{
         cmp::{max, Ordering},
         collections::HashSet,
+        ffi::OsString,
         fmt,
         fs::{self, File},
-        io::{BufReader, BufWriter, Error as IoError, ErrorKind, Read, Seek, Write},
+        io::{self, BufReader, BufWriter, Error as IoError, ErrorKind, Read, Seek, Write},
         path::{Path, PathBuf},
         process::ExitStatus,
         str::FromStr,
         sync::Arc,
     },
-    tar::{self, Archive},
+    tar::{self, Archive, HeaderMode},
     tempfile::TempDir,
     thiserror::Error,
 };
@@ -49,18 +77,38 @@ pub const DEFAULT_FULL_SNAPSHOT_ARCHIVE_INTERVAL_SLOTS: Slot = 100_000;
 pub const DEFAULT_INCREMENTAL_SNAPSHOT_ARCHIVE_INTERVAL_SLOTS: Slot = 100;
 const MAX_SNAPSHOT_DATA_FILE_SIZE: u64 = 32 * 1024 * 1024 * 1024; // 32 GiB
 const VERSION_STRING_V1_2_0: &str = "1.2.0";
+const VERSION_STRING_V1_3_0: &str = "1.3.0";
+/// Extension of the file, next to a bank snapshot's manifest, that holds its storages listing
+/// for snapshot versions that keep the two apart
+const SNAPSHOT_STORAGES_FILE_EXTENSION: &str = "storages";
+/// Older releases cannot load 1.3.0 snapshots, so they, and the accounts delta hashes that only
+/// they record, have to be asked for with `--snapshot-version`
 const DEFAULT_SNAPSHOT_VERSION: SnapshotVersion = SnapshotVersion::V1_2_0;
 pub(crate) const TMP_BANK_SNAPSHOT_PREFIX: &str = "tmp-bank-snapshot-";
 pub const TMP_SNAPSHOT_ARCHIVE_PREFIX: &str = "tmp-snapshot-archive-";
+/// Archives are written to their final file name with this suffix, and only renamed into place
+/// once they are complete
+pub const TMP_SNAPSHOT_ARCHIVE_SUFFIX: &str = ".tmp";
+/// Prefix used by `solana_download_utils` while downloading a snapshot archive
+const TMP_DOWNLOAD_PREFIX: &str = "tmp-";
 pub const MAX_BANK_SNAPSHOTS_TO_RETAIN: usize = 8; // Save some bank snapshots but not too many
 pub const DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN: usize = 2;
 pub const DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN: usize = 4;
 pub const FULL_SNAPSHOT_ARCHIVE_FILENAME_REGEX: &str = r"^snapshot-(?P<slot>[[:digit:]]+)-(?P<hash>[[:alnum:]]+)\.(?P<ext>tar|tar\.bz2|tar\.zst|tar\.gz)$";
 pub const INCREMENTAL_SNAPSHOT_ARCHIVE_FILENAME_REGEX: &str = r"^incremental-snapshot-(?P<base>[[:digit:]]+)-(?P<slot>[[:digit:]]+)-(?P<hash>[[:alnum:]]+)\.(?P<ext>tar|tar\.bz2|tar\.zst|tar\.gz)$";
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub enum SnapshotVersion {
+    #[serde(rename = "1.2.0")]
     V1_2_0,
+    /// Same serialization as 1.2.0, but the storages listing is split out of the bank manifest
+    /// into a file of its own, so the two can be parsed in parallel and the (small) manifest can
+    /// be read without the (large) listing.  The listing is itself written as length-prefixed
+    /// shards of slots, which are deserialized in parallel.  The manifest ends with the accounts
+    /// delta hashes of the recent slots.  Archives start with the version, so that
+    /// `serde_snapshot::probe()` can tell them from their first few blocks.
+    #[serde(rename = "1.3.0")]
+    V1_3_0,
 }
 
 impl Default for SnapshotVersion {
@@ -79,6 +127,7 @@ impl From<SnapshotVersion> for &'static str {
     fn from(snapshot_version: SnapshotVersion) -> &'static str {
         match snapshot_version {
             SnapshotVersion::V1_2_0 => VERSION_STRING_V1_2_0,
+            SnapshotVersion::V1_3_0 => VERSION_STRING_V1_3_0,
         }
     }
 }
@@ -98,6 +147,7 @@ impl FromStr for SnapshotVersion {
         };
         match version_string {
             VERSION_STRING_V1_2_0 => Ok(SnapshotVersion::V1_2_0),
+            VERSION_STRING_V1_3_0 => Ok(SnapshotVersion::V1_3_0),
             _ => Err("unsupported snapshot version"),
         }
     }
@@ -111,17 +161,136 @@ impl SnapshotVersion {
     fn maybe_from_string(version_string: &str) -> Option<SnapshotVersion> {
         version_string.parse::<Self>().ok()
     }
+
+    /// Whether the storages listing of a bank snapshot is in a file of its own, next to the bank
+    /// manifest, instead of in the manifest itself
+    pub fn has_separate_storages_file(self) -> bool {
+        match self {
+            SnapshotVersion::V1_2_0 => false,
+            SnapshotVersion::V1_3_0 => true,
+        }
+    }
 }
 
 /// The different archive formats used for snapshots
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum ArchiveFormat {
+    #[serde(rename = "bz2")]
     TarBzip2,
+    #[serde(rename = "gzip")]
     TarGzip,
+    #[serde(rename = "zstd")]
     TarZstd,
+    #[serde(rename = "tar")]
     Tar,
 }
 
+/// Is `compression_level` valid for `archive_format`?  Uncompressed archives accept (and ignore)
+/// any level.
+pub fn is_valid_archive_compression_level(
+    archive_format: ArchiveFormat,
+    compression_level: i32,
+) -> bool {
+    match archive_format {
+        ArchiveFormat::TarBzip2 => (1..=9).contains(&compression_level),
+        ArchiveFormat::TarGzip => (0..=9).contains(&compression_level),
+        ArchiveFormat::TarZstd => zstd::compression_level_range().contains(&compression_level),
+        ArchiveFormat::Tar => true,
+    }
+}
+
+/// How hard to try to make sure snapshot files and archives survive a crash or power loss once they
+/// have been written
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotDurability {
+    /// Leave it to the OS to write the files out whenever it gets to them
+    #[serde(rename = "none")]
+    NoFsync,
+    /// fsync each file once it has been written
+    FsyncFile,
+    /// fsync each file once it has been written, and then the directory it was written (or
+    /// renamed) into, so the directory entry is durable too
+    FsyncDir,
+}
+
+impl Default for SnapshotDurability {
+    fn default() -> Self {
+        Self::NoFsync
+    }
+}
+
+impl SnapshotDurability {
+    /// Apply the policy to a file that has just been written
+    fn sync_file(self, file: &File) -> std::io::Result<()> {
+        match self {
+            Self::NoFsync => Ok(()),
+            Self::FsyncFile | Self::FsyncDir => file.sync_all(),
+        }
+    }
+
+    /// Apply the policy to a directory that a file has just been written (or renamed) into
+    fn sync_dir(self, dir: &Path) -> std::io::Result<()> {
+        match self {
+            Self::NoFsync | Self::FsyncFile => Ok(()),
+            // Directories can only be opened (and so synced) like this on Unix
+            #[cfg(unix)]
+            Self::FsyncDir => File::open(dir)?.sync_all(),
+            #[cfg(not(unix))]
+            Self::FsyncDir => {
+                let _ = dir;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// When to take full snapshots.  An epoch boundary snapshot is of the first root that is observed
+/// in an epoch, which is past the first slot of the epoch when the root jumps over it, and not of
+/// the first slot of the epoch that was rooted along the way.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FullSnapshotSchedule {
+    /// Every full snapshot archive interval, by block height
+    Interval,
+    /// Only of the first root in each epoch
+    EpochBoundary,
+    /// Every full snapshot archive interval, and of the first root in each epoch
+    IntervalAndEpochBoundary,
+}
+
+impl Default for FullSnapshotSchedule {
+    fn default() -> Self {
+        Self::Interval
+    }
+}
+
+impl FullSnapshotSchedule {
+    pub fn includes_epoch_boundary(self) -> bool {
+        match self {
+            Self::Interval => false,
+            Self::EpochBoundary | Self::IntervalAndEpochBoundary => true,
+        }
+    }
+
+    /// Should a full snapshot be taken of a root at `block_height`, which is the first root in
+    /// its epoch if `is_first_root_in_epoch`?
+    pub fn should_take_full_snapshot(
+        self,
+        block_height: Slot,
+        full_snapshot_archive_interval_slots: Slot,
+        is_first_root_in_epoch: bool,
+    ) -> bool {
+        let at_interval =
+            || should_take_full_snapshot(block_height, full_snapshot_archive_interval_slots);
+        match self {
+            Self::Interval => at_interval(),
+            Self::EpochBoundary => is_first_root_in_epoch,
+            Self::IntervalAndEpochBoundary => is_first_root_in_epoch || at_interval(),
+        }
+    }
+}
+
 /// A slot and the path to its bank snapshot
 #[derive(PartialEq, Eq, Debug)]
 pub struct BankSnapshotInfo {
@@ -148,6 +317,41 @@ impl Ord for BankSnapshotInfo {
 struct SnapshotRootPaths {
     full_snapshot_root_file_path: PathBuf,
     incremental_snapshot_root_file_path: Option<PathBuf>,
+    /// The files of the snapshots' storages listings, if their snapshot versions keep them apart
+    /// from the bank manifests
+    full_snapshot_storages_file_path: Option<PathBuf>,
+    incremental_snapshot_storages_file_path: Option<PathBuf>,
+    full_snapshot_version: SnapshotVersion,
+    incremental_snapshot_version: Option<SnapshotVersion>,
+}
+
+impl SnapshotRootPaths {
+    /// The root paths of the bank snapshot(s) unpacked from a full snapshot archive, and
+    /// optionally an incremental snapshot archive, each with the version of its archive
+    fn new(
+        full_snapshot: (SnapshotVersion, BankSnapshotInfo),
+        incremental_snapshot: Option<(SnapshotVersion, BankSnapshotInfo)>,
+    ) -> Self {
+        let storages_file_path =
+            |(snapshot_version, bank_snapshot_info): &(SnapshotVersion, BankSnapshotInfo)| {
+                snapshot_version
+                    .has_separate_storages_file()
+                    .then(|| get_snapshot_storages_file_path(&bank_snapshot_info.snapshot_path))
+            };
+        Self {
+            full_snapshot_storages_file_path: storages_file_path(&full_snapshot),
+            incremental_snapshot_storages_file_path: incremental_snapshot
+                .as_ref()
+                .and_then(storages_file_path),
+            full_snapshot_version: full_snapshot.0,
+            incremental_snapshot_version: incremental_snapshot
+                .as_ref()
+                .map(|(snapshot_version, _)| *snapshot_version),
+            full_snapshot_root_file_path: full_snapshot.1.snapshot_path,
+            incremental_snapshot_root_file_path: incremental_snapshot
+                .map(|(_, bank_snapshot_info)| bank_snapshot_info.snapshot_path),
+        }
+    }
 }
 
 /// Helper type to bundle up the results from `unarchive_snapshot()`
@@ -207,6 +411,45 @@ pub enum SnapshotError {
 
     #[error("snapshot has mismatch: deserialized bank: {:?}, snapshot archive info: {:?}", .0, .1)]
     MismatchedSlotHash((Slot, Hash), (Slot, Hash)),
+
+    #[error("snapshot archive was not written out intact: {}", .0.display())]
+    ArchiveHashMismatch(PathBuf),
+
+    #[error("accounts scan error: {0}")]
+    Scan(#[from] ScanError),
+
+    #[error("could not create account path {}: {1}", .0.display())]
+    CreateAccountPath(PathBuf, std::io::Error),
+
+    #[error("no storage entries were deserialized from the snapshot")]
+    NoStorageEntries,
+
+    #[error("storage id {0} is larger than allowed max")]
+    StorageIdTooLarge(AppendVecId),
+
+    #[error("the reconstructed accounts db is still shared")]
+    AccountsDbStillShared,
+
+    #[error("the thread notifying the accounts restored from the snapshot panicked")]
+    RestoreNotifierPanicked,
+
+    #[error("could not build the restore notifier thread pool: {0}")]
+    RestoreNotifierThreadPool(rayon::ThreadPoolBuildError),
+
+    #[error("snapshot has at least {0} storages, more than the limit of {1}")]
+    TooManyStorages(usize, usize),
+
+    #[error("storage {} has {1} accounts, more than the limit of {2}", .0.display())]
+    TooManyAccountsInStorage(PathBuf, usize, usize),
+
+    #[error("snapshot storages have at least {0} bytes, more than the limit of {1}")]
+    TooManyStorageBytes(u64, u64),
+
+    #[error("snapshot vetoed by hook '{0}': {1}")]
+    VetoedByHook(String, String),
+
+    #[error("snapshot accounts failed to verify: {0:?}")]
+    AccountsVerificationFailed(BankHashVerificationError),
 }
 pub type Result<T> = std::result::Result<T, SnapshotError>;
 
@@ -219,8 +462,23 @@ fn get_archive_ext(archive_format: ArchiveFormat) -> &'static str {
     }
 }
 
+/// Is this the file name of a snapshot archive that is still being written (or downloaded), or
+/// that was left behind when the validator halted before it was done?  Only the names of snapshot
+/// archives with the temporary suffix or download prefix added count, as the snapshot archives
+/// directory may hold other files too (by default it is the ledger directory).
+fn is_incomplete_snapshot_archive_file_name(file_name: &str) -> bool {
+    file_name
+        .strip_suffix(TMP_SNAPSHOT_ARCHIVE_SUFFIX)
+        .or_else(|| file_name.strip_prefix(TMP_DOWNLOAD_PREFIX))
+        .map_or(false, |file_name| {
+            parse_full_snapshot_archive_filename(file_name).is_ok()
+                || parse_incremental_snapshot_archive_filename(file_name).is_ok()
+        })
+}
+
 /// If the validator halts in the middle of `archive_snapshot_package()`, the temporary staging
-/// directory won't be cleaned up.  Call this function to clean them up.
+/// directory and partially written archive won't be cleaned up, and neither will a partially
+/// downloaded archive.  Call this function to clean them up.
 pub fn remove_tmp_snapshot_archives(snapshot_archives_dir: impl AsRef<Path>) {
     if let Ok(entries) = fs::read_dir(snapshot_archives_dir) {
         for entry in entries.filter_map(|entry| entry.ok()) {
@@ -228,47 +486,136 @@ pub fn remove_tmp_snapshot_archives(snapshot_archives_dir: impl AsRef<Path>) {
                 .file_name()
                 .into_string()
                 .unwrap_or_else(|_| String::new());
+            let path = entry.path();
             if file_name.starts_with(TMP_SNAPSHOT_ARCHIVE_PREFIX) {
-                if entry.path().is_file() {
-                    fs::remove_file(entry.path())
+                if path.is_file() {
+                    fs::remove_file(&path)
                 } else {
-                    fs::remove_dir_all(entry.path())
+                    fs::remove_dir_all(&path)
                 }
-                .unwrap_or_else(|err| {
-                    warn!("Failed to remove {}: {}", entry.path().display(), err)
-                });
+                .unwrap_or_else(|err| warn!("Failed to remove {}: {}", path.display(), err));
+            } else if is_incomplete_snapshot_archive_file_name(&file_name) && path.is_file() {
+                info!("Removing incomplete snapshot archive {}", path.display());
+                fs::remove_file(&path)
+                    .unwrap_or_else(|err| warn!("Failed to remove {}: {}", path.display(), err));
             }
         }
     }
 }
 
-/// Make a snapshot archive out of the snapshot package
+/// Hashes everything written through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::default(),
+        }
+    }
+
+    fn finish(self) -> (W, Hash) {
+        (self.inner, self.hasher.result())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.hash(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hash the contents of the file at `path`, the same way `HashingWriter` would have
+fn hash_file(path: &Path) -> std::io::Result<Hash> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut writer = HashingWriter::new(std::io::sink());
+    std::io::copy(&mut reader, &mut writer)?;
+    Ok(writer.finish().1)
+}
+
+/// Make a snapshot archive out of the snapshot package, as configured by `snapshot_config`.  If
+/// the storages are encrypted, they go into the archive as they are, along with the id of their
+/// master key, and can only be loaded with the same key.  The archive file is given the configured
+/// permissions before anything is written to it.
 pub fn archive_snapshot_package(
     snapshot_package: &SnapshotPackage,
-    maximum_full_snapshot_archives_to_retain: usize,
-    maximum_incremental_snapshot_archives_to_retain: usize,
+    snapshot_config: &SnapshotConfig,
 ) -> Result<()> {
+    let durability = snapshot_config.durability;
+    if let Some(compression_level) = snapshot_config.archive_compression_level {
+        if !is_valid_archive_compression_level(snapshot_package.archive_format(), compression_level)
+        {
+            return Err(SnapshotError::IoWithSource(
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{} is not a compression level for {:?} archives",
+                        compression_level,
+                        snapshot_package.archive_format()
+                    ),
+                ),
+                "archive compression level",
+            ));
+        }
+    }
     info!(
         "Generating snapshot archive for slot {}",
         snapshot_package.slot()
     );
+    // Carry on with the profile of taking the bank snapshot, if there is one
+    let mut profiler = if snapshot_package.profiler.is_enabled() {
+        snapshot_package.profiler.clone()
+    } else {
+        SnapshotProfiler::new(
+            SnapshotProfileKind::Create,
+            snapshot_package.slot(),
+            snapshot_config.profile,
+        )
+    };
 
+    let mut measure_status_cache = Measure::start("serialize-status-cache");
+    let status_cache_path = snapshot_package
+        .snapshot_links
+        .path()
+        .join(SNAPSHOT_STATUS_CACHE_FILE_NAME);
     serialize_status_cache(
         snapshot_package.slot(),
         &snapshot_package.slot_deltas,
-        &snapshot_package
-            .snapshot_links
-            .path()
-            .join(SNAPSHOT_STATUS_CACHE_FILE_NAME),
+        &status_cache_path,
+        durability,
+        snapshot_config.file_permissions,
+        snapshot_package.deterministic_serialization,
     )?;
+    measure_status_cache.stop();
+    profiler.record(
+        "serialize-status-cache",
+        measure_status_cache.as_us(),
+        profiler
+            .is_enabled()
+            .then(|| fs::metadata(&status_cache_path).ok())
+            .flatten()
+            .map(|metadata| metadata.len()),
+    );
 
     let mut timer = Measure::start("snapshot_package-package_snapshots");
+    let mut measure_phase = Measure::start("stage-snapshot");
     let tar_dir = snapshot_package
         .path()
         .parent()
         .expect("Tar output path is invalid");
 
-    fs::create_dir_all(tar_dir)
+    let file_permissions = snapshot_config.file_permissions;
+    file_permissions
+        .create_dir_all(tar_dir)
         .map_err(|e| SnapshotError::IoWithSource(e, "create archive path"))?;
 
     // Create the staging directories
@@ -281,11 +628,15 @@ pub fn archive_snapshot_package(
         ))
         .tempdir_in(tar_dir)
         .map_err(|e| SnapshotError::IoWithSource(e, "create archive tempdir"))?;
+    file_permissions
+        .set_dir_permissions(staging_dir.path())
+        .map_err(|e| SnapshotError::IoWithSource(e, "archive tempdir permissions"))?;
 
     let staging_accounts_dir = staging_dir.path().join("accounts");
     let staging_snapshots_dir = staging_dir.path().join("snapshots");
     let staging_version_file = staging_dir.path().join("version");
-    fs::create_dir_all(&staging_accounts_dir)
+    file_permissions
+        .create_dir_all(&staging_accounts_dir)
         .map_err(|e| SnapshotError::IoWithSource(e, "create staging path"))?;
 
     // Add the snapshots to the staging directory
@@ -295,14 +646,34 @@ pub fn archive_snapshot_package(
     )
     .map_err(|e| SnapshotError::IoWithSource(e, "create staging symlinks"))?;
 
-    // Add the AppendVecs into the compressible list
+    // Add the AppendVecs into the compressible list.  A deterministic snapshot archives them under
+    // the same canonical ids that its bank snapshot lists them with.
+    let deterministic = snapshot_package.deterministic_serialization;
+    let canonical_ids = deterministic.then(|| {
+        canonical_storage_ids(
+            snapshot_package
+                .snapshot_storages
+                .iter()
+                .flatten()
+                .map(|storage| (storage.slot(), storage.append_vec_id())),
+        )
+    });
+    let mut deterministic_storages = vec![];
+    let mut storage_master_key_ids = HashSet::new();
     for storage in snapshot_package.snapshot_storages.iter().flatten() {
         storage.flush()?;
+        storage_master_key_ids.insert(storage.accounts.storage_master_key_id());
         let storage_path = storage.get_path();
-        let output_path = staging_accounts_dir.join(crate::append_vec::AppendVec::file_name(
-            storage.slot(),
-            storage.append_vec_id(),
-        ));
+        let id = canonical_ids
+            .as_ref()
+            .map_or(storage.append_vec_id(), |canonical_ids| {
+                canonical_ids[&(storage.slot(), storage.append_vec_id())]
+            });
+        let file_name = crate::append_vec::AppendVec::file_name(storage.slot(), id);
+        let output_path = staging_accounts_dir.join(&file_name);
+        if deterministic {
+            deterministic_storages.push((id, file_name, storage.accounts.len()));
+        }
 
         // `storage_path` - The file path where the AppendVec itself is located
         // `output_path` - The file path where the AppendVec will be placed in the staging directory.
@@ -317,70 +688,211 @@ pub fn archive_snapshot_package(
 
     // Write version file
     {
-        let mut f = fs::File::create(staging_version_file)
+        let mut f = file_permissions
+            .create_file(staging_version_file)
             .map_err(|e| SnapshotError::IoWithSource(e, "create version file"))?;
         f.write_all(snapshot_package.snapshot_version.as_str().as_bytes())
             .map_err(|e| SnapshotError::IoWithSource(e, "write version file"))?;
     }
+    measure_phase.stop();
+    profiler.record("stage-snapshot", measure_phase.as_us(), None);
+
+    // Storages are either all encrypted, under the same master key, or not at all
+    let storage_master_key_id = match storage_master_key_ids.into_iter().collect::<Vec<_>>()[..] {
+        [] | [None] => None,
+        [Some(storage_master_key_id)] => Some(storage_master_key_id),
+        _ => {
+            return Err(SnapshotError::IoWithSource(
+                IoError::new(
+                    ErrorKind::InvalidData,
+                    "snapshot storages are not all encrypted under the same master key",
+                ),
+                "storage master key id",
+            ))
+        }
+    };
+    if deterministic && storage_master_key_id.is_some() {
+        return Err(SnapshotError::IoWithSource(
+            IoError::new(
+                ErrorKind::InvalidInput,
+                "encrypted storages are sealed under random keys, so they cannot be archived \
+                 deterministically",
+            ),
+            "deterministic snapshot serialization",
+        ));
+    }
+    deterministic_storages.sort_unstable();
+    if let Some(storage_master_key_id) = storage_master_key_id {
+        serialize_snapshot_data_file_capped(
+            &staging_dir.path().join(STORAGE_MASTER_KEY_ID_FILE_NAME),
+            MAX_SNAPSHOT_DATA_FILE_SIZE,
+            SnapshotDurability::default(),
+            file_permissions,
+            |stream| {
+                serialize_into(stream, &storage_master_key_id)?;
+                Ok(())
+            },
+        )?;
+    }
 
-    let file_ext = get_archive_ext(snapshot_package.archive_format());
-
-    // Tar the staging directory into the archive at `archive_path`
-    let archive_path = tar_dir.join(format!(
-        "{}{}.{}",
-        staging_dir_prefix,
-        snapshot_package.slot(),
-        file_ext
-    ));
+    // Tar the staging directory into the archive at `archive_path`, which is only renamed to its
+    // final name once it has been checked against what was written
+    let mut archive_path = snapshot_package.path().clone().into_os_string();
+    archive_path.push(TMP_SNAPSHOT_ARCHIVE_SUFFIX);
+    let archive_path = PathBuf::from(archive_path);
 
-    {
-        let mut archive_file = fs::File::create(&archive_path)?;
+    let mut measure_phase = Measure::start("write-archive");
+    let archive_hash = {
+        let archive_file = file_permissions
+            .create_file(&archive_path)
+            .map_err(|e| SnapshotError::IoWithSource(e, "create archive file"))?;
+        let mut archive_file = HashingWriter::new(archive_file);
 
         let do_archive_files = |encoder: &mut dyn Write| -> Result<()> {
             let mut archive = tar::Builder::new(encoder);
-            for dir in ["accounts", "snapshots"] {
-                archive.append_dir_all(dir, staging_dir.as_ref().join(dir))?;
+            if deterministic {
+                archive.mode(HeaderMode::Deterministic);
+            }
+            let append_snapshots_dir = |archive: &mut tar::Builder<_>| {
+                if deterministic {
+                    append_dir_all_sorted(archive, Path::new("snapshots"), &staging_snapshots_dir)
+                } else {
+                    archive.append_dir_all("snapshots", &staging_snapshots_dir)
+                }
+            };
+            // When the storages listing is kept apart from the bank manifest, the manifest is
+            // small, so put it at the front of the archive where it can be read on its own.  The
+            // version and the storage master key id go ahead of it, for `serde_snapshot::probe()`
+            // to find.  They go first whenever the storages are encrypted, too, so that
+            // `is_snapshot_archive_encrypted()` can tell such archives apart by their start.
+            let snapshots_first = snapshot_package
+                .snapshot_version
+                .has_separate_storages_file();
+            let version_first = snapshots_first || storage_master_key_id.is_some();
+            if version_first {
+                archive.append_path_with_name(staging_dir.as_ref().join("version"), "version")?;
+                if storage_master_key_id.is_some() {
+                    archive.append_path_with_name(
+                        staging_dir.as_ref().join(STORAGE_MASTER_KEY_ID_FILE_NAME),
+                        STORAGE_MASTER_KEY_ID_FILE_NAME,
+                    )?;
+                }
+            }
+            if snapshots_first {
+                append_snapshots_dir(&mut archive)?;
+            }
+            if deterministic {
+                append_storages_deterministically(
+                    &mut archive,
+                    &staging_accounts_dir,
+                    &deterministic_storages,
+                )?;
+            } else {
+                archive.append_dir_all("accounts", &staging_accounts_dir)?;
+            }
+            if !snapshots_first {
+                append_snapshots_dir(&mut archive)?;
+            }
+            if !version_first {
+                archive.append_path_with_name(staging_dir.as_ref().join("version"), "version")?;
             }
-            archive.append_path_with_name(staging_dir.as_ref().join("version"), "version")?;
             archive.into_inner()?;
             Ok(())
         };
 
-        match snapshot_package.archive_format() {
+        let compression_level = snapshot_config.archive_compression_level;
+        let archive_file = match snapshot_package.archive_format() {
             ArchiveFormat::TarBzip2 => {
-                let mut encoder =
-                    bzip2::write::BzEncoder::new(archive_file, bzip2::Compression::best());
+                let compression = compression_level
+                    .map(|level| bzip2::Compression::new(level as u32))
+                    .unwrap_or_else(bzip2::Compression::best);
+                let mut encoder = bzip2::write::BzEncoder::new(archive_file, compression);
                 do_archive_files(&mut encoder)?;
-                encoder.finish()?;
+                encoder.finish()?
             }
             ArchiveFormat::TarGzip => {
-                let mut encoder =
-                    flate2::write::GzEncoder::new(archive_file, flate2::Compression::default());
+                let compression = compression_level
+                    .map(|level| flate2::Compression::new(level as u32))
+                    .unwrap_or_default();
+                let mut encoder = flate2::write::GzEncoder::new(archive_file, compression);
                 do_archive_files(&mut encoder)?;
-                encoder.finish()?;
+                encoder.finish()?
             }
             ArchiveFormat::TarZstd => {
-                let mut encoder = zstd::stream::Encoder::new(archive_file, 0)?;
+                let mut encoder =
+                    zstd::stream::Encoder::new(archive_file, compression_level.unwrap_or(0))?;
                 do_archive_files(&mut encoder)?;
-                encoder.finish()?;
+                encoder.finish()?
             }
             ArchiveFormat::Tar => {
                 do_archive_files(&mut archive_file)?;
+                archive_file
             }
         };
+        let (archive_file, archive_hash) = archive_file.finish();
+        durability
+            .sync_file(&archive_file)
+            .map_err(|e| SnapshotError::IoWithSource(e, "archive file sync"))?;
+        archive_hash
+    };
+    measure_phase.stop();
+    let metadata = fs::metadata(&archive_path)
+        .map_err(|e| SnapshotError::IoWithSource(e, "archive path stat"))?;
+    profiler.record("write-archive", measure_phase.as_us(), Some(metadata.len()));
+
+    // Make sure what is on disk is what was written before anyone else can see it
+    let mut measure_phase = Measure::start("verify-archive");
+    let written_hash =
+        hash_file(&archive_path).map_err(|e| SnapshotError::IoWithSource(e, "archive hash"))?;
+    if written_hash != archive_hash {
+        let _ = fs::remove_file(&archive_path);
+        return Err(SnapshotError::ArchiveHashMismatch(archive_path));
+    }
+    measure_phase.stop();
+    profiler.record(
+        "verify-archive",
+        measure_phase.as_us(),
+        Some(metadata.len()),
+    );
+
+    if !snapshot_config.hooks.is_empty() {
+        let mut measure_phase = Measure::start("run-hooks-before-publication");
+        if let Err(err) = snapshot_config
+            .hooks
+            .run_before_publication(&archive_path, snapshot_package)
+        {
+            let _ = fs::remove_file(&archive_path);
+            return Err(err);
+        }
+        measure_phase.stop();
+        profiler.record("run-hooks-before-publication", measure_phase.as_us(), None);
     }
 
     // Atomically move the archive into position for other validators to find
-    let metadata = fs::metadata(&archive_path)
-        .map_err(|e| SnapshotError::IoWithSource(e, "archive path stat"))?;
-    fs::rename(&archive_path, &snapshot_package.path())
+    let mut measure_phase = Measure::start("rename-archive");
+    atomic_replace_file(&archive_path, snapshot_package.path())
         .map_err(|e| SnapshotError::IoWithSource(e, "archive path rename"))?;
+    unmark_downloaded_snapshot_archive(snapshot_package.path());
+    durability
+        .sync_dir(tar_dir)
+        .map_err(|e| SnapshotError::IoWithSource(e, "archive dir sync"))?;
+    measure_phase.stop();
+    profiler.record("rename-archive", measure_phase.as_us(), None);
+    if snapshot_package.snapshot_type == SnapshotType::FullSnapshot {
+        snapshot_config
+            .hooks
+            .full_snapshot_published(snapshot_package.slot());
+    }
 
+    let mut measure_phase = Measure::start("purge-old-archives");
     purge_old_snapshot_archives(
         tar_dir,
-        maximum_full_snapshot_archives_to_retain,
-        maximum_incremental_snapshot_archives_to_retain,
+        snapshot_config.maximum_full_snapshot_archives_to_retain,
+        snapshot_config.maximum_incremental_snapshot_archives_to_retain,
     );
+    measure_phase.stop();
+    profiler.record("purge-old-archives", measure_phase.as_us(), None);
+    profiler.finish(snapshot_package.path(), snapshot_config.file_permissions);
 
     timer.stop();
     info!(
@@ -399,6 +911,259 @@ pub fn archive_snapshot_package(
     Ok(())
 }
 
+/// Whether the storages in the snapshot archive at `archive_path` are encrypted.  Archives with
+/// encrypted storages start with their version and the storage master key id, whatever their
+/// version, so only the first few blocks of the archive are decompressed.  Nodes that do not have
+/// the master key cannot load such archives, so they are neither advertised nor served.
+pub fn is_snapshot_archive_encrypted(
+    archive_path: impl AsRef<Path>,
+    archive_format: ArchiveFormat,
+) -> Result<bool> {
+    let archive_file = BufReader::new(File::open(archive_path)?);
+    let tar_stream: Box<dyn Read> = match archive_format {
+        ArchiveFormat::TarBzip2 => Box::new(BzDecoder::new(archive_file)),
+        ArchiveFormat::TarGzip => Box::new(GzDecoder::new(archive_file)),
+        ArchiveFormat::TarZstd => Box::new(zstd::stream::read::Decoder::new(archive_file)?),
+        ArchiveFormat::Tar => Box::new(archive_file),
+    };
+    let mut header_bytes = Vec::with_capacity(serde_snapshot::MAX_PROBE_BYTES);
+    tar_stream
+        .take(serde_snapshot::MAX_PROBE_BYTES as u64)
+        .read_to_end(&mut header_bytes)?;
+    Ok(matches!(
+        serde_snapshot::probe(&header_bytes),
+        SnapshotCompatibility::Compatible { sections, .. } if sections.encrypted_storages
+    ))
+}
+
+/// Probes the start of a snapshot archive with `serde_snapshot::probe()` while it is being
+/// fetched.  The archive is fed in a chunk at a time, each of which is decompressed only once, and
+/// no more of the tar stream is kept than the probe could need.
+pub struct SnapshotArchiveProbe {
+    decoder: ProbeDecoder,
+}
+
+enum ProbeDecoder {
+    Bzip2(bzip2::write::BzDecoder<ProbeBuffer>),
+    Gzip(flate2::write::GzDecoder<ProbeBuffer>),
+    Zstd(zstd::stream::write::Decoder<'static, ProbeBuffer>),
+    Tar(ProbeBuffer),
+}
+
+/// Keeps the first `serde_snapshot::MAX_PROBE_BYTES` of the tar stream, and drops the rest
+#[derive(Default)]
+struct ProbeBuffer(Vec<u8>);
+
+impl Write for ProbeBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf
+            .len()
+            .min(serde_snapshot::MAX_PROBE_BYTES.saturating_sub(self.0.len()));
+        self.0.extend_from_slice(&buf[..len]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ProbeDecoder {
+    /// Decompress `archive_bytes` through to the buffer
+    fn write_through(&mut self, archive_bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Bzip2(decoder) => {
+                decoder.write_all(archive_bytes)?;
+                decoder.flush()
+            }
+            Self::Gzip(decoder) => {
+                decoder.write_all(archive_bytes)?;
+                decoder.flush()
+            }
+            Self::Zstd(decoder) => {
+                decoder.write_all(archive_bytes)?;
+                decoder.flush()
+            }
+            Self::Tar(buffer) => buffer.write_all(archive_bytes),
+        }
+    }
+
+    fn tar_bytes(&self) -> &[u8] {
+        match self {
+            Self::Bzip2(decoder) => &decoder.get_ref().0,
+            Self::Gzip(decoder) => &decoder.get_ref().0,
+            Self::Zstd(decoder) => &decoder.get_ref().0,
+            Self::Tar(buffer) => &buffer.0,
+        }
+    }
+}
+
+impl SnapshotArchiveProbe {
+    pub fn new(archive_format: ArchiveFormat) -> Result<Self> {
+        let buffer = ProbeBuffer::default();
+        let decoder = match archive_format {
+            ArchiveFormat::TarBzip2 => ProbeDecoder::Bzip2(bzip2::write::BzDecoder::new(buffer)),
+            ArchiveFormat::TarGzip => ProbeDecoder::Gzip(flate2::write::GzDecoder::new(buffer)),
+            ArchiveFormat::TarZstd => {
+                ProbeDecoder::Zstd(zstd::stream::write::Decoder::new(buffer)?)
+            }
+            ArchiveFormat::Tar => ProbeDecoder::Tar(buffer),
+        };
+        Ok(Self { decoder })
+    }
+
+    /// Feed the next `archive_bytes` of the archive in, and probe the start of it that has been
+    /// fed so far.  The archive may be cut off anywhere.
+    pub fn probe(&mut self, archive_bytes: &[u8]) -> SnapshotCompatibility {
+        let decompressed = self.decoder.write_through(archive_bytes);
+        match serde_snapshot::probe(self.decoder.tar_bytes()) {
+            // no more of the stream decompresses once it is broken
+            SnapshotCompatibility::Incomplete if decompressed.is_err() => {
+                SnapshotCompatibility::NotASnapshot
+            }
+            compatibility => compatibility,
+        }
+    }
+}
+
+/// Rotate the master key that the storages in a snapshot archive are encrypted under.  Only the
+/// wrapped data keys at the front of each storage change, but as they are stored in the archive,
+/// the whole archive is rewritten to a temporary file that then replaces it.
+pub fn rotate_snapshot_archive_storage_encryption_key(
+    snapshot_archive_info: &impl SnapshotArchiveInfoGetter,
+    old_master_key: &MasterKey,
+    new_master_key: &MasterKey,
+    durability: SnapshotDurability,
+) -> Result<()> {
+    let archive_path = snapshot_archive_info.path();
+    let archive_file = BufReader::new(File::open(archive_path)?);
+    let decoder: Box<dyn Read> = match snapshot_archive_info.archive_format() {
+        ArchiveFormat::TarBzip2 => Box::new(BzDecoder::new(archive_file)),
+        ArchiveFormat::TarGzip => Box::new(GzDecoder::new(archive_file)),
+        ArchiveFormat::TarZstd => Box::new(zstd::stream::read::Decoder::new(archive_file)?),
+        ArchiveFormat::Tar => Box::new(archive_file),
+    };
+
+    let mut rotated_archive_path = archive_path.clone().into_os_string();
+    rotated_archive_path.push(TMP_SNAPSHOT_ARCHIVE_SUFFIX);
+    let rotated_archive_path = PathBuf::from(rotated_archive_path);
+    // The rotated archive is no more visible than the original one, not even before it gets the
+    // original one's permissions
+    let rotated_archive_file = FilePermissions::OWNER_ONLY.create_file(&rotated_archive_path)?;
+    fs::set_permissions(
+        &rotated_archive_path,
+        fs::metadata(archive_path)?.permissions(),
+    )?;
+
+    // Copy every entry over as is, except for the storage master key id, which is replaced, and
+    // the fronts of the storages, which are rewrapped
+    let do_rotate_archive_files = |encoder: &mut dyn Write| -> Result<bool> {
+        let mut archive = Archive::new(decoder);
+        let mut rotated_archive = tar::Builder::new(encoder);
+        let mut found_storage_master_key_id = false;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let mut header = entry.header().clone();
+            if path == Path::new(STORAGE_MASTER_KEY_ID_FILE_NAME) {
+                let storage_master_key_id: Hash = bincode::options()
+                    .with_limit(MAX_SNAPSHOT_DATA_FILE_SIZE)
+                    .with_fixint_encoding()
+                    .deserialize_from(&mut entry)?;
+                if storage_master_key_id != old_master_key.id() {
+                    return Err(SnapshotError::IoWithSource(
+                        IoError::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "snapshot archive storages are encrypted under master key {}, \
+                                 not {}",
+                                storage_master_key_id,
+                                old_master_key.id()
+                            ),
+                        ),
+                        "rewrap storage keys",
+                    ));
+                }
+                let storage_master_key_id = bincode::serialize(&new_master_key.id())?;
+                header.set_size(storage_master_key_id.len() as u64);
+                rotated_archive.append_data(&mut header, path, storage_master_key_id.as_slice())?;
+                found_storage_master_key_id = true;
+            } else if path.starts_with("accounts") && header.entry_type().is_file() {
+                let (prefix, prefix_len) = storage_encryption::rewrap_encrypted_storage(
+                    &mut entry,
+                    old_master_key,
+                    new_master_key,
+                )
+                .map_err(|e| SnapshotError::IoWithSource(e, "rewrap storage keys"))?;
+                header.set_size(header.size()? - prefix_len + prefix.len() as u64);
+                rotated_archive.append_data(&mut header, path, prefix.as_slice().chain(entry))?;
+            } else {
+                rotated_archive.append_data(&mut header, path, &mut entry)?;
+            }
+        }
+        rotated_archive.into_inner()?;
+        Ok(found_storage_master_key_id)
+    };
+
+    let result = (|| -> Result<()> {
+        let (rotated_archive_file, found_storage_master_key_id) = match snapshot_archive_info
+            .archive_format()
+        {
+            ArchiveFormat::TarBzip2 => {
+                let mut encoder =
+                    bzip2::write::BzEncoder::new(rotated_archive_file, bzip2::Compression::best());
+                let found_storage_master_key_id = do_rotate_archive_files(&mut encoder)?;
+                (encoder.finish()?, found_storage_master_key_id)
+            }
+            ArchiveFormat::TarGzip => {
+                let mut encoder = flate2::write::GzEncoder::new(
+                    rotated_archive_file,
+                    flate2::Compression::default(),
+                );
+                let found_storage_master_key_id = do_rotate_archive_files(&mut encoder)?;
+                (encoder.finish()?, found_storage_master_key_id)
+            }
+            ArchiveFormat::TarZstd => {
+                let mut encoder = zstd::stream::Encoder::new(rotated_archive_file, 0)?;
+                let found_storage_master_key_id = do_rotate_archive_files(&mut encoder)?;
+                (encoder.finish()?, found_storage_master_key_id)
+            }
+            ArchiveFormat::Tar => {
+                let mut rotated_archive_file = rotated_archive_file;
+                let found_storage_master_key_id =
+                    do_rotate_archive_files(&mut rotated_archive_file)?;
+                (rotated_archive_file, found_storage_master_key_id)
+            }
+        };
+        if !found_storage_master_key_id {
+            return Err(SnapshotError::IoWithSource(
+                IoError::new(
+                    ErrorKind::InvalidInput,
+                    "snapshot archive storages are not encrypted",
+                ),
+                "rewrap storage keys",
+            ));
+        }
+        durability
+            .sync_file(&rotated_archive_file)
+            .map_err(|e| SnapshotError::IoWithSource(e, "archive file sync"))?;
+        Ok(())
+    })();
+    if let Err(err) = result {
+        let _ = fs::remove_file(&rotated_archive_path);
+        return Err(err);
+    }
+
+    atomic_replace_file(&rotated_archive_path, archive_path)
+        .map_err(|e| SnapshotError::IoWithSource(e, "archive path rename"))?;
+    if let Some(archive_dir) = archive_path.parent() {
+        durability
+            .sync_dir(archive_dir)
+            .map_err(|e| SnapshotError::IoWithSource(e, "archive dir sync"))?;
+    }
+    Ok(())
+}
+
 /// Get a list of bank snapshots in a directory
 pub fn get_bank_snapshots<P>(bank_snapshots_dir: P) -> Vec<BankSnapshotInfo>
 where
@@ -456,6 +1221,8 @@ where
     serialize_snapshot_data_file_capped::<F>(
         data_file_path,
         MAX_SNAPSHOT_DATA_FILE_SIZE,
+        SnapshotDurability::default(),
+        FilePermissions::default(),
         serializer,
     )
 }
@@ -471,6 +1238,10 @@ pub fn deserialize_snapshot_data_file<T: Sized>(
     let wrapped_data_file_path = SnapshotRootPaths {
         full_snapshot_root_file_path: data_file_path.to_path_buf(),
         incremental_snapshot_root_file_path: None,
+        full_snapshot_storages_file_path: None,
+        incremental_snapshot_storages_file_path: None,
+        full_snapshot_version: SnapshotVersion::default(),
+        incremental_snapshot_version: None,
     };
 
     deserialize_snapshot_data_files_capped(
@@ -494,15 +1265,21 @@ fn deserialize_snapshot_data_files<T: Sized>(
 fn serialize_snapshot_data_file_capped<F>(
     data_file_path: &Path,
     maximum_file_size: u64,
+    durability: SnapshotDurability,
+    file_permissions: FilePermissions,
     serializer: F,
 ) -> Result<u64>
 where
     F: FnOnce(&mut BufWriter<File>) -> Result<()>,
 {
-    let data_file = File::create(data_file_path)?;
+    let data_file = file_permissions.create_file(data_file_path)?;
     let mut data_file_stream = BufWriter::new(data_file);
     serializer(&mut data_file_stream)?;
     data_file_stream.flush()?;
+    durability.sync_file(data_file_stream.get_ref())?;
+    if let Some(data_file_dir) = data_file_path.parent() {
+        durability.sync_dir(data_file_dir)?;
+    }
 
     let consumed_size = data_file_stream.stream_position()?;
     if consumed_size > maximum_file_size {
@@ -543,9 +1320,28 @@ fn deserialize_snapshot_data_files_capped<T: Sized>(
             (None, None)
         };
 
+    let open_storages_file_stream = |snapshot_storages_file_path: &Option<PathBuf>| {
+        snapshot_storages_file_path
+            .as_ref()
+            .map(|path| create_snapshot_data_file_stream(path, maximum_file_size))
+            .transpose()
+    };
+    let mut full_snapshot_storages_file_stream =
+        open_storages_file_stream(&snapshot_root_paths.full_snapshot_storages_file_path)?;
+    let mut incremental_snapshot_storages_file_stream =
+        open_storages_file_stream(&snapshot_root_paths.incremental_snapshot_storages_file_path)?;
+
     let mut snapshot_streams = SnapshotStreams {
         full_snapshot_stream: &mut full_snapshot_data_file_stream,
         incremental_snapshot_stream: incremental_snapshot_data_file_stream.as_mut(),
+        full_snapshot_storages_stream: full_snapshot_storages_file_stream
+            .as_mut()
+            .map(|(_, stream)| stream),
+        incremental_snapshot_storages_stream: incremental_snapshot_storages_file_stream
+            .as_mut()
+            .map(|(_, stream)| stream),
+        full_snapshot_version: snapshot_root_paths.full_snapshot_version,
+        incremental_snapshot_version: snapshot_root_paths.incremental_snapshot_version,
     };
     let ret = deserializer(&mut snapshot_streams)?;
 
@@ -565,6 +1361,23 @@ fn deserialize_snapshot_data_files_capped<T: Sized>(
         )?;
     }
 
+    for (snapshot_storages_file_path, snapshot_storages_file_stream) in [
+        (
+            &snapshot_root_paths.full_snapshot_storages_file_path,
+            &mut full_snapshot_storages_file_stream,
+        ),
+        (
+            &snapshot_root_paths.incremental_snapshot_storages_file_path,
+            &mut incremental_snapshot_storages_file_stream,
+        ),
+    ] {
+        if let (Some(file_path), Some((file_size, file_stream))) =
+            (snapshot_storages_file_path, snapshot_storages_file_stream)
+        {
+            check_deserialize_file_consumed(*file_size, file_path, file_stream)?;
+        }
+    }
+
     Ok(ret)
 }
 
@@ -619,16 +1432,25 @@ where
 }
 
 /// Serialize a bank to a snapshot
-pub fn add_bank_snapshot<P: AsRef<Path>>(
+///
+/// The storages are usually the bank's own (i.e. `&[SnapshotStorage]`), but any
+/// `SnapshotStoragesProvider` can be used to synthesize a snapshot from other storages.
+pub fn add_bank_snapshot<P, S>(
     bank_snapshots_dir: P,
     bank: &Bank,
-    snapshot_storages: &[SnapshotStorage],
+    snapshot_storages: &S,
     snapshot_version: SnapshotVersion,
-) -> Result<BankSnapshotInfo> {
+    durability: SnapshotDurability,
+    file_permissions: FilePermissions,
+) -> Result<BankSnapshotInfo>
+where
+    P: AsRef<Path>,
+    S: SnapshotStoragesProvider + ?Sized,
+{
     let slot = bank.slot();
     // bank_snapshots_dir/slot
     let bank_snapshots_dir = get_bank_snapshots_dir(bank_snapshots_dir, slot);
-    fs::create_dir_all(&bank_snapshots_dir)?;
+    file_permissions.create_dir_all(&bank_snapshots_dir)?;
 
     // the bank snapshot is stored as bank_snapshots_dir/slot/slot
     let snapshot_bank_file_path = bank_snapshots_dir.join(get_snapshot_file_name(slot));
@@ -638,15 +1460,58 @@ pub fn add_bank_snapshot<P: AsRef<Path>>(
     );
 
     let mut bank_serialize = Measure::start("bank-serialize-ms");
-    let bank_snapshot_serializer = move |stream: &mut BufWriter<File>| -> Result<()> {
-        let serde_style = match snapshot_version {
-            SnapshotVersion::V1_2_0 => SerdeStyle::Newer,
-        };
-        bank_to_stream(serde_style, stream.by_ref(), bank, snapshot_storages)?;
-        Ok(())
+    let serde_style = match snapshot_version {
+        SnapshotVersion::V1_2_0 | SnapshotVersion::V1_3_0 => SerdeStyle::Newer,
+    };
+    let consumed_size = if snapshot_version.has_separate_storages_file() {
+        // The manifest goes out without any storages; they are listed in their own file instead
+        let no_snapshot_storages: &[SnapshotStorage] = &[];
+        let slot_delta_hashes = snapshot_storages.slot_delta_hashes();
+        let consumed_size = serialize_snapshot_data_file_capped(
+            &snapshot_bank_file_path,
+            MAX_SNAPSHOT_DATA_FILE_SIZE,
+            durability,
+            file_permissions,
+            |stream| {
+                bank_to_stream(serde_style, stream.by_ref(), bank, no_snapshot_storages)?;
+                slot_delta_hashes_to_stream(stream.by_ref(), &slot_delta_hashes)?;
+                Ok(())
+            },
+        )?;
+        let snapshot_storages_file_path = get_snapshot_storages_file_path(&snapshot_bank_file_path);
+        let storages_consumed_size = serialize_snapshot_data_file_capped(
+            &snapshot_storages_file_path,
+            MAX_SNAPSHOT_DATA_FILE_SIZE,
+            durability,
+            file_permissions,
+            |stream| {
+                account_storage_entries_to_stream(
+                    serde_style,
+                    stream.by_ref(),
+                    &bank.rc.accounts.accounts_db,
+                    snapshot_storages,
+                )?;
+                Ok(())
+            },
+        )?;
+        datapoint_info!(
+            "snapshot-bank-storages-file",
+            ("slot", slot, i64),
+            ("size", storages_consumed_size, i64)
+        );
+        consumed_size
+    } else {
+        serialize_snapshot_data_file_capped(
+            &snapshot_bank_file_path,
+            MAX_SNAPSHOT_DATA_FILE_SIZE,
+            durability,
+            file_permissions,
+            |stream| {
+                bank_to_stream(serde_style, stream.by_ref(), bank, snapshot_storages)?;
+                Ok(())
+            },
+        )?
     };
-    let consumed_size =
-        serialize_snapshot_data_file(&snapshot_bank_file_path, bank_snapshot_serializer)?;
     bank_serialize.stop();
 
     // Monitor sizes because they're capped to MAX_SNAPSHOT_DATA_FILE_SIZE
@@ -673,12 +1538,28 @@ fn serialize_status_cache(
     slot: Slot,
     slot_deltas: &[BankSlotDelta],
     status_cache_path: &Path,
+    durability: SnapshotDurability,
+    file_permissions: FilePermissions,
+    deterministic: bool,
 ) -> Result<()> {
     let mut status_cache_serialize = Measure::start("status_cache_serialize-ms");
-    let consumed_size = serialize_snapshot_data_file(status_cache_path, |stream| {
-        serialize_into(stream, slot_deltas)?;
-        Ok(())
-    })?;
+    let consumed_size = serialize_snapshot_data_file_capped(
+        status_cache_path,
+        MAX_SNAPSHOT_DATA_FILE_SIZE,
+        durability,
+        file_permissions,
+        |stream| {
+            if deterministic {
+                // the slots come out of a hash set
+                let mut slot_deltas = slot_deltas.iter().collect::<Vec<_>>();
+                slot_deltas.sort_unstable_by_key(|(slot, _, _)| *slot);
+                canonical_serialize_into(stream, &slot_deltas)?;
+            } else {
+                serialize_into(stream, slot_deltas)?;
+            }
+            Ok(())
+        },
+    )?;
     status_cache_serialize.stop();
 
     // Monitor sizes because they're capped to MAX_SNAPSHOT_DATA_FILE_SIZE
@@ -695,13 +1576,60 @@ fn serialize_status_cache(
     Ok(())
 }
 
-/// Remove the snapshot directory for this slot
-pub fn remove_bank_snapshot<P>(slot: Slot, bank_snapshots_dir: P) -> Result<()>
-where
-    P: AsRef<Path>,
-{
-    let bank_snapshot_dir = get_bank_snapshots_dir(&bank_snapshots_dir, slot);
-    fs::remove_dir_all(bank_snapshot_dir)?;
+/// Like `tar::Builder::append_dir_all()`, but with the entries of each directory in name order,
+/// rather than in whatever order the file system lists them in
+fn append_dir_all_sorted<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &Path,
+    src_path: &Path,
+) -> io::Result<()> {
+    archive.append_dir(path, src_path)?;
+    let mut file_names = fs::read_dir(src_path)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    file_names.sort_unstable();
+    for file_name in file_names {
+        let (path, src_path) = (path.join(&file_name), src_path.join(&file_name));
+        if src_path.is_dir() {
+            append_dir_all_sorted(archive, &path, &src_path)?;
+        } else {
+            archive.append_path_with_name(&src_path, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Append the staged storages of a deterministic snapshot, `(canonical id, file name, current
+/// length)` in canonical id order, under `accounts/`.  The bytes past their current lengths are
+/// archived as zeros, since they are whatever the append vecs held before they were recycled.
+fn append_storages_deterministically<W: Write>(
+    archive: &mut tar::Builder<W>,
+    staging_accounts_dir: &Path,
+    storages: &[(AppendVecId, String, usize)],
+) -> io::Result<()> {
+    let accounts_dir = Path::new("accounts");
+    archive.append_dir(accounts_dir, staging_accounts_dir)?;
+    for (_id, file_name, current_len) in storages {
+        let file = File::open(staging_accounts_dir.join(file_name))?;
+        let metadata = file.metadata()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Deterministic);
+        let current_len = (*current_len as u64).min(metadata.len());
+        let data = file
+            .take(current_len)
+            .chain(io::repeat(0).take(metadata.len() - current_len));
+        archive.append_data(&mut header, accounts_dir.join(file_name), data)?;
+    }
+    Ok(())
+}
+
+/// Remove the snapshot directory for this slot
+pub fn remove_bank_snapshot<P>(slot: Slot, bank_snapshots_dir: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let bank_snapshot_dir = get_bank_snapshots_dir(&bank_snapshots_dir, slot);
+    fs::remove_dir_all(bank_snapshot_dir)?;
     Ok(())
 }
 
@@ -711,6 +1639,7 @@ pub struct BankFromArchiveTimings {
     pub full_snapshot_untar_us: u64,
     pub incremental_snapshot_untar_us: u64,
     pub verify_snapshot_bank_us: u64,
+    pub reconstruct_accounts_db_stats: ReconstructAccountsDbStats,
 }
 
 // From testing, 4 seems to be a sweet spot for ranges of 60M-360M accounts and 16-64 cores. This may need to be tuned later.
@@ -735,44 +1664,36 @@ pub fn bank_from_snapshot_archives(
     test_hash_calculation: bool,
     accounts_db_skip_shrink: bool,
     verify_index: bool,
+    snapshot_config: &SnapshotConfig,
     accounts_db_config: Option<AccountsDbConfig>,
     accounts_update_notifier: Option<AccountsUpdateNotifier>,
 ) -> Result<(Bank, BankFromArchiveTimings)> {
-    check_are_snapshots_compatible(
+    let latest_snapshot_archive_path = incremental_snapshot_archive_info
+        .map_or(
+            full_snapshot_archive_info.path(),
+            |incremental_snapshot_archive_info| incremental_snapshot_archive_info.path(),
+        )
+        .clone();
+    let accounts_db_config = accounts_db_config_for_snapshot_archives(
+        accounts_db_config,
         full_snapshot_archive_info,
         incremental_snapshot_archive_info,
-    )?;
-
-    let parallel_divisions = std::cmp::min(
-        PARALLEL_UNTAR_READERS_DEFAULT,
-        std::cmp::max(1, num_cpus::get() / 4),
     );
-
-    let unarchived_full_snapshot = unarchive_snapshot(
-        &bank_snapshots_dir,
-        TMP_SNAPSHOT_ARCHIVE_PREFIX,
-        full_snapshot_archive_info.path(),
-        "snapshot untar",
-        account_paths,
-        full_snapshot_archive_info.archive_format(),
-        parallel_divisions,
-    )?;
-
-    let mut unarchived_incremental_snapshot =
-        if let Some(incremental_snapshot_archive_info) = incremental_snapshot_archive_info {
-            let unarchived_incremental_snapshot = unarchive_snapshot(
-                &bank_snapshots_dir,
-                TMP_SNAPSHOT_ARCHIVE_PREFIX,
-                incremental_snapshot_archive_info.path(),
-                "incremental snapshot untar",
-                account_paths,
-                incremental_snapshot_archive_info.archive_format(),
-                parallel_divisions,
-            )?;
-            Some(unarchived_incremental_snapshot)
-        } else {
-            None
-        };
+    let mut profiler = SnapshotProfiler::new(
+        SnapshotProfileKind::Load,
+        incremental_snapshot_archive_info
+            .map_or(full_snapshot_archive_info.slot(), |info| info.slot()),
+        snapshot_config.profile,
+    );
+    let (unarchived_full_snapshot, mut unarchived_incremental_snapshot) =
+        unarchive_snapshot_archives(
+            &bank_snapshots_dir,
+            account_paths,
+            full_snapshot_archive_info,
+            incremental_snapshot_archive_info,
+            snapshot_config,
+            &mut profiler,
+        )?;
 
     let mut unpacked_append_vec_map = unarchived_full_snapshot.unpacked_append_vec_map;
     if let Some(ref mut unarchive_preparation_result) = unarchived_incremental_snapshot {
@@ -782,7 +1703,7 @@ pub fn bank_from_snapshot_archives(
     }
 
     let mut measure_rebuild = Measure::start("rebuild bank from snapshots");
-    let bank = rebuild_bank_from_snapshots(
+    let (bank, reconstruct_accounts_db_stats) = rebuild_bank_from_snapshots(
         &unarchived_full_snapshot.unpacked_snapshots_dir_and_version,
         unarchived_incremental_snapshot
             .as_ref()
@@ -805,6 +1726,17 @@ pub fn bank_from_snapshot_archives(
     )?;
     measure_rebuild.stop();
     info!("{}", measure_rebuild);
+    profiler.record(
+        "remap-storages",
+        reconstruct_accounts_db_stats.remap_us,
+        None,
+    );
+    profiler.record(
+        "notify-restored-accounts",
+        reconstruct_accounts_db_stats.notify_us,
+        None,
+    );
+    profiler.record("rebuild-bank", measure_rebuild.as_us(), None);
 
     let mut measure_verify = Measure::start("verify");
     if !bank.verify_snapshot_bank(
@@ -816,6 +1748,19 @@ pub fn bank_from_snapshot_archives(
         panic!("Snapshot bank for slot {} failed to verify", bank.slot());
     }
     measure_verify.stop();
+    profiler.record("verify-bank", measure_verify.as_us(), None);
+    profiler.finish(
+        latest_snapshot_archive_path,
+        snapshot_config.file_permissions,
+    );
+
+    // Now that the bank is verified, or is being verified in the background, the filler
+    // accounts can go in alongside whatever is done with it next, if they were left out of
+    // rebuilding it
+    bank.rc
+        .accounts
+        .accounts_db
+        .maybe_add_filler_accounts_in_background(genesis_config.epoch_schedule);
 
     let timings = BankFromArchiveTimings {
         rebuild_bank_from_snapshots_us: measure_rebuild.as_us(),
@@ -825,10 +1770,79 @@ pub fn bank_from_snapshot_archives(
                 unarchive_preparation_result.measure_untar.as_us()
             }),
         verify_snapshot_bank_us: measure_verify.as_us(),
+        reconstruct_accounts_db_stats,
     };
     Ok((bank, timings))
 }
 
+/// Unpack a full snapshot archive, and optionally an incremental snapshot archive, into
+/// `bank_snapshots_dir` and `account_paths`.  Encrypted storages are unpacked as they are, and
+/// only decrypted as the AccountsDb opens them.  The time each step takes is recorded with
+/// `profiler`.
+fn unarchive_snapshot_archives(
+    bank_snapshots_dir: impl AsRef<Path>,
+    account_paths: &[PathBuf],
+    full_snapshot_archive_info: &FullSnapshotArchiveInfo,
+    incremental_snapshot_archive_info: Option<&IncrementalSnapshotArchiveInfo>,
+    snapshot_config: &SnapshotConfig,
+    profiler: &mut SnapshotProfiler,
+) -> Result<(UnarchivedSnapshot, Option<UnarchivedSnapshot>)> {
+    check_are_snapshots_compatible(
+        full_snapshot_archive_info,
+        incremental_snapshot_archive_info,
+    )?;
+
+    let parallel_divisions = std::cmp::min(
+        PARALLEL_UNTAR_READERS_DEFAULT,
+        std::cmp::max(1, num_cpus::get() / 4),
+    );
+    let archive_bytes = |snapshot_archive_path: &Path| {
+        fs::metadata(snapshot_archive_path)
+            .ok()
+            .map(|metadata| metadata.len())
+    };
+
+    let unarchived_full_snapshot = unarchive_snapshot(
+        &bank_snapshots_dir,
+        TMP_SNAPSHOT_ARCHIVE_PREFIX,
+        full_snapshot_archive_info.path(),
+        "snapshot untar",
+        account_paths,
+        full_snapshot_archive_info.archive_format(),
+        parallel_divisions,
+        snapshot_config.file_permissions,
+    )?;
+    profiler.record(
+        "unarchive-full-snapshot",
+        unarchived_full_snapshot.measure_untar.as_us(),
+        archive_bytes(full_snapshot_archive_info.path()),
+    );
+
+    let unarchived_incremental_snapshot =
+        if let Some(incremental_snapshot_archive_info) = incremental_snapshot_archive_info {
+            let unarchived_incremental_snapshot = unarchive_snapshot(
+                &bank_snapshots_dir,
+                TMP_SNAPSHOT_ARCHIVE_PREFIX,
+                incremental_snapshot_archive_info.path(),
+                "incremental snapshot untar",
+                account_paths,
+                incremental_snapshot_archive_info.archive_format(),
+                parallel_divisions,
+                snapshot_config.file_permissions,
+            )?;
+            profiler.record(
+                "unarchive-incremental-snapshot",
+                unarchived_incremental_snapshot.measure_untar.as_us(),
+                archive_bytes(incremental_snapshot_archive_info.path()),
+            );
+            Some(unarchived_incremental_snapshot)
+        } else {
+            None
+        };
+
+    Ok((unarchived_full_snapshot, unarchived_incremental_snapshot))
+}
+
 /// Rebuild bank from snapshot archives.  This function searches `snapshot_archives_dir` for the
 /// highest full snapshot and highest corresponding incremental snapshot, then rebuilds the bank.
 #[allow(clippy::too_many_arguments)]
@@ -847,6 +1861,7 @@ pub fn bank_from_latest_snapshot_archives(
     test_hash_calculation: bool,
     accounts_db_skip_shrink: bool,
     verify_index: bool,
+    snapshot_config: &SnapshotConfig,
     accounts_db_config: Option<AccountsDbConfig>,
     accounts_update_notifier: Option<AccountsUpdateNotifier>,
 ) -> Result<(
@@ -891,6 +1906,7 @@ pub fn bank_from_latest_snapshot_archives(
         test_hash_calculation,
         accounts_db_skip_shrink,
         verify_index,
+        snapshot_config,
         accounts_db_config,
         accounts_update_notifier,
     )?;
@@ -915,6 +1931,175 @@ pub fn bank_from_latest_snapshot_archives(
     ))
 }
 
+/// Export every account owned by `program_id` as of the full (and incremental) snapshot archive
+/// to `output_path`, along with the slot and hash of the archives they came from, once the
+/// accounts have been verified against that hash.  Only the AccountsDb is rebuilt, in
+/// `account_paths` and as configured by `accounts_db_config` (e.g. with the storage encryption
+/// key), not the whole bank.  See `program_accounts_snapshot` for the format, and for reading the
+/// export back.
+#[allow(clippy::too_many_arguments)]
+pub fn export_program_accounts_from_snapshot_archives(
+    account_paths: &[PathBuf],
+    bank_snapshots_dir: impl AsRef<Path>,
+    full_snapshot_archive_info: &FullSnapshotArchiveInfo,
+    incremental_snapshot_archive_info: Option<&IncrementalSnapshotArchiveInfo>,
+    genesis_config: &GenesisConfig,
+    snapshot_config: &SnapshotConfig,
+    accounts_db_config: Option<AccountsDbConfig>,
+    program_id: &Pubkey,
+    output_path: impl AsRef<Path>,
+) -> Result<ProgramAccountsSnapshotHeader> {
+    let accounts_db_config = accounts_db_config_for_snapshot_archives(
+        accounts_db_config,
+        full_snapshot_archive_info,
+        incremental_snapshot_archive_info,
+    );
+    // The export is not profiled, only the loading of banks is
+    let (unarchived_full_snapshot, mut unarchived_incremental_snapshot) =
+        unarchive_snapshot_archives(
+            &bank_snapshots_dir,
+            account_paths,
+            full_snapshot_archive_info,
+            incremental_snapshot_archive_info,
+            snapshot_config,
+            &mut SnapshotProfiler::new(
+                SnapshotProfileKind::Load,
+                full_snapshot_archive_info.slot(),
+                false,
+            ),
+        )?;
+
+    let (snapshot_version, snapshot_root_paths) = verify_unpacked_snapshots(
+        &unarchived_full_snapshot.unpacked_snapshots_dir_and_version,
+        unarchived_incremental_snapshot
+            .as_ref()
+            .map(|unarchived_snapshot| &unarchived_snapshot.unpacked_snapshots_dir_and_version),
+    )?;
+    // The unarchived snapshots have to be kept around, as their bank snapshots are removed along
+    // with them
+    let mut unpacked_append_vec_map = unarchived_full_snapshot.unpacked_append_vec_map;
+    if let Some(unarchived_incremental_snapshot) = unarchived_incremental_snapshot.as_mut() {
+        unpacked_append_vec_map.extend(std::mem::take(
+            &mut unarchived_incremental_snapshot.unpacked_append_vec_map,
+        ));
+    }
+
+    // Only the program's accounts make it into the secondary index, so that they can be scanned
+    // without going through all of the others
+    let account_secondary_indexes = AccountSecondaryIndexes {
+        keys: Some(AccountSecondaryIndexesIncludeExclude {
+            exclude: false,
+            keys: vec![*program_id].into_iter().collect(),
+        }),
+        indexes: vec![AccountIndex::ProgramId].into_iter().collect(),
+    };
+    let (accounts_db, slot, bank_hash_info, capitalization) =
+        deserialize_snapshot_data_files(&snapshot_root_paths, |snapshot_streams| {
+            Ok(accounts_db_from_snapshot_streams(
+                snapshot_version,
+                snapshot_streams,
+                account_paths,
+                unpacked_append_vec_map,
+                genesis_config,
+                account_secondary_indexes,
+                false,
+                None,
+                AccountShrinkThreshold::default(),
+                false,
+                accounts_db_config,
+                None,
+            )?)
+        })?;
+
+    // Make sure the accounts are really the ones the archives claim to hold: the archives are named
+    // after the accounts hash of the snapshot, which has to be the hash the accounts add up to
+    let (expected_slot, expected_hash) = incremental_snapshot_archive_info.map_or(
+        (
+            full_snapshot_archive_info.slot(),
+            *full_snapshot_archive_info.hash(),
+        ),
+        |incremental_snapshot_archive_info| {
+            (
+                incremental_snapshot_archive_info.slot(),
+                *incremental_snapshot_archive_info.hash(),
+            )
+        },
+    );
+    if slot != expected_slot || bank_hash_info.snapshot_hash != expected_hash {
+        return Err(SnapshotError::MismatchedSlotHash(
+            (slot, bank_hash_info.snapshot_hash),
+            (expected_slot, expected_hash),
+        ));
+    }
+    // Every slot in the snapshot is rooted, so no ancestors are needed to see the latest version
+    // of every account
+    let ancestors = Ancestors::default();
+    accounts_db
+        .verify_bank_hash_and_lamports(slot, &ancestors, capitalization, false)
+        .map_err(SnapshotError::AccountsVerificationFailed)?;
+
+    // Only the pubkeys of the program's accounts are held on to, the accounts themselves are
+    // loaded again one by one as they are written out.  The index has every account the program
+    // ever owned in the snapshot, so the latest versions are checked for still being owned by it.
+    let accounts = Accounts::new_empty(accounts_db);
+    let (mut program_account_pubkeys, _used_index) = accounts.accounts_db.index_scan_accounts(
+        &ancestors,
+        0,
+        IndexKey::ProgramId(*program_id),
+        |collector: &mut Vec<Pubkey>, some_account_tuple| {
+            if let Some((pubkey, account, _slot)) = some_account_tuple {
+                if account.lamports() != 0 && account.owner() == program_id {
+                    collector.push(*pubkey);
+                }
+            }
+        },
+        ScanConfig::new(true),
+    )?;
+    program_account_pubkeys.sort_unstable();
+    let program_accounts = program_account_pubkeys.iter().map(|pubkey| {
+        accounts
+            .load_with_fixed_root(&ancestors, pubkey)
+            .map(|(account, _slot)| (*pubkey, account))
+            .ok_or_else(|| {
+                IoError::new(
+                    ErrorKind::NotFound,
+                    format!("account {} could not be loaded again", pubkey),
+                )
+            })
+    });
+
+    let header = ProgramAccountsSnapshotHeader {
+        version: PROGRAM_ACCOUNTS_SNAPSHOT_VERSION,
+        program_id: *program_id,
+        slot,
+        accounts_hash: bank_hash_info.snapshot_hash,
+        full_snapshot_slot: full_snapshot_archive_info.slot(),
+        full_snapshot_hash: *full_snapshot_archive_info.hash(),
+        incremental_snapshot: incremental_snapshot_archive_info
+            .map(|info| (info.slot(), *info.hash())),
+        account_count: program_account_pubkeys.len() as u64,
+    };
+
+    // Like the snapshot archives, the export only shows up under its name once it is complete
+    let output_path = output_path.as_ref();
+    let mut tmp_output_path = output_path.as_os_str().to_owned();
+    tmp_output_path.push(TMP_SNAPSHOT_ARCHIVE_SUFFIX);
+    let tmp_output_path = PathBuf::from(tmp_output_path);
+    let output_file = snapshot_config
+        .file_permissions
+        .create_file(&tmp_output_path)
+        .map_err(|e| SnapshotError::IoWithSource(e, "create program accounts snapshot"))?;
+    write_program_accounts_snapshot(&output_file, &header, program_accounts)
+        .and_then(|_| output_file.sync_all())
+        .and_then(|_| atomic_replace_file(&tmp_output_path, output_path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp_output_path);
+            SnapshotError::IoWithSource(e, "write program accounts snapshot")
+        })?;
+
+    Ok(header)
+}
+
 /// Check to make sure the deserialized bank's slot and hash matches the snapshot archive's slot
 /// and hash
 fn verify_bank_against_expected_slot_hash(
@@ -946,6 +2131,7 @@ fn unarchive_snapshot<P, Q>(
     account_paths: &[PathBuf],
     archive_format: ArchiveFormat,
     parallel_divisions: usize,
+    file_permissions: FilePermissions,
 ) -> Result<UnarchivedSnapshot>
 where
     P: AsRef<Path>,
@@ -954,6 +2140,7 @@ where
     let unpack_dir = tempfile::Builder::new()
         .prefix(unpacked_snapshots_dir_prefix)
         .tempdir_in(bank_snapshots_dir)?;
+    file_permissions.set_dir_permissions(unpack_dir.path())?;
     let unpacked_snapshots_dir = unpack_dir.path().join("snapshots");
 
     let mut measure_untar = Measure::start(measure_name);
@@ -963,6 +2150,7 @@ where
         account_paths,
         archive_format,
         parallel_divisions,
+        file_permissions,
     )?;
     measure_untar.stop();
     info!("{}", measure_untar);
@@ -1008,6 +2196,44 @@ fn check_are_snapshots_compatible(
         })
 }
 
+/// Move the file at `from` to `to`, atomically replacing `to` if it already exists.  Both paths
+/// must be on the same filesystem.
+///
+/// On Unix this is just `rename(2)`.  On Windows, `fs::rename()` (i.e. `MoveFileExW` with
+/// `MOVEFILE_REPLACE_EXISTING`) refuses to replace a read-only file, and fails with "access denied"
+/// for a short while after another process (e.g. a virus scanner or the search indexer) has had
+/// either file open, so those cases are handled here.
+pub(crate) fn atomic_replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    #[cfg(not(windows))]
+    {
+        fs::rename(from, to)
+    }
+    #[cfg(windows)]
+    {
+        const MAX_ACCESS_DENIED_RETRIES: usize = 5;
+
+        if let Ok(metadata) = fs::metadata(to) {
+            let mut permissions = metadata.permissions();
+            if permissions.readonly() {
+                permissions.set_readonly(false);
+                fs::set_permissions(to, permissions)?;
+            }
+        }
+
+        let mut backoff = std::time::Duration::from_millis(10);
+        for _ in 0..MAX_ACCESS_DENIED_RETRIES {
+            match fs::rename(from, to) {
+                Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        fs::rename(from, to)
+    }
+}
+
 /// Get the `&str` from a `&Path`
 pub fn path_to_file_name_str(path: &Path) -> Result<&str> {
     path.file_name()
@@ -1016,6 +2242,57 @@ pub fn path_to_file_name_str(path: &Path) -> Result<&str> {
         .ok_or_else(|| SnapshotError::FileNameToStrError(path.to_path_buf()))
 }
 
+/// Where the marker that records that the snapshot archive at `snapshot_archive_path` was
+/// downloaded from another node is kept
+pub fn downloaded_snapshot_archive_marker_path(snapshot_archive_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = OsString::from(snapshot_archive_path.as_ref());
+    path.push(".downloaded");
+    PathBuf::from(path)
+}
+
+/// Record that the snapshot archive at `snapshot_archive_path` is being downloaded from another
+/// node, so that the storages in it are fully validated every time it is loaded.  This is done
+/// before the download starts, so that a downloaded archive is never found without its marker.
+pub fn mark_downloaded_snapshot_archive(snapshot_archive_path: impl AsRef<Path>) -> Result<()> {
+    let marker_path = downloaded_snapshot_archive_marker_path(snapshot_archive_path);
+    File::create(marker_path)?;
+    Ok(())
+}
+
+/// Remove the marker of the snapshot archive at `snapshot_archive_path`, if it has one
+pub fn unmark_downloaded_snapshot_archive(snapshot_archive_path: impl AsRef<Path>) {
+    let marker_path = downloaded_snapshot_archive_marker_path(snapshot_archive_path);
+    let _ = fs::remove_file(marker_path);
+}
+
+/// Was the snapshot archive at `snapshot_archive_path` downloaded from another node?
+pub fn is_downloaded_snapshot_archive(snapshot_archive_path: impl AsRef<Path>) -> bool {
+    downloaded_snapshot_archive_marker_path(snapshot_archive_path).is_file()
+}
+
+/// The AccountsDb configuration to load the full (and incremental) snapshot archive with, which
+/// is `accounts_db_config` but for the storages of downloaded archives always being fully
+/// validated: only a node's own snapshots are known to hold files written by an `AppendVec`
+fn accounts_db_config_for_snapshot_archives(
+    accounts_db_config: Option<AccountsDbConfig>,
+    full_snapshot_archive_info: &FullSnapshotArchiveInfo,
+    incremental_snapshot_archive_info: Option<&IncrementalSnapshotArchiveInfo>,
+) -> Option<AccountsDbConfig> {
+    let is_downloaded = is_downloaded_snapshot_archive(full_snapshot_archive_info.path())
+        || incremental_snapshot_archive_info
+            .map_or(false, |info| is_downloaded_snapshot_archive(info.path()));
+    accounts_db_config.map(|accounts_db_config| {
+        if is_downloaded {
+            AccountsDbConfig {
+                storage_validation: AppendVecValidation::Full,
+                ..accounts_db_config
+            }
+        } else {
+            accounts_db_config
+        }
+    })
+}
+
 /// Build the full snapshot archive path from its components: the snapshot archives directory, the
 /// snapshot slot, the accounts hash, and the archive format.
 pub fn build_full_snapshot_archive_path(
@@ -1127,6 +2404,16 @@ pub fn parse_incremental_snapshot_archive_filename(
     })
 }
 
+/// Incomplete archives are never picked up as snapshot archives, even if their file name happens
+/// to look like one
+fn is_complete_snapshot_archive_path(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|file_name| file_name.to_str())
+        .map_or(false, |file_name| {
+            !is_incomplete_snapshot_archive_file_name(file_name)
+        })
+}
+
 /// Get a list of the full snapshot archives in a directory
 pub fn get_full_snapshot_archives<P>(snapshot_archives_dir: P) -> Vec<FullSnapshotArchiveInfo>
 where
@@ -1144,7 +2431,9 @@ where
         Ok(files) => files
             .filter_map(|entry| {
                 entry.map_or(None, |entry| {
-                    FullSnapshotArchiveInfo::new_from_path(entry.path()).ok()
+                    is_complete_snapshot_archive_path(&entry.path())
+                        .then(|| FullSnapshotArchiveInfo::new_from_path(entry.path()).ok())
+                        .flatten()
                 })
             })
             .collect(),
@@ -1170,7 +2459,9 @@ where
         Ok(files) => files
             .filter_map(|entry| {
                 entry.map_or(None, |entry| {
-                    IncrementalSnapshotArchiveInfo::new_from_path(entry.path()).ok()
+                    is_complete_snapshot_archive_path(&entry.path())
+                        .then(|| IncrementalSnapshotArchiveInfo::new_from_path(entry.path()).ok())
+                        .flatten()
                 })
             })
             .collect(),
@@ -1257,6 +2548,8 @@ pub fn purge_old_snapshot_archives<P>(
         );
         fs::remove_file(old_archive.path())
             .unwrap_or_else(|err| info!("Failed to remove old full snapshot archive: {}", err));
+        snapshot_profile::remove_snapshot_profiles(old_archive.path());
+        unmark_downloaded_snapshot_archive(old_archive.path());
     }
 
     // Purge incremental snapshots with a different base slot than the highest full snapshot slot.
@@ -1306,7 +2599,9 @@ pub fn purge_old_snapshot_archives<P>(
             );
             fs::remove_file(incremental_snapshot_archive.path()).unwrap_or_else(|err| {
                 info!("Failed to remove old incremental snapshot archive: {}", err)
-            })
+            });
+            snapshot_profile::remove_snapshot_profiles(incremental_snapshot_archive.path());
+            unmark_downloaded_snapshot_archive(incremental_snapshot_archive.path());
         });
 }
 
@@ -1315,6 +2610,7 @@ fn unpack_snapshot_local<T: 'static + Read + std::marker::Send, F: Fn() -> T>(
     ledger_dir: &Path,
     account_paths: &[PathBuf],
     parallel_archivers: usize,
+    file_permissions: FilePermissions,
 ) -> Result<UnpackedAppendVecMap> {
     assert!(parallel_archivers > 0);
     // a shared 'reader' that reads the decompressed stream once, keeps some history, and acts as a reader for multiple parallel archive readers
@@ -1336,7 +2632,13 @@ fn unpack_snapshot_local<T: 'static + Read + std::marker::Send, F: Fn() -> T>(
                 divisions: parallel_archivers,
             });
             let mut archive = Archive::new(reader);
-            unpack_snapshot(&mut archive, ledger_dir, account_paths, parallel_selector)
+            unpack_snapshot(
+                &mut archive,
+                ledger_dir,
+                account_paths,
+                parallel_selector,
+                file_permissions,
+            )
         })
         .collect::<Vec<_>>();
     let mut unpacked_append_vec_map = UnpackedAppendVecMap::new();
@@ -1353,6 +2655,7 @@ fn untar_snapshot_in<P: AsRef<Path>>(
     account_paths: &[PathBuf],
     archive_format: ArchiveFormat,
     parallel_divisions: usize,
+    file_permissions: FilePermissions,
 ) -> Result<UnpackedAppendVecMap> {
     let open_file = || File::open(&snapshot_tar).unwrap();
     let account_paths_map = match archive_format {
@@ -1361,24 +2664,28 @@ fn untar_snapshot_in<P: AsRef<Path>>(
             unpack_dir,
             account_paths,
             parallel_divisions,
+            file_permissions,
         )?,
         ArchiveFormat::TarGzip => unpack_snapshot_local(
             || GzDecoder::new(BufReader::new(open_file())),
             unpack_dir,
             account_paths,
             parallel_divisions,
+            file_permissions,
         )?,
         ArchiveFormat::TarZstd => unpack_snapshot_local(
             || zstd::stream::read::Decoder::new(BufReader::new(open_file())).unwrap(),
             unpack_dir,
             account_paths,
             parallel_divisions,
+            file_permissions,
         )?,
         ArchiveFormat::Tar => unpack_snapshot_local(
             || BufReader::new(open_file()),
             unpack_dir,
             account_paths,
             parallel_divisions,
+            file_permissions,
         )?,
     };
     Ok(account_paths_map)
@@ -1412,6 +2719,30 @@ fn verify_unpacked_snapshots_dir_and_version(
     Ok((snapshot_version, root_paths))
 }
 
+/// Check the bank snapshots unpacked from a full snapshot archive, and optionally an incremental
+/// snapshot archive, and find the files to rebuild from in them.  The version is the one to
+/// deserialize them with, i.e. the incremental snapshot's if there is one.
+fn verify_unpacked_snapshots(
+    full_snapshot_unpacked_snapshots_dir_and_version: &UnpackedSnapshotsDirAndVersion,
+    incremental_snapshot_unpacked_snapshots_dir_and_version: Option<
+        &UnpackedSnapshotsDirAndVersion,
+    >,
+) -> Result<(SnapshotVersion, SnapshotRootPaths)> {
+    let full_snapshot = verify_unpacked_snapshots_dir_and_version(
+        full_snapshot_unpacked_snapshots_dir_and_version,
+    )?;
+    let incremental_snapshot = incremental_snapshot_unpacked_snapshots_dir_and_version
+        .map(verify_unpacked_snapshots_dir_and_version)
+        .transpose()?;
+    let snapshot_version = incremental_snapshot
+        .as_ref()
+        .map_or(full_snapshot.0, |(snapshot_version, _)| *snapshot_version);
+    Ok((
+        snapshot_version,
+        SnapshotRootPaths::new(full_snapshot, incremental_snapshot),
+    ))
+}
+
 #[allow(clippy::too_many_arguments)]
 fn rebuild_bank_from_snapshots(
     full_snapshot_unpacked_snapshots_dir_and_version: &UnpackedSnapshotsDirAndVersion,
@@ -1431,40 +2762,24 @@ fn rebuild_bank_from_snapshots(
     verify_index: bool,
     accounts_db_config: Option<AccountsDbConfig>,
     accounts_update_notifier: Option<AccountsUpdateNotifier>,
-) -> Result<Bank> {
-    let (full_snapshot_version, full_snapshot_root_paths) =
-        verify_unpacked_snapshots_dir_and_version(
-            full_snapshot_unpacked_snapshots_dir_and_version,
-        )?;
-    let (incremental_snapshot_version, incremental_snapshot_root_paths) =
-        if let Some(snapshot_unpacked_snapshots_dir_and_version) =
-            incremental_snapshot_unpacked_snapshots_dir_and_version
-        {
-            let (snapshot_version, bank_snapshot_info) = verify_unpacked_snapshots_dir_and_version(
-                snapshot_unpacked_snapshots_dir_and_version,
-            )?;
-            (Some(snapshot_version), Some(bank_snapshot_info))
-        } else {
-            (None, None)
-        };
+) -> Result<(Bank, ReconstructAccountsDbStats)> {
+    let (snapshot_version, snapshot_root_paths) = verify_unpacked_snapshots(
+        full_snapshot_unpacked_snapshots_dir_and_version,
+        incremental_snapshot_unpacked_snapshots_dir_and_version,
+    )?;
     info!(
         "Loading bank from full snapshot {} and incremental snapshot {:?}",
-        full_snapshot_root_paths.snapshot_path.display(),
-        incremental_snapshot_root_paths
+        snapshot_root_paths.full_snapshot_root_file_path.display(),
+        snapshot_root_paths
+            .incremental_snapshot_root_file_path
             .as_ref()
-            .map(|paths| paths.snapshot_path.display()),
+            .map(|path| path.display()),
     );
 
-    let snapshot_root_paths = SnapshotRootPaths {
-        full_snapshot_root_file_path: full_snapshot_root_paths.snapshot_path,
-        incremental_snapshot_root_file_path: incremental_snapshot_root_paths
-            .map(|root_paths| root_paths.snapshot_path),
-    };
-
-    let bank = deserialize_snapshot_data_files(&snapshot_root_paths, |snapshot_streams| {
-        Ok(
-            match incremental_snapshot_version.unwrap_or(full_snapshot_version) {
-                SnapshotVersion::V1_2_0 => bank_from_streams(
+    let (bank, reconstruct_accounts_db_stats) =
+        deserialize_snapshot_data_files(&snapshot_root_paths, |snapshot_streams| {
+            Ok(match snapshot_version {
+                SnapshotVersion::V1_2_0 | SnapshotVersion::V1_3_0 => bank_from_streams(
                     SerdeStyle::Newer,
                     snapshot_streams,
                     account_paths,
@@ -1481,9 +2796,8 @@ fn rebuild_bank_from_snapshots(
                     accounts_db_config,
                     accounts_update_notifier,
                 ),
-            }?,
-        )
-    })?;
+            }?)
+        })?;
 
     // The status cache is rebuilt from the latest snapshot.  So, if there's an incremental
     // snapshot, use that.  Otherwise use the full snapshot.
@@ -1517,13 +2831,21 @@ fn rebuild_bank_from_snapshots(
     bank.src.append(&slot_deltas);
 
     info!("Loaded bank for slot: {}", bank.slot());
-    Ok(bank)
+    Ok((bank, reconstruct_accounts_db_stats))
 }
 
 fn get_snapshot_file_name(slot: Slot) -> String {
     slot.to_string()
 }
 
+/// The file, next to the bank snapshot file at `snapshot_path`, that holds its storages listing
+/// for snapshot versions that keep the two apart
+pub(crate) fn get_snapshot_storages_file_path(snapshot_path: impl AsRef<Path>) -> PathBuf {
+    snapshot_path
+        .as_ref()
+        .with_extension(SNAPSHOT_STORAGES_FILE_EXTENSION)
+}
+
 fn get_bank_snapshots_dir<P: AsRef<Path>>(path: P, slot: Slot) -> PathBuf {
     path.as_ref().join(slot.to_string())
 }
@@ -1551,6 +2873,7 @@ pub fn verify_snapshot_archive<P, Q, R>(
         &[unpack_dir.to_path_buf()],
         archive_format,
         1,
+        FilePermissions::default(),
     )
     .unwrap();
 
@@ -1595,38 +2918,108 @@ pub fn snapshot_bank(
     root_bank: &Bank,
     status_cache_slot_deltas: Vec<BankSlotDelta>,
     accounts_package_sender: &AccountsPackageSender,
-    bank_snapshots_dir: impl AsRef<Path>,
-    snapshot_archives_dir: impl AsRef<Path>,
-    snapshot_version: SnapshotVersion,
-    archive_format: ArchiveFormat,
+    snapshot_config: &SnapshotConfig,
     hash_for_testing: Option<Hash>,
     snapshot_type: Option<SnapshotType>,
 ) -> Result<()> {
-    let snapshot_storages = get_snapshot_storages(root_bank, snapshot_type);
+    let bank_snapshots_dir = &snapshot_config.bank_snapshots_dir;
+    let file_permissions = snapshot_config.file_permissions;
+    let mut profiler = SnapshotProfiler::new(
+        SnapshotProfileKind::Create,
+        root_bank.slot(),
+        snapshot_config.profile,
+    );
+    let mut measure_snapshot_storages = Measure::start("snapshot-storages");
+    let mut snapshot_storages = get_snapshot_storages(root_bank, snapshot_type);
+    measure_snapshot_storages.stop();
+    profiler.record(
+        "get-snapshot-storages",
+        measure_snapshot_storages.as_us(),
+        None,
+    );
+    let mut pruned_storages_dir = None;
+    if let (Some(SnapshotType::FullSnapshot), Some(retained_epochs)) =
+        (snapshot_type, snapshot_config.full_snapshot_retained_epochs)
+    {
+        let mut measure_prune = Measure::start("prune-snapshot-storages");
+        // The pruned storages are staged along with the bank snapshot, and go away with the
+        // package once it has been archived
+        let storages_dir = tempfile::Builder::new()
+            .prefix(&format!(
+                "{}{}-pruned-storages-",
+                TMP_BANK_SNAPSHOT_PREFIX,
+                root_bank.slot()
+            ))
+            .tempdir_in(bank_snapshots_dir)?;
+        file_permissions.set_dir_permissions(storages_dir.path())?;
+        snapshot_storages = prune_snapshot_storages(
+            root_bank,
+            snapshot_storages,
+            retained_epochs,
+            storages_dir.path(),
+        )?;
+        pruned_storages_dir = Some(storages_dir);
+        measure_prune.stop();
+        profiler.record("prune-snapshot-storages", measure_prune.as_us(), None);
+    }
+
+    let hooks = &snapshot_config.hooks;
+    if !hooks.is_empty() {
+        let mut measure_hooks = Measure::start("run-hooks-before-serialization");
+        hooks.run_before_serialization(root_bank, &snapshot_storages)?;
+        measure_hooks.stop();
+        profiler.record(
+            "run-hooks-before-serialization",
+            measure_hooks.as_us(),
+            None,
+        );
+    }
 
     let mut add_snapshot_time = Measure::start("add-snapshot-ms");
     let bank_snapshot_info = add_bank_snapshot(
-        &bank_snapshots_dir,
+        bank_snapshots_dir,
         root_bank,
         &snapshot_storages,
-        snapshot_version,
+        snapshot_config.snapshot_version,
+        snapshot_config.durability,
+        file_permissions,
     )?;
     add_snapshot_time.stop();
     inc_new_counter_info!("add-snapshot-ms", add_snapshot_time.as_ms() as usize);
+    let bank_snapshot_bytes = profiler.is_enabled().then(|| {
+        [
+            bank_snapshot_info.snapshot_path.clone(),
+            get_snapshot_storages_file_path(&bank_snapshot_info.snapshot_path),
+        ]
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+    });
+    profiler.record(
+        "add-bank-snapshot",
+        add_snapshot_time.as_us(),
+        bank_snapshot_bytes,
+    );
 
-    let accounts_package = AccountsPackage::new(
+    let mut measure_package = Measure::start("package-bank-snapshot");
+    let mut accounts_package = AccountsPackage::new(
         root_bank,
         &bank_snapshot_info,
         bank_snapshots_dir,
         status_cache_slot_deltas,
-        snapshot_archives_dir,
+        &snapshot_config.snapshot_archives_dir,
         snapshot_storages,
-        archive_format,
-        snapshot_version,
+        snapshot_config.archive_format,
+        snapshot_config.snapshot_version,
         hash_for_testing,
         snapshot_type,
     )
     .expect("failed to hard link bank snapshot into a tmpdir");
+    measure_package.stop();
+    profiler.record("package-bank-snapshot", measure_package.as_us(), None);
+    accounts_package.profiler = profiler;
+    accounts_package.pruned_storages_dir = pruned_storages_dir;
 
     accounts_package_sender.send(accounts_package)?;
 
@@ -1675,6 +3068,31 @@ fn get_snapshot_storages(bank: &Bank, snapshot_type: Option<SnapshotType>) -> Sn
     snapshot_storages
 }
 
+/// Leave the storages of the slots before the last `retained_epochs` epochs out of the storages of
+/// a full snapshot of `bank`, except for the latest versions of the accounts in them.  For nodes
+/// that do not serve the history of those slots, e.g. RPC nodes that only serve recent history.
+/// The storages that have accounts left out are rewritten into `pruned_storages_dir`.
+pub fn prune_snapshot_storages(
+    bank: &Bank,
+    snapshot_storages: SnapshotStorages,
+    retained_epochs: Epoch,
+    pruned_storages_dir: &Path,
+) -> Result<SnapshotStorages> {
+    let boundary_slot = bank
+        .epoch_schedule()
+        .get_first_slot_in_epoch(bank.epoch().saturating_sub(retained_epochs));
+    bank.rc
+        .accounts
+        .accounts_db
+        .prune_snapshot_storages(
+            snapshot_storages,
+            boundary_slot,
+            bank.slot(),
+            pruned_storages_dir,
+        )
+        .map_err(|err| SnapshotError::IoWithSource(err, "prune snapshot storages"))
+}
+
 /// Convenience function to create a full snapshot archive out of any Bank, regardless of state.
 /// The Bank will be frozen during the process.
 ///
@@ -1683,14 +3101,8 @@ fn get_snapshot_storages(bank: &Bank, snapshot_type: Option<SnapshotType>) -> Sn
 pub fn bank_to_full_snapshot_archive(
     bank_snapshots_dir: impl AsRef<Path>,
     bank: &Bank,
-    snapshot_version: Option<SnapshotVersion>,
-    snapshot_archives_dir: impl AsRef<Path>,
-    archive_format: ArchiveFormat,
-    maximum_full_snapshot_archives_to_retain: usize,
-    maximum_incremental_snapshot_archives_to_retain: usize,
+    snapshot_config: &SnapshotConfig,
 ) -> Result<FullSnapshotArchiveInfo> {
-    let snapshot_version = snapshot_version.unwrap_or_default();
-
     assert!(bank.is_complete());
     bank.squash(); // Bank may not be a root
     bank.force_flush_accounts_cache();
@@ -1700,19 +3112,21 @@ pub fn bank_to_full_snapshot_archive(
 
     let temp_dir = tempfile::tempdir_in(bank_snapshots_dir)?;
     let snapshot_storages = bank.get_snapshot_storages(None);
-    let bank_snapshot_info =
-        add_bank_snapshot(&temp_dir, bank, &snapshot_storages, snapshot_version)?;
+    let bank_snapshot_info = add_bank_snapshot(
+        &temp_dir,
+        bank,
+        &snapshot_storages,
+        snapshot_config.snapshot_version,
+        snapshot_config.durability,
+        snapshot_config.file_permissions,
+    )?;
 
     package_and_archive_full_snapshot(
         bank,
         &bank_snapshot_info,
         &temp_dir,
-        snapshot_archives_dir,
         snapshot_storages,
-        archive_format,
-        snapshot_version,
-        maximum_full_snapshot_archives_to_retain,
-        maximum_incremental_snapshot_archives_to_retain,
+        snapshot_config,
     )
 }
 
@@ -1726,14 +3140,8 @@ pub fn bank_to_incremental_snapshot_archive(
     bank_snapshots_dir: impl AsRef<Path>,
     bank: &Bank,
     full_snapshot_slot: Slot,
-    snapshot_version: Option<SnapshotVersion>,
-    snapshot_archives_dir: impl AsRef<Path>,
-    archive_format: ArchiveFormat,
-    maximum_full_snapshot_archives_to_retain: usize,
-    maximum_incremental_snapshot_archives_to_retain: usize,
+    snapshot_config: &SnapshotConfig,
 ) -> Result<IncrementalSnapshotArchiveInfo> {
-    let snapshot_version = snapshot_version.unwrap_or_default();
-
     assert!(bank.is_complete());
     assert!(bank.slot() > full_snapshot_slot);
     bank.squash(); // Bank may not be a root
@@ -1744,20 +3152,22 @@ pub fn bank_to_incremental_snapshot_archive(
 
     let temp_dir = tempfile::tempdir_in(bank_snapshots_dir)?;
     let snapshot_storages = bank.get_snapshot_storages(Some(full_snapshot_slot));
-    let bank_snapshot_info =
-        add_bank_snapshot(&temp_dir, bank, &snapshot_storages, snapshot_version)?;
+    let bank_snapshot_info = add_bank_snapshot(
+        &temp_dir,
+        bank,
+        &snapshot_storages,
+        snapshot_config.snapshot_version,
+        snapshot_config.durability,
+        snapshot_config.file_permissions,
+    )?;
 
     package_and_archive_incremental_snapshot(
         bank,
         full_snapshot_slot,
         &bank_snapshot_info,
         &temp_dir,
-        snapshot_archives_dir,
         snapshot_storages,
-        archive_format,
-        snapshot_version,
-        maximum_full_snapshot_archives_to_retain,
-        maximum_incremental_snapshot_archives_to_retain,
+        snapshot_config,
     )
 }
 
@@ -1766,32 +3176,24 @@ pub fn package_and_archive_full_snapshot(
     bank: &Bank,
     bank_snapshot_info: &BankSnapshotInfo,
     bank_snapshots_dir: impl AsRef<Path>,
-    snapshot_archives_dir: impl AsRef<Path>,
     snapshot_storages: SnapshotStorages,
-    archive_format: ArchiveFormat,
-    snapshot_version: SnapshotVersion,
-    maximum_full_snapshot_archives_to_retain: usize,
-    maximum_incremental_snapshot_archives_to_retain: usize,
+    snapshot_config: &SnapshotConfig,
 ) -> Result<FullSnapshotArchiveInfo> {
     let accounts_package = AccountsPackage::new(
         bank,
         bank_snapshot_info,
         bank_snapshots_dir,
         bank.src.slot_deltas(&bank.src.roots()),
-        snapshot_archives_dir,
+        &snapshot_config.snapshot_archives_dir,
         snapshot_storages,
-        archive_format,
-        snapshot_version,
+        snapshot_config.archive_format,
+        snapshot_config.snapshot_version,
         None,
         Some(SnapshotType::FullSnapshot),
     )?;
 
     let snapshot_package = SnapshotPackage::from(accounts_package);
-    archive_snapshot_package(
-        &snapshot_package,
-        maximum_full_snapshot_archives_to_retain,
-        maximum_incremental_snapshot_archives_to_retain,
-    )?;
+    archive_snapshot_package(&snapshot_package, snapshot_config)?;
 
     Ok(FullSnapshotArchiveInfo::new(
         snapshot_package.snapshot_archive_info,
@@ -1799,28 +3201,23 @@ pub fn package_and_archive_full_snapshot(
 }
 
 /// Helper function to hold shared code to package, process, and archive incremental snapshots
-#[allow(clippy::too_many_arguments)]
 pub fn package_and_archive_incremental_snapshot(
     bank: &Bank,
     incremental_snapshot_base_slot: Slot,
     bank_snapshot_info: &BankSnapshotInfo,
     bank_snapshots_dir: impl AsRef<Path>,
-    snapshot_archives_dir: impl AsRef<Path>,
     snapshot_storages: SnapshotStorages,
-    archive_format: ArchiveFormat,
-    snapshot_version: SnapshotVersion,
-    maximum_full_snapshot_archives_to_retain: usize,
-    maximum_incremental_snapshot_archives_to_retain: usize,
+    snapshot_config: &SnapshotConfig,
 ) -> Result<IncrementalSnapshotArchiveInfo> {
     let accounts_package = AccountsPackage::new(
         bank,
         bank_snapshot_info,
         bank_snapshots_dir,
         bank.src.slot_deltas(&bank.src.roots()),
-        snapshot_archives_dir,
+        &snapshot_config.snapshot_archives_dir,
         snapshot_storages,
-        archive_format,
-        snapshot_version,
+        snapshot_config.archive_format,
+        snapshot_config.snapshot_version,
         None,
         Some(SnapshotType::IncrementalSnapshot(
             incremental_snapshot_base_slot,
@@ -1828,11 +3225,7 @@ pub fn package_and_archive_incremental_snapshot(
     )?;
 
     let snapshot_package = SnapshotPackage::from(accounts_package);
-    archive_snapshot_package(
-        &snapshot_package,
-        maximum_full_snapshot_archives_to_retain,
-        maximum_incremental_snapshot_archives_to_retain,
-    )?;
+    archive_snapshot_package(&snapshot_package, snapshot_config)?;
 
     Ok(IncrementalSnapshotArchiveInfo::new(
         incremental_snapshot_base_slot,
@@ -1859,10 +3252,17 @@ pub fn should_take_incremental_snapshot(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING;
+    use crate::{
+        accounts_db::ACCOUNTS_DB_CONFIG_FOR_TESTING,
+        program_accounts_snapshot::ProgramAccountsSnapshotReader,
+        snapshot_hooks::{CommandHook, SnapshotHook, SnapshotHooks},
+        snapshot_profile::SnapshotProfile,
+    };
     use assert_matches::assert_matches;
     use bincode::{deserialize_from, serialize_into};
     use solana_sdk::{
+        account::AccountSharedData,
+        epoch_schedule::EpochSchedule,
         genesis_config::create_genesis_config,
         signature::{Keypair, Signer},
         system_transaction,
@@ -1870,6 +3270,102 @@ mod tests {
     };
     use std::mem::size_of;
 
+    #[test]
+    fn test_is_valid_archive_compression_level() {
+        assert!(is_valid_archive_compression_level(
+            ArchiveFormat::TarBzip2,
+            9
+        ));
+        assert!(!is_valid_archive_compression_level(
+            ArchiveFormat::TarBzip2,
+            0
+        ));
+        assert!(is_valid_archive_compression_level(
+            ArchiveFormat::TarGzip,
+            0
+        ));
+        assert!(!is_valid_archive_compression_level(
+            ArchiveFormat::TarGzip,
+            10
+        ));
+        assert!(is_valid_archive_compression_level(
+            ArchiveFormat::TarZstd,
+            19
+        ));
+        assert!(!is_valid_archive_compression_level(
+            ArchiveFormat::TarZstd,
+            1_000
+        ));
+        assert!(is_valid_archive_compression_level(
+            ArchiveFormat::Tar,
+            1_000
+        ));
+    }
+
+    #[test]
+    fn test_full_snapshot_schedule() {
+        let full_snapshot_archive_interval_slots = 100;
+        for (schedule, at_interval, at_epoch_boundary, at_both) in [
+            (FullSnapshotSchedule::Interval, true, false, true),
+            (FullSnapshotSchedule::EpochBoundary, false, true, true),
+            (
+                FullSnapshotSchedule::IntervalAndEpochBoundary,
+                true,
+                true,
+                true,
+            ),
+        ] {
+            assert!(!schedule.should_take_full_snapshot(
+                123,
+                full_snapshot_archive_interval_slots,
+                false
+            ));
+            assert_eq!(
+                schedule.should_take_full_snapshot(
+                    200,
+                    full_snapshot_archive_interval_slots,
+                    false
+                ),
+                at_interval
+            );
+            assert_eq!(
+                schedule.should_take_full_snapshot(123, full_snapshot_archive_interval_slots, true),
+                at_epoch_boundary
+            );
+            assert_eq!(
+                schedule.should_take_full_snapshot(200, full_snapshot_archive_interval_slots, true),
+                at_both
+            );
+        }
+    }
+
+    #[test]
+    fn test_atomic_replace_file() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let from = temp_dir.path().join("from");
+        let to = temp_dir.path().join("to");
+
+        fs::write(&from, b"first").unwrap();
+        atomic_replace_file(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"first");
+
+        // an existing file is replaced, even if it is read-only
+        fs::write(&from, b"second").unwrap();
+        let mut permissions = fs::metadata(&to).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&to, permissions).unwrap();
+        atomic_replace_file(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), b"second");
+
+        // and a missing source is an error
+        assert_eq!(
+            atomic_replace_file(&from, &to).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
     #[test]
     fn test_serialize_snapshot_data_file_under_limit() {
         let temp_dir = tempfile::TempDir::new().unwrap();
@@ -1877,6 +3373,8 @@ mod tests {
         let consumed_size = serialize_snapshot_data_file_capped(
             &temp_dir.path().join("data-file"),
             expected_consumed_size,
+            SnapshotDurability::default(),
+            FilePermissions::default(),
             |stream| {
                 serialize_into(stream, &2323_u32)?;
                 Ok(())
@@ -1886,6 +3384,35 @@ mod tests {
         assert_eq!(consumed_size, expected_consumed_size);
     }
 
+    #[test]
+    fn test_serialize_snapshot_data_file_with_durability() {
+        for durability in [
+            SnapshotDurability::NoFsync,
+            SnapshotDurability::FsyncFile,
+            SnapshotDurability::FsyncDir,
+        ] {
+            let temp_dir = tempfile::TempDir::new().unwrap();
+            let data_file_path = temp_dir.path().join("data-file");
+            let expected_consumed_size = size_of::<u32>() as u64;
+            let consumed_size = serialize_snapshot_data_file_capped(
+                &data_file_path,
+                expected_consumed_size,
+                durability,
+                FilePermissions::default(),
+                |stream| {
+                    serialize_into(stream, &2323_u32)?;
+                    Ok(())
+                },
+            )
+            .unwrap();
+            assert_eq!(consumed_size, expected_consumed_size);
+            assert_eq!(
+                fs::read(&data_file_path).unwrap(),
+                2323_u32.to_le_bytes().to_vec()
+            );
+        }
+    }
+
     #[test]
     fn test_serialize_snapshot_data_file_over_limit() {
         let temp_dir = tempfile::TempDir::new().unwrap();
@@ -1893,6 +3420,8 @@ mod tests {
         let result = serialize_snapshot_data_file_capped(
             &temp_dir.path().join("data-file"),
             expected_consumed_size - 1,
+            SnapshotDurability::default(),
+            FilePermissions::default(),
             |stream| {
                 serialize_into(stream, &2323_u32)?;
                 Ok(())
@@ -1910,6 +3439,8 @@ mod tests {
         serialize_snapshot_data_file_capped(
             &temp_dir.path().join("data-file"),
             expected_consumed_size,
+            SnapshotDurability::default(),
+            FilePermissions::default(),
             |stream| {
                 serialize_into(stream, &expected_data)?;
                 Ok(())
@@ -1920,6 +3451,10 @@ mod tests {
         let snapshot_root_paths = SnapshotRootPaths {
             full_snapshot_root_file_path: temp_dir.path().join("data-file"),
             incremental_snapshot_root_file_path: None,
+            full_snapshot_storages_file_path: None,
+            incremental_snapshot_storages_file_path: None,
+            full_snapshot_version: SnapshotVersion::default(),
+            incremental_snapshot_version: None,
         };
 
         let actual_data = deserialize_snapshot_data_files_capped(
@@ -1944,6 +3479,8 @@ mod tests {
         serialize_snapshot_data_file_capped(
             &temp_dir.path().join("data-file"),
             expected_consumed_size,
+            SnapshotDurability::default(),
+            FilePermissions::default(),
             |stream| {
                 serialize_into(stream, &expected_data)?;
                 Ok(())
@@ -1954,6 +3491,10 @@ mod tests {
         let snapshot_root_paths = SnapshotRootPaths {
             full_snapshot_root_file_path: temp_dir.path().join("data-file"),
             incremental_snapshot_root_file_path: None,
+            full_snapshot_storages_file_path: None,
+            incremental_snapshot_storages_file_path: None,
+            full_snapshot_version: SnapshotVersion::default(),
+            incremental_snapshot_version: None,
         };
 
         let result = deserialize_snapshot_data_files_capped(
@@ -1977,6 +3518,8 @@ mod tests {
         serialize_snapshot_data_file_capped(
             &temp_dir.path().join("data-file"),
             expected_consumed_size * 2,
+            SnapshotDurability::default(),
+            FilePermissions::default(),
             |stream| {
                 serialize_into(stream.by_ref(), &expected_data)?;
                 serialize_into(stream.by_ref(), &expected_data)?;
@@ -1988,6 +3531,10 @@ mod tests {
         let snapshot_root_paths = SnapshotRootPaths {
             full_snapshot_root_file_path: temp_dir.path().join("data-file"),
             incremental_snapshot_root_file_path: None,
+            full_snapshot_storages_file_path: None,
+            incremental_snapshot_storages_file_path: None,
+            full_snapshot_version: SnapshotVersion::default(),
+            incremental_snapshot_version: None,
         };
 
         let result = deserialize_snapshot_data_files_capped(
@@ -2281,6 +3828,62 @@ mod tests {
         assert_eq!(snapshot_archives.len() as Slot, max_slot - min_slot);
     }
 
+    #[test]
+    fn test_get_snapshot_archives_ignores_incomplete_archives() {
+        solana_logger::setup();
+        let temp_snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let hash = Hash::default();
+        for file_name in [
+            format!("snapshot-1-{}.tar.zst", hash),
+            format!("snapshot-2-{}.tar.zst{}", hash, TMP_SNAPSHOT_ARCHIVE_SUFFIX),
+            format!("{}snapshot-3-{}.tar.zst", TMP_DOWNLOAD_PREFIX, hash),
+            format!("incremental-snapshot-1-4-{}.tar.zst", hash),
+            format!(
+                "incremental-snapshot-1-5-{}.tar.zst{}",
+                hash, TMP_SNAPSHOT_ARCHIVE_SUFFIX
+            ),
+            format!(
+                "{}incremental-snapshot-1-6-{}.tar.zst",
+                TMP_DOWNLOAD_PREFIX, hash
+            ),
+        ] {
+            File::create(temp_snapshot_archives_dir.path().join(file_name)).unwrap();
+        }
+        // Files and directories that are not snapshot archives are left alone, whatever their name
+        File::create(temp_snapshot_archives_dir.path().join("ledger.tmp")).unwrap();
+        File::create(temp_snapshot_archives_dir.path().join("tmp-ledger")).unwrap();
+        let tmp_dir = temp_snapshot_archives_dir
+            .path()
+            .join(format!("snapshot-7-{}.tar.zst{}", hash, TMP_SNAPSHOT_ARCHIVE_SUFFIX));
+        fs::create_dir(&tmp_dir).unwrap();
+        File::create(tmp_dir.join("file")).unwrap();
+
+        let full_snapshot_archives = get_full_snapshot_archives(&temp_snapshot_archives_dir);
+        assert_eq!(full_snapshot_archives.len(), 1);
+        assert_eq!(full_snapshot_archives[0].slot(), 1);
+        let incremental_snapshot_archives =
+            get_incremental_snapshot_archives(&temp_snapshot_archives_dir);
+        assert_eq!(incremental_snapshot_archives.len(), 1);
+        assert_eq!(incremental_snapshot_archives[0].slot(), 4);
+
+        remove_tmp_snapshot_archives(&temp_snapshot_archives_dir);
+        let mut remaining_file_names: Vec<_> = fs::read_dir(&temp_snapshot_archives_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        remaining_file_names.sort();
+        assert_eq!(
+            remaining_file_names,
+            vec![
+                format!("incremental-snapshot-1-4-{}.tar.zst", hash),
+                "ledger.tmp".to_string(),
+                format!("snapshot-1-{}.tar.zst", hash),
+                format!("snapshot-7-{}.tar.zst{}", hash, TMP_SNAPSHOT_ARCHIVE_SUFFIX),
+                "tmp-ledger".to_string(),
+            ]
+        );
+    }
+
     #[test]
     fn test_get_incremental_snapshot_archives() {
         solana_logger::setup();
@@ -2432,6 +4035,56 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_downloaded_snapshot_archive_markers() {
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let archive_paths = [1, 3, 50]
+            .iter()
+            .map(|slot| {
+                build_full_snapshot_archive_path(
+                    snapshot_archives_dir.path().to_path_buf(),
+                    *slot,
+                    &Hash::default(),
+                    ArchiveFormat::TarZstd,
+                )
+            })
+            .collect::<Vec<_>>();
+        for archive_path in &archive_paths {
+            File::create(archive_path).unwrap();
+        }
+        mark_downloaded_snapshot_archive(&archive_paths[1]).unwrap();
+        mark_downloaded_snapshot_archive(&archive_paths[2]).unwrap();
+        // the markers are not mistaken for archives
+        assert_eq!(get_full_snapshot_archives(&snapshot_archives_dir).len(), 3);
+
+        let storage_validation = |archive_path: &PathBuf| {
+            accounts_db_config_for_snapshot_archives(
+                Some(AccountsDbConfig {
+                    storage_validation: AppendVecValidation::HeaderOnly,
+                    ..AccountsDbConfig::default()
+                }),
+                &FullSnapshotArchiveInfo::new_from_path(archive_path.clone()).unwrap(),
+                None,
+            )
+            .unwrap()
+            .storage_validation
+        };
+        assert_eq!(
+            storage_validation(&archive_paths[0]),
+            AppendVecValidation::HeaderOnly
+        );
+        assert_eq!(
+            storage_validation(&archive_paths[2]),
+            AppendVecValidation::Full
+        );
+
+        // the markers go along with the archives they are for
+        purge_old_snapshot_archives(&snapshot_archives_dir, 1, 1);
+        assert!(!archive_paths[1].exists());
+        assert!(!is_downloaded_snapshot_archive(&archive_paths[1]));
+        assert!(is_downloaded_snapshot_archive(&archive_paths[2]));
+    }
+
     /// Mimic a running node's behavior w.r.t. purging old snapshot archives.  Take snapshots in a
     /// loop, and periodically purge old snapshot archives.  After purging, check to make sure the
     /// snapshot archives on disk are correct.
@@ -2628,13 +4281,21 @@ mod tests {
         let snapshot_archive_info = bank_to_full_snapshot_archive(
             &bank_snapshots_dir,
             &original_bank,
-            None,
-            snapshot_archives_dir.path(),
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
         )
         .unwrap();
+        // Only the finished archive is left behind
+        assert_eq!(
+            fs::read_dir(&snapshot_archives_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<Vec<_>>(),
+            vec![snapshot_archive_info.path().clone()]
+        );
 
         let (roundtrip_bank, _) = bank_from_snapshot_archives(
             &[PathBuf::from(accounts_dir.path())],
@@ -2652,6 +4313,7 @@ mod tests {
             false,
             false,
             false,
+            &SnapshotConfig::default(),
             Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
             None,
         )
@@ -2660,79 +4322,45 @@ mod tests {
         assert_eq!(original_bank, roundtrip_bank);
     }
 
-    /// Test roundtrip of bank to a full snapshot, then back again.  This test is more involved
-    /// than the simple version above; creating multiple banks over multiple slots and doing
-    /// multiple transfers.  So this full snapshot should contain more data.
+    /// Test that filler accounts left out of rebuilding a bank from a snapshot are added in the
+    /// background afterwards
     #[test]
-    fn test_roundtrip_bank_to_and_from_snapshot_complex() {
+    fn test_bank_from_full_snapshot_with_lazy_filler_accounts() {
         solana_logger::setup();
-        let collector = Pubkey::new_unique();
-        let key1 = Keypair::new();
-        let key2 = Keypair::new();
-        let key3 = Keypair::new();
-        let key4 = Keypair::new();
-        let key5 = Keypair::new();
-
-        let (genesis_config, mint_keypair) = create_genesis_config(1_000_000);
+        // filler accounts only go in slots from within the last epoch, so keep it short
+        let genesis_config = GenesisConfig {
+            epoch_schedule: EpochSchedule::custom(32, 32, false),
+            ..GenesisConfig::default()
+        };
         let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
-        bank0.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
-        bank0.transfer(2, &mint_keypair, &key2.pubkey()).unwrap();
-        bank0.transfer(3, &mint_keypair, &key3.pubkey()).unwrap();
         while !bank0.is_complete() {
             bank0.register_tick(&Hash::new_unique());
         }
-
-        let slot = 1;
-        let bank1 = Arc::new(Bank::new_from_parent(&bank0, &collector, slot));
-        bank1.transfer(3, &mint_keypair, &key3.pubkey()).unwrap();
-        bank1.transfer(4, &mint_keypair, &key4.pubkey()).unwrap();
-        bank1.transfer(5, &mint_keypair, &key5.pubkey()).unwrap();
-        while !bank1.is_complete() {
-            bank1.register_tick(&Hash::new_unique());
-        }
-
-        let slot = slot + 1;
-        let bank2 = Arc::new(Bank::new_from_parent(&bank1, &collector, slot));
-        bank2.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
-        while !bank2.is_complete() {
-            bank2.register_tick(&Hash::new_unique());
-        }
-
-        let slot = slot + 1;
-        let bank3 = Arc::new(Bank::new_from_parent(&bank2, &collector, slot));
-        bank3.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
-        while !bank3.is_complete() {
-            bank3.register_tick(&Hash::new_unique());
-        }
-
-        let slot = slot + 1;
-        let bank4 = Arc::new(Bank::new_from_parent(&bank3, &collector, slot));
-        bank4.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
-        while !bank4.is_complete() {
-            bank4.register_tick(&Hash::new_unique());
+        let original_bank = Bank::new_from_parent(&bank0, &Pubkey::new_unique(), 40);
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
         }
 
         let accounts_dir = tempfile::TempDir::new().unwrap();
         let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
         let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
-        let snapshot_archive_format = ArchiveFormat::TarGzip;
-
-        let full_snapshot_archive_info = bank_to_full_snapshot_archive(
-            bank_snapshots_dir.path(),
-            &bank4,
-            None,
-            snapshot_archives_dir.path(),
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+        let snapshot_archive_info = bank_to_full_snapshot_archive(
+            &bank_snapshots_dir,
+            &original_bank,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: ArchiveFormat::Tar,
+                ..SnapshotConfig::default()
+            },
         )
         .unwrap();
 
-        let (roundtrip_bank, _) = bank_from_snapshot_archives(
+        let filler_account_count = 50;
+        let (bank, _) = bank_from_snapshot_archives(
             &[PathBuf::from(accounts_dir.path())],
             &[],
             bank_snapshots_dir.path(),
-            &full_snapshot_archive_info,
+            &snapshot_archive_info,
             None,
             &genesis_config,
             None,
@@ -2744,74 +4372,1002 @@ mod tests {
             false,
             false,
             false,
-            Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
+            &SnapshotConfig::default(),
+            Some(AccountsDbConfig {
+                filler_account_count: Some(filler_account_count),
+                lazy_filler_accounts: true,
+                ..ACCOUNTS_DB_CONFIG_FOR_TESTING
+            }),
             None,
         )
         .unwrap();
 
-        assert_eq!(*bank4, roundtrip_bank);
+        // Cleaning, like shrinking and snapshotting, waits for the filler accounts to be added
+        let accounts_db = &bank.rc.accounts.accounts_db;
+        bank.clean_accounts(true, false, None);
+        assert!(accounts_db.filler_accounts_added());
+        let filler_accounts = bank
+            .get_all_accounts_with_modified_slots()
+            .unwrap()
+            .into_iter()
+            .filter(|(pubkey, _, _)| accounts_db.is_filler_account(pubkey))
+            .count();
+        assert_eq!(filler_accounts, filler_account_count);
     }
 
-    /// Test roundtrip of bank to snapshots, then back again, with incremental snapshots.  In this
-    /// version, build up a few slots and take a full snapshot.  Continue on a few more slots and
-    /// take an incremental snapshot.  Rebuild the bank from both the incremental snapshot and full
-    /// snapshot.
-    ///
-    /// For the full snapshot, touch all the accounts, but only one for the incremental snapshot.
-    /// This is intended to mimic the real behavior of transactions, where only a small number of
-    /// accounts are modified often, which are captured by the incremental snapshot.  The majority
-    /// of the accounts are not modified often, and are captured by the full snapshot.
+    /// Test rebuilding a bank from a full snapshot with the accounts hash verified in the
+    /// background
     #[test]
-    fn test_roundtrip_bank_to_and_from_incremental_snapshot() {
+    fn test_bank_from_full_snapshot_with_background_hash_verification() {
         solana_logger::setup();
-        let collector = Pubkey::new_unique();
-        let key1 = Keypair::new();
-        let key2 = Keypair::new();
-        let key3 = Keypair::new();
-        let key4 = Keypair::new();
-        let key5 = Keypair::new();
-
-        let (genesis_config, mint_keypair) = create_genesis_config(1_000_000);
-        let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
-        bank0.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
-        bank0.transfer(2, &mint_keypair, &key2.pubkey()).unwrap();
-        bank0.transfer(3, &mint_keypair, &key3.pubkey()).unwrap();
-        while !bank0.is_complete() {
-            bank0.register_tick(&Hash::new_unique());
-        }
-
-        let slot = 1;
-        let bank1 = Arc::new(Bank::new_from_parent(&bank0, &collector, slot));
-        bank1.transfer(3, &mint_keypair, &key3.pubkey()).unwrap();
-        bank1.transfer(4, &mint_keypair, &key4.pubkey()).unwrap();
-        bank1.transfer(5, &mint_keypair, &key5.pubkey()).unwrap();
-        while !bank1.is_complete() {
-            bank1.register_tick(&Hash::new_unique());
+        let (genesis_config, _mint_keypair) = create_genesis_config(1_000_000);
+        let original_bank = Bank::new_for_tests(&genesis_config);
+        original_bank.store_account(
+            &Pubkey::new_unique(),
+            &AccountSharedData::new(1_000, 0, &Pubkey::new_unique()),
+        );
+        original_bank.set_capitalization();
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
         }
 
         let accounts_dir = tempfile::TempDir::new().unwrap();
         let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
         let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
-        let snapshot_archive_format = ArchiveFormat::TarZstd;
+        let snapshot_archive_info = bank_to_full_snapshot_archive(
+            &bank_snapshots_dir,
+            &original_bank,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: ArchiveFormat::Tar,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
 
-        let full_snapshot_slot = slot;
-        let full_snapshot_archive_info = bank_to_full_snapshot_archive(
+        let (bank, _) = bank_from_snapshot_archives(
+            &[PathBuf::from(accounts_dir.path())],
+            &[],
             bank_snapshots_dir.path(),
-            &bank1,
+            &snapshot_archive_info,
+            None,
+            &genesis_config,
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            false,
+            false,
+            &SnapshotConfig::default(),
+            Some(AccountsDbConfig {
+                verify_accounts_hash_in_background: true,
+                ..ACCOUNTS_DB_CONFIG_FOR_TESTING
+            }),
             None,
-            snapshot_archives_dir.path(),
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
         )
         .unwrap();
 
-        let slot = slot + 1;
-        let bank2 = Arc::new(Bank::new_from_parent(&bank1, &collector, slot));
-        bank2.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
-        while !bank2.is_complete() {
-            bank2.register_tick(&Hash::new_unique());
-        }
+        let receiver = bank.take_accounts_hash_verification_receiver().unwrap();
+        assert!(bank.take_accounts_hash_verification_receiver().is_none());
+        assert!(receiver.recv().unwrap());
+        assert!(!bank.is_accounts_hash_verification_pending());
+        assert_eq!(original_bank, bank);
+    }
+
+    /// Test roundtrip of bank to a full snapshot that leaves out the storages of older epochs,
+    /// then back again
+    #[test]
+    fn test_roundtrip_bank_to_and_from_pruned_full_snapshot() {
+        solana_logger::setup();
+        let (mut genesis_config, _mint_keypair) = create_genesis_config(1_000_000);
+        genesis_config.epoch_schedule = EpochSchedule::custom(32, 32, false);
+        let rewritten_key = Pubkey::new_unique();
+        let retained_key = Pubkey::new_unique();
+        let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+        bank0.store_account(
+            &rewritten_key,
+            &AccountSharedData::new(1_000, 0, &Pubkey::new_unique()),
+        );
+        bank0.store_account(
+            &retained_key,
+            &AccountSharedData::new(2_000, 0, &Pubkey::new_unique()),
+        );
+        bank0.set_capitalization();
+        while !bank0.is_complete() {
+            bank0.register_tick(&Hash::new_unique());
+        }
+        let original_bank = Bank::new_from_parent(&bank0, &Pubkey::new_unique(), 40);
+        original_bank.store_account(
+            &rewritten_key,
+            &AccountSharedData::new(3_000, 0, &Pubkey::new_unique()),
+        );
+        original_bank.set_capitalization();
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
+        }
+        original_bank.squash();
+        original_bank.force_flush_accounts_cache();
+        original_bank.update_accounts_hash();
+
+        let snapshot_storages = original_bank.get_snapshot_storages(None);
+        let pruned_storages_dir = tempfile::TempDir::new().unwrap();
+        let pruned_storages = prune_snapshot_storages(
+            &original_bank,
+            snapshot_storages.clone(),
+            0,
+            pruned_storages_dir.path(),
+        )
+        .unwrap();
+        let count_accounts = |storages: &SnapshotStorages| {
+            storages
+                .iter()
+                .flatten()
+                .map(|storage| storage.all_accounts().len())
+                .sum::<usize>()
+        };
+        assert!(count_accounts(&pruned_storages) < count_accounts(&snapshot_storages));
+
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_version = SnapshotVersion::default();
+        let bank_snapshot_info = add_bank_snapshot(
+            &bank_snapshots_dir,
+            &original_bank,
+            &pruned_storages,
+            snapshot_version,
+            SnapshotDurability::default(),
+            FilePermissions::default(),
+        )
+        .unwrap();
+        let snapshot_archive_info = package_and_archive_full_snapshot(
+            &original_bank,
+            &bank_snapshot_info,
+            &bank_snapshots_dir,
+            pruned_storages,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: ArchiveFormat::Tar,
+                snapshot_version,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
+
+        let (roundtrip_bank, _) = bank_from_snapshot_archives(
+            &[PathBuf::from(accounts_dir.path())],
+            &[],
+            bank_snapshots_dir.path(),
+            &snapshot_archive_info,
+            None,
+            &genesis_config,
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            false,
+            false,
+            &SnapshotConfig::default(),
+            Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(original_bank, roundtrip_bank);
+        assert_eq!(roundtrip_bank.get_balance(&retained_key), 2_000);
+        assert_eq!(roundtrip_bank.get_balance(&rewritten_key), 3_000);
+    }
+
+    /// Test that the storages of a bank whose AccountsDb has a storage encryption key are encrypted
+    /// in the account paths and in its snapshot archives, and that the archives roundtrip back to
+    /// the bank, both before and after rotating the master key
+    #[test]
+    fn test_roundtrip_bank_to_and_from_encrypted_full_snapshot() {
+        solana_logger::setup();
+        let genesis_config = GenesisConfig::default();
+        let accounts_db_config = |storage_encryption_key: Option<&MasterKey>| {
+            Some(AccountsDbConfig {
+                storage_encryption_key: storage_encryption_key.cloned().map(Arc::new),
+                ..ACCOUNTS_DB_CONFIG_FOR_TESTING
+            })
+        };
+        let master_key = MasterKey::new_rand();
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let original_bank = Bank::new_with_paths(
+            &genesis_config,
+            vec![accounts_dir.path().to_path_buf()],
+            &[],
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            AccountShrinkThreshold::default(),
+            false,
+            accounts_db_config(Some(&master_key)),
+            None,
+        );
+
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
+        }
+        original_bank.squash();
+        original_bank.force_flush_accounts_cache();
+        original_bank.update_accounts_hash();
+
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let archive = |bank: &Bank| {
+            let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_storages = bank.get_snapshot_storages(None);
+            let bank_snapshot_info = add_bank_snapshot(
+                &bank_snapshots_dir,
+                bank,
+                &snapshot_storages,
+                SnapshotVersion::default(),
+                SnapshotDurability::default(),
+                FilePermissions::default(),
+            )
+            .unwrap();
+            let accounts_package = AccountsPackage::new(
+                bank,
+                &bank_snapshot_info,
+                &bank_snapshots_dir,
+                bank.src.slot_deltas(&bank.src.roots()),
+                &snapshot_archives_dir,
+                snapshot_storages,
+                ArchiveFormat::TarZstd,
+                SnapshotVersion::default(),
+                None,
+                Some(SnapshotType::FullSnapshot),
+            )
+            .unwrap();
+            let snapshot_package = SnapshotPackage::from(accounts_package);
+            archive_snapshot_package(&snapshot_package, &SnapshotConfig::default()).unwrap();
+            (
+                FullSnapshotArchiveInfo::new(snapshot_package.snapshot_archive_info),
+                snapshot_archives_dir,
+            )
+        };
+        // Every storage of `bank` is encrypted on disk, or every one is not
+        let are_storages_encrypted = |bank: &Bank| {
+            let storages = bank.get_snapshot_storages(None);
+            let encrypted: HashSet<_> = storages
+                .iter()
+                .flatten()
+                .map(|storage| {
+                    storage_encryption::is_encrypted_storage(&storage.get_path()).unwrap()
+                })
+                .collect();
+            assert_eq!(encrypted.len(), 1);
+            encrypted.contains(&true)
+        };
+        let load = |snapshot_archive_info: &FullSnapshotArchiveInfo,
+                    storage_encryption_key: Option<&MasterKey>| {
+            let accounts_dir = tempfile::TempDir::new().unwrap();
+            bank_from_snapshot_archives(
+                &[PathBuf::from(accounts_dir.path())],
+                &[],
+                bank_snapshots_dir.path(),
+                snapshot_archive_info,
+                None,
+                &genesis_config,
+                None,
+                None,
+                AccountSecondaryIndexes::default(),
+                false,
+                None,
+                AccountShrinkThreshold::default(),
+                false,
+                false,
+                false,
+                &SnapshotConfig::default(),
+                accounts_db_config(storage_encryption_key),
+                None,
+            )
+            .map(|(bank, _)| (bank, accounts_dir))
+        };
+
+        let is_archive_encrypted = |snapshot_archive_info: &FullSnapshotArchiveInfo| {
+            is_snapshot_archive_encrypted(
+                snapshot_archive_info.path(),
+                snapshot_archive_info.archive_format(),
+            )
+            .unwrap()
+        };
+
+        let (snapshot_archive_info, _snapshot_archives_dir) = archive(&original_bank);
+        assert!(are_storages_encrypted(&original_bank));
+        assert!(is_archive_encrypted(&snapshot_archive_info));
+        assert!(load(&snapshot_archive_info, None).is_err());
+        assert!(load(&snapshot_archive_info, Some(&MasterKey::new_rand())).is_err());
+        let (roundtrip_bank, _accounts_dir) =
+            load(&snapshot_archive_info, Some(&master_key)).unwrap();
+        assert_eq!(roundtrip_bank, original_bank);
+        assert!(are_storages_encrypted(&roundtrip_bank));
+
+        let new_master_key = MasterKey::new_rand();
+        rotate_snapshot_archive_storage_encryption_key(
+            &snapshot_archive_info,
+            &master_key,
+            &new_master_key,
+            SnapshotDurability::default(),
+        )
+        .unwrap();
+        assert!(is_archive_encrypted(&snapshot_archive_info));
+        assert!(load(&snapshot_archive_info, Some(&master_key)).is_err());
+        let (roundtrip_bank, _accounts_dir) =
+            load(&snapshot_archive_info, Some(&new_master_key)).unwrap();
+        assert_eq!(roundtrip_bank, original_bank);
+        assert!(are_storages_encrypted(&roundtrip_bank));
+        // Rotating it again with the key it is no longer under fails, and leaves it be
+        assert!(rotate_snapshot_archive_storage_encryption_key(
+            &snapshot_archive_info,
+            &master_key,
+            &new_master_key,
+            SnapshotDurability::default(),
+        )
+        .is_err());
+        assert!(load(&snapshot_archive_info, Some(&new_master_key)).is_ok());
+
+        // A bank loaded from an archive that is not encrypted has its storages encrypted as soon as
+        // they are opened, and its archives are encrypted too
+        let plain_bank = Bank::new_for_tests(&genesis_config);
+        while !plain_bank.is_complete() {
+            plain_bank.register_tick(&Hash::new_unique());
+        }
+        plain_bank.squash();
+        plain_bank.force_flush_accounts_cache();
+        plain_bank.update_accounts_hash();
+        let (plain_snapshot_archive_info, _plain_snapshot_archives_dir) = archive(&plain_bank);
+        assert!(!are_storages_encrypted(&plain_bank));
+        assert!(!is_archive_encrypted(&plain_snapshot_archive_info));
+        assert!(rotate_snapshot_archive_storage_encryption_key(
+            &plain_snapshot_archive_info,
+            &master_key,
+            &new_master_key,
+            SnapshotDurability::default(),
+        )
+        .is_err());
+        let (roundtrip_bank, _accounts_dir) =
+            load(&plain_snapshot_archive_info, Some(&master_key)).unwrap();
+        assert_eq!(roundtrip_bank, plain_bank);
+        assert!(are_storages_encrypted(&roundtrip_bank));
+        let (snapshot_archive_info, _snapshot_archives_dir) = archive(&roundtrip_bank);
+        assert!(is_archive_encrypted(&snapshot_archive_info));
+        assert!(load(&snapshot_archive_info, None).is_err());
+        assert_eq!(
+            load(&snapshot_archive_info, Some(&master_key)).unwrap().0,
+            plain_bank
+        );
+    }
+
+    /// Test that profiling a snapshot writes out the phases of creating it, carried over from
+    /// taking the bank snapshot to archiving it, and those of loading it
+    #[test]
+    fn test_roundtrip_bank_to_and_from_full_snapshot_with_profile() {
+        solana_logger::setup();
+        let genesis_config = GenesisConfig::default();
+        let original_bank = Bank::new_for_tests(&genesis_config);
+
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
+        }
+        original_bank.squash();
+        original_bank.force_flush_accounts_cache();
+        original_bank.update_accounts_hash();
+
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_config = SnapshotConfig {
+            bank_snapshots_dir: bank_snapshots_dir.path().to_path_buf(),
+            snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+            archive_format: ArchiveFormat::TarZstd,
+            profile: true,
+            ..SnapshotConfig::default()
+        };
+        let (accounts_package_sender, accounts_package_receiver) = std::sync::mpsc::channel();
+        snapshot_bank(
+            &original_bank,
+            original_bank.src.slot_deltas(&original_bank.src.roots()),
+            &accounts_package_sender,
+            &snapshot_config,
+            None,
+            Some(SnapshotType::FullSnapshot),
+        )
+        .unwrap();
+        let snapshot_package = SnapshotPackage::from(accounts_package_receiver.recv().unwrap());
+        archive_snapshot_package(&snapshot_package, &snapshot_config).unwrap();
+        let snapshot_archive_info =
+            FullSnapshotArchiveInfo::new(snapshot_package.snapshot_archive_info);
+
+        let phase_names = |profile: &SnapshotProfile| {
+            profile
+                .phases
+                .iter()
+                .map(|phase| phase.name.clone())
+                .collect::<Vec<_>>()
+        };
+        let create_profile: SnapshotProfile = serde_json::from_slice(
+            &fs::read(snapshot_profile::snapshot_profile_path(
+                snapshot_archive_info.path(),
+                SnapshotProfileKind::Create,
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(create_profile.slot, original_bank.slot());
+        assert_eq!(
+            phase_names(&create_profile),
+            vec![
+                "get-snapshot-storages",
+                "add-bank-snapshot",
+                "package-bank-snapshot",
+                "serialize-status-cache",
+                "stage-snapshot",
+                "write-archive",
+                "verify-archive",
+                "rename-archive",
+                "purge-old-archives",
+            ]
+        );
+        let write_archive = &create_profile.phases[5];
+        assert_eq!(
+            write_archive.bytes,
+            Some(fs::metadata(snapshot_archive_info.path()).unwrap().len())
+        );
+
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let (roundtrip_bank, _) = bank_from_snapshot_archives(
+            &[PathBuf::from(accounts_dir.path())],
+            &[],
+            bank_snapshots_dir.path(),
+            &snapshot_archive_info,
+            None,
+            &genesis_config,
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            false,
+            false,
+            &snapshot_config,
+            Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
+            None,
+        )
+        .unwrap();
+        assert_eq!(roundtrip_bank, original_bank);
+
+        let load_profile: SnapshotProfile = serde_json::from_slice(
+            &fs::read(snapshot_profile::snapshot_profile_path(
+                snapshot_archive_info.path(),
+                SnapshotProfileKind::Load,
+            ))
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            phase_names(&load_profile),
+            vec![
+                "unarchive-full-snapshot",
+                "remap-storages",
+                "notify-restored-accounts",
+                "rebuild-bank",
+                "verify-bank",
+            ]
+        );
+    }
+
+    /// A hook that vetoes every snapshot at one of its two checks
+    #[derive(Debug)]
+    struct VetoingHook {
+        before_serialization: bool,
+    }
+
+    impl SnapshotHook for VetoingHook {
+        fn name(&self) -> &str {
+            "vetoing"
+        }
+
+        fn before_serialization(
+            &self,
+            _bank: &Bank,
+            _snapshot_storages: &[SnapshotStorage],
+        ) -> std::result::Result<(), String> {
+            if self.before_serialization {
+                Err("vetoed before serialization".to_string())
+            } else {
+                Ok(())
+            }
+        }
+
+        fn before_publication(
+            &self,
+            archive_path: &Path,
+            _snapshot_package: &SnapshotPackage,
+        ) -> std::result::Result<(), String> {
+            // the archive is complete by the time it is checked
+            assert!(archive_path.is_file());
+            Err("vetoed before publication".to_string())
+        }
+    }
+
+    /// Test that a hook vetoing a snapshot before serialization keeps the bank snapshot from
+    /// being taken, and vetoing it before publication keeps its archive from being published
+    #[test]
+    fn test_snapshot_vetoed_by_hook() {
+        solana_logger::setup();
+        let genesis_config = GenesisConfig::default();
+        let original_bank = Bank::new_for_tests(&genesis_config);
+
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
+        }
+        original_bank.squash();
+        original_bank.force_flush_accounts_cache();
+        original_bank.update_accounts_hash();
+
+        for before_serialization in [true, false] {
+            let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_config = SnapshotConfig {
+                bank_snapshots_dir: bank_snapshots_dir.path().to_path_buf(),
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: ArchiveFormat::TarZstd,
+                hooks: SnapshotHooks::new(vec![Arc::new(VetoingHook {
+                    before_serialization,
+                })]),
+                ..SnapshotConfig::default()
+            };
+            let (accounts_package_sender, accounts_package_receiver) = std::sync::mpsc::channel();
+            let result = snapshot_bank(
+                &original_bank,
+                original_bank.src.slot_deltas(&original_bank.src.roots()),
+                &accounts_package_sender,
+                &snapshot_config,
+                None,
+                Some(SnapshotType::FullSnapshot),
+            );
+            if before_serialization {
+                assert_matches!(
+                    result,
+                    Err(SnapshotError::VetoedByHook(name, _)) if name == "vetoing"
+                );
+                assert!(accounts_package_receiver.try_recv().is_err());
+                assert!(get_bank_snapshots(&bank_snapshots_dir).is_empty());
+                continue;
+            }
+            result.unwrap();
+
+            let snapshot_package = SnapshotPackage::from(accounts_package_receiver.recv().unwrap());
+            assert_matches!(
+                archive_snapshot_package(&snapshot_package, &snapshot_config),
+                Err(SnapshotError::VetoedByHook(name, _)) if name == "vetoing"
+            );
+            assert!(!snapshot_package.path().exists());
+            assert!(fs::read_dir(&snapshot_archives_dir)
+                .unwrap()
+                .next()
+                .is_none());
+            assert_eq!(
+                snapshot_config.hooks.last_published_full_snapshot_slot(),
+                None
+            );
+        }
+    }
+
+    /// Test that a command hook vetoes an archive when its program fails, and that only an
+    /// archive that gets past it counts as published
+    #[cfg(unix)]
+    #[test]
+    fn test_snapshot_publication_with_command_hook() {
+        solana_logger::setup();
+        let genesis_config = GenesisConfig::default();
+        let original_bank = Bank::new_for_tests(&genesis_config);
+
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
+        }
+        original_bank.squash();
+        original_bank.force_flush_accounts_cache();
+        original_bank.update_accounts_hash();
+
+        for (program, is_published) in [("false", false), ("true", true)] {
+            let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_config = SnapshotConfig {
+                bank_snapshots_dir: bank_snapshots_dir.path().to_path_buf(),
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: ArchiveFormat::TarZstd,
+                hooks: SnapshotHooks::new(vec![Arc::new(CommandHook::new(PathBuf::from(program)))]),
+                ..SnapshotConfig::default()
+            };
+            let (accounts_package_sender, accounts_package_receiver) = std::sync::mpsc::channel();
+            snapshot_bank(
+                &original_bank,
+                original_bank.src.slot_deltas(&original_bank.src.roots()),
+                &accounts_package_sender,
+                &snapshot_config,
+                None,
+                Some(SnapshotType::FullSnapshot),
+            )
+            .unwrap();
+
+            let snapshot_package = SnapshotPackage::from(accounts_package_receiver.recv().unwrap());
+            let result = archive_snapshot_package(&snapshot_package, &snapshot_config);
+            if is_published {
+                result.unwrap();
+                assert!(snapshot_package.path().is_file());
+                assert_eq!(
+                    snapshot_config.hooks.last_published_full_snapshot_slot(),
+                    Some(original_bank.slot())
+                );
+            } else {
+                assert_matches!(
+                    result,
+                    Err(SnapshotError::VetoedByHook(name, _)) if name == program
+                );
+                assert!(!snapshot_package.path().exists());
+                assert_eq!(
+                    snapshot_config.hooks.last_published_full_snapshot_slot(),
+                    None
+                );
+            }
+        }
+    }
+
+    /// Test that the archive, and the storages unpacked from it, get the configured permissions
+    #[cfg(unix)]
+    #[test]
+    fn test_roundtrip_bank_to_and_from_full_snapshot_with_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        solana_logger::setup();
+        let genesis_config = GenesisConfig::default();
+        let original_bank = Bank::new_for_tests(&genesis_config);
+
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
+        }
+        original_bank.squash();
+        original_bank.force_flush_accounts_cache();
+        original_bank.update_accounts_hash();
+
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_storages = original_bank.get_snapshot_storages(None);
+        let bank_snapshot_info = add_bank_snapshot(
+            &bank_snapshots_dir,
+            &original_bank,
+            &snapshot_storages,
+            SnapshotVersion::default(),
+            SnapshotDurability::default(),
+            FilePermissions::OWNER_ONLY,
+        )
+        .unwrap();
+        let accounts_package = AccountsPackage::new(
+            &original_bank,
+            &bank_snapshot_info,
+            &bank_snapshots_dir,
+            original_bank.src.slot_deltas(&original_bank.src.roots()),
+            &snapshot_archives_dir,
+            snapshot_storages,
+            ArchiveFormat::Tar,
+            SnapshotVersion::default(),
+            None,
+            Some(SnapshotType::FullSnapshot),
+        )
+        .unwrap();
+        let snapshot_package = SnapshotPackage::from(accounts_package);
+        archive_snapshot_package(
+            &snapshot_package,
+            &SnapshotConfig {
+                file_permissions: FilePermissions::OWNER_ONLY,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
+        let snapshot_archive_info =
+            FullSnapshotArchiveInfo::new(snapshot_package.snapshot_archive_info);
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(snapshot_archive_info.path()), 0o600);
+        let bank_snapshot_dir = bank_snapshot_info.snapshot_path.parent().unwrap();
+        assert_eq!(mode(bank_snapshot_dir), 0o700);
+        for entry in fs::read_dir(bank_snapshot_dir).unwrap() {
+            assert_eq!(mode(&entry.unwrap().path()), 0o600);
+        }
+
+        let (roundtrip_bank, _) = bank_from_snapshot_archives(
+            &[PathBuf::from(accounts_dir.path())],
+            &[],
+            bank_snapshots_dir.path(),
+            &snapshot_archive_info,
+            None,
+            &genesis_config,
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            false,
+            false,
+            &SnapshotConfig {
+                file_permissions: FilePermissions::OWNER_ONLY,
+                ..SnapshotConfig::default()
+            },
+            Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
+            None,
+        )
+        .unwrap();
+        assert_eq!(original_bank, roundtrip_bank);
+
+        let unpacked_accounts_dir = accounts_dir.path().join("accounts");
+        assert_eq!(mode(&unpacked_accounts_dir), 0o700);
+        let storage_paths: Vec<_> = fs::read_dir(&unpacked_accounts_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert!(!storage_paths.is_empty());
+        for storage_path in storage_paths {
+            assert_eq!(mode(&storage_path), 0o600);
+        }
+    }
+
+    /// Test that the same bank archives to the same bytes with deterministic snapshot
+    /// serialization, whether it is archived from the account paths it was built in, or from the
+    /// account paths it was rebuilt in from that archive, where its storages have other ids and
+    /// its maps another order
+    #[test]
+    fn test_deterministic_snapshot_archives() {
+        solana_logger::setup();
+        let collector = Pubkey::new_unique();
+        let (genesis_config, mint_keypair) = create_genesis_config(1_000_000);
+        let accounts_db_config = || AccountsDbConfig {
+            deterministic_snapshot_serialization: true,
+            ..ACCOUNTS_DB_CONFIG_FOR_TESTING
+        };
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let mut bank = Arc::new(Bank::new_with_paths(
+            &genesis_config,
+            vec![accounts_dir.path().to_path_buf()],
+            &[],
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            AccountShrinkThreshold::default(),
+            false,
+            Some(accounts_db_config()),
+            None,
+        ));
+        for slot in 1..=4 {
+            bank = Arc::new(Bank::new_from_parent(&bank, &collector, slot));
+            for lamports in 1..=4 {
+                bank.transfer(lamports, &mint_keypair, &Pubkey::new_unique())
+                    .unwrap();
+            }
+            while !bank.is_complete() {
+                bank.register_tick(&Hash::new_unique());
+            }
+            bank.squash();
+        }
+        // so that the bank is not shrunk any further when it is rebuilt
+        bank.clean_accounts(true, false, None);
+        bank.shrink_all_slots(false, None);
+
+        let archive = |bank: &Bank, snapshot_version| {
+            let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_config = SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: ArchiveFormat::Tar,
+                snapshot_version,
+                ..SnapshotConfig::default()
+            };
+            let snapshot_archive_info =
+                bank_to_full_snapshot_archive(&bank_snapshots_dir, bank, &snapshot_config).unwrap();
+            let accounts_dir = tempfile::TempDir::new().unwrap();
+            let (rebuilt_bank, _) = bank_from_snapshot_archives(
+                &[accounts_dir.path().to_path_buf()],
+                &[],
+                bank_snapshots_dir.path(),
+                &snapshot_archive_info,
+                None,
+                &genesis_config,
+                None,
+                None,
+                AccountSecondaryIndexes::default(),
+                false,
+                None,
+                AccountShrinkThreshold::default(),
+                false,
+                true,
+                false,
+                &snapshot_config,
+                Some(accounts_db_config()),
+                None,
+            )
+            .unwrap();
+            (
+                fs::read(snapshot_archive_info.path()).unwrap(),
+                rebuilt_bank,
+                accounts_dir,
+            )
+        };
+
+        for snapshot_version in [SnapshotVersion::V1_2_0, SnapshotVersion::V1_3_0] {
+            let (archived_bytes, rebuilt_bank, _accounts_dir) = archive(&bank, snapshot_version);
+            assert_eq!(*bank, rebuilt_bank);
+            let (rearchived_bytes, _, _) = archive(&rebuilt_bank, snapshot_version);
+            assert!(archived_bytes == rearchived_bytes);
+        }
+    }
+
+    /// Test roundtrip of bank to a full snapshot, then back again.  This test is more involved
+    /// than the simple version above; creating multiple banks over multiple slots and doing
+    /// multiple transfers.  So this full snapshot should contain more data.
+    #[test]
+    fn test_roundtrip_bank_to_and_from_snapshot_complex() {
+        solana_logger::setup();
+        let collector = Pubkey::new_unique();
+        let key1 = Keypair::new();
+        let key2 = Keypair::new();
+        let key3 = Keypair::new();
+        let key4 = Keypair::new();
+        let key5 = Keypair::new();
+
+        let (genesis_config, mint_keypair) = create_genesis_config(1_000_000);
+        let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+        bank0.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
+        bank0.transfer(2, &mint_keypair, &key2.pubkey()).unwrap();
+        bank0.transfer(3, &mint_keypair, &key3.pubkey()).unwrap();
+        while !bank0.is_complete() {
+            bank0.register_tick(&Hash::new_unique());
+        }
+
+        let slot = 1;
+        let bank1 = Arc::new(Bank::new_from_parent(&bank0, &collector, slot));
+        bank1.transfer(3, &mint_keypair, &key3.pubkey()).unwrap();
+        bank1.transfer(4, &mint_keypair, &key4.pubkey()).unwrap();
+        bank1.transfer(5, &mint_keypair, &key5.pubkey()).unwrap();
+        while !bank1.is_complete() {
+            bank1.register_tick(&Hash::new_unique());
+        }
+
+        let slot = slot + 1;
+        let bank2 = Arc::new(Bank::new_from_parent(&bank1, &collector, slot));
+        bank2.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
+        while !bank2.is_complete() {
+            bank2.register_tick(&Hash::new_unique());
+        }
+
+        let slot = slot + 1;
+        let bank3 = Arc::new(Bank::new_from_parent(&bank2, &collector, slot));
+        bank3.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
+        while !bank3.is_complete() {
+            bank3.register_tick(&Hash::new_unique());
+        }
+
+        let slot = slot + 1;
+        let bank4 = Arc::new(Bank::new_from_parent(&bank3, &collector, slot));
+        bank4.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
+        while !bank4.is_complete() {
+            bank4.register_tick(&Hash::new_unique());
+        }
+
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archive_format = ArchiveFormat::TarGzip;
+
+        let full_snapshot_archive_info = bank_to_full_snapshot_archive(
+            bank_snapshots_dir.path(),
+            &bank4,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
+
+        let (roundtrip_bank, _) = bank_from_snapshot_archives(
+            &[PathBuf::from(accounts_dir.path())],
+            &[],
+            bank_snapshots_dir.path(),
+            &full_snapshot_archive_info,
+            None,
+            &genesis_config,
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            false,
+            false,
+            &SnapshotConfig::default(),
+            Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(*bank4, roundtrip_bank);
+    }
+
+    /// Test roundtrip of bank to snapshots, then back again, with incremental snapshots.  In this
+    /// version, build up a few slots and take a full snapshot.  Continue on a few more slots and
+    /// take an incremental snapshot.  Rebuild the bank from both the incremental snapshot and full
+    /// snapshot.
+    ///
+    /// For the full snapshot, touch all the accounts, but only one for the incremental snapshot.
+    /// This is intended to mimic the real behavior of transactions, where only a small number of
+    /// accounts are modified often, which are captured by the incremental snapshot.  The majority
+    /// of the accounts are not modified often, and are captured by the full snapshot.
+    #[test]
+    fn test_roundtrip_bank_to_and_from_incremental_snapshot() {
+        solana_logger::setup();
+        let collector = Pubkey::new_unique();
+        let key1 = Keypair::new();
+        let key2 = Keypair::new();
+        let key3 = Keypair::new();
+        let key4 = Keypair::new();
+        let key5 = Keypair::new();
+
+        let (genesis_config, mint_keypair) = create_genesis_config(1_000_000);
+        let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+        bank0.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
+        bank0.transfer(2, &mint_keypair, &key2.pubkey()).unwrap();
+        bank0.transfer(3, &mint_keypair, &key3.pubkey()).unwrap();
+        while !bank0.is_complete() {
+            bank0.register_tick(&Hash::new_unique());
+        }
+
+        let slot = 1;
+        let bank1 = Arc::new(Bank::new_from_parent(&bank0, &collector, slot));
+        bank1.transfer(3, &mint_keypair, &key3.pubkey()).unwrap();
+        bank1.transfer(4, &mint_keypair, &key4.pubkey()).unwrap();
+        bank1.transfer(5, &mint_keypair, &key5.pubkey()).unwrap();
+        while !bank1.is_complete() {
+            bank1.register_tick(&Hash::new_unique());
+        }
+
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archive_format = ArchiveFormat::TarZstd;
+
+        let full_snapshot_slot = slot;
+        let full_snapshot_archive_info = bank_to_full_snapshot_archive(
+            bank_snapshots_dir.path(),
+            &bank1,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
+
+        let slot = slot + 1;
+        let bank2 = Arc::new(Bank::new_from_parent(&bank1, &collector, slot));
+        bank2.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
+        while !bank2.is_complete() {
+            bank2.register_tick(&Hash::new_unique());
+        }
 
         let slot = slot + 1;
         let bank3 = Arc::new(Bank::new_from_parent(&bank2, &collector, slot));
@@ -2831,11 +5387,11 @@ mod tests {
             bank_snapshots_dir.path(),
             &bank4,
             full_snapshot_slot,
-            None,
-            snapshot_archives_dir.path(),
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
         )
         .unwrap();
 
@@ -2855,6 +5411,7 @@ mod tests {
             false,
             false,
             false,
+            &SnapshotConfig::default(),
             Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
             None,
         )
@@ -2863,6 +5420,387 @@ mod tests {
         assert_eq!(*bank4, roundtrip_bank);
     }
 
+    /// Test probing the start of snapshot archives for whether they can be loaded
+    #[test]
+    fn test_probe_snapshot_archive() {
+        solana_logger::setup();
+        let genesis_config = GenesisConfig::default();
+        let original_bank = Bank::new_for_tests(&genesis_config);
+        while !original_bank.is_complete() {
+            original_bank.register_tick(&Hash::new_unique());
+        }
+
+        for (snapshot_version, archive_format, expected_compatibility) in [
+            (
+                SnapshotVersion::V1_3_0,
+                ArchiveFormat::TarZstd,
+                SnapshotCompatibility::Compatible {
+                    version: SnapshotVersion::V1_3_0,
+                    sections: serde_snapshot::SnapshotSections {
+                        separate_storages_file: true,
+                        encrypted_storages: false,
+                    },
+                },
+            ),
+            (
+                SnapshotVersion::V1_3_0,
+                ArchiveFormat::TarGzip,
+                SnapshotCompatibility::Compatible {
+                    version: SnapshotVersion::V1_3_0,
+                    sections: serde_snapshot::SnapshotSections {
+                        separate_storages_file: true,
+                        encrypted_storages: false,
+                    },
+                },
+            ),
+            (
+                SnapshotVersion::V1_2_0,
+                ArchiveFormat::Tar,
+                SnapshotCompatibility::Undetermined,
+            ),
+        ] {
+            let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+            let snapshot_archive_info = bank_to_full_snapshot_archive(
+                bank_snapshots_dir.path(),
+                &original_bank,
+                &SnapshotConfig {
+                    snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                    archive_format,
+                    snapshot_version,
+                    ..SnapshotConfig::default()
+                },
+            )
+            .unwrap();
+
+            let archive_bytes = fs::read(snapshot_archive_info.path()).unwrap();
+            let mut probe = SnapshotArchiveProbe::new(archive_format).unwrap();
+            assert_eq!(
+                probe.probe(&archive_bytes[..16]),
+                SnapshotCompatibility::Incomplete
+            );
+            let compatibility = archive_bytes[16..]
+                .chunks(64)
+                .map(|chunk| probe.probe(chunk))
+                .find(|compatibility| *compatibility != SnapshotCompatibility::Incomplete);
+            assert_eq!(compatibility, Some(expected_compatibility.clone()));
+
+            let mut probe = SnapshotArchiveProbe::new(archive_format).unwrap();
+            assert_eq!(probe.probe(&archive_bytes), expected_compatibility);
+        }
+
+        // A stream that does not decompress can never become a snapshot archive
+        let mut probe = SnapshotArchiveProbe::new(ArchiveFormat::TarZstd).unwrap();
+        assert_eq!(
+            probe.probe(&[0xff; 64]),
+            SnapshotCompatibility::NotASnapshot
+        );
+    }
+
+    /// Test roundtrip of bank to full and incremental snapshots that keep the storages listing in
+    /// a file of its own, and that the bank manifest comes before the storages in the archive
+    #[test]
+    fn test_roundtrip_bank_to_and_from_snapshots_with_separate_storages_file() {
+        solana_logger::setup();
+        let collector = Pubkey::new_unique();
+        let key1 = Keypair::new();
+        let key2 = Keypair::new();
+        let snapshot_version = SnapshotVersion::V1_3_0;
+        assert!(snapshot_version.has_separate_storages_file());
+
+        let (genesis_config, mint_keypair) = create_genesis_config(1_000_000);
+        let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+        bank0.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
+        while !bank0.is_complete() {
+            bank0.register_tick(&Hash::new_unique());
+        }
+
+        let slot = 1;
+        let bank1 = Arc::new(Bank::new_from_parent(&bank0, &collector, slot));
+        bank1.transfer(2, &mint_keypair, &key2.pubkey()).unwrap();
+        while !bank1.is_complete() {
+            bank1.register_tick(&Hash::new_unique());
+        }
+
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archive_format = ArchiveFormat::Tar;
+
+        let full_snapshot_slot = slot;
+        let full_snapshot_archive_info = bank_to_full_snapshot_archive(
+            bank_snapshots_dir.path(),
+            &bank1,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                snapshot_version,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
+
+        let entry_paths: Vec<_> =
+            Archive::new(File::open(full_snapshot_archive_info.path()).unwrap())
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().into_owned())
+                .collect();
+        let manifest_position = |file_name: String| {
+            entry_paths
+                .iter()
+                .position(|path| {
+                    path == &Path::new("snapshots")
+                        .join(full_snapshot_slot.to_string())
+                        .join(&file_name)
+                })
+                .unwrap()
+        };
+        let storages_file_position = manifest_position(format!("{}.storages", full_snapshot_slot));
+        let bank_file_position = manifest_position(full_snapshot_slot.to_string());
+        let first_storage_position = entry_paths
+            .iter()
+            .position(|path| path.starts_with("accounts") && path != Path::new("accounts"))
+            .unwrap();
+        assert!(bank_file_position < first_storage_position);
+        assert!(storages_file_position < first_storage_position);
+
+        let slot = slot + 1;
+        let bank2 = Arc::new(Bank::new_from_parent(&bank1, &collector, slot));
+        bank2.transfer(1, &mint_keypair, &key1.pubkey()).unwrap();
+        while !bank2.is_complete() {
+            bank2.register_tick(&Hash::new_unique());
+        }
+
+        let incremental_snapshot_archive_info = bank_to_incremental_snapshot_archive(
+            bank_snapshots_dir.path(),
+            &bank2,
+            full_snapshot_slot,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                snapshot_version,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
+
+        let (roundtrip_bank, _) = bank_from_snapshot_archives(
+            &[PathBuf::from(accounts_dir.path())],
+            &[],
+            bank_snapshots_dir.path(),
+            &full_snapshot_archive_info,
+            Some(&incremental_snapshot_archive_info),
+            &genesis_config,
+            None,
+            None,
+            AccountSecondaryIndexes::default(),
+            false,
+            None,
+            AccountShrinkThreshold::default(),
+            false,
+            false,
+            false,
+            &SnapshotConfig::default(),
+            Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(*bank2, roundtrip_bank);
+    }
+
+    #[test]
+    fn test_export_program_accounts_from_snapshot_archives() {
+        solana_logger::setup();
+        let collector = Pubkey::new_unique();
+        let program_id = Pubkey::new_unique();
+        let other_program_id = Pubkey::new_unique();
+        let keys: Vec<_> = (0..4).map(|_| Pubkey::new_unique()).collect();
+
+        let (genesis_config, _mint_keypair) = create_genesis_config(1_000_000);
+        let bank0 = Arc::new(Bank::new_for_tests(&genesis_config));
+        for (i, key) in keys.iter().take(3).enumerate() {
+            bank0.store_account(key, &AccountSharedData::new(i as u64 + 1, i, &program_id));
+        }
+        bank0.set_capitalization();
+        while !bank0.is_complete() {
+            bank0.register_tick(&Hash::new_unique());
+        }
+
+        let bank1 = Arc::new(Bank::new_from_parent(&bank0, &collector, 1));
+        while !bank1.is_complete() {
+            bank1.register_tick(&Hash::new_unique());
+        }
+
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let export_dir = tempfile::TempDir::new().unwrap();
+        let full_snapshot_archive_info = bank_to_full_snapshot_archive(
+            bank_snapshots_dir.path(),
+            &bank1,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: ArchiveFormat::Tar,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
+
+        let expected_program_accounts = |bank: &Bank| {
+            let mut program_accounts = bank
+                .get_program_accounts(&program_id, ScanConfig::default())
+                .unwrap();
+            program_accounts.sort_unstable_by_key(|(pubkey, _)| *pubkey);
+            program_accounts
+        };
+        // Once bank2 is rooted, scanning bank1 sees its accounts too
+        let expected_full_snapshot_accounts = expected_program_accounts(&bank1);
+
+        // After the full snapshot one account moves to another program, one is closed, and a new
+        // one is created
+        let bank2 = Arc::new(Bank::new_from_parent(&bank1, &collector, 2));
+        bank2.store_account(&keys[1], &AccountSharedData::new(2, 1, &other_program_id));
+        bank2.store_account(&keys[2], &AccountSharedData::new(0, 0, &program_id));
+        bank2.store_account(&keys[3], &AccountSharedData::new(4, 3, &program_id));
+        bank2.set_capitalization();
+        while !bank2.is_complete() {
+            bank2.register_tick(&Hash::new_unique());
+        }
+        let incremental_snapshot_archive_info = bank_to_incremental_snapshot_archive(
+            bank_snapshots_dir.path(),
+            &bank2,
+            full_snapshot_archive_info.slot(),
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: ArchiveFormat::Tar,
+                ..SnapshotConfig::default()
+            },
+        )
+        .unwrap();
+
+        let export_program_accounts = |incremental_snapshot_archive_info, output_path| {
+            let header = export_program_accounts_from_snapshot_archives(
+                &[accounts_dir.path().join(output_path)],
+                bank_snapshots_dir.path(),
+                &full_snapshot_archive_info,
+                incremental_snapshot_archive_info,
+                &genesis_config,
+                &SnapshotConfig::default(),
+                None,
+                &program_id,
+                export_dir.path().join(output_path),
+            )
+            .unwrap();
+            let reader =
+                ProgramAccountsSnapshotReader::open(export_dir.path().join(output_path)).unwrap();
+            assert_eq!(reader.header(), &header);
+            let accounts: Vec<_> = reader
+                .map(|item| {
+                    let (pubkey, account) = item.unwrap();
+                    (pubkey, AccountSharedData::from(account))
+                })
+                .collect();
+            (header, accounts)
+        };
+        let (header, accounts) = export_program_accounts(None, "full");
+        assert_eq!(header.program_id, program_id);
+        assert_eq!(header.slot, 1);
+        assert_eq!(header.accounts_hash, *full_snapshot_archive_info.hash());
+        assert_eq!(header.incremental_snapshot, None);
+        assert_eq!(header.account_count, 3);
+        assert_eq!(accounts, expected_full_snapshot_accounts);
+
+        let (header, accounts) =
+            export_program_accounts(Some(&incremental_snapshot_archive_info), "incremental");
+        assert_eq!(header.slot, 2);
+        assert_eq!(header.full_snapshot_slot, 1);
+        assert_eq!(
+            header.incremental_snapshot,
+            Some((2, *incremental_snapshot_archive_info.hash()))
+        );
+        let expected_accounts = expected_program_accounts(&bank2);
+        assert_eq!(
+            expected_accounts
+                .iter()
+                .map(|(pubkey, _)| *pubkey)
+                .collect::<HashSet<_>>(),
+            vec![keys[0], keys[3]].into_iter().collect()
+        );
+        assert_eq!(accounts, expected_accounts);
+    }
+
+    /// Test that the program accounts are not exported from snapshot archives whose accounts do
+    /// not add up to the hash the archives are named after
+    #[test]
+    fn test_export_program_accounts_from_tampered_snapshot_archive() {
+        solana_logger::setup();
+        let program_id = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+
+        let (genesis_config, _mint_keypair) = create_genesis_config(1_000_000);
+        let bank = Bank::new_for_tests(&genesis_config);
+        bank.store_account(&key, &AccountSharedData::new(1, 0, &program_id));
+        bank.set_capitalization();
+        while !bank.is_complete() {
+            bank.register_tick(&Hash::new_unique());
+        }
+        bank.squash();
+        bank.force_flush_accounts_cache();
+        bank.update_accounts_hash();
+        // The account changes after its hash went into the accounts hash
+        bank.rc.accounts.accounts_db.store_uncached(
+            bank.slot(),
+            &[(&key, &AccountSharedData::new(2, 0, &program_id))],
+        );
+
+        let accounts_dir = tempfile::TempDir::new().unwrap();
+        let bank_snapshots_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_archives_dir = tempfile::TempDir::new().unwrap();
+        let export_dir = tempfile::TempDir::new().unwrap();
+        let snapshot_config = SnapshotConfig {
+            snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+            archive_format: ArchiveFormat::Tar,
+            ..SnapshotConfig::default()
+        };
+        let snapshot_storages = bank.get_snapshot_storages(None);
+        let bank_snapshot_info = add_bank_snapshot(
+            &bank_snapshots_dir,
+            &bank,
+            &snapshot_storages,
+            snapshot_config.snapshot_version,
+            snapshot_config.durability,
+            snapshot_config.file_permissions,
+        )
+        .unwrap();
+        let full_snapshot_archive_info = package_and_archive_full_snapshot(
+            &bank,
+            &bank_snapshot_info,
+            &bank_snapshots_dir,
+            snapshot_storages,
+            &snapshot_config,
+        )
+        .unwrap();
+
+        let output_path = export_dir.path().join("program_accounts");
+        assert_matches!(
+            export_program_accounts_from_snapshot_archives(
+                &[accounts_dir.path().to_path_buf()],
+                bank_snapshots_dir.path(),
+                &full_snapshot_archive_info,
+                None,
+                &genesis_config,
+                &snapshot_config,
+                None,
+                &program_id,
+                &output_path,
+            ),
+            Err(SnapshotError::AccountsVerificationFailed(_))
+        );
+        assert!(!output_path.exists());
+    }
+
     /// Test rebuilding bank from the latest snapshot archives
     #[test]
     fn test_bank_from_latest_snapshot_archives() {
@@ -2899,11 +5837,11 @@ mod tests {
         bank_to_full_snapshot_archive(
             &bank_snapshots_dir,
             &bank1,
-            None,
-            &snapshot_archives_dir,
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
         )
         .unwrap();
 
@@ -2932,11 +5870,11 @@ mod tests {
             &bank_snapshots_dir,
             &bank4,
             full_snapshot_slot,
-            None,
-            &snapshot_archives_dir,
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
         )
         .unwrap();
 
@@ -2955,6 +5893,7 @@ mod tests {
             false,
             false,
             false,
+            &SnapshotConfig::default(),
             Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
             None,
         )
@@ -3032,11 +5971,11 @@ mod tests {
         let full_snapshot_archive_info = bank_to_full_snapshot_archive(
             bank_snapshots_dir.path(),
             &bank1,
-            None,
-            snapshot_archives_dir.path(),
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
         )
         .unwrap();
 
@@ -3072,11 +6011,11 @@ mod tests {
             bank_snapshots_dir.path(),
             &bank2,
             full_snapshot_slot,
-            None,
-            snapshot_archives_dir.path(),
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
         )
         .unwrap();
         let (deserialized_bank, _) = bank_from_snapshot_archives(
@@ -3095,6 +6034,7 @@ mod tests {
             false,
             false,
             false,
+            &SnapshotConfig::default(),
             Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
             None,
         )
@@ -3134,11 +6074,11 @@ mod tests {
             bank_snapshots_dir.path(),
             &bank4,
             full_snapshot_slot,
-            None,
-            snapshot_archives_dir.path(),
-            snapshot_archive_format,
-            DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
+            &SnapshotConfig {
+                snapshot_archives_dir: snapshot_archives_dir.path().to_path_buf(),
+                archive_format: snapshot_archive_format,
+                ..SnapshotConfig::default()
+            },
         )
         .unwrap();
 
@@ -3158,6 +6098,7 @@ mod tests {
             false,
             false,
             false,
+            &SnapshotConfig::default(),
             Some(ACCOUNTS_DB_CONFIG_FOR_TESTING),
             None,
         )
@@ -0,0 +1,1216 @@
+//! Optional encryption at rest of the append vecs in the account paths
+//!
+//! An encrypted append vec is sealed in chunks of `CHUNK_LEN` bytes, each under a fresh random
+//! nonce, with the slot and id of the storage and the index of the chunk as the associated data,
+//! so chunks cannot be moved to another storage or reordered.  The data key the chunks are sealed
+//! with is wrapped by a master key that the operator provides, see `MasterKeyProvider`, and is
+//! stored in a header at the front of the file, so the file can be decrypted on its own again when
+//! it is opened.  The data key is wrapped with the rest of the header as the associated data: the
+//! slot and id of the storage, its sizes, and a hash of the nonces of its chunks, so a storage
+//! cannot pass for another one, be cut short, or have a chunk swapped for one sealed before it.
+//! Snapshot archives carry the encrypted files as they are, along with the id of the master key,
+//! so rotating the master key, or giving a storage another id, only has to rewrap its data key
+//! instead of re-encrypting it.  The account paths are rebuilt from the archives when a node
+//! starts, so only the archives need rotating.
+//!
+//! A chunk is only decrypted once it is first read, and only the chunks that were written to are
+//! sealed again when the append vec is flushed.  The chunks are decrypted into an unlinked file in
+//! the temporary directory (`TMPDIR`), so that the kernel can write back and drop their pages under
+//! memory pressure, as it does for plain append vecs, rather than keep every chunk that was ever
+//! read in memory.  Whatever is decrypted can reach the disk there, so `TMPDIR` should be on a
+//! file system that the operator trusts with the plaintext, e.g. an encrypted one.
+//!
+//! The storages of an archive that is not encrypted, e.g. one downloaded from a peer, are only
+//! plaintext in the account paths from the time they are unpacked until they are opened, when
+//! they are sealed once, a chunk at a time.
+use {
+    bincode::Options,
+    rand::{thread_rng, Rng},
+    ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, MAX_TAG_LEN, NONCE_LEN},
+    serde::{Deserialize, Serialize},
+    solana_sdk::{
+        clock::Slot,
+        hash::{hashv, Hash, Hasher},
+    },
+    std::{
+        cmp,
+        convert::TryFrom,
+        fmt,
+        fs::{self, File, OpenOptions},
+        io::{self, BufReader, Read, Seek, SeekFrom, Write},
+        ops::Range,
+        path::{Path, PathBuf},
+        process::Command,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// Name of the file, at the top level of a snapshot archive, that holds the id of the master key
+/// its storages are encrypted under, if they are
+pub const STORAGE_MASTER_KEY_ID_FILE_NAME: &str = "storage_master_key_id";
+
+pub const KEY_LEN: usize = 32;
+
+/// How many bytes of a storage are sealed together
+pub const CHUNK_LEN: usize = 64 * 1024;
+/// ChaCha20-Poly1305 tags are the longest there are
+const TAG_LEN: usize = MAX_TAG_LEN;
+/// How far apart the sealed chunks are in the file: each is stored as its nonce, its ciphertext,
+/// and its tag, so any one of them can be sealed again in place
+const SEALED_CHUNK_LEN: u64 = (NONCE_LEN + CHUNK_LEN + TAG_LEN) as u64;
+
+/// What an encrypted storage file starts with.  A plain append vec starts with the write version
+/// of its first account, which would have to be in the quintillions to look like this.
+const ENCRYPTED_STORAGE_MAGIC: &[u8; 8] = b"\0SOLENC1";
+/// Encrypted storage headers are far shorter than this
+const MAX_ENCRYPTED_STORAGE_HEADER_LEN: u32 = 1024;
+
+const MASTER_KEY_ID_DOMAIN: &[u8] = b"solana-storage-master-key-id";
+const WRAPPED_DATA_KEY_DOMAIN: &[u8] = b"solana-storage-wrapped-data-key";
+
+fn aead_key(key: &[u8; KEY_LEN]) -> LessSafeKey {
+    LessSafeKey::new(
+        UnboundKey::new(&CHACHA20_POLY1305, key)
+            .expect("KEY_LEN is the ChaCha20-Poly1305 key length"),
+    )
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is corrupt, or was encrypted with another key", what),
+    )
+}
+
+/// The key that the data keys are wrapped with
+#[derive(Clone, PartialEq, Eq)]
+pub struct MasterKey {
+    key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MasterKey").field("id", &self.id()).finish()
+    }
+}
+
+impl MasterKey {
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        Self { key }
+    }
+
+    pub fn new_rand() -> Self {
+        Self::new(thread_rng().gen())
+    }
+
+    /// Read the master key from a file holding exactly the key's bytes
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        MasterKeyFile::new(path.as_ref().to_path_buf()).master_key()
+    }
+
+    /// The master key in `bytes`, which are either exactly the key's bytes, or the key in hex,
+    /// optionally surrounded by whitespace
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if let Ok(key) = <[u8; KEY_LEN]>::try_from(bytes) {
+            return Some(Self::new(key));
+        }
+        let hex = std::str::from_utf8(bytes).ok()?.trim();
+        if hex.len() != 2 * KEY_LEN {
+            return None;
+        }
+        let mut key = [0u8; KEY_LEN];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+        }
+        Some(Self::new(key))
+    }
+
+    /// Identifies the master key without revealing it, so the right key can be picked (or a
+    /// wrong one rejected) when unwrapping
+    pub fn id(&self) -> Hash {
+        hashv(&[MASTER_KEY_ID_DOMAIN, &self.key])
+    }
+
+    /// Wrap `data_key`, so that it only unwraps with the same `associated_data`
+    pub fn wrap(&self, data_key: &DataKey, associated_data: &[u8]) -> WrappedDataKey {
+        let nonce = thread_rng().gen();
+        let mut wrapped_key = data_key.key.to_vec();
+        aead_key(&self.key)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from([WRAPPED_DATA_KEY_DOMAIN, associated_data].concat()),
+                &mut wrapped_key,
+            )
+            .expect("a data key is far shorter than the longest message that can be sealed");
+        WrappedDataKey {
+            master_key_id: self.id(),
+            nonce,
+            wrapped_key,
+        }
+    }
+
+    pub fn unwrap(
+        &self,
+        wrapped_data_key: &WrappedDataKey,
+        associated_data: &[u8],
+    ) -> io::Result<DataKey> {
+        if wrapped_data_key.master_key_id != self.id() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "data key was wrapped by master key {}, not {}",
+                    wrapped_data_key.master_key_id,
+                    self.id()
+                ),
+            ));
+        }
+        let mut wrapped_key = wrapped_data_key.wrapped_key.clone();
+        let key = aead_key(&self.key)
+            .open_in_place(
+                Nonce::assume_unique_for_key(wrapped_data_key.nonce),
+                Aad::from([WRAPPED_DATA_KEY_DOMAIN, associated_data].concat()),
+                &mut wrapped_key,
+            )
+            .ok()
+            .and_then(|key| <[u8; KEY_LEN]>::try_from(&*key).ok())
+            .ok_or_else(|| corrupt("wrapped data key"))?;
+        Ok(DataKey { key })
+    }
+}
+
+/// Where the master key comes from, so that it need not sit in a file next to the storages it
+/// protects.  It is only asked for once, when the node starts.
+pub trait MasterKeyProvider: fmt::Debug + Send + Sync {
+    fn master_key(&self) -> io::Result<MasterKey>;
+}
+
+/// A master key that is already at hand, e.g. one that was read from a config
+impl MasterKeyProvider for MasterKey {
+    fn master_key(&self) -> io::Result<MasterKey> {
+        Ok(self.clone())
+    }
+}
+
+/// A file holding exactly the master key's bytes
+#[derive(Debug)]
+pub struct MasterKeyFile {
+    path: PathBuf,
+}
+
+impl MasterKeyFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl MasterKeyProvider for MasterKeyFile {
+    fn master_key(&self) -> io::Result<MasterKey> {
+        let bytes = fs::read(&self.path)?;
+        let key = <[u8; KEY_LEN]>::try_from(bytes.as_slice()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} does not hold a {} byte storage encryption key",
+                    self.path.display(),
+                    KEY_LEN
+                ),
+            )
+        })?;
+        Ok(MasterKey::new(key))
+    }
+}
+
+/// A program that writes the master key to stdout, either as exactly its bytes or in hex, e.g.
+/// one that fetches it from a key management service
+#[derive(Debug)]
+pub struct MasterKeyCommand {
+    program: PathBuf,
+}
+
+impl MasterKeyCommand {
+    pub fn new(program: PathBuf) -> Self {
+        Self { program }
+    }
+}
+
+impl MasterKeyProvider for MasterKeyCommand {
+    fn master_key(&self) -> io::Result<MasterKey> {
+        let output = Command::new(&self.program).output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} failed with {}: {}",
+                    self.program.display(),
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            ));
+        }
+        MasterKey::from_bytes(&output.stdout).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} did not write a {} byte storage encryption key",
+                    self.program.display(),
+                    KEY_LEN
+                ),
+            )
+        })
+    }
+}
+
+/// The key that a single storage is encrypted with
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey {
+    key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("DataKey")
+    }
+}
+
+impl DataKey {
+    pub fn new_rand() -> Self {
+        Self {
+            key: thread_rng().gen(),
+        }
+    }
+
+    /// The associated data of chunk `index` of the storage bound to `binding`
+    fn chunk_associated_data(binding: StorageBinding, index: u64) -> [u8; 24] {
+        let mut associated_data = [0u8; 24];
+        associated_data[..8].copy_from_slice(&binding.slot.to_le_bytes());
+        associated_data[8..16].copy_from_slice(&binding.id.to_le_bytes());
+        associated_data[16..].copy_from_slice(&index.to_le_bytes());
+        associated_data
+    }
+
+    /// Seal `chunk`, chunk `index` of the storage bound to `binding`, in place under a fresh
+    /// nonce, which is returned
+    fn seal_chunk(
+        &self,
+        binding: StorageBinding,
+        index: u64,
+        chunk: &mut Vec<u8>,
+    ) -> [u8; NONCE_LEN] {
+        let nonce = thread_rng().gen();
+        aead_key(&self.key)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(Self::chunk_associated_data(binding, index)),
+                chunk,
+            )
+            .expect("a chunk is far shorter than the longest message that can be sealed");
+        nonce
+    }
+
+    /// Open the sealed `chunk`, chunk `index` of the storage bound to `binding`, in place,
+    /// returning its plaintext
+    fn open_chunk<'a>(
+        &self,
+        binding: StorageBinding,
+        index: u64,
+        nonce: [u8; NONCE_LEN],
+        chunk: &'a mut [u8],
+    ) -> io::Result<&'a mut [u8]> {
+        aead_key(&self.key)
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(Self::chunk_associated_data(binding, index)),
+                chunk,
+            )
+            .map_err(|_| corrupt("encrypted storage"))
+    }
+}
+
+/// A data key as it is stored in the header of an encrypted storage
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct WrappedDataKey {
+    master_key_id: Hash,
+    nonce: [u8; NONCE_LEN],
+    /// The sealed data key, followed by its tag
+    wrapped_key: Vec<u8>,
+}
+
+impl WrappedDataKey {
+    /// The id of the master key this data key is wrapped by
+    pub fn master_key_id(&self) -> &Hash {
+        &self.master_key_id
+    }
+}
+
+/// The slot and append vec id of a storage, which its data key is bound to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StorageBinding {
+    pub slot: Slot,
+    pub id: u64,
+}
+
+impl fmt::Display for StorageBinding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.slot, self.id)
+    }
+}
+
+/// What an encrypted storage file holds ahead of the sealed chunks.  Its serialized length does
+/// not depend on what it holds, so it can be rewritten in place.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct EncryptedStorageHeader {
+    wrapped_data_key: WrappedDataKey,
+    binding: StorageBinding,
+    /// The slot and id that the chunks are sealed for.  It is the binding the storage had when it
+    /// was last empty, and is kept when the storage is given another id at load, which would
+    /// otherwise mean sealing every chunk again.
+    chunk_binding: StorageBinding,
+    /// The size of the append vec, which the file is not
+    file_size: u64,
+    /// How many bytes from the start of the append vec are sealed in the file.  The chunks after
+    /// those are all zeros.
+    stored_len: u64,
+    /// The hash of the nonces of the chunks that are sealed in the file, in order.  Every chunk is
+    /// sealed under a fresh nonce, so none can be swapped for one that was sealed before it.
+    nonces_hash: Hash,
+}
+
+impl EncryptedStorageHeader {
+    fn new(
+        master_key: &MasterKey,
+        data_key: &DataKey,
+        binding: StorageBinding,
+        chunk_binding: StorageBinding,
+        file_size: u64,
+        stored_len: u64,
+        nonces_hash: Hash,
+    ) -> Self {
+        // The data key is wrapped with the rest of the header, once it is all in
+        let mut header = Self {
+            wrapped_data_key: WrappedDataKey {
+                master_key_id: Hash::default(),
+                nonce: [0; NONCE_LEN],
+                wrapped_key: Vec::new(),
+            },
+            binding,
+            chunk_binding,
+            file_size,
+            stored_len,
+            nonces_hash,
+        };
+        header.wrapped_data_key = master_key.wrap(data_key, &header.associated_data());
+        header
+    }
+
+    /// Everything in the header but the wrapped data key, which the data key is wrapped with as
+    /// associated data
+    fn associated_data(&self) -> [u8; 80] {
+        let mut associated_data = [0u8; 80];
+        associated_data[..8].copy_from_slice(&self.binding.slot.to_le_bytes());
+        associated_data[8..16].copy_from_slice(&self.binding.id.to_le_bytes());
+        associated_data[16..24].copy_from_slice(&self.chunk_binding.slot.to_le_bytes());
+        associated_data[24..32].copy_from_slice(&self.chunk_binding.id.to_le_bytes());
+        associated_data[32..40].copy_from_slice(&self.file_size.to_le_bytes());
+        associated_data[40..48].copy_from_slice(&self.stored_len.to_le_bytes());
+        associated_data[48..].copy_from_slice(self.nonces_hash.as_ref());
+        associated_data
+    }
+
+    fn unwrap_data_key(&self, master_key: &MasterKey) -> io::Result<DataKey> {
+        master_key.unwrap(&self.wrapped_data_key, &self.associated_data())
+    }
+
+    /// The same header, with its data key wrapped by `master_key` and bound to `binding`
+    fn rewrapped(
+        &self,
+        master_key: &MasterKey,
+        data_key: &DataKey,
+        binding: StorageBinding,
+    ) -> Self {
+        Self::new(
+            master_key,
+            data_key,
+            binding,
+            self.chunk_binding,
+            self.file_size,
+            self.stored_len,
+            self.nonces_hash,
+        )
+    }
+
+    /// How many chunks of the storage are sealed in the file
+    fn num_stored_chunks(&self) -> u64 {
+        num_chunks(self.stored_len)
+    }
+
+    /// The magic, the length of the header, and the header, which the sealed chunks follow
+    fn to_prefix(&self) -> Vec<u8> {
+        let header = bincode::serialize(self).expect("serialize encrypted storage header");
+        let mut prefix = ENCRYPTED_STORAGE_MAGIC.to_vec();
+        prefix.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        prefix.extend_from_slice(&header);
+        prefix
+    }
+
+    /// Read the prefix written by `to_prefix()` from the start of `reader`, returning the header
+    /// and how long the prefix was.  Errors if `reader` does not start with the magic.
+    fn read_prefix(reader: &mut impl Read) -> io::Result<(Self, u64)> {
+        let mut magic = [0u8; ENCRYPTED_STORAGE_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != ENCRYPTED_STORAGE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "storage is not encrypted",
+            ));
+        }
+        let mut header_len = [0u8; 4];
+        reader.read_exact(&mut header_len)?;
+        let header_len = u32::from_le_bytes(header_len);
+        if header_len > MAX_ENCRYPTED_STORAGE_HEADER_LEN {
+            return Err(corrupt("encrypted storage header"));
+        }
+        let mut header = vec![0u8; header_len as usize];
+        reader.read_exact(&mut header)?;
+        let header: Self = bincode::options()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .deserialize(&header)
+            .map_err(|_| corrupt("encrypted storage header"))?;
+        if header.stored_len > header.file_size {
+            return Err(corrupt("encrypted storage header"));
+        }
+        let prefix_len = (ENCRYPTED_STORAGE_MAGIC.len() + 4) as u64 + u64::from(header_len);
+        Ok((header, prefix_len))
+    }
+}
+
+/// Whether the storage file at `path` is encrypted
+pub fn is_encrypted_storage(path: &Path) -> io::Result<bool> {
+    let mut magic = [0u8; ENCRYPTED_STORAGE_MAGIC.len()];
+    let mut file = File::open(path)?;
+    let mut len = 0;
+    while len < magic.len() {
+        match file.read(&mut magic[len..])? {
+            0 => return Ok(false),
+            read => len += read,
+        }
+    }
+    Ok(&magic == ENCRYPTED_STORAGE_MAGIC)
+}
+
+/// How many chunks the first `len` bytes of a storage are in
+fn num_chunks(len: u64) -> u64 {
+    (len + CHUNK_LEN as u64 - 1) / CHUNK_LEN as u64
+}
+
+/// The plaintext offsets of chunk `index` of a storage of `file_size` bytes
+fn chunk_range(file_size: u64, index: u64) -> Range<u64> {
+    let start = index * CHUNK_LEN as u64;
+    start..cmp::min(start + CHUNK_LEN as u64, file_size)
+}
+
+/// Where chunk `index` is sealed in a file whose prefix is `prefix_len` bytes long
+fn sealed_chunk_offset(prefix_len: u64, index: u64) -> u64 {
+    prefix_len + index * SEALED_CHUNK_LEN
+}
+
+/// The hash of the nonces of the chunks that are sealed in a file, see `EncryptedStorageHeader`
+fn nonces_hash(nonces: &[[u8; NONCE_LEN]]) -> Hash {
+    let mut hasher = Hasher::default();
+    nonces.iter().for_each(|nonce| hasher.hash(nonce));
+    hasher.result()
+}
+
+/// An encrypted storage file, which is read and written a chunk at a time.  Nothing is decrypted
+/// but the chunks that are read, and nothing is sealed but the chunks that are written.
+#[derive(Debug)]
+pub struct EncryptedStorage {
+    file: Mutex<File>,
+    master_key: Arc<MasterKey>,
+    data_key: DataKey,
+    prefix_len: u64,
+    file_size: u64,
+    /// The header as it is to be written, and whether it has changed since it last was.  Its data
+    /// key is only wrapped again once it is written.
+    header: Mutex<(EncryptedStorageHeader, bool)>,
+    /// The nonce each chunk is sealed under in the file, which it must still be when it is read
+    nonces: Mutex<Vec<[u8; NONCE_LEN]>>,
+}
+
+impl EncryptedStorage {
+    /// Create the file of an empty storage of `file_size` bytes at `path`, sealed under a new data
+    /// key wrapped by `master_key`, and bound to `binding`
+    pub fn create(
+        path: &Path,
+        master_key: Arc<MasterKey>,
+        binding: StorageBinding,
+        file_size: u64,
+    ) -> io::Result<Self> {
+        let data_key = DataKey::new_rand();
+        let header = EncryptedStorageHeader::new(
+            &master_key,
+            &data_key,
+            binding,
+            binding,
+            file_size,
+            0,
+            nonces_hash(&[]),
+        );
+        let prefix = header.to_prefix();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.write_all(&prefix)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            master_key,
+            data_key,
+            prefix_len: prefix.len() as u64,
+            file_size,
+            header: Mutex::new((header, false)),
+            nonces: Mutex::new(vec![[0; NONCE_LEN]; num_chunks(file_size) as usize]),
+        })
+    }
+
+    /// Open the encrypted storage at `path`, unwrapping its data key with `master_key`.  Errors
+    /// if the storage is not bound to `binding`, or if any of its sealed chunks fail to decrypt,
+    /// or are not the ones its header was written with; they are decrypted one at a time, and
+    /// only to check them.
+    pub fn open(
+        path: &Path,
+        master_key: Arc<MasterKey>,
+        binding: StorageBinding,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len();
+        let (header, prefix_len) = EncryptedStorageHeader::read_prefix(&mut BufReader::new(&file))?;
+        if header.binding != binding {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is sealed as storage {}, not {}",
+                    path.display(),
+                    header.binding,
+                    binding
+                ),
+            ));
+        }
+        let data_key = header.unwrap_data_key(&master_key)?;
+        let num_stored_chunks = header.num_stored_chunks();
+        let stored_end = match num_stored_chunks.checked_sub(1) {
+            Some(last_chunk) => {
+                let range = chunk_range(header.file_size, last_chunk);
+                sealed_chunk_offset(prefix_len, last_chunk)
+                    + (NONCE_LEN + TAG_LEN) as u64
+                    + (range.end - range.start)
+            }
+            None => prefix_len,
+        };
+        if len < stored_end {
+            return Err(corrupt(&format!("encrypted storage {}", path.display())));
+        }
+
+        let storage = Self {
+            file: Mutex::new(file),
+            master_key,
+            data_key,
+            prefix_len,
+            file_size: header.file_size,
+            nonces: Mutex::new(vec![[0; NONCE_LEN]; num_chunks(header.file_size) as usize]),
+            header: Mutex::new((header, false)),
+        };
+        let mut chunk = vec![0u8; CHUNK_LEN];
+        let mut nonces = Vec::with_capacity(num_stored_chunks as usize);
+        for index in 0..num_stored_chunks {
+            let range = chunk_range(storage.file_size, index);
+            nonces.push(
+                storage
+                    .read_sealed_chunk(index, &mut chunk[..(range.end - range.start) as usize])?,
+            );
+        }
+        if nonces_hash(&nonces) != storage.header.lock().unwrap().0.nonces_hash {
+            return Err(corrupt(&format!("encrypted storage {}", path.display())));
+        }
+        storage.nonces.lock().unwrap()[..nonces.len()].copy_from_slice(&nonces);
+        Ok(storage)
+    }
+
+    /// The size of the append vec
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// How many bytes from the start of the append vec are sealed in the file
+    pub fn stored_len(&self) -> u64 {
+        self.header.lock().unwrap().0.stored_len
+    }
+
+    /// The id of the master key the storage is encrypted under
+    pub fn master_key_id(&self) -> Hash {
+        self.master_key.id()
+    }
+
+    /// The indexes of the chunks that hold the bytes in `range`
+    pub fn chunks_in(&self, range: Range<usize>) -> Range<usize> {
+        if range.start >= range.end {
+            return 0..0;
+        }
+        range.start / CHUNK_LEN..(range.end + CHUNK_LEN - 1) / CHUNK_LEN
+    }
+
+    /// The bytes of the append vec that chunk `index` holds
+    pub fn chunk_range(&self, index: usize) -> Range<usize> {
+        let range = chunk_range(self.file_size, index as u64);
+        range.start as usize..range.end as usize
+    }
+
+    /// How many chunks the append vec is made up of
+    pub fn num_chunks(&self) -> usize {
+        num_chunks(self.file_size) as usize
+    }
+
+    /// Decrypt chunk `index` into `chunk`, which must be as long as the chunk.  A chunk that is
+    /// not sealed in the file is all zeros.  Errors if the chunk in the file is not the one that
+    /// was last sealed there.
+    pub fn read_chunk(&self, index: u64, chunk: &mut [u8]) -> io::Result<()> {
+        let range = chunk_range(self.file_size, index);
+        if chunk.len() as u64 != range.end - range.start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "buffer is not as long as the chunk",
+            ));
+        }
+        if index >= self.header.lock().unwrap().0.num_stored_chunks() {
+            chunk.fill(0);
+            return Ok(());
+        }
+        let nonce = self.read_sealed_chunk(index, chunk)?;
+        if nonce != self.nonces.lock().unwrap()[index as usize] {
+            return Err(corrupt("encrypted storage"));
+        }
+        Ok(())
+    }
+
+    /// Decrypt chunk `index` from the file into `chunk`, returning the nonce it is sealed under
+    fn read_sealed_chunk(&self, index: u64, chunk: &mut [u8]) -> io::Result<[u8; NONCE_LEN]> {
+        let chunk_binding = self.header.lock().unwrap().0.chunk_binding;
+        let mut sealed = vec![0u8; NONCE_LEN + chunk.len() + TAG_LEN];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(sealed_chunk_offset(self.prefix_len, index)))?;
+            file.read_exact(&mut sealed)?;
+        }
+        let nonce = <[u8; NONCE_LEN]>::try_from(&sealed[..NONCE_LEN])
+            .expect("the sealed chunk starts with its nonce");
+        let plaintext =
+            self.data_key
+                .open_chunk(chunk_binding, index, nonce, &mut sealed[NONCE_LEN..])?;
+        chunk.copy_from_slice(plaintext);
+        Ok(nonce)
+    }
+
+    /// Seal `chunk`, the whole of chunk `index`, under a fresh nonce, and write it over the chunk
+    /// in the file.  It is only read back once the header says it is stored, see
+    /// `set_stored_len()`, and the file only opens again once the header is written.
+    pub fn write_chunk(&self, index: u64, chunk: &[u8]) -> io::Result<()> {
+        let range = chunk_range(self.file_size, index);
+        if chunk.len() as u64 != range.end - range.start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "buffer is not as long as the chunk",
+            ));
+        }
+        let mut header = self.header.lock().unwrap();
+        let mut sealed = chunk.to_vec();
+        let nonce = self
+            .data_key
+            .seal_chunk(header.0.chunk_binding, index, &mut sealed);
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(sealed_chunk_offset(self.prefix_len, index)))?;
+            file.write_all(&nonce)?;
+            file.write_all(&sealed)?;
+        }
+        self.nonces.lock().unwrap()[index as usize] = nonce;
+        header.1 = true;
+        Ok(())
+    }
+
+    /// Say in the header that the first `stored_len` bytes are sealed in the file, once it is
+    /// next written
+    pub fn set_stored_len(&self, stored_len: u64) {
+        let mut header = self.header.lock().unwrap();
+        if header.0.stored_len != stored_len {
+            header.0.stored_len = stored_len;
+            header.1 = true;
+        }
+    }
+
+    /// Bind the storage to `binding` instead, once the header is next written.  Only the data key
+    /// is wrapped again; the sealed chunks stay as they are, and stay sealed for the binding they
+    /// were sealed for, unless nothing is stored, e.g. because the storage is being recycled.
+    pub fn set_binding(&self, binding: StorageBinding) {
+        let mut header = self.header.lock().unwrap();
+        if header.0.binding != binding {
+            header.0.binding = binding;
+            if header.0.stored_len == 0 {
+                header.0.chunk_binding = binding;
+            }
+            header.1 = true;
+        }
+    }
+
+    /// Write the header over the one in the file if it changed, and sync the file
+    pub fn write_header_and_sync(&self) -> io::Result<()> {
+        let mut header = self.header.lock().unwrap();
+        let mut file = self.file.lock().unwrap();
+        if header.1 {
+            let nonces_hash = {
+                let nonces = self.nonces.lock().unwrap();
+                nonces_hash(&nonces[..header.0.num_stored_chunks() as usize])
+            };
+            header.0 = EncryptedStorageHeader::new(
+                &self.master_key,
+                &self.data_key,
+                header.0.binding,
+                header.0.chunk_binding,
+                self.file_size,
+                header.0.stored_len,
+                nonces_hash,
+            );
+            let prefix = header.0.to_prefix();
+            assert_eq!(prefix.len() as u64, self.prefix_len);
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&prefix)?;
+            header.1 = false;
+        }
+        file.sync_data()
+    }
+}
+
+/// Seal the plain append vec at `path`, of which the first `stored_len` bytes are stored, under a
+/// new data key wrapped by `master_key`, and bound to `binding`.  The sealed file is written a
+/// chunk at a time next to `path`, and renamed over it once it is complete and synced, so `path`
+/// always holds a whole storage.
+pub fn seal_plain_storage(
+    path: &Path,
+    master_key: Arc<MasterKey>,
+    binding: StorageBinding,
+    stored_len: u64,
+) -> io::Result<()> {
+    let mut plain_file = File::open(path)?;
+    let plain_metadata = plain_file.metadata()?;
+    let file_size = plain_metadata.len();
+    if stored_len > file_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} only holds {} bytes of accounts, not {}",
+                path.display(),
+                file_size,
+                stored_len
+            ),
+        ));
+    }
+    let mut tmp_file_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_file_name.push(".encrypting");
+    let tmp_path = path.with_file_name(tmp_file_name);
+
+    let write_tmp_file = || -> io::Result<()> {
+        let storage = EncryptedStorage::create(&tmp_path, master_key, binding, file_size)?;
+        fs::set_permissions(&tmp_path, plain_metadata.permissions())?;
+        storage.set_stored_len(stored_len);
+        let mut chunk = vec![0u8; CHUNK_LEN];
+        for index in 0..num_chunks(stored_len) {
+            let range = chunk_range(file_size, index);
+            let chunk = &mut chunk[..(range.end - range.start) as usize];
+            plain_file.read_exact(chunk)?;
+            storage.write_chunk(index, chunk)?;
+        }
+        storage.write_header_and_sync()
+    };
+    write_tmp_file()
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|err| {
+            let _ = fs::remove_file(&tmp_path);
+            err
+        })
+}
+
+/// Bind the encrypted storage at `path`, which is bound to `from`, to `to` instead, e.g. once it
+/// has been renamed to another append vec id.  Only the header at the front of the file is
+/// rewritten.
+pub fn rebind_encrypted_storage(
+    path: &Path,
+    master_key: &MasterKey,
+    from: StorageBinding,
+    to: StorageBinding,
+) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let (header, prefix_len) = EncryptedStorageHeader::read_prefix(&mut BufReader::new(&file))?;
+    if header.binding != from {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is sealed as storage {}, not {}",
+                path.display(),
+                header.binding,
+                from
+            ),
+        ));
+    }
+    let data_key = header.unwrap_data_key(master_key)?;
+    let header = header.rewrapped(master_key, &data_key, to);
+    let prefix = header.to_prefix();
+    assert_eq!(prefix.len() as u64, prefix_len);
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&prefix)?;
+    file.sync_data()
+}
+
+/// Rewrap the data key of the encrypted storage that `reader` is at the start of, which is
+/// wrapped by `old_master_key`, with `new_master_key`.  Returns what to replace the start of the
+/// storage with, and how long the start it replaces is; the sealed chunks after it do not change.
+pub fn rewrap_encrypted_storage(
+    reader: &mut impl Read,
+    old_master_key: &MasterKey,
+    new_master_key: &MasterKey,
+) -> io::Result<(Vec<u8>, u64)> {
+    let (header, prefix_len) = EncryptedStorageHeader::read_prefix(reader)?;
+    let data_key = header.unwrap_data_key(old_master_key)?;
+    let header = header.rewrapped(new_master_key, &data_key, header.binding);
+    Ok((header.to_prefix(), prefix_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINDING: StorageBinding = StorageBinding { slot: 123, id: 0 };
+
+    /// Seal `plaintext`, the first bytes of a storage of `file_size` bytes, at `path`
+    fn seal(master_key: &Arc<MasterKey>, path: &Path, plaintext: &[u8], file_size: u64) {
+        let mut plain = plaintext.to_vec();
+        plain.resize(file_size as usize, 0);
+        fs::write(path, &plain).unwrap();
+        seal_plain_storage(path, master_key.clone(), BINDING, plaintext.len() as u64).unwrap();
+    }
+
+    fn decrypt(
+        master_key: &Arc<MasterKey>,
+        path: &Path,
+        binding: StorageBinding,
+    ) -> io::Result<Vec<u8>> {
+        let storage = EncryptedStorage::open(path, master_key.clone(), binding)?;
+        let mut decrypted = vec![0u8; storage.file_size() as usize];
+        for index in 0..storage.num_chunks() {
+            let range = storage.chunk_range(index);
+            storage.read_chunk(index as u64, &mut decrypted[range])?;
+        }
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt_storage() {
+        let master_key = Arc::new(MasterKey::new_rand());
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("123.0");
+        for len in [
+            0,
+            1,
+            CHUNK_LEN - 1,
+            CHUNK_LEN,
+            CHUNK_LEN + 1,
+            3 * CHUNK_LEN + 7,
+        ] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let file_size = (len + 100) as u64;
+            seal(&master_key, &path, &plaintext, file_size);
+            assert!(is_encrypted_storage(&path).unwrap());
+            assert!(!dir.path().join("123.0.encrypting").exists());
+
+            let decrypted = decrypt(&master_key, &path, BINDING).unwrap();
+            assert_eq!(decrypted.len() as u64, file_size);
+            assert_eq!(&decrypted[..len], plaintext.as_slice());
+            assert!(decrypted[len..].iter().all(|byte| *byte == 0));
+        }
+
+        // A plain append vec is told apart from an encrypted one
+        fs::write(&path, [0u8; 100]).unwrap();
+        assert!(!is_encrypted_storage(&path).unwrap());
+        assert!(EncryptedStorage::open(&path, master_key.clone(), BINDING).is_err());
+        fs::write(&path, [0u8; 3]).unwrap();
+        assert!(!is_encrypted_storage(&path).unwrap());
+    }
+
+    #[test]
+    fn test_write_chunks_in_place() {
+        let master_key = Arc::new(MasterKey::new_rand());
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("123.0");
+        let file_size = 3 * CHUNK_LEN as u64;
+        let storage =
+            EncryptedStorage::create(&path, master_key.clone(), BINDING, file_size).unwrap();
+        assert_eq!(storage.num_chunks(), 3);
+        assert_eq!(storage.chunks_in(0..0), 0..0);
+        assert_eq!(storage.chunks_in(CHUNK_LEN - 1..CHUNK_LEN + 1), 0..2);
+        assert_eq!(storage.chunks_in(CHUNK_LEN..2 * CHUNK_LEN), 1..2);
+
+        // Only what the header says is stored is read back
+        let first = vec![1u8; CHUNK_LEN];
+        let second = vec![2u8; CHUNK_LEN];
+        storage.write_chunk(0, &first).unwrap();
+        storage.write_chunk(1, &second).unwrap();
+        storage.set_stored_len(2 * CHUNK_LEN as u64);
+        storage.write_header_and_sync().unwrap();
+        let mut chunk = vec![0u8; CHUNK_LEN];
+        storage.read_chunk(1, &mut chunk).unwrap();
+        assert_eq!(chunk, second);
+        storage.read_chunk(2, &mut chunk).unwrap();
+        assert!(chunk.iter().all(|byte| *byte == 0));
+        drop(storage);
+
+        // Sealing one chunk again leaves the others as they were in the file
+        let sealed = fs::read(&path).unwrap();
+        let storage = EncryptedStorage::open(&path, master_key.clone(), BINDING).unwrap();
+        let rewritten = vec![3u8; CHUNK_LEN];
+        storage.write_chunk(1, &rewritten).unwrap();
+        storage.write_header_and_sync().unwrap();
+        drop(storage);
+        let resealed = fs::read(&path).unwrap();
+        let second_offset = resealed.len() - SEALED_CHUNK_LEN as usize;
+        let first_offset = second_offset - SEALED_CHUNK_LEN as usize;
+        assert_eq!(
+            resealed[first_offset..second_offset],
+            sealed[first_offset..second_offset]
+        );
+        assert_ne!(resealed[second_offset..], sealed[second_offset..]);
+        let decrypted = decrypt(&master_key, &path, BINDING).unwrap();
+        assert_eq!(&decrypted[..CHUNK_LEN], first.as_slice());
+        assert_eq!(&decrypted[CHUNK_LEN..2 * CHUNK_LEN], rewritten.as_slice());
+
+        // The chunk it was sealed over cannot be put back, with either header
+        let mut stale_chunk = resealed.clone();
+        stale_chunk[second_offset..].copy_from_slice(&sealed[second_offset..]);
+        fs::write(&path, &stale_chunk).unwrap();
+        assert!(decrypt(&master_key, &path, BINDING).is_err());
+        let mut stale_header = sealed.clone();
+        stale_header[second_offset..].copy_from_slice(&resealed[second_offset..]);
+        fs::write(&path, &stale_header).unwrap();
+        assert!(decrypt(&master_key, &path, BINDING).is_err());
+
+        // Nor once the file is open
+        fs::write(&path, &resealed).unwrap();
+        let storage = EncryptedStorage::open(&path, master_key.clone(), BINDING).unwrap();
+        fs::write(&path, &stale_chunk).unwrap();
+        assert!(storage.read_chunk(1, &mut chunk).is_err());
+    }
+
+    #[test]
+    fn test_rebind_recycled_storage() {
+        let master_key = Arc::new(MasterKey::new_rand());
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("123.0");
+        let file_size = 2 * CHUNK_LEN as u64;
+        let storage =
+            EncryptedStorage::create(&path, master_key.clone(), BINDING, file_size).unwrap();
+        storage.write_chunk(0, &vec![1u8; CHUNK_LEN]).unwrap();
+        storage.set_stored_len(CHUNK_LEN as u64);
+        storage.write_header_and_sync().unwrap();
+        let sealed = fs::read(&path).unwrap();
+
+        // Chunks sealed once the emptied storage is rebound are sealed for its new binding, so
+        // the ones sealed before cannot pass for them
+        let recycled = StorageBinding { slot: 456, id: 1 };
+        storage.set_stored_len(0);
+        storage.set_binding(recycled);
+        let refilled = vec![2u8; CHUNK_LEN];
+        storage.write_chunk(0, &refilled).unwrap();
+        storage.set_stored_len(CHUNK_LEN as u64);
+        storage.write_header_and_sync().unwrap();
+        drop(storage);
+        assert_eq!(
+            &decrypt(&master_key, &path, recycled).unwrap()[..CHUNK_LEN],
+            refilled.as_slice()
+        );
+        let mut stale = fs::read(&path).unwrap();
+        let prefix_len = stale.len() - SEALED_CHUNK_LEN as usize;
+        stale[prefix_len..].copy_from_slice(&sealed[prefix_len..]);
+        fs::write(&path, &stale).unwrap();
+        assert!(decrypt(&master_key, &path, recycled).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_tampered_storage() {
+        let master_key = Arc::new(MasterKey::new_rand());
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("123.0");
+        let plaintext: Vec<u8> = (0..2 * CHUNK_LEN + 7).map(|i| (i % 251) as u8).collect();
+        seal(&master_key, &path, &plaintext, 3 * CHUNK_LEN as u64);
+        let encrypted = fs::read(&path).unwrap();
+        let prefix_len =
+            encrypted.len() - (2 * SEALED_CHUNK_LEN as usize + NONCE_LEN + 7 + TAG_LEN);
+        assert!(!encrypted
+            .windows(CHUNK_LEN)
+            .any(|window| window == &plaintext[..CHUNK_LEN]));
+
+        let check = |tampered: &[u8]| {
+            fs::write(&path, tampered).unwrap();
+            assert!(decrypt(&master_key, &path, BINDING).is_err());
+        };
+
+        // Flipping any bit is caught, in the chunks or in the sizes of the header
+        let mut tampered = encrypted.clone();
+        tampered[prefix_len + CHUNK_LEN + 3] ^= 1;
+        check(&tampered);
+        let mut tampered = encrypted.clone();
+        tampered[prefix_len - 1] ^= 1;
+        check(&tampered);
+
+        // So is cutting the storage short, even on a chunk boundary
+        check(&encrypted[..encrypted.len() - 1]);
+        check(&encrypted[..prefix_len + 2 * SEALED_CHUNK_LEN as usize]);
+
+        // And reordering its chunks
+        let chunk = |index: usize| {
+            let start = prefix_len + index * SEALED_CHUNK_LEN as usize;
+            &encrypted[start..start + SEALED_CHUNK_LEN as usize]
+        };
+        let mut reordered = encrypted[..prefix_len].to_vec();
+        reordered.extend_from_slice(chunk(1));
+        reordered.extend_from_slice(chunk(0));
+        reordered.extend_from_slice(&encrypted[prefix_len + 2 * SEALED_CHUNK_LEN as usize..]);
+        check(&reordered);
+
+        // Or opening it as another storage, or with another key
+        fs::write(&path, &encrypted).unwrap();
+        assert!(decrypt(&master_key, &path, StorageBinding { slot: 123, id: 1 }).is_err());
+        assert!(decrypt(&Arc::new(MasterKey::new_rand()), &path, BINDING).is_err());
+        assert_eq!(
+            &decrypt(&master_key, &path, BINDING).unwrap()[..plaintext.len()],
+            plaintext.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_rebind_encrypted_storage() {
+        let master_key = Arc::new(MasterKey::new_rand());
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("123.0");
+        let plaintext: Vec<u8> = (0..CHUNK_LEN + 7).map(|i| (i % 251) as u8).collect();
+        seal(&master_key, &path, &plaintext, 2 * CHUNK_LEN as u64);
+        let encrypted = fs::read(&path).unwrap();
+
+        // Only the header is rewritten
+        let to = StorageBinding { slot: 123, id: 7 };
+        assert!(rebind_encrypted_storage(&path, &master_key, to, BINDING).is_err());
+        rebind_encrypted_storage(&path, &master_key, BINDING, to).unwrap();
+        let rebound = fs::read(&path).unwrap();
+        assert_eq!(rebound.len(), encrypted.len());
+        let chunks_offset = encrypted.len() - (SEALED_CHUNK_LEN as usize + NONCE_LEN + 7 + TAG_LEN);
+        assert_eq!(rebound[chunks_offset..], encrypted[chunks_offset..]);
+        assert!(decrypt(&master_key, &path, BINDING).is_err());
+        assert_eq!(
+            &decrypt(&master_key, &path, to).unwrap()[..plaintext.len()],
+            plaintext.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_wrap_unwrap_and_rewrap() {
+        let master_key = Arc::new(MasterKey::new_rand());
+        let data_key = DataKey::new_rand();
+        let wrapped_data_key = master_key.wrap(&data_key, b"associated");
+        assert_eq!(wrapped_data_key.master_key_id(), &master_key.id());
+        assert_eq!(
+            master_key.unwrap(&wrapped_data_key, b"associated").unwrap(),
+            data_key
+        );
+
+        // Other associated data is rejected
+        assert!(master_key.unwrap(&wrapped_data_key, b"other").is_err());
+
+        // So is the wrong master key
+        let other_master_key = Arc::new(MasterKey::new_rand());
+        assert!(other_master_key
+            .unwrap(&wrapped_data_key, b"associated")
+            .is_err());
+
+        // And a corrupt wrapped key
+        let mut corrupt_wrapped_data_key = wrapped_data_key.clone();
+        corrupt_wrapped_data_key.wrapped_key[0] ^= 1;
+        assert!(master_key
+            .unwrap(&corrupt_wrapped_data_key, b"associated")
+            .is_err());
+
+        // Rotating the master key keeps the sealed chunks of a storage
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("123.0");
+        let plaintext: Vec<u8> = (0..CHUNK_LEN + 7).map(|i| (i % 251) as u8).collect();
+        seal(&master_key, &path, &plaintext, 2 * CHUNK_LEN as u64);
+        let encrypted = fs::read(&path).unwrap();
+        let mut reader = encrypted.as_slice();
+        let (prefix, prefix_len) =
+            rewrap_encrypted_storage(&mut reader, &master_key, &other_master_key).unwrap();
+        let mut rotated = prefix;
+        rotated.extend_from_slice(&encrypted[prefix_len as usize..]);
+        fs::write(&path, &rotated).unwrap();
+        assert!(decrypt(&master_key, &path, BINDING).is_err());
+        assert_eq!(
+            &decrypt(&other_master_key, &path, BINDING).unwrap()[..plaintext.len()],
+            plaintext.as_slice()
+        );
+
+        // Only encrypted storages can be rewrapped
+        assert!(
+            rewrap_encrypted_storage(&mut &[0u8; 100][..], &master_key, &other_master_key).is_err()
+        );
+    }
+
+    #[test]
+    fn test_master_key_providers() {
+        let master_key = MasterKey::new_rand();
+        assert_eq!(master_key.master_key().unwrap(), master_key);
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let key_path = dir.path().join("key");
+        fs::write(&key_path, master_key.key).unwrap();
+        assert_eq!(
+            MasterKeyFile::new(key_path.clone()).master_key().unwrap(),
+            master_key
+        );
+        fs::write(&key_path, &master_key.key[1..]).unwrap();
+        assert!(MasterKeyFile::new(key_path).master_key().is_err());
+
+        // Programs can write the key out as it is, or in hex
+        let hex: String = master_key
+            .key
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_eq!(
+            MasterKey::from_bytes(&master_key.key),
+            Some(master_key.clone())
+        );
+        assert_eq!(
+            MasterKey::from_bytes(format!("{}\n", hex).as_bytes()),
+            Some(master_key.clone())
+        );
+        assert_eq!(MasterKey::from_bytes(&hex.as_bytes()[2..]), None);
+        assert_eq!(
+            MasterKey::from_bytes(format!("{}zz", &hex[2..]).as_bytes()),
+            None
+        );
+
+        // A program that fails, or that is not there, gives no key
+        assert!(MasterKeyCommand::new(PathBuf::from("false"))
+            .master_key()
+            .is_err());
+        assert!(MasterKeyCommand::new(dir.path().join("missing"))
+            .master_key()
+            .is_err());
+    }
+}
@@ -241,6 +241,10 @@ pub mod nonce_must_be_writable {
     solana_sdk::declare_id!("BiCU7M5w8ZCMykVSyhZ7Q3m2SWoR2qrEQ86ERcDX77ME");
 }
 
+pub mod gossip_snapshot_archive_sizes {
+    solana_sdk::declare_id!("CZkDLsVeAqBgkbPQ7ykCaaG1YNXZZZEnihNWdp1j4mbC");
+}
+
 lazy_static! {
     /// Map of feature identifiers to user-visible description
     pub static ref FEATURE_NAMES: HashMap<Pubkey, &'static str> = [
@@ -296,6 +300,7 @@ lazy_static! {
         (add_compute_budget_program::id(), "Add compute_budget_program"),
         (reject_deployment_of_unresolved_syscalls::id(), "Reject deployment of programs with unresolved syscall symbols"),
         (nonce_must_be_writable::id(), "nonce must be writable"),
+        (gossip_snapshot_archive_sizes::id(), "gossip snapshot archive sizes"),
         /*************** ADD NEW FEATURES HERE ***************/
     ]
     .iter()
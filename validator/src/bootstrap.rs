@@ -12,12 +12,8 @@ use {
         gossip_service::GossipService,
     },
     solana_runtime::{
-        snapshot_archive_info::SnapshotArchiveInfoGetter,
-        snapshot_package::SnapshotType,
-        snapshot_utils::{
-            self, DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-        },
+        snapshot_archive_info::SnapshotArchiveInfoGetter, snapshot_config::SnapshotConfig,
+        snapshot_package::SnapshotType, snapshot_utils,
     },
     solana_sdk::{
         clock::Slot,
@@ -41,6 +37,22 @@ use {
     },
 };
 
+/// The snapshot configuration that downloaded snapshots are kept under, which is the validator's
+/// own, or the defaults if it does not generate snapshots
+fn snapshot_config_or_default(validator_config: &ValidatorConfig) -> SnapshotConfig {
+    validator_config.snapshot_config.clone().unwrap_or_default()
+}
+
+/// Whether the validator is configured to load snapshot archives with encrypted storages
+fn has_storage_encryption_key(validator_config: &ValidatorConfig) -> bool {
+    validator_config
+        .accounts_db_config
+        .as_ref()
+        .map_or(false, |accounts_db_config| {
+            accounts_db_config.storage_encryption_key.is_some()
+        })
+}
+
 #[derive(Debug)]
 pub struct RpcBootstrapConfig {
     pub no_genesis_fetch: bool,
@@ -545,20 +557,15 @@ mod without_incremental_snapshots {
                                 gossip.take().unwrap();
                             cluster_info.save_contact_info();
                             gossip_exit_flag.store(true, Ordering::Relaxed);
-                            let (maximum_full_snapshot_archives_to_retain, maximum_incremental_snapshot_archives_to_retain) = if let Some(snapshot_config) =
-                                validator_config.snapshot_config.as_ref()
-                            {
-                                (snapshot_config.maximum_full_snapshot_archives_to_retain, snapshot_config.maximum_incremental_snapshot_archives_to_retain)
-                            } else {
-                                (DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN, DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN)
-                            };
+                            let snapshot_config = snapshot_config_or_default(validator_config);
                             let ret = download_snapshot_archive(
                                 &rpc_contact_info.rpc,
                                 snapshot_archives_dir,
                                 snapshot_hash,
                                 SnapshotType::FullSnapshot,
-                                maximum_full_snapshot_archives_to_retain,
-                                maximum_incremental_snapshot_archives_to_retain,
+                                snapshot_config.maximum_full_snapshot_archives_to_retain,
+                                snapshot_config.maximum_incremental_snapshot_archives_to_retain,
+                                has_storage_encryption_key(validator_config),
                                 use_progress_bar,
                                 &mut Some(Box::new(|download_progress: &DownloadProgressRecord| {
                                     debug!("Download progress: {:?}", download_progress);
@@ -807,8 +814,19 @@ mod with_incremental_snapshots {
     struct PeerSnapshotHash {
         rpc_contact_info: ContactInfo,
         snapshot_hash: SnapshotHash,
+        /// How many bytes of snapshot archives downloading `snapshot_hash` from the peer takes,
+        /// if the peer advertises the sizes of all of them
+        download_size: Option<u64>,
     }
 
+    /// Advertised download sizes less than the median of them divided by this are implausible for
+    /// archives of the same snapshot, and are ignored
+    const MAX_DOWNLOAD_SIZE_DEVIATION: u64 = 4;
+
+    /// Peers whose advertised download size is at most the median of them plus this fraction of
+    /// it are all picked from alike
+    const DOWNLOAD_SIZE_TOLERANCE_DIVISOR: u64 = 4;
+
     /// A snapshot hash.  In this context (bootstrap *with* incremental snapshots), a snapshot hash
     /// is _both_ a full snapshot hash and an (optional) incremental snapshot hash.
     #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
@@ -1070,6 +1088,7 @@ mod with_incremental_snapshots {
                 let PeerSnapshotHash {
                     rpc_contact_info: final_rpc_contact_info,
                     snapshot_hash: final_snapshot_hash,
+                    ..
                 } = get_final_peer_snapshot_hash(&peer_snapshot_hashes);
                 info!(
                     "Highest available snapshot slot is {}, available from {} node{}: {:?}",
@@ -1230,9 +1249,12 @@ mod with_incremental_snapshots {
             }
 
             if let Some(snapshot_hash) = highest_snapshot_hash {
+                let download_size =
+                    get_download_size_for_peer(cluster_info, &rpc_peer.id, &snapshot_hash);
                 peer_snapshot_hashes.push(PeerSnapshotHash {
                     rpc_contact_info: rpc_peer.clone(),
                     snapshot_hash,
+                    download_size,
                 });
             };
         }
@@ -1320,14 +1342,43 @@ mod with_incremental_snapshots {
     }
 
     /// Get a final peer from the remaining peer snapshot hashes.  At this point all the snapshot
-    /// hashes should (must) be the same, and only the peers are different.  Pick an element from
-    /// the slice at random and return it.
+    /// hashes should (must) be the same, and only the peers are different.  The archives of the
+    /// same snapshot can still differ in size by their archive format and compression level, so
+    /// if any peers advertise plausible archive sizes, those whose download is well above the
+    /// median are left out.  Peers that do not advertise a size are kept.  The sizes are not
+    /// verified, so advertising a smaller one does not make a peer any likelier to be picked than
+    /// the others within the tolerance.  Pick an element from those left at random and return it.
     fn get_final_peer_snapshot_hash(peer_snapshot_hashes: &[PeerSnapshotHash]) -> PeerSnapshotHash {
         assert!(!peer_snapshot_hashes.is_empty());
 
+        let mut download_sizes: Vec<_> = peer_snapshot_hashes
+            .iter()
+            .filter_map(|peer_snapshot_hash| peer_snapshot_hash.download_size)
+            .collect();
+        download_sizes.sort_unstable();
+        let candidates: Vec<_> = match download_sizes.get(download_sizes.len() / 2) {
+            None => peer_snapshot_hashes.iter().collect(),
+            Some(&median_download_size) => {
+                let min_plausible_download_size =
+                    median_download_size / MAX_DOWNLOAD_SIZE_DEVIATION;
+                let max_download_size = median_download_size
+                    .saturating_add(median_download_size / DOWNLOAD_SIZE_TOLERANCE_DIVISOR);
+                peer_snapshot_hashes
+                    .iter()
+                    .filter(|peer_snapshot_hash| {
+                        peer_snapshot_hash
+                            .download_size
+                            .map_or(true, |download_size| {
+                                (min_plausible_download_size..=max_download_size)
+                                    .contains(&download_size)
+                            })
+                    })
+                    .collect()
+            }
+        };
+
         // pick a final rpc peer at random
-        let final_peer_snapshot_hash =
-            &peer_snapshot_hashes[thread_rng().gen_range(0, peer_snapshot_hashes.len())];
+        let final_peer_snapshot_hash = candidates[thread_rng().gen_range(0, candidates.len())];
 
         // It is a programmer bug if the assert fires!  By the time this function is called, the
         // only remaining `incremental_snapshot_hashes` should all be the same.
@@ -1452,20 +1503,7 @@ mod with_incremental_snapshots {
         desired_snapshot_hash: (Slot, Hash),
         snapshot_type: SnapshotType,
     ) -> Result<(), String> {
-        let (
-            maximum_full_snapshot_archives_to_retain,
-            maximum_incremental_snapshot_archives_to_retain,
-        ) = if let Some(snapshot_config) = validator_config.snapshot_config.as_ref() {
-            (
-                snapshot_config.maximum_full_snapshot_archives_to_retain,
-                snapshot_config.maximum_incremental_snapshot_archives_to_retain,
-            )
-        } else {
-            (
-                DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-                DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
-            )
-        };
+        let snapshot_config = snapshot_config_or_default(validator_config);
         *start_progress.write().unwrap() = ValidatorStartProgress::DownloadingSnapshot {
             slot: desired_snapshot_hash.0,
             rpc_addr: rpc_contact_info.rpc,
@@ -1475,8 +1513,9 @@ mod with_incremental_snapshots {
             snapshot_archives_dir,
             desired_snapshot_hash,
             snapshot_type,
-            maximum_full_snapshot_archives_to_retain,
-            maximum_incremental_snapshot_archives_to_retain,
+            snapshot_config.maximum_full_snapshot_archives_to_retain,
+            snapshot_config.maximum_incremental_snapshot_archives_to_retain,
+            has_storage_encryption_key(validator_config),
             use_progress_bar,
             &mut Some(Box::new(|download_progress: &DownloadProgressRecord| {
                 debug!("Download progress: {:?}", download_progress);
@@ -1591,6 +1630,32 @@ mod with_incremental_snapshots {
             )
     }
 
+    /// Get the total size of the snapshot archives of `snapshot_hash` that a peer advertises in
+    /// CRDS, if it advertises the sizes of all of them
+    fn get_download_size_for_peer(
+        cluster_info: &ClusterInfo,
+        peer: &Pubkey,
+        snapshot_hash: &SnapshotHash,
+    ) -> Option<u64> {
+        let crds_value::SnapshotArchiveSizes {
+            full, incremental, ..
+        } = cluster_info.get_snapshot_archive_sizes_for_node(peer)?;
+        let find_size = |sizes: &[(Slot, u64)], slot: Slot| {
+            sizes
+                .iter()
+                .find(|(size_slot, _size)| *size_slot == slot)
+                .map(|(_slot, size)| *size)
+        };
+        let full_size = find_size(&full, snapshot_hash.full.0)?;
+        match snapshot_hash.incr {
+            None => Some(full_size),
+            Some((incremental_slot, _hash)) => {
+                let incremental_size = find_size(&incremental, incremental_slot)?;
+                Some(full_size.saturating_add(incremental_size))
+            }
+        }
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -1607,6 +1672,7 @@ mod with_incremental_snapshots {
                         full: full_snapshot_hash,
                         incr: incremental_snapshot_hash,
                     },
+                    download_size: None,
                 }
             }
         }
@@ -1816,5 +1882,48 @@ mod with_incremental_snapshots {
             retain_peer_snapshot_hashes_with_highest_incremental_snapshot_slot(&mut actual);
             assert_eq!(expected, actual);
         }
+
+        #[test]
+        fn test_get_final_peer_snapshot_hash_with_download_sizes() {
+            let contact_info = default_contact_info_for_tests();
+            let peer_snapshot_hash = |download_size| PeerSnapshotHash {
+                download_size,
+                ..PeerSnapshotHash::new(
+                    contact_info.clone(),
+                    (200_000, Hash::default()),
+                    Some((200_100, Hash::default())),
+                )
+            };
+
+            // The median is 11_000, so 100 is implausibly small and 20_000 is above the tolerance,
+            // while the peer without a size stays a candidate
+            let peer_snapshot_hashes = vec![
+                peer_snapshot_hash(None),
+                peer_snapshot_hash(Some(100)),
+                peer_snapshot_hash(Some(11_000)),
+                peer_snapshot_hash(Some(20_000)),
+                peer_snapshot_hash(Some(10_000)),
+            ];
+            let mut picked_download_sizes = HashSet::new();
+            for _ in 0..100 {
+                picked_download_sizes
+                    .insert(get_final_peer_snapshot_hash(&peer_snapshot_hashes).download_size);
+            }
+            // Within the tolerance, the smaller download is not preferred
+            assert_eq!(
+                picked_download_sizes,
+                [None, Some(10_000), Some(11_000)]
+                    .iter()
+                    .cloned()
+                    .collect::<HashSet<_>>()
+            );
+
+            // Without any sizes advertised, any peer may be picked
+            let peer_snapshot_hashes = vec![peer_snapshot_hash(None), peer_snapshot_hash(None)];
+            assert_eq!(
+                get_final_peer_snapshot_hash(&peer_snapshot_hashes).download_size,
+                None
+            );
+        }
     }
 }
@@ -12,6 +12,7 @@ use {
         input_validators::{
             is_keypair, is_keypair_or_ask_keyword, is_niceness_adjustment_valid, is_parsable,
             is_pow2, is_pubkey, is_pubkey_or_keypair, is_slot, is_valid_percentage,
+            is_within_range,
         },
         keypair::SKIP_SEED_PHRASE_VALIDATION_ARG,
     },
@@ -21,12 +22,13 @@ use {
     },
     solana_core::{
         ledger_cleanup_service::{DEFAULT_MAX_LEDGER_SHREDS, DEFAULT_MIN_MAX_LEDGER_SHREDS},
+        snapshot_packager_service::MIN_SNAPSHOT_GOSSIP_PUSH_INTERVAL_MS,
         tower_storage,
         tpu::DEFAULT_TPU_COALESCE_MS,
         validator::{is_snapshot_config_valid, Validator, ValidatorConfig, ValidatorStartProgress},
     },
     solana_gossip::{
-        cluster_info::{Node, VALIDATOR_PORT_RANGE},
+        cluster_info::{Node, MAX_SNAPSHOT_HASHES, VALIDATOR_PORT_RANGE},
         contact_info::ContactInfo,
     },
     solana_ledger::blockstore_db::BlockstoreRecoveryMode,
@@ -37,21 +39,26 @@ use {
     solana_rpc::{rpc::JsonRpcConfig, rpc_pubsub_service::PubSubConfig},
     solana_runtime::{
         accounts_db::{
-            AccountShrinkThreshold, AccountsDbConfig, DEFAULT_ACCOUNTS_SHRINK_OPTIMIZE_TOTAL_SPACE,
-            DEFAULT_ACCOUNTS_SHRINK_RATIO,
+            AccountShrinkThreshold, AccountsDbConfig, SnapshotRestoreNotifyMode,
+            DEFAULT_ACCOUNTS_SHRINK_OPTIMIZE_TOTAL_SPACE, DEFAULT_ACCOUNTS_SHRINK_RATIO,
         },
         accounts_index::{
             AccountIndex, AccountSecondaryIndexes, AccountSecondaryIndexesIncludeExclude,
             AccountsIndexConfig,
         },
-        hardened_unpack::MAX_GENESIS_ARCHIVE_UNPACKED_SIZE,
+        append_vec::AppendVecValidation,
+        hardened_unpack::{FilePermissions, MAX_GENESIS_ARCHIVE_UNPACKED_SIZE},
+        serde_snapshot::SnapshotStorageLimits,
         snapshot_config::SnapshotConfig,
+        snapshot_hooks::{CommandHook, SnapshotHook, SnapshotHooks},
         snapshot_utils::{
-            self, ArchiveFormat, SnapshotVersion, DEFAULT_FULL_SNAPSHOT_ARCHIVE_INTERVAL_SLOTS,
+            self, ArchiveFormat, FullSnapshotSchedule, SnapshotDurability, SnapshotVersion,
+            DEFAULT_FULL_SNAPSHOT_ARCHIVE_INTERVAL_SLOTS,
             DEFAULT_INCREMENTAL_SNAPSHOT_ARCHIVE_INTERVAL_SLOTS,
             DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN,
             DEFAULT_MAX_INCREMENTAL_SNAPSHOT_ARCHIVES_TO_RETAIN,
         },
+        storage_encryption::{MasterKeyCommand, MasterKeyFile, MasterKeyProvider},
     },
     solana_sdk::{
         clock::{Slot, DEFAULT_S_PER_SLOT},
@@ -517,6 +524,7 @@ pub fn main() {
         .to_string();
     let default_rpc_threads = num_cpus::get().to_string();
     let default_accountsdb_repl_threads = num_cpus::get().to_string();
+    let default_accountsdb_plugin_restore_notify_threads = num_cpus::get().to_string();
     let default_maximum_full_snapshot_archives_to_retain =
         &DEFAULT_MAX_FULL_SNAPSHOT_ARCHIVES_TO_RETAIN.to_string();
     let default_maximum_incremental_snapshot_archives_to_retain =
@@ -530,6 +538,12 @@ pub fn main() {
     let default_accounts_shrink_optimize_total_space =
         &DEFAULT_ACCOUNTS_SHRINK_OPTIMIZE_TOTAL_SPACE.to_string();
     let default_accounts_shrink_ratio = &DEFAULT_ACCOUNTS_SHRINK_RATIO.to_string();
+    let default_snapshot_max_storages = &SnapshotStorageLimits::DEFAULT.max_storages.to_string();
+    let default_snapshot_max_accounts_per_storage = &SnapshotStorageLimits::DEFAULT
+        .max_accounts_per_storage
+        .to_string();
+    let default_snapshot_max_storage_bytes =
+        &SnapshotStorageLimits::DEFAULT.max_storage_bytes.to_string();
 
     let matches = App::new(crate_name!()).about(crate_description!())
         .version(solana_version::version!())
@@ -893,6 +907,18 @@ pub fn main() {
                 .default_value(default_full_snapshot_archive_interval_slots)
                 .help("Number of slots between generating full snapshots")
         )
+        .arg(
+            Arg::with_name("full_snapshot_schedule")
+                .long("full-snapshot-schedule")
+                .possible_values(&["interval", "epoch-boundary", "interval-and-epoch-boundary"])
+                .default_value("interval")
+                .value_name("SCHEDULE")
+                .takes_value(true)
+                .help("When to generate full snapshots: every full snapshot interval, of the \
+                       first root in each epoch, or both.  The first root in an epoch is the \
+                       first one that the node sets, which may be past the first slot of \
+                       the epoch")
+        )
         .arg(
             Arg::with_name("maximum_full_snapshots_to_retain")
                 .long("maximum-full-snapshots-to-retain")
@@ -986,7 +1012,11 @@ pub fn main() {
                 .validator(is_parsable::<SnapshotVersion>)
                 .takes_value(true)
                 .default_value(SnapshotVersion::default().into())
-                .help("Output snapshot version"),
+                .help(
+                    "Output snapshot version. Only 1.3.0 snapshots record the accounts delta \
+                     hashes of their most recent slots, which are spot-checked against their \
+                     storages when the snapshots are loaded",
+                ),
         )
         .arg(
             Arg::with_name("limit_ledger_size")
@@ -1378,6 +1408,28 @@ pub fn main() {
                 .hidden(true)
                 .help("Specify the configuration file for the AccountsDb plugin."),
         )
+        .arg(
+            Arg::with_name("accountsdb_plugin_restore_notify_mode")
+                .long("accountsdb-plugin-restore-notify-mode")
+                .possible_values(&["inline", "background", "pool"])
+                .default_value("background")
+                .value_name("MODE")
+                .takes_value(true)
+                .help("How to notify the AccountsDb plugins of the accounts restored from a \
+                       snapshot at startup: inline after generating the index, on a background \
+                       thread while generating it, or on a pool of \
+                       --accountsdb-plugin-restore-notify-threads threads while generating it"),
+        )
+        .arg(
+            Arg::with_name("accountsdb_plugin_restore_notify_threads")
+                .long("accountsdb-plugin-restore-notify-threads")
+                .value_name("NUMBER")
+                .takes_value(true)
+                .validator(is_parsable::<usize>)
+                .default_value(&default_accountsdb_plugin_restore_notify_threads)
+                .help("Number of threads to notify the AccountsDb plugins of the restored \
+                       accounts with, when --accountsdb-plugin-restore-notify-mode is pool"),
+        )
         .arg(
             Arg::with_name("halt_on_known_validators_accounts_hash_mismatch")
                 .alias("halt-on-trusted-validators-accounts-hash-mismatch")
@@ -1407,6 +1459,194 @@ pub fn main() {
                 .takes_value(true)
                 .help("Snapshot archive format to use."),
         )
+        .arg(
+            Arg::with_name("snapshot_archive_compression_level")
+                .long("snapshot-archive-compression-level")
+                .value_name("LEVEL")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .validator(is_parsable::<i32>)
+                .help("Compression level to use for snapshot archives, instead of the archive \
+                       format's default"),
+        )
+        .arg(
+            Arg::with_name("snapshot_config")
+                .long("snapshot-config")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "full_snapshot_interval_slots",
+                    "incremental_snapshot_interval_slots",
+                    "full_snapshot_schedule",
+                    "full_snapshot_retained_epochs",
+                    "maximum_full_snapshots_to_retain",
+                    "maximum_incremental_snapshots_to_retain",
+                    "snapshot_archive_format",
+                    "snapshot_archive_compression_level",
+                    "snapshot_version",
+                    "snapshot_durability",
+                    "snapshot_file_permissions",
+                    "snapshot_packager_niceness_adj",
+                    "snapshot_gossip_advertisement_depth",
+                    "snapshot_gossip_push_interval_ms",
+                    "snapshot_profile",
+                ])
+                .help("Load the snapshot configuration from a YAML FILE. The other snapshot \
+                       generation arguments, but for --snapshot-hook-command, cannot be given \
+                       along with it. Missing settings get their defaults, and the directories \
+                       default to --snapshots"),
+        )
+        .arg(
+            Arg::with_name("snapshot_durability")
+                .long("snapshot-durability")
+                .possible_values(&["none", "fsync-file", "fsync-dir"])
+                .default_value("none")
+                .value_name("POLICY")
+                .takes_value(true)
+                .help("Whether to fsync bank snapshot files and snapshot archives once written. \
+                       fsync-dir also fsyncs the directories they are written into"),
+        )
+        .arg(
+            Arg::with_name("full_snapshot_retained_epochs")
+                .long("full-snapshot-retained-epochs")
+                .value_name("NUMBER")
+                .takes_value(true)
+                .validator(is_parsable::<u64>)
+                .help("Leave the account storages of slots from before the last NUMBER epochs \
+                       out of full snapshot archives, keeping only the latest versions of the \
+                       accounts in them. For RPC nodes that do not serve older history"),
+        )
+        .arg(
+            Arg::with_name("snapshot_profile")
+                .long("snapshot-profile")
+                .takes_value(false)
+                .help("Write the timings and byte counts of each phase of creating and loading \
+                       snapshots as JSON next to their archives. Also turned on by setting \
+                       SOLANA_SNAPSHOT_PROFILE"),
+        )
+        .arg(
+            Arg::with_name("snapshot_hook_command")
+                .long("snapshot-hook-command")
+                .value_name("PROGRAM")
+                .takes_value(true)
+                .multiple(true)
+                .help("Run PROGRAM on each snapshot archive before it is published, with the \
+                       path of the archive and the slot and hash of its snapshot as the \
+                       arguments. The archive is not published if PROGRAM fails. May be \
+                       specified multiple times, the programs are run in that order"),
+        )
+        .arg(
+            Arg::with_name("snapshot_gossip_advertisement_depth")
+                .long("snapshot-gossip-advertisement-depth")
+                .value_name("NUMBER")
+                .takes_value(true)
+                .validator(|s| is_within_range(s, 1, MAX_SNAPSHOT_HASHES))
+                .help("Advertise the hashes of at most NUMBER of the latest full snapshots, and \
+                       of the incremental snapshots based on the latest of them, over gossip \
+                       [default: as many as are retained]"),
+        )
+        .arg(
+            Arg::with_name("snapshot_gossip_push_interval_ms")
+                .long("snapshot-gossip-push-interval-ms")
+                .value_name("MILLISECONDS")
+                .takes_value(true)
+                .validator(|s| {
+                    let interval_ms = u64::from_str(&s).map_err(|e| e.to_string())?;
+                    if interval_ms < MIN_SNAPSHOT_GOSSIP_PUSH_INTERVAL_MS {
+                        return Err(format!(
+                            "snapshot gossip push interval must be at least {} ms",
+                            MIN_SNAPSHOT_GOSSIP_PUSH_INTERVAL_MS
+                        ));
+                    }
+                    Ok(())
+                })
+                .help("Push the advertised snapshot hashes and archive sizes to gossip again \
+                       every MILLISECONDS, and not only as new snapshots are made"),
+        )
+        .arg(
+            Arg::with_name("accounts_db_storage_encryption_key")
+                .long("accounts-db-storage-encryption-key")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with("accounts_db_storage_encryption_key_command")
+                .help("Encrypt the account storages, in the accounts paths and in snapshot \
+                       archives, under the 32 byte master key in FILE, and use it to decrypt \
+                       them when loading a snapshot. Storages are decrypted into unlinked \
+                       files in TMPDIR as they are read, so TMPDIR should be on a file system \
+                       trusted with the plaintext"),
+        )
+        .arg(
+            Arg::with_name("accounts_db_storage_encryption_key_command")
+                .long("accounts-db-storage-encryption-key-command")
+                .value_name("PROGRAM")
+                .takes_value(true)
+                .help("Like --accounts-db-storage-encryption-key, but with the master key \
+                       written to stdout by PROGRAM, as 32 bytes or in hex, e.g. from a key \
+                       management service. PROGRAM is run once, at startup"),
+        )
+        .arg(
+            Arg::with_name("snapshot_file_permissions")
+                .long("snapshot-file-permissions")
+                .possible_values(&["default", "owner-only"])
+                .default_value("default")
+                .value_name("POLICY")
+                .takes_value(true)
+                .help("Permissions to give snapshot archives and the account storages unpacked \
+                       from them. owner-only makes files 0600 and directories 0700, instead of \
+                       0644 and 0755"),
+        )
+        .arg(
+            Arg::with_name("accounts_db_storage_validation")
+                .long("accounts-db-storage-validation")
+                .possible_values(&["header-only", "sampled", "full"])
+                .default_value("full")
+                .value_name("LEVEL")
+                .takes_value(true)
+                .help("How thoroughly to check the account storages of a local snapshot before \
+                       loading from it. header-only and sampled boot faster, but only trust \
+                       snapshots this node wrote itself. Snapshots downloaded from other \
+                       nodes are always fully checked, on every restart"),
+        )
+        .arg(
+            Arg::with_name("snapshot_max_storages")
+                .long("snapshot-max-storages")
+                .value_name("NUMBER")
+                .takes_value(true)
+                .validator(is_parsable::<usize>)
+                .default_value(default_snapshot_max_storages)
+                .help("Refuse to load a snapshot with more account storages than this"),
+        )
+        .arg(
+            Arg::with_name("snapshot_max_accounts_per_storage")
+                .long("snapshot-max-accounts-per-storage")
+                .value_name("NUMBER")
+                .takes_value(true)
+                .validator(is_parsable::<usize>)
+                .default_value(default_snapshot_max_accounts_per_storage)
+                .help("Refuse to load a snapshot with an account storage of more accounts than \
+                       this, whatever the --accounts-db-storage-validation level"),
+        )
+        .arg(
+            Arg::with_name("snapshot_max_storage_bytes")
+                .long("snapshot-max-storage-bytes")
+                .value_name("BYTES")
+                .takes_value(true)
+                .validator(is_parsable::<u64>)
+                .default_value(default_snapshot_max_storage_bytes)
+                .help("Refuse to load a snapshot whose account storages add up to more bytes \
+                       than this"),
+        )
+        .arg(
+            Arg::with_name("deterministic_snapshot_serialization")
+                .long("deterministic-snapshot-serialization")
+                .takes_value(false)
+                .conflicts_with_all(&[
+                    "accounts_db_storage_encryption_key",
+                    "accounts_db_storage_encryption_key_command",
+                ])
+                .help("Serialize and archive snapshots deterministically, so nodes with the \
+                       same bank produce the same bytes"),
+        )
         .arg(
             Arg::with_name("max_genesis_archive_unpacked_size")
                 .long("max-genesis-archive-unpacked-size")
@@ -1548,6 +1788,15 @@ pub fn main() {
             .validator(is_parsable::<usize>)
             .takes_value(true)
             .help("How many accounts to add to stress the system. Accounts are ignored in operations related to correctness."))
+         .arg(Arg::with_name("accounts_filler_lazy")
+            .long("accounts-filler-lazy")
+            .requires("accounts_filler_count")
+            .takes_value(false)
+            .help("Add the filler accounts in the background once the bank has been loaded from the snapshot, instead of before starting up"))
+         .arg(Arg::with_name("accounts_db_verify_hash_in_background")
+            .long("accounts-db-verify-hash-in-background")
+            .takes_value(false)
+            .help("Verify the accounts hash of the snapshot in the background instead of before starting up. The validator does not vote until the verification passes, and exits if it fails."))
          .arg(
             Arg::with_name("accounts_db_test_hash_calculation")
                 .long("accounts-db-test-hash-calculation")
@@ -2118,10 +2367,73 @@ pub fn main() {
     }
 
     let filler_account_count = value_t!(matches, "accounts_filler_count", usize).ok();
+    let storage_validation = {
+        let storage_validation_str =
+            value_t_or_exit!(matches, "accounts_db_storage_validation", String);
+        match storage_validation_str.as_str() {
+            "header-only" => AppendVecValidation::HeaderOnly,
+            "sampled" => AppendVecValidation::Sampled,
+            "full" => AppendVecValidation::Full,
+            _ => panic!(
+                "Storage validation level not recognized: {}",
+                storage_validation_str
+            ),
+        }
+    };
+    let snapshot_restore_notify_mode = {
+        let notify_mode_str =
+            value_t_or_exit!(matches, "accountsdb_plugin_restore_notify_mode", String);
+        match notify_mode_str.as_str() {
+            "inline" => SnapshotRestoreNotifyMode::Inline,
+            "background" => SnapshotRestoreNotifyMode::Background,
+            "pool" => SnapshotRestoreNotifyMode::Pool(value_t_or_exit!(
+                matches,
+                "accountsdb_plugin_restore_notify_threads",
+                usize
+            )),
+            _ => panic!("Restore notify mode not recognized: {}", notify_mode_str),
+        }
+    };
+    let storage_encryption_key_provider = matches
+        .value_of("accounts_db_storage_encryption_key")
+        .map(|key_file| {
+            Box::new(MasterKeyFile::new(PathBuf::from(key_file))) as Box<dyn MasterKeyProvider>
+        })
+        .or_else(|| {
+            matches
+                .value_of("accounts_db_storage_encryption_key_command")
+                .map(|program| {
+                    Box::new(MasterKeyCommand::new(PathBuf::from(program)))
+                        as Box<dyn MasterKeyProvider>
+                })
+        });
+    let storage_encryption_key = storage_encryption_key_provider.map(|provider| {
+        Arc::new(provider.master_key().unwrap_or_else(|err| {
+            eprintln!("Unable to get the storage encryption key: {}", err);
+            exit(1);
+        }))
+    });
     let mut accounts_db_config = AccountsDbConfig {
         index: Some(accounts_index_config),
         accounts_hash_cache_path: Some(ledger_path.clone()),
         filler_account_count,
+        lazy_filler_accounts: matches.is_present("accounts_filler_lazy"),
+        verify_accounts_hash_in_background: matches
+            .is_present("accounts_db_verify_hash_in_background"),
+        snapshot_restore_notify_mode,
+        storage_validation,
+        deterministic_snapshot_serialization: matches
+            .is_present("deterministic_snapshot_serialization"),
+        snapshot_storage_limits: SnapshotStorageLimits {
+            max_storages: value_t_or_exit!(matches, "snapshot_max_storages", usize),
+            max_accounts_per_storage: value_t_or_exit!(
+                matches,
+                "snapshot_max_accounts_per_storage",
+                usize
+            ),
+            max_storage_bytes: value_t_or_exit!(matches, "snapshot_max_storage_bytes", u64),
+        },
+        storage_encryption_key,
         ..AccountsDbConfig::default()
     };
 
@@ -2351,12 +2663,6 @@ pub fn main() {
     });
 
     let maximum_local_snapshot_age = value_t_or_exit!(matches, "maximum_local_snapshot_age", u64);
-    let maximum_full_snapshot_archives_to_retain =
-        value_t_or_exit!(matches, "maximum_full_snapshots_to_retain", usize);
-    let maximum_incremental_snapshot_archives_to_retain =
-        value_t_or_exit!(matches, "maximum_incremental_snapshots_to_retain", usize);
-    let snapshot_packager_niceness_adj =
-        value_t_or_exit!(matches, "snapshot_packager_niceness_adj", i8);
     let minimal_snapshot_download_speed =
         value_t_or_exit!(matches, "minimal_snapshot_download_speed", f32);
     let maximum_snapshot_download_abort =
@@ -2368,64 +2674,181 @@ pub fn main() {
         ledger_path.clone()
     };
     let bank_snapshots_dir = snapshot_archives_dir.join("snapshot");
-    fs::create_dir_all(&bank_snapshots_dir).unwrap_or_else(|err| {
-        eprintln!(
-            "Failed to create snapshots directory {:?}: {}",
-            bank_snapshots_dir, err
-        );
-        exit(1);
-    });
 
-    let archive_format = {
-        let archive_format_str = value_t_or_exit!(matches, "snapshot_archive_format", String);
-        match archive_format_str.as_str() {
-            "bz2" => ArchiveFormat::TarBzip2,
-            "gzip" => ArchiveFormat::TarGzip,
-            "zstd" => ArchiveFormat::TarZstd,
-            "tar" | "none" => ArchiveFormat::Tar,
-            _ => panic!("Archive format not recognized: {}", archive_format_str),
+    let snapshot_hooks = SnapshotHooks::new(
+        values_t!(matches, "snapshot_hook_command", PathBuf)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|program| Arc::new(CommandHook::new(program)) as Arc<dyn SnapshotHook>)
+            .collect(),
+    );
+    let snapshot_config = if let Some(snapshot_config_file) = matches.value_of("snapshot_config") {
+        let mut snapshot_config: SnapshotConfig =
+            solana_cli_config::load_config_file(snapshot_config_file).unwrap_or_else(|err| {
+                eprintln!(
+                    "Unable to load snapshot config from {}: {}",
+                    snapshot_config_file, err
+                );
+                exit(1);
+            });
+        if snapshot_config.snapshot_archives_dir.as_os_str().is_empty() {
+            snapshot_config.snapshot_archives_dir = snapshot_archives_dir;
         }
-    };
+        if snapshot_config.bank_snapshots_dir.as_os_str().is_empty() {
+            snapshot_config.bank_snapshots_dir =
+                snapshot_config.snapshot_archives_dir.join("snapshot");
+        }
+        // Neither the accounts hash settings nor the hooks live in the file
+        snapshot_config.accounts_hash_use_index =
+            validator_config.accounts_db_use_index_hash_calculation;
+        snapshot_config.accounts_hash_debug_verify =
+            validator_config.accounts_db_test_hash_calculation;
+        snapshot_config.hooks = snapshot_hooks;
+        snapshot_config
+    } else {
+        let maximum_full_snapshot_archives_to_retain =
+            value_t_or_exit!(matches, "maximum_full_snapshots_to_retain", usize);
+        let maximum_incremental_snapshot_archives_to_retain =
+            value_t_or_exit!(matches, "maximum_incremental_snapshots_to_retain", usize);
+        let snapshot_packager_niceness_adj =
+            value_t_or_exit!(matches, "snapshot_packager_niceness_adj", i8);
+
+        let archive_format = {
+            let archive_format_str = value_t_or_exit!(matches, "snapshot_archive_format", String);
+            match archive_format_str.as_str() {
+                "bz2" => ArchiveFormat::TarBzip2,
+                "gzip" => ArchiveFormat::TarGzip,
+                "zstd" => ArchiveFormat::TarZstd,
+                "tar" | "none" => ArchiveFormat::Tar,
+                _ => panic!("Archive format not recognized: {}", archive_format_str),
+            }
+        };
 
-    let snapshot_version =
-        matches
-            .value_of("snapshot_version")
-            .map_or(SnapshotVersion::default(), |s| {
-                s.parse::<SnapshotVersion>().unwrap_or_else(|err| {
-                    eprintln!("Error: {}", err);
-                    exit(1)
-                })
-            });
+        let archive_compression_level =
+            value_t!(matches, "snapshot_archive_compression_level", i32).ok();
+
+        let snapshot_durability = {
+            let snapshot_durability_str = value_t_or_exit!(matches, "snapshot_durability", String);
+            match snapshot_durability_str.as_str() {
+                "none" => SnapshotDurability::NoFsync,
+                "fsync-file" => SnapshotDurability::FsyncFile,
+                "fsync-dir" => SnapshotDurability::FsyncDir,
+                _ => panic!(
+                    "Snapshot durability not recognized: {}",
+                    snapshot_durability_str
+                ),
+            }
+        };
 
-    let incremental_snapshot_interval_slots =
-        value_t_or_exit!(matches, "incremental_snapshot_interval_slots", u64);
-    let (full_snapshot_archive_interval_slots, incremental_snapshot_archive_interval_slots) =
-        if incremental_snapshot_interval_slots > 0 {
-            if matches.is_present("incremental_snapshots") {
-                (
-                    value_t_or_exit!(matches, "full_snapshot_interval_slots", u64),
-                    incremental_snapshot_interval_slots,
-                )
-            } else {
-                (incremental_snapshot_interval_slots, Slot::MAX)
+        let snapshot_file_permissions = {
+            let snapshot_file_permissions_str =
+                value_t_or_exit!(matches, "snapshot_file_permissions", String);
+            match snapshot_file_permissions_str.as_str() {
+                "default" => FilePermissions::DEFAULT,
+                "owner-only" => FilePermissions::OWNER_ONLY,
+                _ => panic!(
+                    "Snapshot file permissions not recognized: {}",
+                    snapshot_file_permissions_str
+                ),
             }
+        };
+
+        let snapshot_version =
+            matches
+                .value_of("snapshot_version")
+                .map_or(SnapshotVersion::default(), |s| {
+                    s.parse::<SnapshotVersion>().unwrap_or_else(|err| {
+                        eprintln!("Error: {}", err);
+                        exit(1)
+                    })
+                });
+
+        let incremental_snapshot_interval_slots =
+            value_t_or_exit!(matches, "incremental_snapshot_interval_slots", u64);
+        let (full_snapshot_archive_interval_slots, incremental_snapshot_archive_interval_slots) =
+            if incremental_snapshot_interval_slots > 0 {
+                if matches.is_present("incremental_snapshots") {
+                    (
+                        value_t_or_exit!(matches, "full_snapshot_interval_slots", u64),
+                        incremental_snapshot_interval_slots,
+                    )
+                } else {
+                    (incremental_snapshot_interval_slots, Slot::MAX)
+                }
+            } else {
+                (Slot::MAX, Slot::MAX)
+            };
+        let full_snapshot_schedule = if full_snapshot_archive_interval_slots == Slot::MAX {
+            // Snapshots are disabled
+            FullSnapshotSchedule::Interval
         } else {
-            (Slot::MAX, Slot::MAX)
+            let full_snapshot_schedule_str =
+                value_t_or_exit!(matches, "full_snapshot_schedule", String);
+            match full_snapshot_schedule_str.as_str() {
+                "interval" => FullSnapshotSchedule::Interval,
+                "epoch-boundary" => FullSnapshotSchedule::EpochBoundary,
+                "interval-and-epoch-boundary" => FullSnapshotSchedule::IntervalAndEpochBoundary,
+                _ => panic!(
+                    "Full snapshot schedule not recognized: {}",
+                    full_snapshot_schedule_str
+                ),
+            }
         };
 
-    validator_config.snapshot_config = Some(SnapshotConfig {
-        full_snapshot_archive_interval_slots,
-        incremental_snapshot_archive_interval_slots,
-        bank_snapshots_dir,
-        snapshot_archives_dir: snapshot_archives_dir.clone(),
-        archive_format,
-        snapshot_version,
-        maximum_full_snapshot_archives_to_retain,
-        maximum_incremental_snapshot_archives_to_retain,
-        accounts_hash_use_index: validator_config.accounts_db_use_index_hash_calculation,
-        accounts_hash_debug_verify: validator_config.accounts_db_test_hash_calculation,
-        packager_thread_niceness_adj: snapshot_packager_niceness_adj,
+        SnapshotConfig {
+            full_snapshot_archive_interval_slots,
+            full_snapshot_schedule,
+            incremental_snapshot_archive_interval_slots,
+            bank_snapshots_dir,
+            snapshot_archives_dir,
+            archive_format,
+            archive_compression_level,
+            snapshot_version,
+            durability: snapshot_durability,
+            full_snapshot_retained_epochs: value_t!(matches, "full_snapshot_retained_epochs", u64)
+                .ok(),
+            file_permissions: snapshot_file_permissions,
+            maximum_full_snapshot_archives_to_retain,
+            maximum_incremental_snapshot_archives_to_retain,
+            gossip_advertisement_depth: value_t!(
+                matches,
+                "snapshot_gossip_advertisement_depth",
+                usize
+            )
+            .ok(),
+            gossip_push_interval_ms: value_t!(matches, "snapshot_gossip_push_interval_ms", u64)
+                .ok(),
+            accounts_hash_use_index: validator_config.accounts_db_use_index_hash_calculation,
+            accounts_hash_debug_verify: validator_config.accounts_db_test_hash_calculation,
+            packager_thread_niceness_adj: snapshot_packager_niceness_adj,
+            profile: matches.is_present("snapshot_profile"),
+            hooks: snapshot_hooks,
+        }
+    };
+    fs::create_dir_all(&snapshot_config.bank_snapshots_dir).unwrap_or_else(|err| {
+        eprintln!(
+            "Failed to create snapshots directory {:?}: {}",
+            snapshot_config.bank_snapshots_dir, err
+        );
+        exit(1);
     });
+    if let Some(archive_compression_level) = snapshot_config.archive_compression_level {
+        if !snapshot_utils::is_valid_archive_compression_level(
+            snapshot_config.archive_format,
+            archive_compression_level,
+        ) {
+            eprintln!(
+                "Invalid snapshot archive compression level for {:?} archives: {}",
+                snapshot_config.archive_format, archive_compression_level
+            );
+            exit(1);
+        }
+    }
+    let snapshot_archives_dir = snapshot_config.snapshot_archives_dir.clone();
+    let full_snapshot_archive_interval_slots = snapshot_config.full_snapshot_archive_interval_slots;
+    let incremental_snapshot_archive_interval_slots =
+        snapshot_config.incremental_snapshot_archive_interval_slots;
+    validator_config.snapshot_config = Some(snapshot_config);
 
     validator_config.accounts_hash_interval_slots =
         value_t_or_exit!(matches, "accounts-hash-interval-slots", u64);